@@ -0,0 +1,550 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use libactionkv::{
+    debug_data_file, display_key, ActionKV, ByteStr, CsvEncoding, CsvImport, Options,
+};
+use serde_json::json;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+Usage:
+    akv_mem.exe FILE get KEY
+    akv_mem.exe FILE delete KEY
+    akv_mem.exe FILE insert KEY VALUE
+    akv_mem.exe FILE update KEY VALUE
+    akv_mem.exe FILE debug-index
+    akv_mem.exe FILE hot-prefixes
+    akv_mem.exe FILE admin-log
+    akv_mem.exe FILE compact
+    akv_mem.exe FILE verify [--deep|--fast]
+    akv_mem.exe FILE scan PATTERN
+    akv_mem.exe FILE grep REGEX
+    akv_mem.exe FILE delete-glob PATTERN
+    akv_mem.exe FILE import [--format jsonl] INPUT|-
+    akv_mem.exe FILE import --format csv --key-col COLUMN --value-col COLUMN
+                [--key-type text|hex|i64] [--value-type text|hex|i64]
+                [--delimiter CHAR] INPUT|-
+    akv_mem.exe FILE export [--sorted|--history] OUTPUT|-
+    akv_mem.exe FILE sync SOURCE
+    akv_mem.exe debug-segment DATA_FILE [--from OFFSET]
+
+Long running operations (import, compact, export, verify) show a progress
+bar on stderr, --quiet hides it and --json-progress prints JSON lines
+instead. verify --deep checks the checksums of all records on every core,
+--fast only checks the framing of the records and the index. verify opens
+the store read only, what it finds wrong is left for it to report.
+
+export writes JSON lines in data file order, --sorted sorts them by key
+so exports of the same data are identical and can be diffed. --history
+writes every put and delete still in the data file in the order they were
+made, importing it replays the deletes.
+
+admin-log lists the compactions, repairs, purges, restores and setting
+changes done to the store, oldest first. It and debug-index open the store
+read only, so the index is shown as it is on disk.
+
+debug-segment walks the records of a data file (the data file of a store
+directory) without opening the store, printing a JSON line per record with
+its offset, header fields, whether its checksum holds and the start of its
+key, from the first record or from OFFSET.
+";
+
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq)]
+enum ProgressMode {
+    Bar,
+    Quiet,
+    Json,
+}
+
+/// Progress of one long running operation, `total` is `None` when it isn't
+/// known up front (e.g. an import from stdin).
+enum Reporter {
+    Quiet,
+    Bar(ProgressBar),
+    Json {
+        op: &'static str,
+        started: Instant,
+        last_report: Option<Instant>,
+    },
+}
+
+impl Reporter {
+    fn new(mode: ProgressMode, op: &'static str, total: Option<u64>, bytes: bool) -> Self {
+        match mode {
+            ProgressMode::Quiet => Reporter::Quiet,
+            ProgressMode::Json => Reporter::Json {
+                op,
+                started: Instant::now(),
+                last_report: None,
+            },
+            ProgressMode::Bar => {
+                let (done, rate) = if bytes {
+                    ("{bytes}/{total_bytes}", "{binary_bytes_per_sec}")
+                } else {
+                    ("{pos}/{len}", "{per_sec}")
+                };
+                let template = match total {
+                    Some(_) => format!(
+                        "{{msg}} [{{bar:40}}] {} {{percent}}% {} eta {{eta}}",
+                        done, rate
+                    ),
+                    None => format!(
+                        "{{msg}} {{spinner}} {} {}",
+                        done.split('/').next().unwrap(),
+                        rate
+                    ),
+                };
+                let bar = match total {
+                    Some(total) => ProgressBar::new(total),
+                    None => ProgressBar::new_spinner(),
+                };
+                bar.set_style(ProgressStyle::with_template(&template).unwrap());
+                bar.set_message(op);
+                Reporter::Bar(bar)
+            }
+        }
+    }
+    fn update(&mut self, done: u64, total: Option<u64>) {
+        match self {
+            Reporter::Quiet => {}
+            Reporter::Bar(bar) => bar.set_position(done),
+            Reporter::Json {
+                op,
+                started,
+                last_report,
+            } => {
+                let now = Instant::now();
+                if last_report.is_some_and(|last| now - last < JSON_PROGRESS_INTERVAL)
+                    && Some(done) != total
+                {
+                    return;
+                }
+                *last_report = Some(now);
+                let elapsed = (now - *started).as_secs_f64();
+                let rate = if elapsed > 0.0 {
+                    done as f64 / elapsed
+                } else {
+                    0.0
+                };
+                let eta = match total {
+                    Some(total) if rate > 0.0 => Some(total.saturating_sub(done) as f64 / rate),
+                    _ => None,
+                };
+                eprintln!(
+                    "{}",
+                    json!({
+                        "op": op,
+                        "done": done,
+                        "total": total,
+                        "elapsed_secs": elapsed,
+                        "rate": rate,
+                        "eta_secs": eta,
+                    })
+                );
+            }
+        }
+    }
+    fn finish(&self) {
+        if let Reporter::Bar(bar) = self {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+fn csv_encoding(name: &str) -> CsvEncoding {
+    match name {
+        "text" => CsvEncoding::Text,
+        "hex" => CsvEncoding::Hex,
+        "i64" => CsvEncoding::I64,
+        _ => {
+            eprintln!("Unsupported column type {:?}", name);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Reports how many bytes were read through it.
+struct Counting<'a, R> {
+    inner: R,
+    read: u64,
+    total: Option<u64>,
+    reporter: &'a mut Reporter,
+}
+
+impl<R: Read> Read for Counting<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read > 0 {
+            self.read += read as u64;
+            self.reporter.update(self.read, self.total);
+        }
+        Ok(read)
+    }
+}
+
+/// Prints the records of a data file, opening the store could repair it.
+fn debug_segment(args: &[String]) {
+    let mut path = None;
+    let mut from = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--from" => match rest.next().map(|offset| offset.parse::<u64>()) {
+                Some(Ok(offset)) => from = Some(offset),
+                _ => {
+                    eprintln!("--from takes a byte offset");
+                    std::process::exit(1);
+                }
+            },
+            file => path = Some(file),
+        }
+    }
+    let path = Path::new(path.expect(USAGE));
+    let records = debug_data_file(path, from).expect("Unable to read data file.");
+    let mut out = io::stdout().lock();
+    for record in records {
+        match record {
+            Ok(record) => writeln!(out, "{}", record).expect("Unable to write to stdout."),
+            Err(err) => {
+                eprintln!("Reading the data file failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut progress_mode = ProgressMode::Bar;
+    let args: Vec<String> = std::env::args()
+        .filter(|arg| match arg.as_str() {
+            "--quiet" => {
+                progress_mode = ProgressMode::Quiet;
+                false
+            }
+            "--json-progress" => {
+                progress_mode = ProgressMode::Json;
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    let f_name = args.get(1).expect(USAGE);
+    if f_name == "debug-segment" {
+        debug_segment(&args[2..]);
+        return;
+    }
+    let op = args.get(2).expect(USAGE).as_ref();
+    let key_option = args.get(3);
+    let value_option = args.get(4);
+
+    // verify and the debugging commands look at the store as they find it,
+    // a writer open would recover a torn index, cut off a torn record and
+    // finish an interrupted compaction first
+    let read_only = matches!(op, "verify" | "debug-index" | "admin-log");
+    let options = Options {
+        read_only,
+        ..Options::default()
+    };
+    let mut s = match ActionKV::open_with_options(Path::new(&f_name), options) {
+        Ok(s) => s,
+        Err(err) if op == "verify" => {
+            eprintln!("Verification failed: {}", err);
+            std::process::exit(1);
+        }
+        Err(err) => panic!("Unable to open file: {}", err),
+    };
+    if op == "verify" {
+        let mode = key_option.map(String::as_str);
+        if !matches!(mode, None | Some("--fast") | Some("--deep")) {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+        let mut reporter = Reporter::new(progress_mode, "verify", Some(0), true);
+        let mut progress = |done, total| {
+            if let Reporter::Bar(bar) = &reporter {
+                bar.set_length(total);
+            }
+            reporter.update(done, Some(total));
+        };
+        let verified = match mode {
+            Some("--fast") => s.verify_fast_with_progress(&mut progress),
+            Some("--deep") => {
+                let workers = thread::available_parallelism().map_or(1, |workers| workers.get());
+                s.verify_deep(workers, &mut progress)
+            }
+            _ => s.verify_with_progress(&mut progress),
+        };
+        reporter.finish();
+        match verified {
+            Ok(report) => println!(
+                "OK: {} records in {} bytes, {} index entries",
+                report.records, report.bytes, report.index_entries
+            ),
+            Err(err) => {
+                eprintln!("Verification failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if op == "debug-index" {
+        let report = s.debug_index().expect("Unable to read index file.");
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return;
+    }
+    if op == "admin-log" {
+        for event in s.admin_log().expect("Unable to read the admin log.") {
+            println!("{}", event);
+        }
+        return;
+    }
+    s.load().expect("Unable to load data from file.");
+    if op == "hot-prefixes" {
+        let hottest = s
+            .recorded_hot_prefixes()
+            .expect("Unable to read recorded access counts.");
+        for (prefix, accesses) in hottest {
+            println!("{}\t{}", accesses, display_key(&prefix));
+        }
+        return;
+    }
+    if op == "import" {
+        let mut rest = args[3..].iter().map(String::as_str);
+        let mut input = None;
+        let mut csv = false;
+        let mut csv_import = CsvImport::new("", "");
+        while let Some(arg) = rest.next() {
+            let mut value = || rest.next().expect(USAGE);
+            match arg {
+                "--format" => match value() {
+                    "jsonl" => csv = false,
+                    "csv" => csv = true,
+                    format => {
+                        eprintln!("Unsupported import format {:?}", format);
+                        std::process::exit(1);
+                    }
+                },
+                "--key-col" => csv_import.key_column = value().to_string(),
+                "--value-col" => csv_import.value_column = value().to_string(),
+                "--key-type" => csv_import.key_encoding = csv_encoding(value()),
+                "--value-type" => csv_import.value_encoding = csv_encoding(value()),
+                "--delimiter" => match value().as_bytes() {
+                    [delimiter] => csv_import.delimiter = *delimiter,
+                    _ => {
+                        eprintln!("The delimiter has to be a single byte");
+                        std::process::exit(1);
+                    }
+                },
+                path => input = Some(path),
+            }
+        }
+        if csv && (csv_import.key_column.is_empty() || csv_import.value_column.is_empty()) {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+        let (reader, total): (Box<dyn Read>, _) = match input.expect(USAGE) {
+            "-" => (Box::new(io::stdin().lock()), None),
+            path => {
+                let file = File::open(path).expect("Unable to open import file.");
+                let len = file.metadata().map(|metadata| metadata.len()).ok();
+                (Box::new(file), len)
+            }
+        };
+        let mut reporter = Reporter::new(progress_mode, "import", total, true);
+        let counting = Counting {
+            inner: reader,
+            read: 0,
+            total,
+            reporter: &mut reporter,
+        };
+        let imported = match csv {
+            true => s.import_csv(counting, &csv_import),
+            false => s.import_jsonl(BufReader::new(counting)),
+        };
+        reporter.finish();
+        match imported {
+            Ok(written) => println!("Imported {} records", written),
+            Err(err) => {
+                eprintln!("Import failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if op == "compact" {
+        let mut reporter = Reporter::new(progress_mode, "compact", Some(0), false);
+        let report = s
+            .compact_with_progress(|done, total| {
+                if let Reporter::Bar(bar) = &reporter {
+                    bar.set_length(total);
+                }
+                reporter.update(done, Some(total));
+            })
+            .expect("Unable to compact the store.");
+        reporter.finish();
+        println!(
+            "Compacted {} -> {} bytes, {} records kept",
+            report.bytes_before, report.bytes_after, report.kept
+        );
+        return;
+    }
+    if op == "export" {
+        let mut sorted = false;
+        let mut history = false;
+        let mut output = None;
+        for arg in &args[3..] {
+            match arg.as_str() {
+                "--sorted" => sorted = true,
+                "--history" => history = true,
+                path => output = Some(path),
+            }
+        }
+        let writer: Box<dyn Write> = match output.expect(USAGE) {
+            "-" => Box::new(io::stdout().lock()),
+            path => Box::new(File::create(path).expect("Unable to create export file.")),
+        };
+        let writer = BufWriter::new(writer);
+        let mut reporter = Reporter::new(progress_mode, "export", Some(0), history);
+        let progress = |done, total| {
+            if let Reporter::Bar(bar) = &reporter {
+                bar.set_length(total);
+            }
+            reporter.update(done, Some(total));
+        };
+        let exported = match (sorted, history) {
+            (true, true) => {
+                eprintln!("--sorted and --history can't be combined");
+                std::process::exit(1);
+            }
+            (true, false) => s.export_jsonl_with_progress(writer, progress),
+            (false, true) => s.export_jsonl_history_with_progress(writer, progress),
+            (false, false) => s.export_jsonl_unsorted_with_progress(writer, progress),
+        };
+        reporter.finish();
+        match exported {
+            Ok(written) => eprintln!("Exported {} records", written),
+            Err(err) => {
+                eprintln!("Export failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let key: &ByteStr = key_option.expect(USAGE).as_ref();
+    match op {
+        "get" => match s.get(key).unwrap() {
+            Some(value) => {
+                println!("{:?}", String::from_utf8(value).unwrap())
+            }
+            None => {
+                println!("{:?} not found", String::from_utf8(Vec::from(key)).unwrap())
+            }
+        },
+        "scan" => {
+            for key in s.scan_glob(key).expect("Unable to scan keys.") {
+                println!("{}", display_key(&key));
+            }
+        }
+        "grep" => {
+            let pattern = key_option.expect(USAGE);
+            let workers = thread::available_parallelism().map_or(1, |workers| workers.get());
+            match s.search_values_parallel(pattern, workers) {
+                Ok(keys) => {
+                    for key in keys {
+                        println!("{}", display_key(&key));
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Search failed: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        "sync" => {
+            let source = key_option.expect(USAGE);
+            let mut source =
+                ActionKV::open(Path::new(source)).expect("Unable to open source store");
+            let report = s
+                .repair_from(&mut source)
+                .expect("Unable to sync the store.");
+            for key in &report.only_here {
+                println!("only in {}: {}", f_name, display_key(key));
+            }
+            println!(
+                "Synced {} differing buckets: {} keys added, {} updated, {} only in {}",
+                report.buckets,
+                report.added.len(),
+                report.updated.len(),
+                report.only_here.len(),
+                f_name
+            );
+        }
+        "delete-glob" => {
+            let deleted = s.delete_glob(key).expect("Unable to delete keys.");
+            println!("Deleted {} keys", deleted);
+        }
+        "delete" => match s.delete(key) {
+            Ok(_) => {
+                println!(
+                    "Value under {:?} was deleted",
+                    String::from_utf8(Vec::from(key)).unwrap()
+                )
+            }
+            Err(_) => {
+                println!(
+                    "{:?} not found in file",
+                    String::from_utf8(Vec::from(key)).unwrap()
+                )
+            }
+        },
+        "insert" => {
+            let value = value_option.expect(USAGE).as_ref();
+            match s.insert(key, value) {
+                Ok(_) => {
+                    println!(
+                        "{:?} was inserted under {:?}",
+                        String::from_utf8(Vec::from(value)).unwrap(),
+                        String::from_utf8(Vec::from(key)).unwrap()
+                    );
+                }
+                Err(_) => {
+                    println!(
+                        "{:?} was not inserted under {:?}. {:?}",
+                        String::from_utf8(Vec::from(value)).unwrap(),
+                        String::from_utf8(Vec::from(key)).unwrap(),
+                        &USAGE
+                    )
+                }
+            }
+        }
+        "update" => {
+            let value = value_option.expect(USAGE).as_ref();
+            match s.update(key, value) {
+                Ok(_) => {
+                    println!(
+                        "{:?} was updated under {:?}",
+                        String::from_utf8(Vec::from(value)).unwrap(),
+                        String::from_utf8(Vec::from(key)).unwrap()
+                    );
+                }
+                Err(_) => {
+                    println!(
+                        "{:?} was not updated under {:?}. {:?}",
+                        String::from_utf8(Vec::from(value)).unwrap(),
+                        String::from_utf8(Vec::from(key)).unwrap(),
+                        &USAGE
+                    )
+                }
+            }
+        }
+        _ => {
+            eprintln!("{:?}", &USAGE)
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use libactionkv::ActionKV;
+use std::fs;
+use std::path::Path;
+
+const DIR: &str = "bench_insert";
+const RECORDS: usize = 200;
+
+fn fresh_store() -> ActionKV {
+    let _ = fs::remove_dir_all(DIR);
+    ActionKV::open(Path::new(DIR)).expect("failed to open bench store")
+}
+
+/// `RECORDS` inserts of small values, each persisting the index like a
+/// regular insert does.
+fn inserts(c: &mut Criterion) {
+    c.bench_function("insert 200 small records", |b| {
+        b.iter_batched(
+            fresh_store,
+            |mut store| {
+                for i in 0..RECORDS {
+                    store
+                        .insert(format!("key{}", i).as_bytes(), b"value")
+                        .expect("insert failed");
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    let _ = fs::remove_dir_all(DIR);
+}
+
+fn gets(c: &mut Criterion) {
+    let mut store = fresh_store();
+    for i in 0..RECORDS {
+        store
+            .insert(format!("key{}", i).as_bytes(), b"value")
+            .expect("insert failed");
+    }
+    c.bench_function("get 200 small records", |b| {
+        b.iter(|| {
+            for i in 0..RECORDS {
+                store
+                    .get(format!("key{}", i).as_bytes())
+                    .expect("get failed");
+            }
+        })
+    });
+    drop(store);
+    let _ = fs::remove_dir_all(DIR);
+}
+
+criterion_group!(benches, inserts, gets);
+criterion_main!(benches);
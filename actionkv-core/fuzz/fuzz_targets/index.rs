@@ -0,0 +1,8 @@
+#![no_main]
+
+use libactionkv::fuzzing::decode_index_record;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_index_record(data);
+});
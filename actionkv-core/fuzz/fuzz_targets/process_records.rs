@@ -0,0 +1,13 @@
+#![no_main]
+
+use libactionkv::fuzzing::decode_record;
+use libfuzzer_sys::fuzz_target;
+
+// the first two bytes pick the framing like the data file header flags do
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let flags = u16::from_le_bytes([data[0], data[1]]);
+    let _ = decode_record(&data[2..], flags);
+});
@@ -0,0 +1,132 @@
+use crate::{ActionKV, ActionKvError, ByteStr};
+use std::io;
+
+/// What an `AccessPolicy` is asked about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+    Delete,
+    /// Reading every key under a prefix, the policy gets the prefix.
+    Scan,
+}
+
+/// What an `AccessPolicy` decides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Allow,
+    Deny,
+}
+
+/// Decides whether an operation on a key may go ahead, see
+/// `Options::access_policy`. Keys come canonicalized, as they are stored.
+pub type AccessPolicy = fn(&Operation, &ByteStr) -> Access;
+
+/// Fails with `ActionKvError::AccessDenied` unless `policy` allows `operation`
+/// on `key`, no policy allows everything.
+pub(crate) fn authorize(
+    policy: Option<AccessPolicy>,
+    operation: Operation,
+    key: &ByteStr,
+) -> io::Result<()> {
+    match policy {
+        Some(policy) if policy(&operation, key) == Access::Deny => {
+            Err(ActionKvError::AccessDenied {
+                operation,
+                key: key.to_vec(),
+            }
+            .into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether `policy` lets `key` be read.
+pub(crate) fn readable(policy: Option<AccessPolicy>, key: &ByteStr) -> bool {
+    authorize(policy, Operation::Read, key).is_ok()
+}
+
+impl ActionKV {
+    pub(crate) fn authorize(&self, operation: Operation, key: &ByteStr) -> io::Result<()> {
+        authorize(self.options.access_policy, operation, key)
+    }
+    /// Whether `key` may be read. Operations going over many keys leave out
+    /// the ones it may not rather than fail.
+    pub(crate) fn readable(&self, key: &ByteStr) -> bool {
+        readable(self.options.access_policy, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::Options;
+    use rstest::*;
+    use serial_test::serial;
+
+    /// Plugins may read `plugin:` and `shared:` keys and write `plugin:` ones.
+    fn plugin_policy(operation: &Operation, key: &ByteStr) -> Access {
+        let allowed = match operation {
+            Operation::Read | Operation::Scan => {
+                key.starts_with(b"plugin:") || key.starts_with(b"shared:")
+            }
+            Operation::Write | Operation::Delete => key.starts_with(b"plugin:"),
+        };
+        match allowed {
+            true => Access::Allow,
+            false => Access::Deny,
+        }
+    }
+    fn denied<T: std::fmt::Debug>(result: io::Result<T>) -> Operation {
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        match ActionKvError::from_io(&err) {
+            Some(ActionKvError::AccessDenied { operation, .. }) => *operation,
+            other => panic!("expected AccessDenied, got {:?}", other),
+        }
+    }
+
+    #[rstest]
+    #[serial]
+    fn test_access_policy() {
+        let mut ctx = TestCtx::setup_with_options(
+            "test_access",
+            Options {
+                access_policy: Some(plugin_policy),
+                ..Options::default()
+            },
+        );
+        let store = &mut ctx.test_file;
+        store.insert(b"plugin:a", b"1").unwrap();
+        assert_eq!(store.get(b"plugin:a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"shared:b").unwrap(), None);
+        assert_eq!(
+            store.scan_filtered(b"plugin:", |_, _| true).unwrap().len(),
+            1
+        );
+
+        assert_eq!(denied(store.get(b"secret")), Operation::Read);
+        assert_eq!(denied(store.insert(b"shared:b", b"2")), Operation::Write);
+        assert_eq!(denied(store.delete(b"secret")), Operation::Delete);
+        assert_eq!(
+            denied(store.scan_filtered(b"", |_, _| true)),
+            Operation::Scan
+        );
+        assert_eq!(
+            denied(store.move_value(b"plugin:a", b"secret")),
+            Operation::Write
+        );
+        let mut tx = store.transaction();
+        tx.insert(b"plugin:c", b"3");
+        tx.delete(b"shared:b");
+        assert_eq!(denied(store.commit(tx)), Operation::Delete);
+        assert_eq!(
+            denied(store.bulk_load(vec![(b"other".to_vec(), b"4".to_vec())])),
+            Operation::Write
+        );
+        // nothing denied was written
+        assert_eq!(store.get(b"plugin:a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"plugin:c").unwrap(), None);
+    }
+}
@@ -0,0 +1,190 @@
+#[cfg(any(test, feature = "access-log"))]
+use crate::{blake3::Blake3, ByteString};
+use crate::{ActionKV, ByteStr, Operation};
+#[cfg(any(test, feature = "access-log"))]
+use std::fmt;
+use std::io;
+#[cfg(any(test, feature = "access-log"))]
+use std::sync::Arc;
+#[cfg(any(test, feature = "access-log"))]
+use std::time::{Duration, Instant};
+
+/*
+    ACCESS LOGS
+    for audits of who touched what without the keys ending up in logs, every
+    get, insert, delete and prefix scan can be reported with how long it took
+    and the key (or prefix) replaced by a hash of it. The hash is BLAKE3 of
+    the salt's length, the salt and the key, cut to 16 bytes: the same key
+    always gives the same hash so access patterns stay visible, and without
+    the salt short or guessable keys can't be found again by hashing
+    candidates.
+*/
+#[cfg(any(test, feature = "access-log"))]
+pub const ACCESS_HASH_LEN: usize = 16;
+
+/// One operation reported to an `AccessLog`.
+#[cfg(any(test, feature = "access-log"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRecord {
+    pub operation: Operation,
+    /// Salted hash of the key, or of the prefix of a scan.
+    pub key_hash: [u8; ACCESS_HASH_LEN],
+    pub latency: Duration,
+    /// Whether the operation succeeded.
+    pub ok: bool,
+}
+
+/// A log line: `op=read key=<hex hash> latency_us=12 ok=true`.
+#[cfg(any(test, feature = "access-log"))]
+impl fmt::Display for AccessRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operation = match self.operation {
+            Operation::Read => "read",
+            Operation::Write => "write",
+            Operation::Delete => "delete",
+            Operation::Scan => "scan",
+        };
+        write!(f, "op={} key=", operation)?;
+        for byte in self.key_hash {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, " latency_us={} ok={}", self.latency.as_micros(), self.ok)
+    }
+}
+
+/// Where `Options::access_log` reports accesses to, e.g. a closure writing
+/// the records to the application's log. Clones share the sink.
+#[cfg(any(test, feature = "access-log"))]
+#[derive(Clone)]
+pub struct AccessLog {
+    salt: ByteString,
+    sink: Arc<dyn Fn(&AccessRecord) + Send + Sync>,
+}
+
+#[cfg(any(test, feature = "access-log"))]
+impl AccessLog {
+    /// Reports to `sink` with keys hashed together with `salt`, which has to
+    /// stay the same for hashes from different runs to be compared.
+    pub fn new<F>(salt: &ByteStr, sink: F) -> Self
+    where
+        F: Fn(&AccessRecord) + Send + Sync + 'static,
+    {
+        AccessLog {
+            salt: salt.to_vec(),
+            sink: Arc::new(sink),
+        }
+    }
+    /// The hash `key` is reported as.
+    pub fn hash(&self, key: &ByteStr) -> [u8; ACCESS_HASH_LEN] {
+        let mut hasher = Blake3::new();
+        hasher.update(&(self.salt.len() as u64).to_le_bytes());
+        hasher.update(&self.salt);
+        hasher.update(key);
+        let mut hash = [0; ACCESS_HASH_LEN];
+        hash.copy_from_slice(&hasher.finalize()[..ACCESS_HASH_LEN]);
+        hash
+    }
+    fn record(&self, operation: Operation, key: &ByteStr, latency: Duration, ok: bool) {
+        (self.sink)(&AccessRecord {
+            operation,
+            key_hash: self.hash(key),
+            latency,
+            ok,
+        });
+    }
+}
+
+/// Leaves the salt out, it is as secret as the keys.
+#[cfg(any(test, feature = "access-log"))]
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessLog").finish_non_exhaustive()
+    }
+}
+
+impl ActionKV {
+    /// Runs `f`, the `operation` on `key`, and reports it to the access log
+    /// with how long it took.
+    #[cfg(any(test, feature = "access-log"))]
+    pub(crate) fn logged<T, F>(
+        &mut self,
+        operation: Operation,
+        key: &ByteStr,
+        f: F,
+    ) -> io::Result<T>
+    where
+        F: FnOnce(&mut ActionKV) -> io::Result<T>,
+    {
+        let Some(log) = self.options.access_log.clone() else {
+            return f(self);
+        };
+        let started = Instant::now();
+        let result = f(self);
+        log.record(operation, key, started.elapsed(), result.is_ok());
+        result
+    }
+    #[cfg(not(any(test, feature = "access-log")))]
+    #[inline(always)]
+    pub(crate) fn logged<T, F>(&mut self, _: Operation, _: &ByteStr, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut ActionKV) -> io::Result<T>,
+    {
+        f(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::Options;
+    use rstest::*;
+    use serial_test::serial;
+    use std::sync::Mutex;
+
+    #[rstest]
+    #[serial]
+    fn test_access_log() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let log = AccessLog::new(b"salt", move |record: &AccessRecord| {
+            sink.lock().unwrap().push(record.clone())
+        });
+        let mut ctx = TestCtx::setup_with_options(
+            "test_access_log",
+            Options {
+                access_log: Some(log.clone()),
+                ..Options::default()
+            },
+        );
+        ctx.test_file.insert(b"alice", b"1").unwrap();
+        ctx.test_file.get(b"alice").unwrap();
+        ctx.test_file.delete(b"bob").unwrap();
+        ctx.test_file.scan_filtered(b"al", |_, _| true).unwrap();
+
+        let records = records.lock().unwrap().clone();
+        let seen: Vec<_> = records
+            .iter()
+            .map(|record| (record.operation, record.key_hash))
+            .collect();
+        assert_eq!(
+            seen,
+            [
+                (Operation::Write, log.hash(b"alice")),
+                (Operation::Read, log.hash(b"alice")),
+                (Operation::Delete, log.hash(b"bob")),
+                (Operation::Scan, log.hash(b"al")),
+            ]
+        );
+        assert!(records.iter().all(|record| record.ok));
+        // neither the key nor the salt show up
+        let line = records[0].to_string();
+        assert!(line.starts_with("op=write key=") && line.contains(" latency_us="));
+        assert!(!line.contains("alice"));
+        assert_ne!(
+            AccessLog::new(b"pepper", |_| {}).hash(b"alice"),
+            log.hash(b"alice")
+        );
+        assert!(!format!("{:?}", log).contains("salt"));
+    }
+}
@@ -0,0 +1,268 @@
+use crate::{ActionKV, ByteString, Transaction};
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+
+/// Where the store answers a `Command`, the receiving end gets exactly one
+/// reply.
+pub type Reply<T> = SyncSender<io::Result<T>>;
+
+/// A request to the thread a `StoreHandle` talks to.
+pub enum Command {
+    Get {
+        key: ByteString,
+        reply: Reply<Option<ByteString>>,
+    },
+    Insert {
+        key: ByteString,
+        value: ByteString,
+        reply: Reply<()>,
+    },
+    Update {
+        key: ByteString,
+        value: ByteString,
+        reply: Reply<()>,
+    },
+    Delete {
+        key: ByteString,
+        reply: Reply<()>,
+    },
+    FlushIndex {
+        reply: Reply<()>,
+    },
+    /// Commits queued one after the other share one sync, see
+    /// `ActionKV::commit_all`.
+    Commit {
+        tx: Transaction,
+        reply: Reply<()>,
+    },
+    /// Runs the closure on the store thread, for everything else.
+    With(Box<dyn FnOnce(&mut ActionKV) + Send>),
+}
+
+impl std::fmt::Debug for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Get { key, .. } => f.debug_struct("Get").field("key", key).finish(),
+            Command::Insert { key, .. } => f.debug_struct("Insert").field("key", key).finish(),
+            Command::Update { key, .. } => f.debug_struct("Update").field("key", key).finish(),
+            Command::Delete { key, .. } => f.debug_struct("Delete").field("key", key).finish(),
+            Command::FlushIndex { .. } => f.write_str("FlushIndex"),
+            Command::Commit { tx, .. } => f.debug_struct("Commit").field("tx", tx).finish(),
+            Command::With(_) => f.write_str("With"),
+        }
+    }
+}
+
+/// Sends commands to a store running on a thread of its own, see
+/// `ActionKV::spawn`. Handles are cheap to clone; the thread ends once the
+/// last one is dropped.
+#[derive(Debug, Clone)]
+pub struct StoreHandle {
+    commands: Sender<Command>,
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the store thread has stopped")
+}
+
+impl StoreHandle {
+    /// Queues `command` behind the ones sent before it, from any handle.
+    /// The reply arrives on the receiver the command carries.
+    pub fn send(&self, command: Command) -> io::Result<()> {
+        self.commands.send(command).map_err(|_| stopped())
+    }
+    /// Sends the command `make` builds around a fresh reply channel and
+    /// waits for the reply.
+    fn call<T, F>(&self, make: F) -> io::Result<T>
+    where
+        F: FnOnce(Reply<T>) -> Command,
+    {
+        let (reply, receiver) = mpsc::sync_channel(1);
+        self.send(make(reply))?;
+        receiver.recv().map_err(|_| stopped())?
+    }
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<ByteString>> {
+        self.call(|reply| Command::Get {
+            key: key.to_vec(),
+            reply,
+        })
+    }
+    pub fn insert(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.call(|reply| Command::Insert {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            reply,
+        })
+    }
+    pub fn update(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.call(|reply| Command::Update {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            reply,
+        })
+    }
+    pub fn delete(&self, key: &[u8]) -> io::Result<()> {
+        self.call(|reply| Command::Delete {
+            key: key.to_vec(),
+            reply,
+        })
+    }
+    pub fn flush_index(&self) -> io::Result<()> {
+        self.call(|reply| Command::FlushIndex { reply })
+    }
+    /// Commits `tx` along with the commits other handles sent meanwhile.
+    /// Begin it on the store thread, e.g. through `with`.
+    pub fn commit(&self, tx: Transaction) -> io::Result<()> {
+        self.call(|reply| Command::Commit { tx, reply })
+    }
+    /// `ActionKV::update_with` run on the store thread, no other command
+    /// gets in between the read and the write.
+    pub fn update_with<F>(&self, key: &[u8], f: F) -> io::Result<Option<ByteString>>
+    where
+        F: FnOnce(Option<ByteString>) -> Option<ByteString> + Send + 'static,
+    {
+        let key = key.to_vec();
+        self.with(move |store| store.update_with(&key, f))?
+    }
+    /// Runs `f` with the store on its thread and returns what it returned.
+    pub fn with<R, F>(&self, f: F) -> io::Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut ActionKV) -> R + Send + 'static,
+    {
+        let (reply, receiver) = mpsc::sync_channel(1);
+        self.send(Command::With(Box::new(move |store| {
+            let _ = reply.send(f(store));
+        })))?;
+        receiver.recv().map_err(|_| stopped())
+    }
+}
+
+impl ActionKV {
+    /// Moves the store to a thread of its own that runs the commands sent
+    /// through the returned handle one at a time, in the order they arrive.
+    /// Joining the thread gives the store back once every handle is gone.
+    pub fn spawn(self) -> io::Result<(StoreHandle, JoinHandle<ActionKV>)> {
+        let (commands, receiver) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("actionkv".to_string())
+            .spawn(move || self.serve(receiver))?;
+        Ok((StoreHandle { commands }, thread))
+    }
+    fn serve(mut self, commands: Receiver<Command>) -> ActionKV {
+        let mut next = None;
+        while let Some(command) = next.take().or_else(|| commands.recv().ok()) {
+            let Command::Commit { tx, reply } = command else {
+                self.run(command);
+                continue;
+            };
+            // commits that queued up behind this one share its sync
+            let mut txs = vec![tx];
+            let mut replies = vec![reply];
+            while let Ok(command) = commands.try_recv() {
+                match command {
+                    Command::Commit { tx, reply } => {
+                        txs.push(tx);
+                        replies.push(reply);
+                    }
+                    command => {
+                        next = Some(command);
+                        break;
+                    }
+                }
+            }
+            for (reply, result) in replies.into_iter().zip(self.commit_all(txs)) {
+                let _ = reply.send(result);
+            }
+        }
+        self
+    }
+    fn run(&mut self, command: Command) {
+        // a caller that stopped waiting for its reply is no reason to stop
+        match command {
+            Command::Get { key, reply } => {
+                let _ = reply.send(self.get(&key));
+            }
+            Command::Insert { key, value, reply } => {
+                let _ = reply.send(self.insert(&key, &value));
+            }
+            Command::Update { key, value, reply } => {
+                let _ = reply.send(self.update(&key, &value));
+            }
+            Command::Delete { key, reply } => {
+                let _ = reply.send(self.delete(&key));
+            }
+            Command::FlushIndex { reply } => {
+                let _ = reply.send(self.flush_index());
+            }
+            Command::Commit { tx, reply } => {
+                let _ = reply.send(self.commit(tx));
+            }
+            Command::With(f) => f(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[serial]
+    fn test_store_handle() {
+        let (store, _dir) = TestCtx::setup("test_actor").into_store();
+        let (handle, thread) = store.spawn().unwrap();
+        thread::scope(|scope| {
+            for n in 0..4u8 {
+                let handle = handle.clone();
+                scope.spawn(move || handle.insert(&[n], b"v").unwrap());
+            }
+        });
+        assert_eq!(handle.get(&[2]).unwrap(), Some(b"v".to_vec()));
+        handle.delete(&[2]).unwrap();
+        assert_eq!(handle.get(&[2]).unwrap(), None);
+
+        let (reply, receiver) = mpsc::sync_channel(1);
+        handle
+            .send(Command::Get {
+                key: vec![3],
+                reply,
+            })
+            .unwrap();
+        assert_eq!(receiver.recv().unwrap().unwrap(), Some(b"v".to_vec()));
+        assert_eq!(
+            handle.with(|store| store.get(&[1]).unwrap()).unwrap(),
+            Some(b"v".to_vec())
+        );
+
+        let bump =
+            |old: Option<ByteString>| Some([old.unwrap_or_default(), b"v".to_vec()].concat());
+        assert_eq!(
+            handle.update_with(&[1], bump).unwrap(),
+            Some(b"vv".to_vec())
+        );
+
+        thread::scope(|scope| {
+            for n in 0..4u8 {
+                let handle = handle.clone();
+                scope.spawn(move || {
+                    let mut tx = handle.with(|store| store.transaction()).unwrap();
+                    tx.insert(&[b't', n], b"v");
+                    handle.commit(tx).unwrap();
+                });
+            }
+        });
+        assert_eq!(handle.get(b"t\x03").unwrap(), Some(b"v".to_vec()));
+
+        let clone = handle.clone();
+        drop(handle);
+        assert!(clone.flush_index().is_ok());
+        drop(clone);
+        let mut store = thread.join().unwrap();
+        assert_eq!(store.get(&[0]).unwrap(), Some(b"v".to_vec()));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::ActionKV;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/*
+    ADMIN LOG
+    one line per administrative operation, appended to ADMIN_LOG in the
+    store directory, which nothing else writes or compacts, so operators
+    can tell what was done to a store and when:
+    time | action | detail
+    milliseconds since the epoch by the store clock, separated by a space.
+    A line without its newline is what a crash left of the last entry, it
+    is skipped and cut off before the next entry is appended. Stores opened
+    without a directory keep no admin log.
+*/
+pub const ADMIN_LOG_FILE: &str = "ADMIN_LOG";
+
+/// Kinds of operation the admin log records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminAction {
+    Compaction,
+    /// `rebuild_index` or `repair_from`.
+    Repair,
+    /// Data dropped by a retention policy.
+    Purge,
+    /// The store was restored from a backup.
+    Restore,
+    /// A setting was changed on an open store.
+    Config,
+}
+
+impl AdminAction {
+    const ALL: [AdminAction; 5] = [
+        AdminAction::Compaction,
+        AdminAction::Repair,
+        AdminAction::Purge,
+        AdminAction::Restore,
+        AdminAction::Config,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            AdminAction::Compaction => "compaction",
+            AdminAction::Repair => "repair",
+            AdminAction::Purge => "purge",
+            AdminAction::Restore => "restore",
+            AdminAction::Config => "config",
+        }
+    }
+}
+
+/// One entry of the admin log, see `ActionKV::admin_log`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdminEvent {
+    /// When it happened, since the epoch by the store clock.
+    pub at: Duration,
+    pub action: AdminAction,
+    /// What was done, e.g. how much a compaction reclaimed.
+    pub detail: String,
+}
+
+impl AdminEvent {
+    fn parse(line: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed admin log entry {:?}", line),
+            )
+        };
+        let (at, rest) = line.split_once(' ').ok_or_else(invalid)?;
+        let (action, detail) = rest.split_once(' ').unwrap_or((rest, ""));
+        let action = AdminAction::ALL
+            .into_iter()
+            .find(|known| known.name() == action)
+            .ok_or_else(invalid)?;
+        Ok(AdminEvent {
+            at: Duration::from_millis(at.parse().map_err(|_| invalid())?),
+            action,
+            detail: detail.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for AdminEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.at.as_millis(),
+            self.action.name(),
+            self.detail
+        )
+    }
+}
+
+/// Entries of the admin log of the store at `path`, oldest first.
+pub(crate) fn read_admin_log(path: &Path) -> io::Result<Vec<AdminEvent>> {
+    let log = match fs::read_to_string(path.join(ADMIN_LOG_FILE)) {
+        Ok(log) => log,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let complete = log.rfind('\n').map_or("", |end| &log[..end]);
+    complete
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(AdminEvent::parse)
+        .collect()
+}
+
+impl ActionKV {
+    /// Appends `action` to the admin log and syncs it.
+    pub(crate) fn log_admin(&self, action: AdminAction, detail: &str) -> io::Result<()> {
+        let Some(path) = self.path.as_deref() else {
+            return Ok(());
+        };
+        let event = AdminEvent {
+            at: self.clock.now(),
+            action,
+            detail: detail.replace('\n', " "),
+        };
+        let mut f = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path.join(ADMIN_LOG_FILE))?;
+        let mut log = Vec::new();
+        f.read_to_end(&mut log)?;
+        if log.last().is_some_and(|last| *last != b'\n') {
+            let complete = log
+                .iter()
+                .rposition(|byte| *byte == b'\n')
+                .map_or(0, |end| end + 1);
+            f.set_len(complete as u64)?;
+        }
+        f.write_all(format!("{}\n", event).as_bytes())?;
+        f.sync_data()
+    }
+    /// The administrative operations done to the store, oldest first:
+    /// compactions, repairs, purges, restores and configuration changes.
+    pub fn admin_log(&self) -> io::Result<Vec<AdminEvent>> {
+        read_admin_log(self.dir("the admin log")?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::{ManualClock, Options};
+    use rstest::*;
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    #[rstest]
+    #[serial]
+    fn test_admin_log() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1000)));
+        let mut ctx = TestCtx::setup_with_options(
+            "test_admin_log",
+            Options {
+                clock: Some(clock.clone()),
+                ..Options::default()
+            },
+        );
+        let store = &mut ctx.test_file;
+        assert_eq!(store.admin_log().unwrap(), Vec::new());
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", b"2").unwrap();
+        store.compact().unwrap();
+        clock.advance(Duration::from_secs(5));
+        store.set_compaction_rate(Some(1 << 20));
+        store.rebuild_index().unwrap();
+
+        let log = store.admin_log().unwrap();
+        let actions: Vec<_> = log.iter().map(|event| event.action).collect();
+        assert_eq!(
+            actions,
+            vec![
+                AdminAction::Compaction,
+                AdminAction::Config,
+                AdminAction::Repair
+            ]
+        );
+        assert_eq!(log[0].at, Duration::from_secs(1000));
+        assert_eq!(log[1].at, Duration::from_secs(1005));
+        assert_eq!(log[1].detail, "compaction_rate 1048576");
+
+        // a torn last entry is skipped
+        let mut f = OpenOptions::new()
+            .append(true)
+            .open(Path::new("test_admin_log").join(ADMIN_LOG_FILE))
+            .unwrap();
+        f.write_all(b"1006000 purge remov").unwrap();
+        assert_eq!(store.admin_log().unwrap(), log);
+        store.set_compaction_rate(None);
+        let log = store.admin_log().unwrap();
+        assert_eq!(log.len(), 4);
+        assert_eq!(log[3].detail, "compaction_rate unthrottled");
+    }
+}
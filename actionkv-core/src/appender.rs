@@ -0,0 +1,87 @@
+use crate::format::Framing;
+use crate::{write_record, ByteStr, ByteString};
+use std::fs::File;
+use std::io::{self, Write};
+
+/// Scratch space a large record grew is given back instead of being kept
+/// around for the next one.
+const KEEP_CAPACITY: usize = 64 << 10;
+
+/// Appends records to the data file. The file is opened in append mode, so
+/// every write lands at its end without seeking, and where that end is gets
+/// tracked here instead of asking the file system on every insert. Each record
+/// is framed into one reused buffer and handed to the file in a single write,
+/// or held there with the ones after it until `release`.
+#[derive(Debug)]
+pub(crate) struct Appender {
+    tail: u64,
+    buf: ByteString,
+    holding: bool,
+}
+
+impl Appender {
+    pub fn new(tail: u64) -> Self {
+        Appender {
+            tail,
+            buf: ByteString::new(),
+            holding: false,
+        }
+    }
+    /// Offset the next record lands at, which is also the data file length.
+    pub fn tail(&self) -> u64 {
+        self.tail
+    }
+    /// Bytes the reused record buffer holds on to.
+    pub fn buffered(&self) -> usize {
+        self.buf.capacity()
+    }
+    /// Picks up a data file that was written, truncated or replaced by
+    /// something other than `append`. Held records are dropped.
+    pub fn reset(&mut self, tail: u64) {
+        self.tail = tail;
+        self.holding = false;
+        self.buf.clear();
+    }
+    /// Holds the records appended from now on, offsets are handed out as if
+    /// they were written, until `release` writes them all at once.
+    pub fn hold(&mut self) {
+        self.buf.clear();
+        self.holding = true;
+    }
+    pub fn release(&mut self, file: &mut File) -> io::Result<()> {
+        self.holding = false;
+        self.write_out(file)
+    }
+    /// Appends one record, returns its offset and how many bytes it took.
+    pub fn append(
+        &mut self,
+        file: &mut File,
+        framing: Framing,
+        key: &ByteStr,
+        value: &ByteStr,
+    ) -> io::Result<(u64, u64)> {
+        if !self.holding {
+            self.buf.clear();
+        }
+        let len = write_record(&mut self.buf, framing, key, value)?;
+        let position = self.tail;
+        self.tail += len;
+        if !self.holding {
+            self.write_out(file)?;
+        }
+        Ok((position, len))
+    }
+    fn write_out(&mut self, file: &mut File) -> io::Result<()> {
+        let written = file.write_all(&self.buf);
+        self.buf.clear();
+        if self.buf.capacity() > KEEP_CAPACITY {
+            self.buf = ByteString::new();
+        }
+        if let Err(err) = written {
+            // part of the records may have made it, find the end again
+            self.tail = file.metadata()?.len();
+            return Err(err);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+use crate::segment::{self, ACTIVE};
+use crate::ActionKV;
+use arrow_array::builder::{BinaryBuilder, TimestampMillisecondBuilder, UInt64Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef, TimeUnit};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+/*
+    ARROW IPC EXPORT
+    written with the FileWriter of arrow-ipc, a record batch at a time so the
+    store is never in memory as a whole. Columns are key and value (Binary),
+    timestamp (Timestamp in milliseconds, UTC, nullable) and version (UInt64,
+    the position of the record in the log, growing with every write).
+    Records don't carry their write time, the timestamp is the latest it can
+    have been: when the segment holding the record was sealed or merged, as
+    its footer says. It is null for records of the data file and of segments
+    sealed before they had footers.
+*/
+const TIMEZONE: &str = "UTC";
+/// Rows of one record batch.
+const BATCH_ROWS: usize = 64 << 10;
+/// Key and value bytes of one record batch, well below the 2 GiB Binary
+/// offsets can address.
+const BATCH_BYTES: usize = 256 << 20;
+
+fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("key", DataType::Binary, false),
+        Field::new("value", DataType::Binary, false),
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Millisecond, Some(TIMEZONE.into())),
+            true,
+        ),
+        Field::new("version", DataType::UInt64, false),
+    ]))
+}
+
+fn from_arrow(err: ArrowError) -> io::Error {
+    match err {
+        ArrowError::IoError(_, err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+/// Rows of the record batch being put together.
+struct Batch {
+    keys: BinaryBuilder,
+    values: BinaryBuilder,
+    timestamps: TimestampMillisecondBuilder,
+    versions: UInt64Builder,
+    rows: usize,
+    bytes: usize,
+}
+
+impl Batch {
+    fn new() -> Self {
+        Batch {
+            keys: BinaryBuilder::new(),
+            values: BinaryBuilder::new(),
+            timestamps: TimestampMillisecondBuilder::new().with_timezone(TIMEZONE),
+            versions: UInt64Builder::new(),
+            rows: 0,
+            bytes: 0,
+        }
+    }
+    fn is_full(&self, next: usize) -> bool {
+        self.rows == BATCH_ROWS || self.bytes + next > BATCH_BYTES
+    }
+    fn push(&mut self, key: &[u8], value: &[u8], timestamp: Option<i64>, version: u64) {
+        self.keys.append_value(key);
+        self.values.append_value(value);
+        self.timestamps.append_option(timestamp);
+        self.versions.append_value(version);
+        self.rows += 1;
+        self.bytes += key.len() + value.len();
+    }
+    /// Takes the rows pushed so far as a record batch.
+    fn finish(&mut self, schema: &SchemaRef) -> io::Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.keys.finish()),
+            Arc::new(self.values.finish()),
+            Arc::new(self.timestamps.finish()),
+            Arc::new(self.versions.finish()),
+        ];
+        self.rows = 0;
+        self.bytes = 0;
+        RecordBatch::try_new(schema.clone(), columns).map_err(from_arrow)
+    }
+}
+
+impl ActionKV {
+    /// Writes every live pair sorted by key as an Arrow IPC file (Feather
+    /// v2), which DuckDB, Polars and pyarrow read as they are. Columns are
+    /// key, value, timestamp and version. The timestamp is when the segment
+    /// holding the record was sealed, null for records still in the data
+    /// file, see `Options::segment_size`. The version is the position of the
+    /// record in the log, which grows with every write. Keys the access
+    /// policy doesn't let be read are left out. Returns how many pairs were
+    /// written.
+    pub fn export_arrow<W: Write>(&mut self, writer: W) -> io::Result<u64> {
+        let schema = schema();
+        let mut ipc = FileWriter::try_new(writer, &schema).map_err(from_arrow)?;
+        let mut written = 0;
+        let mut batch = Batch::new();
+        for key in self.user_keys(b"")? {
+            if !self.readable(&key) {
+                continue;
+            }
+            let (Some(position), Some(value)) =
+                (self.value_position(&key), self.current_value(&key)?)
+            else {
+                continue;
+            };
+            if key.len() > i32::MAX as usize || value.len() > i32::MAX as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "pairs over 2 GiB don't fit an Arrow Binary column",
+                ));
+            }
+            if batch.rows > 0 && batch.is_full(key.len() + value.len()) {
+                ipc.write(&batch.finish(&schema)?).map_err(from_arrow)?;
+            }
+            let timestamp = match segment::split(position).0 {
+                ACTIVE => None,
+                id => self
+                    .segment_footer(id)?
+                    .map(|footer| footer.created.as_millis() as i64),
+            };
+            batch.push(&key, &value, timestamp, self.log_position(position));
+            written += 1;
+        }
+        if batch.rows > 0 {
+            ipc.write(&batch.finish(&schema)?).map_err(from_arrow)?;
+        }
+        ipc.finish().map_err(from_arrow)?;
+        ipc.into_inner().map_err(from_arrow)?.flush()?;
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::ManualClock;
+    use crate::testing::{self, TestCtx};
+    use crate::Options;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{TimestampMillisecondType, UInt64Type};
+    use arrow_ipc::reader::FileReader;
+    use rstest::*;
+    use serial_test::serial;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[rstest]
+    #[serial]
+    fn test_export_arrow() {
+        let mut ctx = TestCtx::setup("test_arrow");
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        ctx.test_file.insert(b"baz", b"1").unwrap();
+        ctx.test_file.delete(b"baz").unwrap();
+        ctx.test_file.insert(b"abc", b"\xff\x00").unwrap();
+        let mut file = Vec::new();
+        assert_eq!(ctx.test_file.export_arrow(&mut file).unwrap(), 2);
+
+        let reader = FileReader::try_new(Cursor::new(file), None).unwrap();
+        let schema = reader.schema();
+        let names: Vec<&str> = schema
+            .fields()
+            .iter()
+            .map(|field| field.name().as_str())
+            .collect();
+        assert_eq!(names, ["key", "value", "timestamp", "version"]);
+        let batches: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        let keys: Vec<&[u8]> = batch
+            .column(0)
+            .as_binary::<i32>()
+            .iter()
+            .flatten()
+            .collect();
+        assert_eq!(keys, [&b"abc"[..], b"foo"]);
+        let values: Vec<&[u8]> = batch
+            .column(1)
+            .as_binary::<i32>()
+            .iter()
+            .flatten()
+            .collect();
+        assert_eq!(values, [&b"\xff\x00"[..], b"bar"]);
+        // nothing was sealed, when the records were written isn't known
+        assert_eq!(batch.column(2).null_count(), 2);
+        let versions = batch.column(3).as_primitive::<UInt64Type>();
+        // abc was written after foo
+        assert!(versions.value(0) > versions.value(1));
+    }
+    #[rstest]
+    #[serial]
+    fn test_export_arrow_timestamps() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1_000)));
+        let options = Options {
+            clock: Some(clock.clone()),
+            ..Options::default()
+        };
+        let sealing = Options {
+            segment_size: Some(1),
+            ..options.clone()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_arrow", sealing);
+        for i in 0..4u8 {
+            ctx.test_file.insert(&[b'k', i], b"sealed").unwrap();
+        }
+        clock.advance(Duration::from_secs(5));
+        let store = ctx.reopen_with_options(options).unwrap();
+        store.insert(b"last", b"in the data file").unwrap();
+        let mut file = Vec::new();
+        assert_eq!(store.export_arrow(&mut file).unwrap(), 5);
+        let reader = FileReader::try_new(Cursor::new(file), None).unwrap();
+        let batches: Vec<_> = reader.map(Result::unwrap).collect();
+        let timestamps = batches[0]
+            .column(2)
+            .as_primitive::<TimestampMillisecondType>();
+        let keys = batches[0].column(0).as_binary::<i32>();
+        for (key, timestamp) in keys.iter().flatten().zip(timestamps.iter()) {
+            match key {
+                b"last" => assert_eq!(timestamp, None),
+                _ => assert_eq!(timestamp, Some(1_000_000)),
+            }
+        }
+    }
+    #[rstest]
+    #[serial]
+    fn test_export_arrow_chunked_values() {
+        let options = Options {
+            chunk_size: Some(10),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_arrow", options);
+        let big: Vec<u8> = (0..95).collect();
+        ctx.test_file.insert(b"big", &big).unwrap();
+        ctx.test_file.insert(b"small", b"1").unwrap();
+        let mut file = Vec::new();
+        assert_eq!(ctx.test_file.export_arrow(&mut file).unwrap(), 2);
+        let reader = FileReader::try_new(Cursor::new(file), None).unwrap();
+        let batches: Vec<_> = reader.map(Result::unwrap).collect();
+        let values: Vec<&[u8]> = batches[0]
+            .column(1)
+            .as_binary::<i32>()
+            .iter()
+            .flatten()
+            .collect();
+        assert_eq!(values, [&big[..], b"1"]);
+    }
+    #[rstest]
+    #[serial]
+    fn test_export_arrow_leaves_out_unreadable_keys() {
+        let mut ctx = TestCtx::setup("test_arrow");
+        testing::hide_secrets(&mut ctx.test_file, 5);
+        let mut file = Vec::new();
+        assert_eq!(ctx.test_file.export_arrow(&mut file).unwrap(), 5);
+        let secret = file.windows(7).any(|bytes| bytes == b"secret:");
+        assert!(!secret);
+    }
+}
@@ -0,0 +1,279 @@
+/*
+    BLAKE3
+    the portable reference algorithm, unkeyed hashing with 32 byte digests
+    only. Input is split into 1 KiB chunks, each compressed 64 byte block by
+    block, and the chunk chaining values are merged pairwise into a binary
+    tree whose root gives the digest.
+*/
+pub const DIGEST_LEN: usize = 32;
+const BLOCK_LEN: usize = 64;
+const CHUNK_LEN: usize = 1024;
+
+const CHUNK_START: u32 = 1 << 0;
+const CHUNK_END: u32 = 1 << 1;
+const PARENT: u32 = 1 << 2;
+const ROOT: u32 = 1 << 3;
+
+const IV: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+const MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn round(state: &mut [u32; 16], m: &[u32; 16]) {
+    // columns
+    g(state, 0, 4, 8, 12, m[0], m[1]);
+    g(state, 1, 5, 9, 13, m[2], m[3]);
+    g(state, 2, 6, 10, 14, m[4], m[5]);
+    g(state, 3, 7, 11, 15, m[6], m[7]);
+    // diagonals
+    g(state, 0, 5, 10, 15, m[8], m[9]);
+    g(state, 1, 6, 11, 12, m[10], m[11]);
+    g(state, 2, 7, 8, 13, m[12], m[13]);
+    g(state, 3, 4, 9, 14, m[14], m[15]);
+}
+
+fn compress(
+    chaining_value: &[u32; 8],
+    block_words: &[u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 8] {
+    let mut state = [0; 16];
+    state[..8].copy_from_slice(chaining_value);
+    state[8..12].copy_from_slice(&IV[..4]);
+    state[12] = counter as u32;
+    state[13] = (counter >> 32) as u32;
+    state[14] = block_len;
+    state[15] = flags;
+    let mut block = *block_words;
+    for i in 0..7 {
+        round(&mut state, &block);
+        if i < 6 {
+            block = MSG_PERMUTATION.map(|from| block[from]);
+        }
+    }
+    let mut output = [0; 8];
+    for i in 0..8 {
+        output[i] = state[i] ^ state[i + 8];
+    }
+    output
+}
+
+fn block_words(block: &[u8; BLOCK_LEN]) -> [u32; 16] {
+    let mut words = [0; 16];
+    for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    words
+}
+
+/// What is left to compress of a chunk or parent node, once it is known
+/// whether it is the root.
+struct Output {
+    chaining_value: [u32; 8],
+    block_words: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        compress(
+            &self.chaining_value,
+            &self.block_words,
+            self.counter,
+            self.block_len,
+            self.flags,
+        )
+    }
+    fn root_digest(&self) -> [u8; DIGEST_LEN] {
+        let words = compress(
+            &self.chaining_value,
+            &self.block_words,
+            0,
+            self.block_len,
+            self.flags | ROOT,
+        );
+        let mut digest = [0; DIGEST_LEN];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+}
+
+fn parent_output(left: [u32; 8], right: [u32; 8]) -> Output {
+    let mut block_words = [0; 16];
+    block_words[..8].copy_from_slice(&left);
+    block_words[8..].copy_from_slice(&right);
+    Output {
+        chaining_value: IV,
+        block_words,
+        counter: 0,
+        block_len: BLOCK_LEN as u32,
+        flags: PARENT,
+    }
+}
+
+struct ChunkState {
+    chaining_value: [u32; 8],
+    chunk_counter: u64,
+    block: [u8; BLOCK_LEN],
+    block_len: usize,
+    blocks_compressed: usize,
+}
+
+impl ChunkState {
+    fn new(chunk_counter: u64) -> Self {
+        ChunkState {
+            chaining_value: IV,
+            chunk_counter,
+            block: [0; BLOCK_LEN],
+            block_len: 0,
+            blocks_compressed: 0,
+        }
+    }
+    fn len(&self) -> usize {
+        BLOCK_LEN * self.blocks_compressed + self.block_len
+    }
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 {
+            CHUNK_START
+        } else {
+            0
+        }
+    }
+    fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // more input follows, so a full block isn't the chunk's last
+            if self.block_len == BLOCK_LEN {
+                self.chaining_value = compress(
+                    &self.chaining_value,
+                    &block_words(&self.block),
+                    self.chunk_counter,
+                    BLOCK_LEN as u32,
+                    self.start_flag(),
+                );
+                self.blocks_compressed += 1;
+                self.block = [0; BLOCK_LEN];
+                self.block_len = 0;
+            }
+            let take = (BLOCK_LEN - self.block_len).min(input.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&input[..take]);
+            self.block_len += take;
+            input = &input[take..];
+        }
+    }
+    fn output(&self) -> Output {
+        Output {
+            chaining_value: self.chaining_value,
+            block_words: block_words(&self.block),
+            counter: self.chunk_counter,
+            block_len: self.block_len as u32,
+            flags: self.start_flag() | CHUNK_END,
+        }
+    }
+}
+
+/// Incremental BLAKE3, for input that doesn't come in one piece.
+pub(crate) struct Blake3 {
+    chunk_state: ChunkState,
+    /// Chaining values of the complete subtrees to the left of the current
+    /// chunk, the smallest last.
+    cv_stack: Vec<[u32; 8]>,
+}
+
+impl Blake3 {
+    pub fn new() -> Self {
+        Blake3 {
+            chunk_state: ChunkState::new(0),
+            cv_stack: Vec::new(),
+        }
+    }
+    pub fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // more input follows, so a full chunk isn't the root
+            if self.chunk_state.len() == CHUNK_LEN {
+                let mut cv = self.chunk_state.output().chaining_value();
+                let mut total_chunks = self.chunk_state.chunk_counter + 1;
+                // every trailing zero bit of the chunk count completes a subtree
+                while total_chunks & 1 == 0 {
+                    if let Some(left) = self.cv_stack.pop() {
+                        cv = parent_output(left, cv).chaining_value();
+                    }
+                    total_chunks >>= 1;
+                }
+                self.cv_stack.push(cv);
+                self.chunk_state = ChunkState::new(self.chunk_state.chunk_counter + 1);
+            }
+            let take = (CHUNK_LEN - self.chunk_state.len()).min(input.len());
+            self.chunk_state.update(&input[..take]);
+            input = &input[take..];
+        }
+    }
+    pub fn finalize(&self) -> [u8; DIGEST_LEN] {
+        let mut output = self.chunk_state.output();
+        for left in self.cv_stack.iter().rev() {
+            output = parent_output(*left, output.chaining_value());
+        }
+        output.root_digest()
+    }
+}
+
+pub(crate) fn blake3(bytes: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut hasher = Blake3::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+    #[rstest]
+    #[case(
+        b"",
+        "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+    )]
+    #[case(
+        b"abc",
+        "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+    )]
+    fn test_blake3_reference_values(#[case] input: &[u8], #[case] digest: &str) {
+        assert_eq!(hex(&blake3(input)), digest);
+    }
+    #[rstest]
+    fn test_blake3_incremental() {
+        let input: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let mut hasher = Blake3::new();
+        for piece in input.chunks(777) {
+            hasher.update(piece);
+        }
+        assert_eq!(hasher.finalize(), blake3(&input));
+        assert_ne!(blake3(&input[..1024]), blake3(&input[..1025]));
+    }
+}
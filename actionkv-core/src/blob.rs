@@ -0,0 +1,98 @@
+use crate::blake3::{blake3, DIGEST_LEN};
+use crate::{display_key, ActionKV, ByteStr, ByteString};
+use std::io;
+
+/*
+    CONTENT ADDRESSED BLOBS
+    a blob is stored under `blob:` followed by the lowercase hex of the
+    BLAKE3 digest of its bytes, so the same bytes are only ever stored once
+    and a blob can be checked against its key when read back. The empty blob
+    is never written, an empty value reads as a missing key.
+*/
+const BLOB_PREFIX: &[u8] = b"blob:";
+
+pub type BlobDigest = [u8; DIGEST_LEN];
+
+fn blob_key(digest: &BlobDigest) -> ByteString {
+    let mut key = BLOB_PREFIX.to_vec();
+    for byte in digest {
+        key.extend(format!("{:02x}", byte).as_bytes());
+    }
+    key
+}
+
+impl ActionKV {
+    /// Stores `value` under its BLAKE3 digest unless a blob with that digest
+    /// is already there, and returns the digest.
+    pub fn put_blob(&mut self, value: &ByteStr) -> io::Result<BlobDigest> {
+        self.writable()?;
+        let digest = blake3(value);
+        if !self.has_blob(&digest)? {
+            self.insert(&blob_key(&digest), value)?;
+        }
+        Ok(digest)
+    }
+    /// Bytes of the blob with `digest`. Blobs are checked against their
+    /// digest, one that doesn't match is reported as corrupt.
+    pub fn get_blob(&mut self, digest: &BlobDigest) -> io::Result<Option<ByteString>> {
+        if *digest == blake3(&[]) {
+            return Ok(Some(ByteString::new()));
+        }
+        let key = blob_key(digest);
+        let Some(value) = self.get(&key)? else {
+            return Ok(None);
+        };
+        if blake3(&value) != *digest {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("blob {} doesn't match its digest", display_key(&key)),
+            ));
+        }
+        Ok(Some(value))
+    }
+    /// Whether a blob with `digest` is stored, without reading it.
+    pub fn has_blob(&mut self, digest: &BlobDigest) -> io::Result<bool> {
+        if *digest == blake3(&[]) {
+            return Ok(true);
+        }
+        self.read_index()?;
+        Ok(self.position(&blob_key(digest)).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_blob")
+    }
+    #[rstest]
+    #[serial]
+    fn test_blobs(mut ctx: TestCtx) {
+        let store = &mut ctx.test_file;
+        let digest = store.put_blob(b"abc").unwrap();
+        assert_eq!(
+            blob_key(&digest),
+            b"blob:6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85".to_vec()
+        );
+        assert!(store.has_blob(&digest).unwrap());
+        assert_eq!(store.get_blob(&digest).unwrap(), Some(b"abc".to_vec()));
+
+        assert_eq!(store.put_blob(b"abc").unwrap(), digest);
+        assert_eq!(store.stats().io.insert.operations, 1);
+
+        let other = blake3(b"abd");
+        assert!(!store.has_blob(&other).unwrap());
+        assert_eq!(store.get_blob(&other).unwrap(), None);
+        let empty = store.put_blob(b"").unwrap();
+        assert_eq!(store.get_blob(&empty).unwrap(), Some(vec![]));
+
+        store.update(&blob_key(&digest), b"abd").unwrap();
+        assert!(store.get_blob(&digest).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use crate::manifest::Manifest;
+use crate::{ActionKV, ByteStr, ByteString};
+use std::borrow::Cow;
+use std::io;
+use std::path::Path;
+
+/// How keys are normalized before they are written or looked up, so keys
+/// typed in by people don't turn into near duplicates of each other. The
+/// steps run in the order of the fields.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyCanonicalization {
+    /// Unicode NFC normalization of keys that are UTF-8, which needs the
+    /// unicode feature.
+    pub nfc: bool,
+    /// Drop leading and trailing whitespace, only ASCII whitespace for keys
+    /// that aren't UTF-8.
+    pub trim: bool,
+    /// Lowercase the ASCII letters, everything else is kept as it is.
+    pub lowercase_ascii: bool,
+}
+
+impl KeyCanonicalization {
+    pub(crate) fn to_bits(self) -> u8 {
+        self.nfc as u8 | (self.trim as u8) << 1 | (self.lowercase_ascii as u8) << 2
+    }
+    pub(crate) fn from_bits(bits: u8) -> Self {
+        KeyCanonicalization {
+            nfc: bits & 1 != 0,
+            trim: bits & 2 != 0,
+            lowercase_ascii: bits & 4 != 0,
+        }
+    }
+    pub(crate) fn is_identity(&self) -> bool {
+        *self == KeyCanonicalization::default()
+    }
+    fn check_supported(&self) -> io::Result<()> {
+        if self.nfc && cfg!(not(feature = "unicode")) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the store normalizes keys to NFC, which needs the unicode feature",
+            ));
+        }
+        Ok(())
+    }
+    /// `key` the way it is stored.
+    pub fn apply<'k>(&self, key: &'k ByteStr) -> Cow<'k, ByteStr> {
+        let mut key = Cow::Borrowed(key);
+        #[cfg(feature = "unicode")]
+        if self.nfc {
+            if let Ok(text) = std::str::from_utf8(&key) {
+                let nfc = icu_normalizer::ComposingNormalizerBorrowed::new_nfc().normalize(text);
+                if let Cow::Owned(nfc) = nfc {
+                    key = Cow::Owned(nfc.into_bytes());
+                }
+            }
+        }
+        if self.trim {
+            let trimmed = match std::str::from_utf8(&key) {
+                Ok(text) => text.trim().as_bytes(),
+                Err(_) => key.trim_ascii(),
+            };
+            if trimmed.len() != key.len() {
+                key = Cow::Owned(trimmed.to_vec());
+            }
+        }
+        if self.lowercase_ascii && key.iter().any(u8::is_ascii_uppercase) {
+            key = Cow::Owned(key.to_ascii_lowercase());
+        }
+        key
+    }
+    /// `apply` for owned keys.
+    pub(crate) fn apply_owned(&self, key: ByteString) -> ByteString {
+        match self.apply(&key) {
+            Cow::Borrowed(_) => key,
+            Cow::Owned(canonical) => canonical,
+        }
+    }
+    /// Canonicalization the store at `path` uses: the one recorded in its
+    /// manifest, or `requested` for a store with no records yet, which then
+    /// gets it recorded.
+    pub(crate) fn open(
+        path: &Path,
+        requested: KeyCanonicalization,
+        empty: bool,
+        read_only: bool,
+    ) -> io::Result<Self> {
+        let mut manifest = Manifest::read(path)?;
+        let canonicalization = match manifest.key_canonicalization {
+            Some(recorded) => recorded,
+            None if empty && !read_only && !requested.is_identity() => {
+                requested.check_supported()?;
+                manifest.key_canonicalization = Some(requested);
+                manifest.write(path)?;
+                requested
+            }
+            None => KeyCanonicalization::default(),
+        };
+        canonicalization.check_supported()?;
+        Ok(canonicalization)
+    }
+}
+
+impl ActionKV {
+    /// `key` as this store writes and looks it up.
+    pub(crate) fn canonical_key<'k>(&self, key: &'k ByteStr) -> Cow<'k, ByteStr> {
+        self.key_canonicalization.apply(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::Options;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[case(b"  User@Example.COM\t", b"user@example.com")]
+    #[case(b"\xff KEY ", b"\xff key")]
+    #[case(b"plain", b"plain")]
+    fn test_apply(#[case] key: &[u8], #[case] canonical: &[u8]) {
+        let canonicalization = KeyCanonicalization {
+            trim: true,
+            lowercase_ascii: true,
+            ..KeyCanonicalization::default()
+        };
+        assert_eq!(canonicalization.apply(key).as_ref(), canonical);
+    }
+    #[cfg(feature = "unicode")]
+    #[rstest]
+    fn test_apply_nfc() {
+        let nfc = KeyCanonicalization {
+            nfc: true,
+            ..KeyCanonicalization::default()
+        };
+        assert_eq!(
+            nfc.apply("Cafe\u{301}".as_bytes()).as_ref(),
+            "Caf\u{e9}".as_bytes()
+        );
+    }
+    #[rstest]
+    #[serial]
+    fn test_canonical_keys() {
+        let options = Options {
+            key_canonicalization: KeyCanonicalization {
+                trim: true,
+                lowercase_ascii: true,
+                ..KeyCanonicalization::default()
+            },
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_canonical", options);
+        ctx.test_file.insert(b"Alice ", b"1").unwrap();
+        assert_eq!(ctx.test_file.get(b" ALICE").unwrap(), Some(b"1".to_vec()));
+        ctx.test_file.insert(b"alice", b"2").unwrap();
+        ctx.test_file.delete(b"BOB").unwrap();
+        assert_eq!(ctx.test_file.sample(10).unwrap(), vec![b"alice".to_vec()]);
+
+        // the recorded policy wins over the options of later opens
+        let reopened = ctx.reopen().unwrap();
+        assert_eq!(reopened.get(b"ALICE").unwrap(), Some(b"2".to_vec()));
+    }
+}
@@ -0,0 +1,134 @@
+use crate::fault::{IoOp, StoreFile};
+use crate::manifest::MANIFEST_FILE;
+use crate::{platform, ActionKV};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Returned by `quiesce`. It holds the store exclusively, so nothing can write
+/// to it while the guard lives, and writes resume once it is dropped.
+#[derive(Debug)]
+pub struct Quiesced<'a> {
+    /// Borrowed so nothing writes to the store while the guard lives.
+    _store: &'a mut ActionKV,
+    path: PathBuf,
+}
+
+impl Quiesced<'_> {
+    /// Directory that is now safe to snapshot.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Hard links `from` to `to`, copying instead where the filesystem can't link
+/// (e.g. across devices).
+fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Copies the first `len` bytes of `from`, the part a checkpoint can rely on.
+fn copy_prefix(from: &Path, to: &Path, len: u64) -> io::Result<()> {
+    let mut source = File::open(from)?;
+    let mut target = File::create(to)?;
+    io::copy(&mut io::Read::take(&mut source, len), &mut target)?;
+    target.sync_all()
+}
+
+impl ActionKV {
+    /// Files that are never written again and can be shared with a checkpoint,
+    /// the sealed segments.
+    fn sealed_files(&self) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .segment_files()?
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect())
+    }
+    /// Creates a consistent copy of the store in the new directory `path` that
+    /// can be opened as a store of its own. Files that are never modified again
+    /// are hard linked, which takes no time or space, the rest is copied.
+    pub fn checkpoint(&mut self, path: &Path) -> io::Result<()> {
+        let sealed = self.sealed_files()?;
+        let data_len = self.file_.metadata()?.len();
+        let quiesced = self.quiesce()?;
+        fs::create_dir(path)?;
+        for file in &sealed {
+            if let Some(name) = file.file_name() {
+                link_or_copy(file, &path.join(name))?;
+            }
+        }
+        let manifest = quiesced.path().join(MANIFEST_FILE);
+        if manifest.exists() {
+            fs::copy(manifest, path.join(MANIFEST_FILE))?;
+        }
+        copy_prefix(&quiesced.path().join("data"), &path.join("data"), data_len)?;
+        fs::copy(quiesced.path().join("index"), path.join("index"))?;
+        platform::sync_dir(path)
+    }
+    /// Brings the files on disk into a consistent, durable state and keeps
+    /// them that way until the returned guard is dropped, so the directory can
+    /// be snapshotted from outside (filesystem, LVM, ZFS...) and reopened from
+    /// the snapshot without recovery.
+    pub fn quiesce(&mut self) -> io::Result<Quiesced<'_>> {
+        let path = self.dir("quiesce")?.to_path_buf();
+        self.flush_index()?;
+        self.inject(IoOp::Sync, StoreFile::Data)?;
+        self.file_.sync_all()?;
+        self.inject(IoOp::Sync, StoreFile::Index)?;
+        self.index_.sync_all()?;
+        // make renames done by compaction durable as well
+        platform::sync_dir(&path)?;
+        Ok(Quiesced { _store: self, path })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestCtx;
+    use crate::ActionKV;
+    use rstest::*;
+    use serial_test::serial;
+    use std::fs;
+    use std::path::Path;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_checkpoint")
+    }
+    #[rstest]
+    #[serial]
+    fn test_quiesce(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        let snapshot = Path::new("test_checkpoint/snapshot");
+        {
+            let quiesced = ctx.test_file.quiesce().unwrap();
+            fs::create_dir(snapshot).unwrap();
+            for file in ["data", "index"] {
+                fs::copy(quiesced.path().join(file), snapshot.join(file)).unwrap();
+            }
+        }
+        ctx.test_file.insert(b"foo", b"baz").unwrap();
+
+        let mut store = ActionKV::open(snapshot).unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_checkpoint(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        let path = Path::new("test_checkpoint/cp");
+        ctx.test_file.checkpoint(path).unwrap();
+        ctx.test_file.insert(b"foo", b"baz").unwrap();
+
+        let mut checkpoint = ActionKV::open(path).unwrap();
+        assert_eq!(checkpoint.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+        checkpoint.insert(b"qux", b"1").unwrap();
+        assert_eq!(ctx.test_file.get(b"foo").unwrap(), Some(b"baz".to_vec()));
+        assert_eq!(ctx.test_file.get(b"qux").unwrap(), None);
+        assert!(ctx.test_file.checkpoint(path).is_err());
+    }
+}
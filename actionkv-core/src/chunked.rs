@@ -0,0 +1,391 @@
+use crate::blake3::{Blake3, DIGEST_LEN};
+use crate::{namespaced_key, ActionKV, ByteStr, ByteString, KeyEvent, OpClass, Operation};
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{self, Read, Write};
+
+/*
+    LARGE VALUES
+    a value longer than Options::chunk_size is written as chunk records
+    followed by a manifest record, all under internal keys, and the key's own
+    record is dropped from the index. Reads that don't find the key fall back
+    to its manifest. The manifest is written last, so a value cut short by a
+    crash is never seen.
+    chunk:    "+chunk" | key_len | key | chunk number
+                         [u32 BE]        [u64 BE]
+    manifest: "+large" | key_len | key
+              value_len | chunk_len | digest
+              [u64 LE]    [u32 LE]    [u8;32]  BLAKE3 of the whole value
+*/
+const CHUNK_PREFIX: &ByteStr = b"+chunk";
+const MANIFEST_PREFIX: &ByteStr = b"+large";
+const MANIFEST_LEN: usize = 12 + DIGEST_LEN;
+/// Chunks `insert_from_reader` cuts when `Options::chunk_size` isn't set.
+const DEFAULT_CHUNK_SIZE: u32 = 1 << 20;
+
+struct Manifest {
+    value_len: u64,
+    chunk_len: u32,
+    digest: [u8; DIGEST_LEN],
+}
+
+impl Manifest {
+    fn chunks(&self) -> u64 {
+        self.value_len.div_ceil(self.chunk_len as u64)
+    }
+    fn encode(&self) -> ByteString {
+        let mut bytes = ByteString::with_capacity(MANIFEST_LEN);
+        bytes.extend(self.value_len.to_le_bytes());
+        bytes.extend(self.chunk_len.to_le_bytes());
+        bytes.extend(self.digest);
+        bytes
+    }
+    fn decode(bytes: &ByteStr) -> io::Result<Self> {
+        if bytes.len() != MANIFEST_LEN || LittleEndian::read_u32(&bytes[8..12]) == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed large value manifest",
+            ));
+        }
+        let mut digest = [0; DIGEST_LEN];
+        digest.copy_from_slice(&bytes[12..]);
+        Ok(Manifest {
+            value_len: LittleEndian::read_u64(&bytes[..8]),
+            chunk_len: LittleEndian::read_u32(&bytes[8..12]),
+            digest,
+        })
+    }
+}
+
+fn chunk_key(key: &ByteStr, chunk: u64) -> ByteString {
+    let mut chunk_key = namespaced_key(CHUNK_PREFIX, key);
+    chunk_key.extend(chunk.to_be_bytes());
+    chunk_key
+}
+
+fn missing_chunk(chunk: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("chunk {} of a large value is missing", chunk),
+    )
+}
+
+fn digest_mismatch() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "large value doesn't match its digest",
+    )
+}
+
+/// A value written in chunks as scans reading the segments through file
+/// handles of their own find it: where its chunk records are, in order, and
+/// the digest of the whole value.
+#[derive(Debug)]
+pub(crate) struct ChunkedValue {
+    pub(crate) key: ByteString,
+    positions: Vec<u64>,
+    digest: [u8; DIGEST_LEN],
+}
+
+impl ChunkedValue {
+    /// The value put back together from the chunks `read` returns for each
+    /// position, checked against its digest.
+    pub(crate) fn assemble<F>(&self, mut read: F) -> io::Result<ByteString>
+    where
+        F: FnMut(u64) -> io::Result<ByteString>,
+    {
+        let mut hasher = Blake3::new();
+        let mut value = ByteString::new();
+        for position in &self.positions {
+            let chunk = read(*position)?;
+            hasher.update(&chunk);
+            value.extend(chunk);
+        }
+        if hasher.finalize() != self.digest {
+            return Err(digest_mismatch());
+        }
+        Ok(value)
+    }
+}
+
+impl ActionKV {
+    fn manifest(&mut self, key: &ByteStr) -> io::Result<Option<Manifest>> {
+        self.value_of(&namespaced_key(MANIFEST_PREFIX, key), true)?
+            .map(|bytes| Manifest::decode(&bytes))
+            .transpose()
+    }
+    /// Keys of the manifest and chunks of `key`'s value if it was chunked.
+    pub(crate) fn chunk_keys(&mut self, key: &ByteStr) -> io::Result<Vec<ByteString>> {
+        let manifest_key = namespaced_key(MANIFEST_PREFIX, key);
+        if self.position(&manifest_key).is_none() {
+            return Ok(Vec::new());
+        }
+        let chunks = self.manifest(key)?.map_or(0, |manifest| manifest.chunks());
+        let mut keys = vec![manifest_key];
+        keys.extend((0..chunks).map(|chunk| chunk_key(key, chunk)));
+        Ok(keys)
+    }
+    /// Appends tombstones for the manifest and chunks of `key`'s value if it
+    /// was chunked, persisting the index is left to the caller.
+    pub(crate) fn remove_chunks(&mut self, key: &ByteStr) -> io::Result<()> {
+        for chunk_key in self.chunk_keys(key)? {
+            self.remove_(&chunk_key)?;
+        }
+        Ok(())
+    }
+    /// Writes everything `reader` holds as the chunked value of `key`,
+    /// replacing its current value. Returns the length of the value,
+    /// persisting the index is left to the caller.
+    pub(crate) fn insert_chunks<R: Read>(
+        &mut self,
+        key: &ByteStr,
+        mut reader: R,
+        chunk_len: u32,
+    ) -> io::Result<u64> {
+        self.writable()?;
+        self.remove_chunks(key)?;
+        if self.position(key).is_some() {
+            self.remove_(key)?;
+        }
+        let mut hasher = Blake3::new();
+        let mut value_len = 0;
+        let mut chunk = ByteString::with_capacity(chunk_len as usize);
+        for n in 0.. {
+            chunk.clear();
+            reader
+                .by_ref()
+                .take(chunk_len as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            hasher.update(&chunk);
+            value_len += chunk.len() as u64;
+            self.insert_(&chunk_key(key, n), &chunk)?;
+        }
+        let manifest = Manifest {
+            value_len,
+            chunk_len,
+            digest: hasher.finalize(),
+        };
+        self.insert_(&namespaced_key(MANIFEST_PREFIX, key), &manifest.encode())?;
+        Ok(value_len)
+    }
+    /// The records putting `value` in place of the value of `key` in chunks
+    /// of `chunk_len`, for groups writing them together with other records:
+    /// tombstones for the chunks of the old value and the key's own record,
+    /// then the chunks and the manifest.
+    pub(crate) fn chunk_records(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr,
+        chunk_len: u32,
+    ) -> io::Result<Vec<(ByteString, Option<ByteString>)>> {
+        let mut records: Vec<(ByteString, Option<ByteString>)> = self
+            .chunk_keys(key)?
+            .into_iter()
+            .map(|chunk_key| (chunk_key, None))
+            .collect();
+        if self.position(key).is_some() {
+            records.push((key.to_vec(), None));
+        }
+        let mut hasher = Blake3::new();
+        for (n, chunk) in value.chunks(chunk_len as usize).enumerate() {
+            hasher.update(chunk);
+            records.push((chunk_key(key, n as u64), Some(chunk.to_vec())));
+        }
+        let manifest = Manifest {
+            value_len: value.len() as u64,
+            chunk_len,
+            digest: hasher.finalize(),
+        };
+        records.push((
+            namespaced_key(MANIFEST_PREFIX, key),
+            Some(manifest.encode()),
+        ));
+        Ok(records)
+    }
+    /// Writes `value` as the current value of `key`, in chunks when it is
+    /// longer than `Options::chunk_size`, persisting the index is left to
+    /// the caller.
+    pub(crate) fn put_value(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        match self.options.chunk_size {
+            Some(chunk_len) if value.len() > chunk_len as usize => {
+                self.insert_chunks(key, value, chunk_len.max(1))?;
+            }
+            _ => {
+                self.remove_chunks(key)?;
+                self.insert_(key, value)?;
+            }
+        }
+        Ok(())
+    }
+    /// Stores everything read from `reader` as the value of `key`, chunked
+    /// like `Options::chunk_size` (1 MiB if unset) says once it outgrows one
+    /// chunk, so the value never has to be in memory as a whole. Chunked
+    /// values aren't indexed for `search`. Returns the length of the value.
+    pub fn insert_from_reader<R: Read>(&mut self, key: &ByteStr, mut reader: R) -> io::Result<u64> {
+        let chunk_len = self.options.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(1);
+        let mut first = ByteString::new();
+        reader
+            .by_ref()
+            .take(chunk_len as u64 + 1)
+            .read_to_end(&mut first)?;
+        if first.len() <= chunk_len as usize {
+            self.insert(key, &first)?;
+            return Ok(first.len() as u64);
+        }
+        let key = self.canonical_key(key);
+        let key = key.as_ref();
+        self.authorize(Operation::Write, key)?;
+        self.track_access(key)?;
+        self.op_class = OpClass::Insert;
+        self.read_index()?;
+        self.unindex_terms(key)?;
+        let value_len = self.insert_chunks(key, first.as_slice().chain(reader), chunk_len)?;
+        self.io_stats
+            .record_operation(OpClass::Insert, key.len() as u64 + value_len);
+        self.index_written()?;
+        self.notify(KeyEvent::Put(key.to_vec()));
+        Ok(value_len)
+    }
+    /// Writes the current value of `key` to `writer`, a chunk at a time for
+    /// chunked values, and returns its length. A chunked value is checked
+    /// against its digest once all of it went out, an error then means what
+    /// was written is not the value that was stored.
+    pub fn get_to<W: Write>(&mut self, key: &ByteStr, mut writer: W) -> io::Result<Option<u64>> {
+        let key = self.canonical_key(key);
+        let key = key.as_ref();
+        self.authorize(Operation::Read, key)?;
+        self.track_access(key)?;
+        self.read_index()?;
+        let verify = !self.options.skip_checksums;
+        if let Some(value) = self.value_of(key, verify)? {
+            writer.write_all(&value)?;
+            return Ok(Some(value.len() as u64));
+        }
+        let Some(manifest) = self.manifest(key)? else {
+            return Ok(None);
+        };
+        let mut hasher = Blake3::new();
+        for n in 0..manifest.chunks() {
+            let chunk = self
+                .value_of(&chunk_key(key, n), verify)?
+                .ok_or_else(|| missing_chunk(n))?;
+            hasher.update(&chunk);
+            writer.write_all(&chunk)?;
+        }
+        if hasher.finalize() != manifest.digest {
+            return Err(digest_mismatch());
+        }
+        Ok(Some(manifest.value_len))
+    }
+    /// Keys whose value is written in chunks, found by their manifests.
+    pub(crate) fn chunked_keys(&self) -> Vec<ByteString> {
+        self.index
+            .keys()
+            .filter_map(|manifest_key| {
+                let rest = manifest_key.strip_prefix(MANIFEST_PREFIX)?;
+                let (key_len, key) = rest.split_at_checked(4)?;
+                let key_len = u32::from_be_bytes(key_len.try_into().ok()?) as usize;
+                (key.len() == key_len).then(|| key.to_vec())
+            })
+            .collect()
+    }
+    /// Current value of `key` put back together from its chunks, `None`
+    /// unless it was chunked.
+    pub(crate) fn chunked_value(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let manifest_key = namespaced_key(MANIFEST_PREFIX, key);
+        if self.position(&manifest_key).is_none() {
+            return Ok(None);
+        }
+        let mut value = ByteString::new();
+        Ok(self.get_to(key, &mut value)?.map(|_| value))
+    }
+    /// Position of the record holding the value of `key`, its manifest for
+    /// values written in chunks.
+    #[cfg(feature = "arrow")]
+    pub(crate) fn value_position(&self, key: &ByteStr) -> Option<u64> {
+        self.position(key)
+            .or_else(|| self.position(&namespaced_key(MANIFEST_PREFIX, key)))
+    }
+    /// Values written in chunks whose key starts with `prefix`, sorted by key.
+    /// Scans going over the records under a prefix take these along, the
+    /// records of their chunks are under the store's own keys.
+    pub(crate) fn chunked_values(&mut self, prefix: &ByteStr) -> io::Result<Vec<ChunkedValue>> {
+        let mut keys = self.chunked_keys();
+        keys.retain(|key| key.starts_with(prefix));
+        keys.sort();
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let Some(manifest) = self.manifest(&key)? else {
+                continue;
+            };
+            let positions = (0..manifest.chunks())
+                .map(|n| {
+                    self.position(&chunk_key(&key, n))
+                        .ok_or_else(|| missing_chunk(n))
+                })
+                .collect::<io::Result<_>>()?;
+            values.push(ChunkedValue {
+                key,
+                positions,
+                digest: manifest.digest,
+            });
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::{is_internal_key, Options, INDEX_KEY};
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[case(Options::default())]
+    #[case(Options { hashed_keys: true, ..Options::default() })]
+    #[serial]
+    fn test_chunked_values(#[case] options: Options) {
+        let options = Options {
+            chunk_size: Some(10),
+            ..options
+        };
+        let mut ctx = TestCtx::setup_with_options("test_chunked", options.clone());
+        let store = &mut ctx.test_file;
+        let value: ByteString = (0..95u8).collect();
+        store.insert(b"big", &value).unwrap();
+        store.read_index().unwrap();
+        assert!(store.position(b"big").is_none());
+        assert_eq!(store.get(b"big").unwrap(), Some(value.clone()));
+        let mut streamed = ByteString::new();
+        assert_eq!(store.get_to(b"big", &mut streamed).unwrap(), Some(95));
+        assert_eq!(streamed, value);
+
+        store.insert(b"big", b"small").unwrap();
+        assert_eq!(store.get(b"big").unwrap(), Some(b"small".to_vec()));
+        assert_eq!(store.insert_from_reader(b"big", &value[..33]).unwrap(), 33);
+        assert_eq!(store.get(b"big").unwrap(), Some(value[..33].to_vec()));
+        assert_eq!(store.insert_from_reader(b"tiny", &b"abc"[..]).unwrap(), 3);
+        store.read_index().unwrap();
+        assert!(store.position(b"tiny").is_some());
+        store.delete(b"big").unwrap();
+        assert_eq!(store.get(b"big").unwrap(), None);
+        assert_eq!(store.get_to(b"big", io::sink()).unwrap(), None);
+        store.read_index().unwrap();
+        assert!(store
+            .index
+            .keys()
+            .all(|key| !is_internal_key(key) || key.as_slice() == INDEX_KEY));
+
+        store.insert(b"big", &value).unwrap();
+        let store = ctx.reopen_with_options(options).unwrap();
+        assert_eq!(store.get(b"big").unwrap(), Some(value.clone()));
+        store
+            .put_internal(&chunk_key(b"big", 3), b"0123456789")
+            .unwrap();
+        assert!(store.get(b"big").is_err());
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where the store takes the current time from, for access tracking windows,
+/// time series timestamps and anything else that has to know what time it is.
+/// Defaults to `SystemClock`, tests and devices without a real time clock can
+/// set `Options::clock` to their own.
+pub trait Clock: Debug + Send + Sync {
+    /// Time elapsed since the Unix epoch.
+    fn now(&self) -> Duration;
+}
+
+/// The operating system's wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        // a clock set before 1970 is treated as standing at the epoch
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock that only moves when told to, so tests can step through time.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new(now: Duration) -> Self {
+        ManualClock {
+            now: Mutex::new(now),
+        }
+    }
+    pub fn set(&self, now: Duration) {
+        *self
+            .now
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = now;
+    }
+    pub fn advance(&self, by: Duration) {
+        *self
+            .now
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self
+            .now
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
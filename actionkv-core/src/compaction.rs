@@ -0,0 +1,611 @@
+use crate::format::{FileHeader, Framing, DATA_MAGIC, HEADER_LEN, INDEX_MAGIC};
+use crate::index_log::{Delta, IndexLog};
+use crate::stats::OpClass;
+use crate::{
+    format, index_digest, is_internal_key, lock, segment, write_record, ActionKV, AdminAction,
+    ByteStr, ByteString, INDEX_KEY,
+};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+    COMPACTION
+    live records are copied into data.compact and the index written for them
+    into index.compact, both stamped with a fresh generation. The data file is
+    renamed over first, so a crash in between leaves data and index.compact
+    sharing a generation, which open recognizes and finishes. Sealed segments
+    are copied along and removed after, the ones a crash leaves behind are of
+    the old generation and removed on open.
+*/
+const DATA_COMPACT: &str = "data.compact";
+const INDEX_COMPACT: &str = "index.compact";
+
+/// Where a record was found in the data file being compacted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordMeta {
+    pub offset: u64,
+    /// Bytes the record takes on disk including its framing.
+    pub size: u64,
+}
+
+/// What happens to a record while it is copied during compaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    Drop,
+    Replace(ByteString),
+}
+
+/// Called with every live record during `compact`, e.g. to expire, migrate
+/// or scrub values. The store's own bookkeeping keys are never passed to it.
+pub trait CompactionFilter: Debug + Send {
+    fn filter(&self, key: &ByteStr, value: &ByteStr, meta: &RecordMeta) -> FilterDecision;
+}
+
+/// Outcome of one `compact` pass.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Records copied unchanged, the store's own bookkeeping included.
+    pub kept: u64,
+    pub dropped: u64,
+    pub replaced: u64,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// How far the last `compact` got, reported by `stats()`. A compaction that
+/// failed has `records_done` short of `records_total`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionProgress {
+    /// Bytes a second compaction is held to, `None` when unthrottled.
+    pub throttle: Option<u64>,
+    pub records_done: u64,
+    pub records_total: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// Holds compaction I/O to `rate` bytes a second averaged since it started,
+/// sleeping whenever it got ahead.
+struct Throttle {
+    rate: Option<u64>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(rate: Option<u64>) -> Self {
+        Throttle {
+            rate,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+    fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        let Some(rate) = self.rate.filter(|rate| *rate > 0) else {
+            return;
+        };
+        let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+        if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    paused: bool,
+    cancelled: bool,
+}
+
+/// Pauses, resumes or cancels the compactions of a store from another
+/// thread, e.g. to get a merge out of the way of a traffic spike. Got from
+/// `ActionKV::compaction_control`, every clone controls the same store.
+#[derive(Debug, Clone, Default)]
+pub struct CompactionControl {
+    state: Arc<(Mutex<ControlState>, Condvar)>,
+}
+
+impl CompactionControl {
+    fn lock(&self) -> MutexGuard<'_, ControlState> {
+        self.state.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Holds the running compaction, and any started later, before its next
+    /// record until `resume` or `cancel`.
+    pub fn pause(&self) {
+        self.lock().paused = true;
+    }
+    pub fn resume(&self) {
+        self.lock().paused = false;
+        self.state.1.notify_all();
+    }
+    /// Stops the running compaction before its next record, it then fails
+    /// with `ErrorKind::Interrupted` leaving the store as it was before.
+    /// Does nothing when no compaction is running.
+    pub fn cancel(&self) {
+        let mut state = self.lock();
+        state.cancelled = true;
+        state.paused = false;
+        self.state.1.notify_all();
+    }
+    pub fn is_paused(&self) -> bool {
+        self.lock().paused
+    }
+    fn start(&self) {
+        self.lock().cancelled = false;
+    }
+    /// Waits out a pause, `Err` once cancelled. Tells whether it had to wait.
+    fn checkpoint(&self) -> io::Result<bool> {
+        let mut state = self.lock();
+        let paused = state.paused;
+        while state.paused && !state.cancelled {
+            state = self
+                .state
+                .1
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        if state.cancelled {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "compaction was cancelled",
+            ));
+        }
+        Ok(paused)
+    }
+}
+
+fn generation_of(path: &Path, magic: &[u8; 4]) -> io::Result<Option<format::Generation>> {
+    match File::open(path) {
+        Ok(mut file) => {
+            Ok(FileHeader::read_from(&mut file, magic)?.map(|header| header.generation))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Completes or rolls back a compaction that was interrupted by a crash.
+pub(crate) fn finish_interrupted(path: &Path) -> io::Result<()> {
+    let index_compact = path.join(INDEX_COMPACT);
+    if !index_compact.exists() {
+        let _ = fs::remove_file(path.join(DATA_COMPACT));
+        return Ok(());
+    }
+    let data = generation_of(&path.join("data"), DATA_MAGIC)?;
+    let index = generation_of(&index_compact, INDEX_MAGIC)?;
+    if data.is_some() && data == index {
+        fs::rename(index_compact, path.join("index"))?;
+    } else {
+        let _ = fs::remove_file(path.join(DATA_COMPACT));
+        fs::remove_file(index_compact)?;
+    }
+    Ok(())
+}
+
+impl ActionKV {
+    /// The order compaction copies `entries` in: the records of keys under
+    /// one of `Options::key_groups` first, group by group in the order they
+    /// were declared, then everything else. File order is kept within each.
+    fn compaction_order(
+        &mut self,
+        mut entries: Vec<(ByteString, u64)>,
+    ) -> io::Result<Vec<(ByteString, u64)>> {
+        entries.sort_by_key(|(_, position)| *position);
+        if self.options.key_groups.is_empty() {
+            return Ok(entries);
+        }
+        let mut grouped = Vec::with_capacity(entries.len());
+        for (key, position) in entries {
+            let group = if is_internal_key(&key) {
+                None
+            } else if self.framing.hashed_keys {
+                // only the record tells what the key is
+                let record_key = self.get_raw_at(position, false)?.key;
+                self.key_group(&record_key)
+            } else {
+                self.key_group(&key)
+            };
+            grouped.push((group.unwrap_or(usize::MAX), (key, position)));
+        }
+        // stable, so records of a group stay in file order
+        grouped.sort_by_key(|(group, _)| *group);
+        Ok(grouped.into_iter().map(|(_, entry)| entry).collect())
+    }
+    fn key_group(&self, key: &ByteStr) -> Option<usize> {
+        self.options
+            .key_groups
+            .iter()
+            .position(|prefix| key.starts_with(prefix))
+    }
+    /// Run `filter` over every record the next `compact` copies.
+    pub fn set_compaction_filter(&mut self, filter: Box<dyn CompactionFilter>) {
+        self.compaction_filter = Some(filter);
+    }
+    /// Changes `Options::compaction_rate`, taking effect with the next
+    /// `compact`.
+    pub fn set_compaction_rate(&mut self, bytes_per_sec: Option<u64>) {
+        self.options.compaction_rate = bytes_per_sec;
+        let rate = bytes_per_sec.map_or("unthrottled".to_string(), |rate| rate.to_string());
+        // the rate isn't persisted, a lost entry costs less than failing here
+        let _ = self.log_admin(AdminAction::Config, &format!("compaction_rate {}", rate));
+    }
+    /// Handle to pause, resume or cancel compactions of this store while
+    /// they run.
+    pub fn compaction_control(&self) -> CompactionControl {
+        self.compaction_control.clone()
+    }
+    pub(crate) fn compaction_progress(&self) -> CompactionProgress {
+        CompactionProgress {
+            throttle: self.options.compaction_rate,
+            ..self.compaction_progress
+        }
+    }
+    /// Rewrites the store keeping only the current value of every key, which
+    /// drops overwritten values and tombstones, passing user records through the
+    /// compaction filter on the way. Time series points past their retention
+    /// are dropped first, see `enforce_retention_now`.
+    pub fn compact(&mut self) -> io::Result<CompactionReport> {
+        self.compact_with_progress(|_, _| {})
+    }
+    /// `compact` calling `progress` with the number of records gone through so
+    /// far and the total after each one.
+    pub fn compact_with_progress<P>(&mut self, mut progress: P) -> io::Result<CompactionReport>
+    where
+        P: FnMut(u64, u64),
+    {
+        self.writable()?;
+        let dir = self.dir("compaction")?.to_path_buf();
+        self.enforce_retention_now()?;
+        self.compaction_control.start();
+        self.op_class = OpClass::Maintenance;
+        self.read_index()?;
+        let generation = format::new_generation();
+        let mut report = CompactionReport {
+            bytes_before: self.file_.metadata()?.len() + self.sealed_len()?,
+            ..CompactionReport::default()
+        };
+
+        self.prune_inline();
+        // the segments the footers describe are dropped with it
+        let entries: Vec<(ByteString, u64)> = self
+            .index
+            .iter()
+            .filter(|(key, _)| !segment::is_footer_key(key))
+            .map(|(key, position)| (key.clone(), *position))
+            .collect();
+        let entries = self.compaction_order(entries)?;
+        let values = self.compaction_codec(&entries)?;
+        let mut inlined = self.inline_take();
+        let framing = Framing {
+            compressed_values: values.is_some(),
+            ..self.framing
+        };
+
+        let data_compact = dir.join(DATA_COMPACT);
+        let mut f = BufWriter::new(File::create(&data_compact)?);
+        FileHeader::for_data(generation, framing).write_to(&mut f)?;
+        f.write_all(&vec![0; (framing.records_start() - HEADER_LEN) as usize])?;
+        let mut position = framing.records_start();
+        let mut index = HashMap::with_capacity(entries.len());
+        let total = entries.len() as u64;
+        let mut throttle = Throttle::new(self.options.compaction_rate);
+        self.compaction_progress = CompactionProgress {
+            records_total: total,
+            ..CompactionProgress::default()
+        };
+        for (done, (key, offset)) in entries.into_iter().enumerate() {
+            progress(done as u64, total);
+            self.compaction_progress.records_done = done as u64;
+            match self.compaction_control.checkpoint() {
+                // the time spent paused isn't made up for in a burst
+                Ok(true) => throttle = Throttle::new(self.options.compaction_rate),
+                Ok(false) => {}
+                Err(err) => {
+                    drop(f);
+                    fs::remove_file(&data_compact)?;
+                    return Err(err);
+                }
+            }
+            // with hashed keys `key` is the hash, the record has the key itself
+            let record = self.get_at(offset, false)?;
+            let read = framing.record_len(record.key.len() as u32, record.value.len() as u64);
+            self.compaction_progress.bytes_read += read;
+            throttle.consume(read);
+            let decision = match &self.compaction_filter {
+                Some(filter) if !is_internal_key(&key) => filter.filter(
+                    &record.key,
+                    &record.value,
+                    &RecordMeta { offset, size: read },
+                ),
+                _ => FilterDecision::Keep,
+            };
+            let value = match decision {
+                FilterDecision::Keep => {
+                    report.kept += 1;
+                    self.inline_moved(&mut inlined, &record.key, offset, position);
+                    record.value
+                }
+                FilterDecision::Drop => {
+                    report.dropped += 1;
+                    continue;
+                }
+                FilterDecision::Replace(value) => {
+                    report.replaced += 1;
+                    self.inline_put(&record.key, position, &value);
+                    value
+                }
+            };
+            let value = match &values {
+                Some(codec) => codec.encode(&value)?,
+                None => value,
+            };
+            let written = write_record(&mut f, framing, &record.key, &value)?;
+            self.compaction_progress.bytes_written += written;
+            throttle.consume(written);
+            index.insert(key, position);
+            position += written;
+        }
+        progress(total, total);
+        self.compaction_progress.records_done = total;
+        f.flush()?;
+        f.get_ref().sync_data()?;
+        drop(f);
+        report.bytes_after = position;
+
+        let index_compact = dir.join(INDEX_COMPACT);
+        let mut f = BufWriter::new(File::create(&index_compact)?);
+        let mut header = FileHeader::new(INDEX_MAGIC, generation);
+        header.data_len = position;
+        header.write_to(&mut f)?;
+        let index_as_bytes = self.index_codec.encode(&index);
+        let mut index_bytes =
+            HEADER_LEN + write_record(&mut f, Framing::default(), INDEX_KEY, &index_as_bytes)?;
+        if self.options.index_digests {
+            let digest = index_digest::digest_record(&index)?;
+            f.write_all(&digest)?;
+            index_bytes += digest.len() as u64;
+        }
+        if let Some(inlined) = self.inline_record(true)? {
+            f.write_all(&inlined)?;
+            index_bytes += inlined.len() as u64;
+        }
+        f.flush()?;
+        f.get_ref().sync_data()?;
+        drop(f);
+
+        // the dictionaries the new data file needs have to be there first
+        self.save_manifest(values.as_deref())?;
+        // keeps read only opens out of the renames, other writers are kept
+        // out for as long as the store is open
+        let lock = lock::exclusive(&dir)?;
+        fs::rename(&data_compact, dir.join("data"))?;
+        fs::rename(&index_compact, dir.join("index"))?;
+        drop(lock);
+        // their live records were copied, the generation they have is gone
+        self.drop_segments()?;
+        self.file_ = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(dir.join("data"))?;
+        self.index_ = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dir.join("index"))?;
+        self.appender.reset(position);
+        self.index_len = index_bytes;
+        self.generation = Some(generation);
+        self.framing = framing;
+        self.values = values;
+        self.data_start = framing.records_start();
+        self.index_start = HEADER_LEN;
+        self.index = index;
+        self.inline_saved();
+        self.unsaved_records = 0;
+        self.saved_tail = position;
+        self.index_log =
+            IndexLog::in_sync(Delta::base_of(&index_as_bytes), index_bytes - HEADER_LEN, 0);
+        self.compacted_tail = position;
+        self.write_log.compacted();
+        self.io_stats
+            .record_physical(OpClass::Maintenance, position);
+        self.io_stats.record_physical(OpClass::Index, index_bytes);
+
+        if self.tokenizer.is_some() && report.dropped + report.replaced > 0 {
+            self.rebuild_search_index()?;
+        }
+        self.log_admin(
+            AdminAction::Compaction,
+            &format!(
+                "{} -> {} bytes, {} kept, {} dropped, {} replaced",
+                report.bytes_before,
+                report.bytes_after,
+                report.kept,
+                report.dropped,
+                report.replaced
+            ),
+        )?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::{Options, WordTokenizer};
+    use rstest::*;
+    use serial_test::serial;
+
+    #[derive(Debug)]
+    struct ScrubSecrets;
+
+    impl CompactionFilter for ScrubSecrets {
+        fn filter(&self, key: &ByteStr, value: &ByteStr, _meta: &RecordMeta) -> FilterDecision {
+            if key.starts_with(b"tmp:") {
+                FilterDecision::Drop
+            } else if value.starts_with(b"secret") {
+                FilterDecision::Replace(b"***".to_vec())
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_compaction")
+    }
+    #[rstest]
+    #[serial]
+    fn test_compact_keeps_current_values(mut ctx: TestCtx) {
+        let store = &mut ctx.test_file;
+        for i in 0..10u8 {
+            store.insert(b"foo", &[i; 100]).unwrap();
+        }
+        store.insert(b"bar", b"1").unwrap();
+        store.delete(b"bar").unwrap();
+        let report = store.compact().unwrap();
+        assert_eq!(report.kept, 1);
+        assert_eq!(report.bytes_after, HEADER_LEN + 12 + 3 + 100);
+        assert!(report.bytes_before > report.bytes_after);
+
+        store.insert(b"baz", b"2").unwrap();
+        let store = ctx.reopen().unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), Some(vec![9; 100]));
+        assert_eq!(store.get(b"bar").unwrap(), None);
+        assert_eq!(store.get(b"baz").unwrap(), Some(b"2".to_vec()));
+    }
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[serial]
+    fn test_key_groups(#[case] hashed_keys: bool) {
+        let options = Options {
+            key_groups: vec![b"order:".to_vec(), b"user:".to_vec()],
+            hashed_keys,
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_compaction", options);
+        let store = &mut ctx.test_file;
+        for i in 0..20 {
+            store
+                .insert(format!("user:{}", i).as_bytes(), b"u")
+                .unwrap();
+            store
+                .insert(format!("misc:{}", i).as_bytes(), b"m")
+                .unwrap();
+            store
+                .insert(format!("order:{}", i).as_bytes(), b"o")
+                .unwrap();
+        }
+        store.compact().unwrap();
+
+        let mut records = store.scan_filtered(b"", |_, _| true).unwrap();
+        store.read_index().unwrap();
+        let mut positions: Vec<(u64, ByteString)> = records
+            .drain(..)
+            .map(|pair| (store.position(&pair.key).unwrap(), pair.key))
+            .collect();
+        positions.sort();
+        let prefixes: Vec<&ByteStr> = positions.iter().map(|(_, key)| &key[..4]).collect();
+        let regions: Vec<&ByteStr> = prefixes
+            .chunk_by(|a, b| a == b)
+            .map(|region| region[0])
+            .collect();
+        assert_eq!(regions, vec![b"orde", b"user", b"misc"]);
+        assert_eq!(store.get(b"user:7").unwrap(), Some(b"u".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_compaction_filter(mut ctx: TestCtx) {
+        let store = &mut ctx.test_file;
+        store.set_tokenizer(Box::new(WordTokenizer));
+        store.insert(b"tmp:1", b"scratch").unwrap();
+        store.insert(b"user:1", b"secret token").unwrap();
+        store.insert(b"user:2", b"plain").unwrap();
+        store.set_compaction_filter(Box::new(ScrubSecrets));
+        let report = store.compact().unwrap();
+        assert_eq!((report.dropped, report.replaced), (1, 1));
+
+        assert_eq!(store.get(b"tmp:1").unwrap(), None);
+        assert_eq!(store.get(b"user:1").unwrap(), Some(b"***".to_vec()));
+        assert_eq!(store.get(b"user:2").unwrap(), Some(b"plain".to_vec()));
+        assert!(store.search(b"secret").unwrap().is_empty());
+    }
+    #[rstest]
+    #[serial]
+    fn test_interrupted_compaction_is_finished_on_open(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        ctx.test_file.compact().unwrap();
+        // simulate a crash right after the data file was renamed over
+        fs::copy("test_compaction/index", "test_compaction/index.compact").unwrap();
+        fs::write("test_compaction/index", b"stale").unwrap();
+
+        let store = ctx.crash_and_reopen(Options::default()).unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+        assert!(!Path::new("test_compaction/index.compact").exists());
+    }
+    #[rstest]
+    #[serial]
+    fn test_compaction_rate(mut ctx: TestCtx) {
+        let store = &mut ctx.test_file;
+        for i in 0..10u8 {
+            store.insert(&[b'k', i], &[i; 1000]).unwrap();
+        }
+        store.set_compaction_rate(Some(40_000));
+        let started = Instant::now();
+        store.compact().unwrap();
+        // about 20 KB read and written at 40 KB/s
+        assert!(started.elapsed() >= Duration::from_millis(400));
+
+        let progress = store.stats().compaction;
+        assert_eq!(progress.throttle, Some(40_000));
+        assert_eq!(progress.records_done, progress.records_total);
+        assert_eq!(progress.bytes_read, progress.bytes_written);
+        assert!(progress.bytes_written > 10_000);
+    }
+    #[rstest]
+    #[serial]
+    fn test_compaction_control(ctx: TestCtx) {
+        // the compacting thread takes the store along
+        let (mut store, _dir) = ctx.into_store();
+        store.insert(b"foo", b"1").unwrap();
+        store.insert(b"foo", b"2").unwrap();
+        let control = store.compaction_control();
+
+        control.pause();
+        let running = thread::spawn(move || {
+            let report = store.compact();
+            (store, report)
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(!running.is_finished());
+        control.resume();
+        let (mut store, report) = running.join().unwrap();
+        assert_eq!(report.unwrap().kept, 1);
+
+        store.insert(b"foo", b"3").unwrap();
+        let len = fs::metadata("test_compaction/data").unwrap().len();
+        control.pause();
+        let running = thread::spawn(move || {
+            let report = store.compact();
+            (store, report)
+        });
+        thread::sleep(Duration::from_millis(100));
+        control.cancel();
+        let (mut store, report) = running.join().unwrap();
+        assert_eq!(report.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert!(!Path::new("test_compaction/data.compact").exists());
+        assert_eq!(fs::metadata("test_compaction/data").unwrap().len(), len);
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"3".to_vec()));
+        assert!(store.compact().is_ok());
+    }
+}
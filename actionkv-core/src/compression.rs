@@ -0,0 +1,363 @@
+#[cfg(feature = "zstd")]
+use crate::{is_internal_key, manifest::Manifest};
+use crate::{ActionKV, ByteStr, ByteString, Options};
+#[cfg(feature = "zstd")]
+use std::collections::HashMap;
+use std::io;
+#[cfg(feature = "zstd")]
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+#[cfg(feature = "zstd")]
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+/*
+    COMPRESSED VALUES
+    data files stamped with FLAG_COMPRESSED_VALUES prefix every value but the
+    empty tombstone with a tag byte:
+        0 | value            stored as is, compression didn't pay off
+        1 | zstd frame       compressed, against the dictionary whose id the
+                             frame names, see the manifest
+    Dictionaries are trained by compaction from a sample of the live values
+    and every value it copies is compressed against the new one.
+*/
+#[cfg(feature = "zstd")]
+const TAG_RAW: u8 = 0;
+#[cfg(feature = "zstd")]
+const TAG_ZSTD: u8 = 1;
+
+/// Settings of `Options::compression`.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone)]
+pub struct Compression {
+    /// zstd level, 1 (fastest) to 22.
+    pub level: i32,
+    /// Largest dictionary compaction trains, 0 compresses without one.
+    pub dictionary_size: usize,
+    /// Live values compaction samples to train the dictionary on.
+    pub training_samples: usize,
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            level: 3,
+            dictionary_size: 16 << 10,
+            training_samples: 4096,
+        }
+    }
+}
+
+/// Compresses and decompresses the values of a store with compressed values.
+#[cfg(feature = "zstd")]
+pub(crate) struct ValueCodec {
+    level: i32,
+    /// Dictionary new values are compressed against.
+    current: Option<(u32, EncoderDictionary<'static>)>,
+    decoders: HashMap<u32, DecoderDictionary<'static>>,
+    manifest: Manifest,
+}
+
+#[cfg(feature = "zstd")]
+impl std::fmt::Debug for ValueCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ValueCodec")
+            .field("level", &self.level)
+            .field("dictionary", &self.dictionary_id())
+            .finish()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl ValueCodec {
+    /// Codec of the store at `path`, compressing against the newest
+    /// dictionary of its manifest.
+    pub fn open(path: &Path, options: &Options) -> io::Result<Self> {
+        let level = options
+            .compression
+            .as_ref()
+            .map_or(Compression::default().level, |compression| {
+                compression.level
+            });
+        ValueCodec::new(level, Manifest::read(path)?)
+    }
+    fn new(level: i32, manifest: Manifest) -> io::Result<Self> {
+        let decoders = manifest
+            .dictionaries
+            .iter()
+            .map(|(id, dictionary)| (*id, DecoderDictionary::copy(dictionary)))
+            .collect();
+        let current = manifest
+            .dictionaries
+            .last()
+            .map(|(id, dictionary)| (*id, EncoderDictionary::copy(dictionary, level)));
+        Ok(ValueCodec {
+            level,
+            current,
+            decoders,
+            manifest,
+        })
+    }
+    pub fn dictionary_id(&self) -> Option<u32> {
+        self.current.as_ref().map(|(id, _)| *id)
+    }
+    /// Bytes of the dictionaries, counted once for the manifest and once
+    /// for the decoder made of each, plus once for the encoder.
+    pub fn dictionary_bytes(&self) -> usize {
+        let dictionaries: usize = self
+            .manifest
+            .dictionaries
+            .iter()
+            .map(|(_, dictionary)| dictionary.len())
+            .sum();
+        let current = self
+            .manifest
+            .dictionaries
+            .last()
+            .map_or(0, |(_, dictionary)| dictionary.len());
+        2 * dictionaries + current
+    }
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+    pub fn encode(&self, value: &ByteStr) -> io::Result<ByteString> {
+        if value.is_empty() {
+            return Ok(ByteString::new());
+        }
+        let compressed = match &self.current {
+            Some((_, dictionary)) => {
+                zstd::bulk::Compressor::with_prepared_dictionary(dictionary)?.compress(value)?
+            }
+            None => zstd::bulk::compress(value, self.level)?,
+        };
+        let mut encoded = ByteString::with_capacity(1 + compressed.len().min(value.len()));
+        if compressed.len() < value.len() {
+            encoded.push(TAG_ZSTD);
+            encoded.extend(compressed);
+        } else {
+            encoded.push(TAG_RAW);
+            encoded.extend(value);
+        }
+        Ok(encoded)
+    }
+    pub fn decode(&self, mut value: ByteString) -> io::Result<ByteString> {
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+        match value.first() {
+            None => Ok(value),
+            Some(&TAG_RAW) => {
+                value.remove(0);
+                Ok(value)
+            }
+            Some(&TAG_ZSTD) => {
+                let frame = &value[1..];
+                let mut decoded = ByteString::new();
+                // decompressed values can't be larger than any value written
+                let limit = u32::MAX as u64 + 1;
+                match zstd::zstd_safe::get_dict_id_from_frame(frame) {
+                    Some(id) => {
+                        let dictionary = self.decoders.get(&id.get()).ok_or_else(|| {
+                            invalid(format!("value compressed with unknown dictionary {}", id))
+                        })?;
+                        zstd::stream::read::Decoder::with_prepared_dictionary(frame, dictionary)?
+                            .take(limit)
+                            .read_to_end(&mut decoded)?;
+                    }
+                    None => {
+                        zstd::stream::read::Decoder::with_buffer(frame)?
+                            .take(limit)
+                            .read_to_end(&mut decoded)?;
+                    }
+                }
+                if decoded.len() as u64 == limit {
+                    return Err(invalid("compressed value is too large".to_string()));
+                }
+                Ok(decoded)
+            }
+            Some(tag) => Err(invalid(format!("unknown value encoding {}", tag))),
+        }
+    }
+    /// Codec compressing against a dictionary trained on `samples`, which
+    /// still reads values compressed by `self`. Without enough samples to
+    /// train on, the current dictionary is kept.
+    fn retrain(&self, compression: &Compression, samples: &[ByteString]) -> io::Result<Self> {
+        let trained = match compression.dictionary_size {
+            0 => None,
+            size => zstd::dict::from_samples(samples, size).ok(),
+        };
+        let mut manifest = Manifest::default();
+        // values still compressed against the current dictionary stay readable
+        // until the new data file is in place
+        if let Some(id) = self.dictionary_id() {
+            manifest.dictionaries.extend(
+                self.manifest
+                    .dictionaries
+                    .iter()
+                    .find(|(d, _)| *d == id)
+                    .cloned(),
+            );
+        }
+        if let Some(dictionary) = trained {
+            if let Some(id) = zstd::zstd_safe::get_dict_id(&dictionary) {
+                manifest.dictionaries.retain(|(d, _)| *d != id.get());
+                manifest.dictionaries.push((id.get(), dictionary));
+            }
+        }
+        ValueCodec::new(compression.level, manifest)
+    }
+}
+
+/// Stands in for the codec when the zstd feature is off, stores with
+/// compressed values can't be opened then.
+#[cfg(not(feature = "zstd"))]
+#[derive(Debug)]
+pub(crate) enum ValueCodec {}
+
+#[cfg(not(feature = "zstd"))]
+impl ValueCodec {
+    pub fn open(_path: &Path, _options: &Options) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the store compresses its values, which needs the zstd feature",
+        ))
+    }
+    pub fn dictionary_bytes(&self) -> usize {
+        match *self {}
+    }
+    pub fn encode(&self, _value: &ByteStr) -> io::Result<ByteString> {
+        match *self {}
+    }
+    pub fn decode(&self, _value: ByteString) -> io::Result<ByteString> {
+        match *self {}
+    }
+}
+
+impl ActionKV {
+    /// `value` as it is stored in the data file.
+    pub(crate) fn encode_value<'v>(
+        &self,
+        value: &'v ByteStr,
+    ) -> io::Result<std::borrow::Cow<'v, ByteStr>> {
+        match &self.values {
+            Some(codec) => Ok(codec.encode(value)?.into()),
+            None => Ok(value.into()),
+        }
+    }
+    /// Inverse of `encode_value`.
+    pub(crate) fn decode_value(&self, value: ByteString) -> io::Result<ByteString> {
+        match &self.values {
+            Some(codec) => codec.decode(value),
+            None => Ok(value),
+        }
+    }
+    /// Codec the data file compaction writes should use, `None` for plain
+    /// values. Trains a new dictionary on up to `Compression::training_samples`
+    /// of the live values at `entries` when compression is configured.
+    #[cfg(feature = "zstd")]
+    pub(crate) fn compaction_codec(
+        &mut self,
+        entries: &[(ByteString, u64)],
+    ) -> io::Result<Option<Arc<ValueCodec>>> {
+        let Some(compression) = self.options.compression.clone() else {
+            return Ok(self.values.clone());
+        };
+        let mut samples = Vec::new();
+        let live = entries.iter().filter(|(key, _)| !is_internal_key(key));
+        for (_, position) in
+            crate::rng::Rng::new().choose_multiple(live, compression.training_samples)
+        {
+            samples.push(self.get_at(*position, false)?.value);
+        }
+        let codec = match &self.values {
+            Some(codec) => codec.retrain(&compression, &samples)?,
+            None => ValueCodec::new(compression.level, Manifest::default())?
+                .retrain(&compression, &samples)?,
+        };
+        Ok(Some(Arc::new(codec)))
+    }
+    /// Persists the manifest `codec` reads with, unless it already is.
+    #[cfg(feature = "zstd")]
+    pub(crate) fn save_manifest(&self, codec: Option<&ValueCodec>) -> io::Result<()> {
+        match codec {
+            Some(codec)
+                if self
+                    .values
+                    .as_ref()
+                    .map(|values| &values.manifest().dictionaries)
+                    != Some(&codec.manifest().dictionaries) =>
+            {
+                let dir = self.dir("a manifest")?;
+                let mut manifest = codec.manifest().clone();
+                manifest.key_canonicalization = Some(self.key_canonicalization)
+                    .filter(|canonicalization| !canonicalization.is_identity());
+                // the data file isn't replaced yet, its segments still count
+                manifest.segments = Manifest::read(dir)?.segments;
+                manifest.write(dir)
+            }
+            _ => Ok(()),
+        }
+    }
+    #[cfg(not(feature = "zstd"))]
+    pub(crate) fn save_manifest(&self, _codec: Option<&ValueCodec>) -> io::Result<()> {
+        Ok(())
+    }
+    #[cfg(not(feature = "zstd"))]
+    pub(crate) fn compaction_codec(
+        &mut self,
+        _entries: &[(ByteString, u64)],
+    ) -> io::Result<Option<Arc<ValueCodec>>> {
+        Ok(None)
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serial_test::serial;
+
+    fn value(i: u32) -> ByteString {
+        format!(
+            r#"{{"user_id":{},"status":"active","plan":"premium","region":"eu-west-1"}}"#,
+            i
+        )
+        .into_bytes()
+    }
+    #[rstest]
+    #[serial]
+    fn test_dictionary_compression() {
+        let options = Options {
+            compression: Some(Compression::default()),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_compression", options);
+        let store = &mut ctx.test_file;
+        for i in 0..500 {
+            store
+                .insert(format!("user:{}", i).as_bytes(), &value(i))
+                .unwrap();
+        }
+        store.compact().unwrap();
+        assert!(store.values.as_ref().unwrap().dictionary_id().is_some());
+        let compacted = std::fs::metadata("test_compression/data").unwrap().len();
+        let raw: usize = (0..500).map(|i| value(i).len()).sum();
+        assert!((compacted as usize) < raw);
+
+        store.insert(b"user:new", &value(1000)).unwrap();
+        store.delete(b"user:7").unwrap();
+        let store = ctx.reopen().unwrap();
+        assert_eq!(store.get(b"user:42").unwrap(), Some(value(42)));
+        assert_eq!(store.get(b"user:new").unwrap(), Some(value(1000)));
+        assert_eq!(store.get(b"user:7").unwrap(), None);
+    }
+    #[rstest]
+    fn test_incompressible_values_stay_raw() {
+        let codec = ValueCodec::new(3, Manifest::default()).unwrap();
+        let encoded = codec.encode(b"x").unwrap();
+        assert_eq!(encoded, vec![TAG_RAW, b'x']);
+        assert_eq!(codec.decode(encoded).unwrap(), b"x");
+        assert!(codec.decode(vec![TAG_ZSTD, 1, 2, 3]).is_err());
+        assert!(codec.decode(vec![9]).is_err());
+    }
+}
@@ -0,0 +1,293 @@
+//! Deterministic crash simulation. A workload runs against a real store while
+//! the contents of its files are captured after every operation, then for each
+//! operation the on-disk states a crash could have left behind are rebuilt from
+//! the images before and after it, reopened and checked against a model.
+
+use crate::{ActionKV, ActionKvError, ByteString};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Contents of the store files at one point of the workload.
+#[derive(Debug, Clone)]
+pub(crate) struct Image {
+    data: ByteString,
+    index: ByteString,
+}
+
+impl Image {
+    pub fn capture(path: &Path) -> io::Result<Self> {
+        Ok(Image {
+            data: fs::read(path.join("data"))?,
+            index: fs::read(path.join("index"))?,
+        })
+    }
+    pub fn restore(&self, path: &Path) -> io::Result<()> {
+        if path.exists() {
+            fs::remove_dir_all(path)?;
+        }
+        fs::create_dir_all(path)?;
+        fs::write(path.join("data"), &self.data)?;
+        fs::write(path.join("index"), &self.index)
+    }
+}
+
+/// What reached the disk of an operation that was cut short by a crash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Crash {
+    /// Only `kept` bytes of the data append were flushed, the index was never
+    /// rewritten.
+    DroppedBuffer { kept: usize },
+    /// The data append made it, the in-place index rewrite was torn after
+    /// `kept` bytes.
+    TornIndex { kept: usize },
+    /// The flushes were reordered: the index rewrite made it but only `kept`
+    /// bytes of the data append did.
+    Reordered { kept: usize },
+}
+
+impl Crash {
+    /// Disk state this crash leaves when it hits the operation that turned
+    /// `before` into `after`.
+    pub fn apply(&self, before: &Image, after: &Image) -> Image {
+        let appended = &after.data[before.data.len().min(after.data.len())..];
+        let data_with = |kept: usize| {
+            let mut data = before.data.clone();
+            data.extend(&appended[..kept.min(appended.len())]);
+            data
+        };
+        match *self {
+            Crash::DroppedBuffer { kept } => Image {
+                data: data_with(kept),
+                index: before.index.clone(),
+            },
+            Crash::TornIndex { kept } => {
+                let kept = kept.min(after.index.len());
+                let mut index = after.index[..kept].to_vec();
+                if before.index.len() > kept {
+                    index.extend(&before.index[kept..]);
+                }
+                Image {
+                    data: after.data.clone(),
+                    index,
+                }
+            }
+            Crash::Reordered { kept } => Image {
+                data: data_with(kept),
+                index: after.index.clone(),
+            },
+        }
+    }
+}
+
+/// Which crashes to inject, the same seed always yields the same crashes.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SimConfig {
+    pub seed: u64,
+    /// Random cut points tried per operation and kind of crash, on top of the
+    /// edges (nothing and everything written).
+    pub points_per_op: usize,
+}
+
+impl SimConfig {
+    pub fn crashes(&self, rng: &mut StdRng, before: &Image, after: &Image) -> Vec<Crash> {
+        let appended = after.data.len().saturating_sub(before.data.len());
+        let index_len = after.index.len();
+        let mut cuts = |len: usize| {
+            let mut cuts = vec![0, len];
+            cuts.extend((0..self.points_per_op).map(|_| rng.gen_range(0..=len)));
+            cuts
+        };
+        let mut crashes = Vec::new();
+        for kept in cuts(appended) {
+            crashes.push(Crash::DroppedBuffer { kept });
+            crashes.push(Crash::Reordered { kept });
+        }
+        for kept in cuts(index_len) {
+            crashes.push(Crash::TornIndex { kept });
+        }
+        crashes
+    }
+}
+
+pub(crate) type Model = BTreeMap<ByteString, ByteString>;
+
+/// Live user keys and values of a reopened store.
+pub(crate) fn contents(store: &mut ActionKV) -> io::Result<Model> {
+    let mut model = Model::new();
+    for key in store.scan_glob(b"*")? {
+        if let Some(value) = store.get(&key)? {
+            model.insert(key, value);
+        }
+    }
+    Ok(model)
+}
+
+/// Reopens `crashed` at `path` and checks the invariants: a crash that lost
+/// data must leave exactly the state before or after the operation, never a
+/// mix of both, and the store has to stay writable. Reordered flushes may
+/// also be refused on open, as long as that is reported as mismatched files
+/// rather than by serving wrong values.
+pub(crate) fn check_crash(
+    path: &Path,
+    crash: Crash,
+    crashed: &Image,
+    before: &Model,
+    after: &Model,
+) -> Result<(), String> {
+    crashed.restore(path).map_err(|err| err.to_string())?;
+    let mut store = match ActionKV::open(path) {
+        Ok(store) => store,
+        Err(err) => {
+            return match (crash, ActionKvError::from_io(&err)) {
+                (Crash::Reordered { .. }, Some(ActionKvError::FilesMismatched { .. })) => Ok(()),
+                _ => Err(format!("{:?}: open failed: {}", crash, err)),
+            }
+        }
+    };
+    let state = contents(&mut store).map_err(|err| format!("{:?}: {}", crash, err))?;
+    if state != *before && state != *after {
+        return Err(format!(
+            "{:?}: store holds neither the state before nor after the operation: {:?}",
+            crash, state
+        ));
+    }
+    if matches!(crash, Crash::DroppedBuffer { .. }) && state != *before {
+        return Err(format!("{:?}: uncommitted data became visible", crash));
+    }
+    store
+        .insert(b"probe", b"1")
+        .map_err(|err| format!("{:?}: store is not writable: {}", crash, err))?;
+    drop(store);
+    let mut store = ActionKV::open(path).map_err(|err| format!("{:?}: {}", crash, err))?;
+    let mut expected = state;
+    expected.insert(b"probe".to_vec(), b"1".to_vec());
+    match contents(&mut store) {
+        Ok(reopened) if reopened == expected => Ok(()),
+        Ok(reopened) => Err(format!(
+            "{:?}: write after recovery was lost, expected {:?} but found {:?}",
+            crash, expected, reopened
+        )),
+        Err(err) => Err(format!("{:?}: {}", crash, err)),
+    }
+}
+
+/// One step of a simulated workload.
+#[derive(Debug, Clone)]
+pub(crate) enum Op {
+    Insert(ByteString, ByteString),
+    Delete(ByteString),
+    BulkLoad(Vec<(ByteString, ByteString)>),
+}
+
+impl Op {
+    pub fn run(&self, store: &mut ActionKV, model: &mut Model) -> io::Result<()> {
+        match self {
+            Op::Insert(key, value) => {
+                store.insert(key, value)?;
+                model.insert(key.clone(), value.clone());
+            }
+            Op::Delete(key) => {
+                store.delete(key)?;
+                model.remove(key);
+            }
+            Op::BulkLoad(pairs) => {
+                store.bulk_load(pairs.clone())?;
+                model.extend(pairs.iter().cloned());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A random workload over a small key space, so updates and deletes of
+/// existing keys are common.
+pub(crate) fn workload(rng: &mut StdRng, len: usize) -> Vec<Op> {
+    let key = |rng: &mut StdRng| format!("key{}", rng.gen_range(0..8)).into_bytes();
+    let value = |rng: &mut StdRng| {
+        let len = rng.gen_range(1..40);
+        (0..len).map(|_| rng.gen::<u8>()).collect::<ByteString>()
+    };
+    (0..len)
+        .map(|_| match rng.gen_range(0..10) {
+            0..=5 => Op::Insert(key(rng), value(rng)),
+            6..=8 => Op::Delete(key(rng)),
+            _ => Op::BulkLoad((0..3).map(|_| (key(rng), value(rng))).collect()),
+        })
+        .collect()
+}
+
+/// Runs a workload of `len` operations and checks every crash `config`
+/// generates for each of them, returning how many crashes were checked.
+pub(crate) fn simulate(dir: &Path, config: SimConfig, len: usize) -> Result<usize, String> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let live = dir.join("live");
+    let crashed_path = dir.join("crashed");
+    fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+    let mut store = ActionKV::open(&live).map_err(|err| err.to_string())?;
+    let mut model = Model::new();
+    let mut image = Image::capture(&live).map_err(|err| err.to_string())?;
+    let mut checked = 0;
+    for op in workload(&mut rng, len) {
+        let before = model.clone();
+        op.run(&mut store, &mut model)
+            .map_err(|err| format!("{:?} failed: {}", op, err))?;
+        let next = Image::capture(&live).map_err(|err| err.to_string())?;
+        for crash in config.crashes(&mut rng, &image, &next) {
+            let crashed = crash.apply(&image, &next);
+            check_crash(&crashed_path, crash, &crashed, &before, &model)
+                .map_err(|err| format!("after {:?}: {}", op, err))?;
+            checked += 1;
+        }
+        image = next;
+    }
+    Ok(checked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+    use serial_test::serial;
+
+    struct SimDir(&'static str);
+
+    impl Drop for SimDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0);
+        }
+    }
+
+    #[rstest]
+    #[case(1)]
+    #[case(2)]
+    #[case(3)]
+    #[serial]
+    fn test_crash_simulation(#[case] seed: u64) {
+        let dir = SimDir("test_crash_sim");
+        let config = SimConfig {
+            seed,
+            points_per_op: 3,
+        };
+        let checked = simulate(Path::new(dir.0), config, 25).unwrap();
+        assert!(checked > 25 * 6);
+    }
+    #[rstest]
+    fn test_torn_index_keeps_old_tail() {
+        let before = Image {
+            data: vec![1; 4],
+            index: vec![1; 10],
+        };
+        let after = Image {
+            data: vec![1; 6],
+            index: vec![2; 6],
+        };
+        let crashed = Crash::TornIndex { kept: 3 }.apply(&before, &after);
+        assert_eq!(crashed.index, [2, 2, 2, 1, 1, 1, 1, 1, 1, 1]);
+        let crashed = Crash::DroppedBuffer { kept: 1 }.apply(&before, &after);
+        assert_eq!((crashed.data.len(), crashed.index), (5, before.index));
+    }
+}
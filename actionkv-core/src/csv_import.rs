@@ -0,0 +1,184 @@
+use crate::debug::decode_hex;
+use crate::{ActionKV, ByteStr, ByteString};
+use std::io::{self, Read};
+
+/// How the text of a CSV cell turns into the bytes of a key or value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CsvEncoding {
+    /// The bytes of the cell as they are.
+    #[default]
+    Text,
+    /// Hex encoded bytes, e.g. `00ff`.
+    Hex,
+    /// A decimal integer, stored as 8 big endian bytes so non-negative
+    /// numbers sort like the numbers themselves.
+    I64,
+}
+
+impl CsvEncoding {
+    fn decode(&self, cell: &ByteStr) -> Result<ByteString, String> {
+        let text = || std::str::from_utf8(cell).map_err(|_| "is not UTF-8".to_string());
+        match self {
+            CsvEncoding::Text => Ok(cell.to_vec()),
+            CsvEncoding::Hex => {
+                let text = text()?;
+                decode_hex(text.trim()).ok_or_else(|| format!("{:?} is not valid hex", text))
+            }
+            CsvEncoding::I64 => {
+                let text = text()?;
+                let number: i64 = text
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("{:?} is not an integer", text))?;
+                Ok(number.to_be_bytes().to_vec())
+            }
+        }
+    }
+}
+
+/// Which CSV columns `ActionKV::import_csv` takes keys and values from, by
+/// the names in the header row, and how it decodes them.
+#[derive(Debug, Clone)]
+pub struct CsvImport {
+    pub key_column: String,
+    pub value_column: String,
+    pub key_encoding: CsvEncoding,
+    pub value_encoding: CsvEncoding,
+    pub delimiter: u8,
+}
+
+impl CsvImport {
+    /// Text keys and values from comma separated columns.
+    pub fn new(key_column: &str, value_column: &str) -> Self {
+        CsvImport {
+            key_column: key_column.to_string(),
+            value_column: value_column.to_string(),
+            key_encoding: CsvEncoding::Text,
+            value_encoding: CsvEncoding::Text,
+            delimiter: b',',
+        }
+    }
+}
+
+impl ActionKV {
+    /// Bulk loads one pair per row of the CSV read from `reader`, which
+    /// starts with a header row naming the columns. Rows are loaded as they
+    /// are read, a row that can't be decoded stops the import with its line
+    /// number, keeping what was loaded before it. Returns how many records
+    /// were written.
+    pub fn import_csv<R: Read>(&mut self, reader: R, import: &CsvImport) -> io::Result<u64> {
+        let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(import.delimiter)
+            .from_reader(reader);
+        let headers = reader
+            .byte_headers()
+            .map_err(|err| invalid(err.to_string()))?
+            .clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header == name.as_bytes())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("the CSV has no column named {:?}", name),
+                    )
+                })
+        };
+        let key_column = column(&import.key_column)?;
+        let value_column = column(&import.value_column)?;
+        let mut failure = None;
+        let pairs = reader.byte_records().map_while(|row| {
+            let pair = row.map_err(|err| err.to_string()).and_then(|row| {
+                let line = row.position().map_or(0, |position| position.line());
+                let cell = |index: usize| {
+                    row.get(index)
+                        .ok_or_else(|| format!("line {}: the row is cut short", line))
+                };
+                let key = import
+                    .key_encoding
+                    .decode(cell(key_column)?)
+                    .map_err(|reason| format!("line {}: key {}", line, reason))?;
+                let value = import
+                    .value_encoding
+                    .decode(cell(value_column)?)
+                    .map_err(|reason| format!("line {}: value {}", line, reason))?;
+                Ok((key, value))
+            });
+            match pair {
+                Ok(pair) => Some(pair),
+                Err(reason) => {
+                    failure = Some(invalid(reason));
+                    None
+                }
+            }
+        });
+        let written = self.bulk_load(pairs)?;
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(written),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_csv_import")
+    }
+    #[rstest]
+    #[serial]
+    fn test_import_csv(mut ctx: TestCtx) {
+        let input = "name;id;payload\nfoo;1;00ff\n\"b;ar\";-2;01\n";
+        let import = CsvImport {
+            key_encoding: CsvEncoding::I64,
+            value_encoding: CsvEncoding::Hex,
+            delimiter: b';',
+            ..CsvImport::new("id", "payload")
+        };
+        assert_eq!(
+            ctx.test_file.import_csv(input.as_bytes(), &import).unwrap(),
+            2
+        );
+        assert_eq!(
+            ctx.test_file.get(&1i64.to_be_bytes()).unwrap(),
+            Some(vec![0x00, 0xff])
+        );
+
+        let by_name = CsvImport {
+            delimiter: b';',
+            ..CsvImport::new("name", "id")
+        };
+        ctx.test_file
+            .import_csv(input.as_bytes(), &by_name)
+            .unwrap();
+        assert_eq!(ctx.test_file.get(b"b;ar").unwrap(), Some(b"-2".to_vec()));
+
+        let missing = CsvImport::new("name", "price");
+        assert!(ctx
+            .test_file
+            .import_csv(input.as_bytes(), &missing)
+            .is_err());
+        let input = "id,payload\n1,00\nx,01\n";
+        let comma_separated = CsvImport {
+            delimiter: b',',
+            ..import
+        };
+        let err = ctx
+            .test_file
+            .import_csv(input.as_bytes(), &comma_separated)
+            .unwrap_err();
+        assert!(err.to_string().starts_with("line 3: key"));
+        assert_eq!(
+            ctx.test_file.get(&1i64.to_be_bytes()).unwrap(),
+            Some(vec![0x00])
+        );
+    }
+}
@@ -0,0 +1,374 @@
+use crate::ByteStr;
+#[cfg(any(feature = "json", feature = "csv"))]
+use crate::ByteString;
+#[cfg(feature = "json")]
+use crate::{
+    format::{display_generation, FileHeader, Framing, DATA_MAGIC, INDEX_MAGIC},
+    index_digest::DIGEST_KEY,
+    index_log::{Delta, DELTA_KEY},
+    inline::INLINE_KEY,
+    is_corruption, segment, ActionKV, IndexDigest,
+};
+#[cfg(feature = "json")]
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+#[cfg(feature = "json")]
+use crc::crc32;
+#[cfg(feature = "json")]
+use serde_json::{json, Value};
+#[cfg(feature = "json")]
+use std::collections::HashMap;
+#[cfg(feature = "json")]
+use std::fs::{self, File};
+#[cfg(feature = "json")]
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+#[cfg(feature = "json")]
+use std::path::Path;
+
+/// Bytes of a key `debug_data_file` shows.
+#[cfg(feature = "json")]
+const KEY_PREVIEW: usize = 32;
+
+/// Keys are shown as text when they are valid UTF-8 and as `0x..` hex otherwise.
+pub fn display_key(key: &ByteStr) -> String {
+    match std::str::from_utf8(key) {
+        Ok(text) => text.to_string(),
+        Err(_) => {
+            let hex: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("0x{}", hex)
+        }
+    }
+}
+
+/// Bytes of a hex string, `None` unless it is made of pairs of hex digits.
+#[cfg(any(feature = "json", feature = "csv"))]
+pub(crate) fn decode_hex(hex: &str) -> Option<ByteString> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(feature = "json")]
+impl ActionKV {
+    /// Decodes the index file as it is on disk into JSON: its header, every record
+    /// with its checksum status and the key -> offset entries it claims, each
+    /// checked against the current length of the segment it points into.
+    pub fn debug_index(&mut self) -> io::Result<Value> {
+        let header = match FileHeader::read_from(&mut self.index_, INDEX_MAGIC) {
+            Ok(Some(header)) => json!({
+                "version": header.version,
+                "flags": header.flags,
+                "generation": display_generation(&header.generation),
+                "data_len": header.data_len,
+            }),
+            Ok(None) => Value::Null,
+            Err(err) => json!({ "error": err.to_string() }),
+        };
+        let mut raw = ByteString::new();
+        self.index_.seek(SeekFrom::Start(0))?;
+        self.index_.read_to_end(&mut raw)?;
+        let data_len = self.file_.metadata()?.len();
+        let mut sealed_lens = HashMap::new();
+        for (id, path) in self.segment_files()? {
+            sealed_lens.insert(id, fs::metadata(path)?.len());
+        }
+        let in_log = |position: u64| match segment::split(position) {
+            (segment::ACTIVE, offset) => offset < data_len,
+            (id, offset) => sealed_lens.get(&id).is_some_and(|len| offset < *len),
+        };
+
+        let mut records = Vec::new();
+        let mut offset = self.index_start as usize;
+        while raw.len().saturating_sub(offset) >= 12 {
+            let header = &raw[offset..offset + 12];
+            let saved_checksum = LittleEndian::read_u32(&header[0..4]);
+            let key_len = LittleEndian::read_u32(&header[4..8]) as usize;
+            let value_len = LittleEndian::read_u32(&header[8..12]) as usize;
+            let body = &raw[offset + 12..];
+            if body.len() < key_len + value_len {
+                records.push(json!({
+                    "offset": offset,
+                    "error": format!(
+                        "truncated record, header claims {} bytes but only {} are left",
+                        key_len + value_len,
+                        body.len()
+                    ),
+                }));
+                break;
+            }
+            let (key, value) = body[..key_len + value_len].split_at(key_len);
+            let checksum = crc32::checksum_ieee(&body[..key_len + value_len]);
+            if key == DIGEST_KEY {
+                let digest = match IndexDigest::decode(value) {
+                    Ok(digest) => json!({
+                        "keys": digest.keys,
+                        "hash": format!("{:016x}", digest.hash),
+                    }),
+                    Err(err) => json!({ "error": err.to_string() }),
+                };
+                records.push(json!({
+                    "offset": offset,
+                    "key": display_key(key),
+                    "checksum": format!("{:08x}", saved_checksum),
+                    "checksum_ok": checksum == saved_checksum,
+                    "digest": digest,
+                }));
+                offset += 12 + key_len + value_len;
+                continue;
+            }
+            if key == INLINE_KEY {
+                records.push(json!({
+                    "offset": offset,
+                    "key": display_key(key),
+                    "checksum": format!("{:08x}", saved_checksum),
+                    "checksum_ok": checksum == saved_checksum,
+                    "inlined_values": value.get(..8).map(LittleEndian::read_u64),
+                }));
+                offset += 12 + key_len + value_len;
+                continue;
+            }
+            if key == DELTA_KEY {
+                let delta = match Delta::decode(value) {
+                    Ok(delta) => {
+                        let entries: Vec<Value> = delta
+                            .entries
+                            .iter()
+                            .map(|(key, position)| {
+                                json!({
+                                    "key": display_key(key),
+                                    "offset": position,
+                                    "in_data_file": position.is_none_or(in_log),
+                                })
+                            })
+                            .collect();
+                        json!({ "data_len": delta.data_len, "entries": entries })
+                    }
+                    Err(err) => json!({ "error": err.to_string() }),
+                };
+                records.push(json!({
+                    "offset": offset,
+                    "key": display_key(key),
+                    "checksum": format!("{:08x}", saved_checksum),
+                    "checksum_ok": checksum == saved_checksum,
+                    "delta": delta,
+                }));
+                offset += 12 + key_len + value_len;
+                continue;
+            }
+            let entries = match self.index_codec.decode(value) {
+                Ok(index) => {
+                    let mut entries: Vec<(ByteString, u64)> = index.into_iter().collect();
+                    entries.sort();
+                    let entries: Vec<Value> = entries
+                        .iter()
+                        .map(|(key, position)| {
+                            json!({
+                                "key": display_key(key),
+                                "offset": position,
+                                "in_data_file": in_log(*position),
+                            })
+                        })
+                        .collect();
+                    json!(entries)
+                }
+                Err(err) => json!({ "error": err.to_string() }),
+            };
+            records.push(json!({
+                "offset": offset,
+                "key": display_key(key),
+                "checksum": format!("{:08x}", saved_checksum),
+                "checksum_ok": checksum == saved_checksum,
+                "value_len": value_len,
+                "entries": entries,
+            }));
+            offset += 12 + key_len + value_len;
+        }
+        Ok(json!({
+            "header": header,
+            "index_file_bytes": raw.len(),
+            "trailing_bytes": raw.len() - offset,
+            "data_file_bytes": data_len,
+            "records": records,
+        }))
+    }
+}
+
+/// Walks the records of the data file at `path` from offset `from`, its
+/// first record by default, without opening the store, so a damaged store
+/// is looked at as it is left. Every record comes out as JSON with its
+/// offset, header fields, whether its checksum holds and the start of its
+/// key. A record failing its checksum is stepped over by the lengths it
+/// claims, the walk ends with an error entry at one whose lengths can't be
+/// right, as there is no telling where the next record starts.
+#[cfg(feature = "json")]
+pub fn debug_data_file(path: &Path, from: Option<u64>) -> io::Result<DataFileWalk> {
+    let mut f = File::open(path)?;
+    let end = f.metadata()?.len();
+    let (framing, start) = match FileHeader::read_from(&mut f, DATA_MAGIC)? {
+        Some(header) => {
+            let framing = Framing::from_header(&header)?;
+            (framing, framing.records_start())
+        }
+        None => (Framing::default(), 0),
+    };
+    Ok(DataFileWalk {
+        f: BufReader::new(f),
+        framing,
+        position: from.unwrap_or(start),
+        end,
+    })
+}
+
+/// Records of a data file as they are on disk, see `debug_data_file`.
+#[cfg(feature = "json")]
+#[derive(Debug)]
+pub struct DataFileWalk {
+    f: BufReader<File>,
+    framing: Framing,
+    position: u64,
+    end: u64,
+}
+
+#[cfg(feature = "json")]
+impl DataFileWalk {
+    fn read_record(&mut self, offset: u64) -> io::Result<Value> {
+        let framing = self.framing;
+        let f = &mut self.f;
+        f.seek(SeekFrom::Start(offset))?;
+        let saved_checksum = f.read_u32::<LittleEndian>()?;
+        let (key_len, value_len) = framing.read_lengths(f)?;
+        let padding = framing.read_padding_len(f)?;
+        let data_start = f.stream_position()?;
+        let data_len = (key_len as u64).saturating_add(value_len);
+        let next = data_start
+            .saturating_add(data_len)
+            .saturating_add(padding as u64);
+        if next > self.end {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "record claims {} bytes but the file ends {} bytes after its header",
+                    next - data_start,
+                    self.end - data_start
+                ),
+            ));
+        }
+        let mut data = vec![0; data_len as usize];
+        f.read_exact(&mut data)?;
+        self.position = next;
+        let checksum = framing.checksum(key_len, value_len, padding, &data);
+        let key = &data[..key_len as usize];
+        Ok(json!({
+            "offset": offset,
+            "checksum": format!("{:08x}", saved_checksum),
+            "checksum_ok": checksum == saved_checksum,
+            "key_len": key_len,
+            "value_len": value_len,
+            "padding": padding,
+            "key": display_key(&key[..key.len().min(KEY_PREVIEW)]),
+            "next": next,
+        }))
+    }
+}
+
+#[cfg(feature = "json")]
+impl Iterator for DataFileWalk {
+    type Item = io::Result<Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.end {
+            return None;
+        }
+        let offset = self.position;
+        let record = self.read_record(offset);
+        if record.is_err() {
+            self.position = self.end;
+        }
+        match record {
+            Err(err) if is_corruption(&err) => Some(Ok(json!({
+                "offset": offset,
+                "error": err.to_string(),
+            }))),
+            record => Some(record),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+    use crate::format::HEADER_LEN;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_debug")
+    }
+    #[rstest]
+    #[serial]
+    fn test_debug_index(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        ctx.test_file.insert(b"\xff", b"baz").unwrap();
+        let report = ctx.test_file.debug_index().unwrap();
+
+        assert_eq!(report["trailing_bytes"], 0);
+        assert_eq!(report["header"]["data_len"], HEADER_LEN + 18 + 16);
+        let record = &report["records"][0];
+        assert_eq!(record["key"], "+index");
+        assert_eq!(record["checksum_ok"], true);
+        assert_eq!(
+            record["entries"],
+            json!([
+                {"key": "foo", "offset": HEADER_LEN, "in_data_file": true},
+            ])
+        );
+        // the second insert only appended what it changed
+        let delta = &report["records"][1];
+        assert_eq!(delta["key"], "+delta");
+        assert_eq!(delta["checksum_ok"], true);
+        assert_eq!(
+            delta["delta"],
+            json!({
+                "data_len": HEADER_LEN + 18 + 16,
+                "entries": [
+                    {"key": "0xff", "offset": HEADER_LEN + 18, "in_data_file": true},
+                ],
+            })
+        );
+    }
+    #[rstest]
+    #[serial]
+    fn test_debug_data_file(mut ctx: TestCtx) {
+        let path = Path::new("test_debug/data");
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        ctx.test_file.insert(&[b'k'; 40], b"baz").unwrap();
+        let mut data = std::fs::read(path).unwrap();
+        // the value of foo
+        data[HEADER_LEN as usize + 15] ^= 0xff;
+        data.extend(b"torn");
+        std::fs::write(path, &data).unwrap();
+
+        let records: Vec<Value> = debug_data_file(path, None)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["offset"], HEADER_LEN);
+        assert_eq!(records[0]["key"], "foo");
+        assert_eq!(records[0]["checksum_ok"], false);
+        assert_eq!(records[1]["offset"], HEADER_LEN + 18);
+        assert_eq!(records[1]["checksum_ok"], true);
+        assert_eq!(records[1]["key_len"], 40);
+        assert_eq!(records[1]["key"], "k".repeat(KEY_PREVIEW));
+        assert_eq!(records[2]["offset"], HEADER_LEN + 18 + 55);
+        assert!(records[2]["error"].is_string());
+
+        let from = debug_data_file(path, Some(HEADER_LEN + 18)).unwrap();
+        assert_eq!(from.count(), 2);
+    }
+}
@@ -0,0 +1,423 @@
+use crate::debug::decode_hex;
+use crate::scan::visit_records;
+use crate::{is_internal_key, ActionKV, ByteStr, ByteString, Operation};
+use serde_json::{Map, Value};
+use std::io::{self, BufRead, Write};
+
+/// Puts `bytes` under `field` when they are UTF-8 text and hex encoded
+/// under `field_hex` otherwise.
+fn encode_field(record: &mut Map<String, Value>, field: &str, bytes: &ByteStr) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => record.insert(field.to_string(), Value::from(text)),
+        Err(_) => {
+            let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            record.insert(format!("{}_hex", field), Value::from(hex))
+        }
+    };
+}
+
+/// Reads back what `encode_field` wrote. For `value`, JSON other than a string
+/// is stored as its serialized form.
+fn decode_field(record: &mut Value, field: &str) -> io::Result<ByteString> {
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+    let hex_field = format!("{}_hex", field);
+    match (
+        record.get_mut(field).map(Value::take),
+        record.get(&hex_field),
+    ) {
+        (Some(Value::String(text)), _) => Ok(text.into_bytes()),
+        (Some(value), _) if field == "value" => {
+            serde_json::to_vec(&value).map_err(|err| invalid(err.to_string()))
+        }
+        (Some(_), _) => Err(invalid(format!("{:?} must be a string", field))),
+        (None, Some(Value::String(hex))) => {
+            decode_hex(hex).ok_or_else(|| invalid(format!("{:?} is not valid hex", hex_field)))
+        }
+        (None, _) => Err(invalid(format!("{:?} is missing", field))),
+    }
+}
+
+fn write_jsonl_line<W: Write>(writer: &mut W, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+    let mut record = Map::new();
+    encode_field(&mut record, "key", key);
+    encode_field(&mut record, "value", value);
+    serde_json::to_writer(&mut *writer, &record)?;
+    writer.write_all(b"\n")
+}
+
+fn write_jsonl_delete<W: Write>(writer: &mut W, key: &ByteStr) -> io::Result<()> {
+    let mut record = Map::new();
+    encode_field(&mut record, "key", key);
+    record.insert("deleted".to_string(), Value::Bool(true));
+    serde_json::to_writer(&mut *writer, &record)?;
+    writer.write_all(b"\n")
+}
+
+/// One `{"key": "...", "value": ...}` line, see `export_jsonl`, or a
+/// `{"key": "...", "deleted": true}` one, which has no value.
+fn parse_jsonl_line(line: &str) -> io::Result<(ByteString, Option<ByteString>)> {
+    let mut record: Value = serde_json::from_str(line)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let key = decode_field(&mut record, "key")?;
+    match record.get("deleted") {
+        Some(Value::Bool(true)) => Ok((key, None)),
+        _ => Ok((key, Some(decode_field(&mut record, "value")?))),
+    }
+}
+
+impl ActionKV {
+    /// Writes every live pair as a JSON line sorted by key: `{"key": ..., "value": ...}`
+    /// with keys and values as strings, or as `key_hex`/`value_hex` when they
+    /// aren't UTF-8. The output only depends on the pairs stored, so two
+    /// exports of the same data can be diffed. Returns how many pairs were
+    /// written. Keys the access policy doesn't let be read are left out.
+    pub fn export_jsonl<W: Write>(&mut self, writer: W) -> io::Result<u64> {
+        self.export_jsonl_with_progress(writer, |_, _| {})
+    }
+    /// `export_jsonl` calling `progress` with the number of keys gone through
+    /// so far and the total after each one.
+    pub fn export_jsonl_with_progress<W, P>(
+        &mut self,
+        mut writer: W,
+        mut progress: P,
+    ) -> io::Result<u64>
+    where
+        W: Write,
+        P: FnMut(u64, u64),
+    {
+        self.authorize(Operation::Scan, b"")?;
+        let keys: Vec<ByteString> = self
+            .user_keys(b"")?
+            .into_iter()
+            .filter(|key| self.readable(key))
+            .collect();
+        let total = keys.len() as u64;
+        let mut written = 0;
+        for (done, key) in keys.into_iter().enumerate() {
+            progress(done as u64, total);
+            let Some(value) = self.current_value(&key)? else {
+                continue;
+            };
+            write_jsonl_line(&mut writer, &key, &value)?;
+            written += 1;
+        }
+        progress(total, total);
+        writer.flush()?;
+        Ok(written)
+    }
+    /// `export_jsonl` in data file order rather than sorted, which reads the
+    /// data file front to back and doesn't hold every key in memory at
+    /// once. Values written in chunks come last. The order changes as the store is written and compacted.
+    pub fn export_jsonl_unsorted<W: Write>(&mut self, writer: W) -> io::Result<u64> {
+        self.export_jsonl_unsorted_with_progress(writer, |_, _| {})
+    }
+    /// `export_jsonl_unsorted` calling `progress` with the number of records
+    /// gone through so far and the total after each one.
+    pub fn export_jsonl_unsorted_with_progress<W, P>(
+        &mut self,
+        mut writer: W,
+        mut progress: P,
+    ) -> io::Result<u64>
+    where
+        W: Write,
+        P: FnMut(u64, u64),
+    {
+        let positions = self.prefix_positions(b"")?;
+        let chunked = self.chunked_values(b"")?;
+        let total = (positions.len() + chunked.len()) as u64;
+        let mut written = 0;
+        progress(0, total);
+        visit_records(
+            self.dir("exports")?,
+            self.framing,
+            self.values.as_deref(),
+            &positions,
+            &chunked,
+            self.scan_keys(b""),
+            |key, value| {
+                write_jsonl_line(&mut writer, &key, &value)?;
+                written += 1;
+                progress(written, total);
+                Ok(())
+            },
+        )?;
+        progress(total, total);
+        writer.flush()?;
+        Ok(written)
+    }
+    /// The history of the store as JSON lines in the order it was written:
+    /// every value put, overwritten ones included, as with `export_jsonl`, and
+    /// every delete as `{"key": ..., "deleted": true}`. Importing it replays
+    /// the deletes in between, so change data capture consumers and replicas
+    /// bootstrapped from it go through the same changes. Compaction drops
+    /// overwritten values and deletes, the history starts with what it kept.
+    /// Returns how many lines were written.
+    pub fn export_jsonl_history<W: Write>(&mut self, writer: W) -> io::Result<u64> {
+        self.export_jsonl_history_with_progress(writer, |_, _| {})
+    }
+    /// `export_jsonl_history` calling `progress` with the bytes of the log
+    /// gone through so far and the total after each record.
+    pub fn export_jsonl_history_with_progress<W, P>(
+        &mut self,
+        mut writer: W,
+        mut progress: P,
+    ) -> io::Result<u64>
+    where
+        W: Write,
+        P: FnMut(u64, u64),
+    {
+        self.authorize(Operation::Scan, b"")?;
+        let total = self.log_bytes()?;
+        let mut done = 0;
+        let mut written = 0;
+        progress(done, total);
+        for record in self.log_iter()? {
+            let record = record?;
+            done += record.size;
+            progress(done.min(total), total);
+            if is_internal_key(&record.key) {
+                continue;
+            }
+            match record.tombstone {
+                true => write_jsonl_delete(&mut writer, &record.key)?,
+                false => write_jsonl_line(&mut writer, &record.key, &record.value)?,
+            }
+            written += 1;
+        }
+        progress(total, total);
+        writer.flush()?;
+        Ok(written)
+    }
+    /// Bulk loads JSON lines from `reader` as they are read, so the input can be
+    /// far larger than memory. Blank lines are skipped, a malformed line stops
+    /// the import with its line number, keeping what was loaded before it.
+    /// Delete lines from `export_jsonl_history` end the bulk load of the pairs
+    /// before them and delete their key. Returns how many records were written.
+    pub fn import_jsonl<R: BufRead>(&mut self, reader: R) -> io::Result<u64> {
+        let mut lines = reader.lines().enumerate();
+        let mut written = 0;
+        loop {
+            let mut failure = None;
+            let mut deleted = None;
+            let mut pairs = lines
+                .by_ref()
+                .map_while(|(line_number, line)| {
+                    match line.and_then(|line| match line.trim() {
+                        "" => Ok(None),
+                        line => parse_jsonl_line(line).map(Some),
+                    }) {
+                        Ok(Some((key, None))) => {
+                            deleted = Some(key);
+                            None
+                        }
+                        Ok(Some((key, Some(value)))) => Some(Some((key, value))),
+                        Ok(None) => Some(None),
+                        Err(err) => {
+                            failure = Some(io::Error::new(
+                                err.kind(),
+                                format!("line {}: {}", line_number + 1, err),
+                            ));
+                            None
+                        }
+                    }
+                })
+                .flatten()
+                .peekable();
+            // deletes in a row don't need a bulk load each in between
+            if pairs.peek().is_some() {
+                written += self.bulk_load(pairs)?;
+            }
+            if let Some(err) = failure {
+                return Err(err);
+            }
+            match deleted {
+                Some(key) => {
+                    self.delete(&key)?;
+                    written += 1;
+                }
+                None => return Ok(written),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, TestCtx};
+    use crate::Options;
+    use rstest::*;
+    use serial_test::serial;
+    use std::io;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_dump")
+    }
+    #[rstest]
+    #[serial]
+    fn test_import_jsonl(mut ctx: TestCtx) {
+        let input =
+            "{\"key\": \"foo\", \"value\": \"bar\"}\n\n{\"key\": \"doc\", \"value\": {\"a\": 1}}\n";
+        assert_eq!(ctx.test_file.import_jsonl(input.as_bytes()).unwrap(), 2);
+        assert_eq!(ctx.test_file.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+        assert_eq!(
+            ctx.test_file.get(b"doc").unwrap(),
+            Some(b"{\"a\":1}".to_vec())
+        );
+
+        let input = "{\"key\": \"baz\", \"value\": \"1\"}\n{\"key\": 2, \"value\": \"x\"}\n";
+        let err = ctx.test_file.import_jsonl(input.as_bytes()).unwrap_err();
+        assert!(err.to_string().starts_with("line 2:"));
+        assert_eq!(ctx.test_file.get(b"baz").unwrap(), Some(b"1".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_export_round_trip(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        ctx.test_file.insert(b"\xff", b"\xfe\x01").unwrap();
+        let mut dump = Vec::new();
+        assert_eq!(ctx.test_file.export_jsonl(&mut dump).unwrap(), 2);
+        assert_eq!(
+            String::from_utf8(dump.clone()).unwrap(),
+            "{\"key\":\"foo\",\"value\":\"bar\"}\n{\"key_hex\":\"ff\",\"value_hex\":\"fe01\"}\n"
+        );
+
+        ctx.test_file.delete(b"\xff").unwrap();
+        ctx.test_file.import_jsonl(dump.as_slice()).unwrap();
+        assert_eq!(ctx.test_file.get(b"\xff").unwrap(), Some(vec![0xfe, 1]));
+    }
+    #[rstest]
+    #[serial]
+    fn test_export_order(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"b", b"1").unwrap();
+        ctx.test_file.insert(b"a", b"2").unwrap();
+        ctx.test_file.insert(b"c", b"3").unwrap();
+        ctx.test_file.insert(b"b", b"4").unwrap();
+        let mut unsorted = Vec::new();
+        assert_eq!(
+            ctx.test_file.export_jsonl_unsorted(&mut unsorted).unwrap(),
+            3
+        );
+        assert_eq!(
+            String::from_utf8(unsorted).unwrap(),
+            "{\"key\":\"a\",\"value\":\"2\"}\n{\"key\":\"c\",\"value\":\"3\"}\n{\"key\":\"b\",\"value\":\"4\"}\n"
+        );
+        let mut sorted = Vec::new();
+        ctx.test_file.export_jsonl(&mut sorted).unwrap();
+        ctx.test_file.compact().unwrap();
+        let mut again = Vec::new();
+        ctx.test_file.export_jsonl(&mut again).unwrap();
+        assert_eq!(sorted, again);
+        assert!(String::from_utf8(sorted)
+            .unwrap()
+            .starts_with("{\"key\":\"a\""));
+    }
+    #[rstest]
+    #[serial]
+    fn test_export_chunked_values() {
+        let options = Options {
+            chunk_size: Some(10),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_dump", options);
+        let big = "0123456789".repeat(5);
+        ctx.test_file.insert(b"small", b"1").unwrap();
+        ctx.test_file.insert(b"big", big.as_bytes()).unwrap();
+        let expected = format!(
+            "{{\"key\":\"big\",\"value\":\"{}\"}}\n{{\"key\":\"small\",\"value\":\"1\"}}\n",
+            big
+        );
+        let mut sorted = Vec::new();
+        assert_eq!(ctx.test_file.export_jsonl(&mut sorted).unwrap(), 2);
+        assert_eq!(String::from_utf8(sorted.clone()).unwrap(), expected);
+        let mut unsorted = Vec::new();
+        assert_eq!(
+            ctx.test_file.export_jsonl_unsorted(&mut unsorted).unwrap(),
+            2
+        );
+        assert_eq!(unsorted.len(), sorted.len());
+
+        let mut replica = TestCtx::setup("test_dump_replica");
+        replica.test_file.import_jsonl(unsorted.as_slice()).unwrap();
+        assert_eq!(
+            replica.test_file.get(b"big").unwrap(),
+            Some(big.into_bytes())
+        );
+    }
+    #[rstest]
+    #[serial]
+    fn test_export_history(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"1").unwrap();
+        ctx.test_file.insert(b"bar", b"2").unwrap();
+        ctx.test_file.insert(b"foo", b"3").unwrap();
+        ctx.test_file.delete(b"bar").unwrap();
+        ctx.test_file.delete(b"\xff").unwrap();
+        let mut history = Vec::new();
+        assert_eq!(ctx.test_file.export_jsonl_history(&mut history).unwrap(), 5);
+        let history = String::from_utf8(history).unwrap();
+        assert_eq!(
+            history,
+            "{\"key\":\"foo\",\"value\":\"1\"}\n{\"key\":\"bar\",\"value\":\"2\"}\n\
+             {\"key\":\"foo\",\"value\":\"3\"}\n{\"deleted\":true,\"key\":\"bar\"}\n\
+             {\"deleted\":true,\"key_hex\":\"ff\"}\n"
+        );
+
+        // replaying it deletes what the lines before it put
+        let mut replica = TestCtx::setup("test_dump_replica");
+        replica.test_file.insert(b"\xff", b"stale").unwrap();
+        assert_eq!(
+            replica.test_file.import_jsonl(history.as_bytes()).unwrap(),
+            5
+        );
+        assert_eq!(replica.test_file.get(b"foo").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(replica.test_file.get(b"bar").unwrap(), None);
+        assert_eq!(replica.test_file.get(b"\xff").unwrap(), None);
+    }
+    #[rstest]
+    #[serial]
+    fn test_export_leaves_out_unreadable_keys(mut ctx: TestCtx) {
+        testing::hide_secrets(&mut ctx.test_file, 5);
+        let mut sorted = Vec::new();
+        assert_eq!(ctx.test_file.export_jsonl(&mut sorted).unwrap(), 5);
+        let mut unsorted = Vec::new();
+        assert_eq!(
+            ctx.test_file.export_jsonl_unsorted(&mut unsorted).unwrap(),
+            5
+        );
+        for dump in [sorted, unsorted] {
+            assert!(!String::from_utf8(dump).unwrap().contains("secret"));
+        }
+    }
+    #[rstest]
+    #[serial]
+    fn test_export_progress(mut ctx: TestCtx) {
+        for i in 0..4u8 {
+            ctx.test_file.insert(&[b'k', i], b"v").unwrap();
+        }
+        ctx.test_file.delete(&[b'k', 0]).unwrap();
+        let check = |calls: Vec<(u64, u64)>, total: u64| {
+            assert!(calls.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+            assert!(calls.iter().all(|&(done, of)| of == total && done <= of));
+            assert_eq!(calls.last(), Some(&(total, total)));
+        };
+        let mut calls = Vec::new();
+        ctx.test_file
+            .export_jsonl_with_progress(io::sink(), |done, total| calls.push((done, total)))
+            .unwrap();
+        check(calls, 3);
+        let mut calls = Vec::new();
+        ctx.test_file
+            .export_jsonl_unsorted_with_progress(io::sink(), |done, total| {
+                calls.push((done, total))
+            })
+            .unwrap();
+        check(calls, 3);
+        let mut calls = Vec::new();
+        ctx.test_file
+            .export_jsonl_history_with_progress(io::sink(), |done, total| calls.push((done, total)))
+            .unwrap();
+        let bytes = ctx.test_file.log_bytes().unwrap();
+        assert!(bytes > 0);
+        check(calls, bytes);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{ActionKV, INDEX_KEY};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::thread;
+
+/// Returned by `flush_on_panic`, it stands in for the store. When the thread
+/// panics while it is alive, it persists the in-memory index and syncs both
+/// files on the way out, see `ActionKV::emergency_flush`.
+#[derive(Debug)]
+pub struct FlushOnPanic<'a> {
+    store: &'a mut ActionKV,
+}
+
+impl Deref for FlushOnPanic<'_> {
+    type Target = ActionKV;
+
+    fn deref(&self) -> &ActionKV {
+        self.store
+    }
+}
+
+impl DerefMut for FlushOnPanic<'_> {
+    fn deref_mut(&mut self) -> &mut ActionKV {
+        self.store
+    }
+}
+
+impl Drop for FlushOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            // nobody is left to report a failure to
+            let _ = self.store.emergency_flush();
+        }
+    }
+}
+
+impl ActionKV {
+    /// Guards the store for a long running thread: if it panics, records that
+    /// made it into the data file but not yet into the saved index (e.g. of an
+    /// interrupted `bulk_load`) are kept instead of being cut off on the next
+    /// open. Signals that kill the process don't unwind, a daemon has to turn
+    /// them into a regular shutdown for this to run.
+    pub fn flush_on_panic(&mut self) -> FlushOnPanic<'_> {
+        FlushOnPanic { store: self }
+    }
+    /// Best effort attempt to make everything written so far durable: the
+    /// index is saved again against the data file as it ends now, then both
+    /// files are synced.
+    pub fn emergency_flush(&mut self) -> io::Result<()> {
+        if self.options.read_only {
+            return Ok(());
+        }
+        // appends cut short by the panic may have left the tail behind
+        self.appender.reset(self.file_.metadata()?.len());
+        // an index that is only a pointer to the index file is saved already
+        if !self.index.contains_key(INDEX_KEY) {
+            self.store_index_on_disk(INDEX_KEY)?;
+        }
+        self.file_.sync_data()?;
+        self.index_.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestCtx;
+    use crate::Options;
+    use rstest::*;
+    use serial_test::serial;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_emergency")
+    }
+    #[rstest]
+    #[serial]
+    fn test_flush_on_panic_keeps_interrupted_bulk_load(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut store = ctx.test_file.flush_on_panic();
+            let pairs = (0..10_000u32).map(|i| {
+                if i == 9_000 {
+                    panic!("producer failed");
+                }
+                (format!("key:{}", i).into_bytes(), vec![1; 10])
+            });
+            let _ = store.bulk_load(pairs);
+        }));
+        assert!(result.is_err());
+
+        let store = ctx.crash_and_reopen(Options::default()).unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+        assert_eq!(store.get(b"key:4095").unwrap(), Some(vec![1; 10]));
+    }
+}
@@ -0,0 +1,277 @@
+use crate::format::{FORMAT_VERSION, OLDEST_FORMAT_VERSION};
+use crate::{display_key, ByteString, IndexDigest, Operation};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Errors of the store. `SharedStore` returns them as they are, the other
+/// public methods return `io::Result` and the store's own failures reach
+/// callers wrapped in an `io::Error` of kind
+/// `InvalidData` (`PermissionDenied` for `ReadOnly` and `AccessDenied`,
+/// `ResourceBusy` for `Locked`, `TimedOut` for `DeadlineExceeded`, `Unsupported` for the format
+/// versions, `NotFound` for `KeyNotFound`, the kind of the last error for
+/// `RetriesExhausted`). `ActionKvError::from` turns any of them back into
+/// this enum, so `?` converts in a function returning
+/// `Result<_, ActionKvError>`; errors that aren't the store's own become
+/// `Io`. `ActionKvError::from_io` only borrows it.
+#[derive(Debug)]
+pub enum ActionKvError {
+    /// Reading or writing a file failed, e.g. the disk is full.
+    Io(io::Error),
+    /// A record failed its checksum: `actual` was computed from what was
+    /// read, `expected` was saved in front of it. `offset` is where the
+    /// record starts in the data file, its segment in the top 16 bits if it
+    /// is in a sealed one (see `Options::segment_size`), `None` when it was
+    /// read as part of a stream.
+    Corruption {
+        offset: Option<u64>,
+        expected: u32,
+        actual: u32,
+    },
+    /// The data and index files in the store directory don't belong together,
+    /// e.g. the data file was truncated or replaced while the index was kept.
+    FilesMismatched { reason: String },
+    /// Bytes that passed their checksum, or a value expected to hold JSON,
+    /// couldn't be decoded: `what` was being read, `reason` says why.
+    Serialization { what: &'static str, reason: String },
+    /// `key` has no value but the operation needs one.
+    KeyNotFound { key: ByteString },
+    /// In a store with hashed keys, `key` hashes the same as the key whose
+    /// record the index points at.
+    HashCollision { key: ByteString, other: ByteString },
+    /// The store was opened with `Options::read_only` and can't be written.
+    ReadOnly,
+    /// Another writer has the store directory open, a directory takes one
+    /// writer at a time next to any number of `Options::read_only` opens.
+    Locked,
+    /// A transaction was committed after its deadline and was abandoned.
+    DeadlineExceeded,
+    /// `key` was written by someone else since the transaction began, it
+    /// has to be retried from the start.
+    Conflict { key: ByteString },
+    /// The store was written in a format `version` newer than this version
+    /// of the crate reads, see `FORMAT_VERSION`.
+    FormatTooNew { version: u16 },
+    /// The store was written in a format `version` older than this version
+    /// of the crate still reads, see `OLDEST_FORMAT_VERSION`.
+    FormatTooOld { version: u16 },
+    /// `Options::access_policy` denied `operation` on `key`.
+    AccessDenied {
+        operation: Operation,
+        key: ByteString,
+    },
+    /// A read or write kept failing with a transient error after `attempts`
+    /// tries, see `Options::retry`. `last` is the error of the last one.
+    RetriesExhausted { attempts: u32, last: io::Error },
+    /// The index doesn't match the digest saved with it, `found` is what was
+    /// computed from `against`, see `Options::index_digests`.
+    IndexDiverged {
+        saved: IndexDigest,
+        found: IndexDigest,
+        against: &'static str,
+    },
+}
+
+impl ActionKvError {
+    /// The store's error `err` wraps, without taking it.
+    pub fn from_io(err: &io::Error) -> Option<&ActionKvError> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<ActionKvError>())
+    }
+    /// A copy of the error, for handing one failure to several callers.
+    /// Plain `io::Error`s keep their kind and message.
+    pub(crate) fn duplicate(&self) -> ActionKvError {
+        fn copy_io(err: &io::Error) -> io::Error {
+            match ActionKvError::from_io(err) {
+                Some(inner) => inner.duplicate().into(),
+                None => io::Error::new(err.kind(), err.to_string()),
+            }
+        }
+        match self {
+            ActionKvError::Io(err) => ActionKvError::Io(copy_io(err)),
+            ActionKvError::Corruption {
+                offset,
+                expected,
+                actual,
+            } => ActionKvError::Corruption {
+                offset: *offset,
+                expected: *expected,
+                actual: *actual,
+            },
+            ActionKvError::FilesMismatched { reason } => ActionKvError::FilesMismatched {
+                reason: reason.clone(),
+            },
+            ActionKvError::Serialization { what, reason } => ActionKvError::Serialization {
+                what,
+                reason: reason.clone(),
+            },
+            ActionKvError::KeyNotFound { key } => ActionKvError::KeyNotFound { key: key.clone() },
+            ActionKvError::HashCollision { key, other } => ActionKvError::HashCollision {
+                key: key.clone(),
+                other: other.clone(),
+            },
+            ActionKvError::ReadOnly => ActionKvError::ReadOnly,
+            ActionKvError::Locked => ActionKvError::Locked,
+            ActionKvError::DeadlineExceeded => ActionKvError::DeadlineExceeded,
+            ActionKvError::Conflict { key } => ActionKvError::Conflict { key: key.clone() },
+            ActionKvError::FormatTooNew { version } => {
+                ActionKvError::FormatTooNew { version: *version }
+            }
+            ActionKvError::FormatTooOld { version } => {
+                ActionKvError::FormatTooOld { version: *version }
+            }
+            ActionKvError::AccessDenied { operation, key } => ActionKvError::AccessDenied {
+                operation: *operation,
+                key: key.clone(),
+            },
+            ActionKvError::RetriesExhausted { attempts, last } => ActionKvError::RetriesExhausted {
+                attempts: *attempts,
+                last: copy_io(last),
+            },
+            ActionKvError::IndexDiverged {
+                saved,
+                found,
+                against,
+            } => ActionKvError::IndexDiverged {
+                saved: *saved,
+                found: *found,
+                against,
+            },
+        }
+    }
+    /// Fills in where the record is if `err` is a `Corruption` of the record
+    /// at `position`.
+    pub(crate) fn at_offset(mut err: io::Error, position: u64) -> io::Error {
+        let inner = err
+            .get_mut()
+            .and_then(|inner| inner.downcast_mut::<ActionKvError>());
+        if let Some(ActionKvError::Corruption { offset, .. }) = inner {
+            offset.get_or_insert(position);
+        }
+        err
+    }
+}
+
+impl fmt::Display for ActionKvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionKvError::Io(err) => write!(f, "{}", err),
+            ActionKvError::Corruption {
+                offset,
+                expected,
+                actual,
+            } => {
+                write!(f, "data corruption encountered")?;
+                if let Some(offset) = offset {
+                    write!(f, " in the record at offset {}", offset)?;
+                }
+                write!(f, ", checksum {:08x} != {:08x}", actual, expected)
+            }
+            ActionKvError::FilesMismatched { reason } => {
+                write!(f, "data and index files don't match: {}", reason)
+            }
+            ActionKvError::Serialization { what, reason } => {
+                write!(f, "malformed {}: {}", what, reason)
+            }
+            ActionKvError::KeyNotFound { key } => {
+                write!(f, "key {:?} has no value", display_key(key))
+            }
+            ActionKvError::ReadOnly => write!(f, "the store was opened read only"),
+            ActionKvError::Locked => write!(f, "another writer has the store open"),
+            ActionKvError::DeadlineExceeded => write!(f, "the transaction ran past its deadline"),
+            ActionKvError::Conflict { key } => write!(
+                f,
+                "key {:?} was written since the transaction began",
+                display_key(key)
+            ),
+            ActionKvError::HashCollision { key, other } => write!(
+                f,
+                "key {:?} hashes the same as key {:?}",
+                display_key(key),
+                display_key(other)
+            ),
+            ActionKvError::FormatTooNew { version } => write!(
+                f,
+                "the store is in format version {}, this version of actionkv reads up to {}: \
+                 upgrade actionkv to open it",
+                version, FORMAT_VERSION
+            ),
+            ActionKvError::FormatTooOld { version } => write!(
+                f,
+                "the store is in format version {}, this version of actionkv reads {} and \
+                 newer: export it with an older version of actionkv and import it again",
+                version, OLDEST_FORMAT_VERSION
+            ),
+            ActionKvError::AccessDenied { operation, key } => write!(
+                f,
+                "the access policy denies {:?} of key {:?}",
+                operation,
+                display_key(key)
+            ),
+            ActionKvError::RetriesExhausted { attempts, last } => {
+                write!(f, "gave up after {} attempts: {}", attempts, last)
+            }
+            ActionKvError::IndexDiverged {
+                saved,
+                found,
+                against,
+            } => write!(
+                f,
+                "the index was saved with {} but {} has {}",
+                saved, against, found
+            ),
+        }
+    }
+}
+
+impl Error for ActionKvError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ActionKvError::Io(err) => Some(err),
+            ActionKvError::RetriesExhausted { last, .. } => Some(last),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ActionKvError {
+    fn from(err: io::Error) -> Self {
+        if ActionKvError::from_io(&err).is_none() {
+            return ActionKvError::Io(err);
+        }
+        let kind = err.kind();
+        match err
+            .into_inner()
+            .map(|inner| inner.downcast::<ActionKvError>())
+        {
+            Some(Ok(inner)) => *inner,
+            Some(Err(other)) => ActionKvError::Io(io::Error::new(kind, other)),
+            None => ActionKvError::Io(kind.into()),
+        }
+    }
+}
+
+impl From<ActionKvError> for io::Error {
+    fn from(err: ActionKvError) -> Self {
+        let err = match err {
+            ActionKvError::Io(err) => return err,
+            err => err,
+        };
+        let kind = match &err {
+            ActionKvError::KeyNotFound { .. } => io::ErrorKind::NotFound,
+            ActionKvError::ReadOnly | ActionKvError::AccessDenied { .. } => {
+                io::ErrorKind::PermissionDenied
+            }
+            ActionKvError::Locked => io::ErrorKind::ResourceBusy,
+            ActionKvError::DeadlineExceeded => io::ErrorKind::TimedOut,
+            // not corruption, nothing may be repaired or rebuilt over it
+            ActionKvError::FormatTooNew { .. } | ActionKvError::FormatTooOld { .. } => {
+                io::ErrorKind::Unsupported
+            }
+            // callers handling the kind keep seeing the one that failed
+            ActionKvError::RetriesExhausted { last, .. } => last.kind(),
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
+}
@@ -0,0 +1,195 @@
+use crate::ActionKV;
+use std::io;
+#[cfg(any(test, feature = "fault-injection"))]
+use std::sync::{Arc, Mutex};
+
+/// Kind of I/O call a fault can be injected into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoOp {
+    Read,
+    Write,
+    Sync,
+}
+
+/// One of the two files of a store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreFile {
+    Data,
+    Index,
+}
+
+/// A failure to inject: the matching call fails with `kind` instead of
+/// touching the file.
+#[cfg(any(test, feature = "fault-injection"))]
+#[derive(Debug, Clone)]
+pub struct Fault {
+    pub op: IoOp,
+    /// `None` matches calls on either file.
+    pub file: Option<StoreFile>,
+    pub kind: io::ErrorKind,
+    /// Matching calls that still succeed before the fault fires.
+    pub skip: usize,
+    /// How many matching calls fail once it fires, `None` fails all of them.
+    pub times: Option<usize>,
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+impl Fault {
+    /// Fails the next `op` call on either file once.
+    pub fn new(op: IoOp, kind: io::ErrorKind) -> Self {
+        Fault {
+            op,
+            file: None,
+            kind,
+            skip: 0,
+            times: Some(1),
+        }
+    }
+    fn matches(&self, op: IoOp, file: StoreFile) -> bool {
+        self.op == op && self.file.is_none_or(|target| target == file)
+    }
+}
+
+/// Shared handle to the faults armed for a store, see `Options::faults`.
+/// Clones arm and clear the same faults, so a test keeps one to drive the
+/// store it opened with another.
+#[cfg(any(test, feature = "fault-injection"))]
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    armed: Arc<Mutex<Vec<Fault>>>,
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+impl FaultInjector {
+    pub fn new() -> Self {
+        FaultInjector::default()
+    }
+    pub fn arm(&self, fault: Fault) {
+        self.lock().push(fault);
+    }
+    /// Disarms every fault, the store goes back to real I/O.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+    /// Faults that haven't fired as often as they were asked to yet.
+    pub fn armed(&self) -> usize {
+        self.lock().len()
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Fault>> {
+        self.armed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    /// Fails with the first armed fault matching the call, if any.
+    pub(crate) fn check(&self, op: IoOp, file: StoreFile) -> io::Result<()> {
+        let mut armed = self.lock();
+        let Some(i) = armed.iter().position(|fault| fault.matches(op, file)) else {
+            return Ok(());
+        };
+        let fault = &mut armed[i];
+        if fault.skip > 0 {
+            fault.skip -= 1;
+            return Ok(());
+        }
+        let kind = fault.kind;
+        match &mut fault.times {
+            Some(1) => {
+                armed.remove(i);
+            }
+            Some(times) => *times -= 1,
+            None => {}
+        }
+        Err(io::Error::new(
+            kind,
+            format!("injected fault: {:?} on the {:?} file", op, file),
+        ))
+    }
+}
+
+impl ActionKV {
+    /// Gives an armed fault the chance to fail the `op` call about to be made
+    /// on `file`.
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub(crate) fn inject(&self, op: IoOp, file: StoreFile) -> io::Result<()> {
+        match &self.options.faults {
+            Some(faults) => faults.check(op, file),
+            None => Ok(()),
+        }
+    }
+    #[cfg(not(any(test, feature = "fault-injection")))]
+    #[inline(always)]
+    pub(crate) fn inject(&self, _op: IoOp, _file: StoreFile) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::Options;
+    use rstest::*;
+    use serial_test::serial;
+
+    fn setup(faults: &FaultInjector) -> TestCtx {
+        TestCtx::setup_with_options(
+            "test_fault",
+            Options {
+                faults: Some(faults.clone()),
+                ..Options::default()
+            },
+        )
+    }
+    #[rstest]
+    #[serial]
+    fn test_failed_write_keeps_store_usable() {
+        let faults = FaultInjector::new();
+        let mut ctx = setup(&faults);
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+
+        let mut fault = Fault::new(IoOp::Write, io::ErrorKind::StorageFull);
+        fault.file = Some(StoreFile::Index);
+        faults.arm(fault);
+        let err = ctx.test_file.insert(b"baz", b"qux").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(faults.armed(), 0);
+
+        assert_eq!(ctx.test_file.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+        ctx.test_file.insert(b"baz", b"qux").unwrap();
+        assert_eq!(ctx.test_file.get(b"baz").unwrap(), Some(b"qux".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_skip_and_times() {
+        let faults = FaultInjector::new();
+        let mut ctx = setup(&faults);
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        ctx.test_file.get(b"foo").unwrap();
+
+        let mut fault = Fault::new(IoOp::Read, io::ErrorKind::Interrupted);
+        fault.skip = 1;
+        fault.times = Some(2);
+        faults.arm(fault);
+        assert!(ctx.test_file.get(b"foo").is_ok());
+        assert!(ctx.test_file.get(b"foo").is_err());
+        assert!(ctx.test_file.get(b"foo").is_err());
+        assert!(ctx.test_file.get(b"foo").is_ok());
+    }
+    #[rstest]
+    #[serial]
+    fn test_sync_fault() {
+        let faults = FaultInjector::new();
+        let mut ctx = setup(&faults);
+        let mut fault = Fault::new(IoOp::Sync, io::ErrorKind::Other);
+        fault.times = None;
+        faults.arm(fault);
+        assert!(ctx
+            .test_file
+            .bulk_load(vec![(b"a".to_vec(), b"1".to_vec())])
+            .is_err());
+        assert!(ctx.test_file.quiesce().is_err());
+
+        faults.clear();
+        ctx.test_file.quiesce().unwrap();
+    }
+}
@@ -0,0 +1,551 @@
+use crate::rng::Rng;
+use crate::ActionKvError;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/*
+    FILE HEADER (data and index files)
+    magic | version | flags | generation | data_len | checksum
+    [u8;4]  [u16;1]   [u16;1]  [u8;16]      [u64;1]    [u32;1]
+    generation is shared by the data and index file of one store, data_len is
+    only used by the index file: the length of the data file the index was
+    written against. checksum covers everything in front of it.
+    Files written before the header existed start directly with a record.
+    version 2 data files checksum the lengths of their records as well, see
+    Framing::checksum, index files always use the version 1 checksum.
+
+    COMPATIBILITY
+    version  written by             read by
+    none     before the header      every version
+    1        record checksums over  every version
+             key and value
+    2        record checksums over  2 and newer
+             the lengths as well
+    A header outside OLDEST_FORMAT_VERSION..=FORMAT_VERSION fails to open
+    with ActionKvError::FormatTooOld or ActionKvError::FormatTooNew.
+*/
+pub const HEADER_LEN: u64 = 36;
+pub const DATA_MAGIC: &[u8; 4] = b"AKVD";
+pub const INDEX_MAGIC: &[u8; 4] = b"AKVI";
+pub const FORMAT_VERSION: u16 = 2;
+/// Last version whose record checksums cover only the key and value.
+pub const LEGACY_CHECKSUM_VERSION: u16 = 1;
+/// Oldest format version in a file header this version still reads.
+pub const OLDEST_FORMAT_VERSION: u16 = 1;
+
+/// Data file records store key_len and value_len as LEB128 varints instead of u32s.
+pub const FLAG_VARINT_LENGTHS: u16 = 1;
+/// Values carry a tag byte and may be zstd compressed, see compression.rs.
+pub const FLAG_COMPRESSED_VALUES: u16 = 2;
+/// The index holds hashes of the user keys, see key_hash.rs.
+pub const FLAG_HASHED_KEYS: u16 = 4;
+/// value_len is a u64 (a varint of up to 10 bytes with FLAG_VARINT_LENGTHS),
+/// so a value can be larger than 4 GiB.
+pub const FLAG_WIDE_VALUES: u16 = 8;
+/// High byte of the flags: log2 of the record alignment, 0 for unaligned records.
+pub const ALIGNMENT_SHIFT_MASK: u16 = 0xff00;
+pub const MAX_ALIGNMENT_SHIFT: u8 = 24;
+pub const KNOWN_FLAGS: u16 = FLAG_VARINT_LENGTHS
+    | FLAG_COMPRESSED_VALUES
+    | FLAG_HASHED_KEYS
+    | FLAG_WIDE_VALUES
+    | ALIGNMENT_SHIFT_MASK;
+
+pub type Generation = [u8; 16];
+
+/*
+    RECORD FRAMING
+    fixed:  checksum | key_len | value_len | key | value
+            [u32;1]    [u32;1]   [u32;1]
+    varint: checksum | key_len | value_len | key | value
+            [u32;1]    [1-5 B]   [1-5 B]
+    wide values widen value_len to a u64, or a varint of 1-10 bytes
+    aligned records add the length of the zero padding that follows the value
+            checksum | key_len | value_len | pad_len | key | value | padding
+                                             [u32;1]                 [u8;pad_len]
+    so every record, and the first one after the padded file header, starts
+    on a multiple of the alignment.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Framing {
+    pub varint_lengths: bool,
+    /// log2 of the record alignment, 0 leaves records unaligned.
+    pub alignment_shift: u8,
+    /// Values are encoded by the store's `ValueCodec`.
+    pub compressed_values: bool,
+    /// The index is keyed by `key_hash::index_key`.
+    pub hashed_keys: bool,
+    /// Value lengths are u64s.
+    pub wide_values: bool,
+    /// Record checksums cover the length fields too. Not a flag, it comes
+    /// with the format version of the data file.
+    pub checksum_lengths: bool,
+}
+
+impl Framing {
+    pub fn from_flags(flags: u16) -> Self {
+        Framing {
+            varint_lengths: flags & FLAG_VARINT_LENGTHS != 0,
+            alignment_shift: (flags >> 8) as u8,
+            compressed_values: flags & FLAG_COMPRESSED_VALUES != 0,
+            hashed_keys: flags & FLAG_HASHED_KEYS != 0,
+            wide_values: flags & FLAG_WIDE_VALUES != 0,
+            checksum_lengths: false,
+        }
+    }
+    /// Framing of the data file `header` was read from.
+    pub fn from_header(header: &FileHeader) -> io::Result<Self> {
+        Ok(Framing {
+            checksum_lengths: header.version > LEGACY_CHECKSUM_VERSION,
+            ..Framing::checked_from_flags(header.flags)?
+        })
+    }
+    /// Format version of a data file framed like this.
+    pub fn version(&self) -> u16 {
+        match self.checksum_lengths {
+            true => FORMAT_VERSION,
+            false => LEGACY_CHECKSUM_VERSION,
+        }
+    }
+    /// Checksum of a record holding `data`, its key followed by its value.
+    /// With `checksum_lengths` the lengths go in first, as little endian
+    /// u32s whatever their encoding in the record (a u64 value_len with
+    /// `wide_values`), so a corrupted length is caught rather than misreading
+    /// the records that follow.
+    pub fn checksum(&self, key_len: u32, value_len: u64, padding: u32, data: &[u8]) -> u32 {
+        if !self.checksum_lengths {
+            return crc32::checksum_ieee(data);
+        }
+        let mut lengths = [0u8; 16];
+        LittleEndian::write_u32(&mut lengths[0..4], key_len);
+        let padding_at = if self.wide_values {
+            LittleEndian::write_u64(&mut lengths[4..12], value_len);
+            12
+        } else {
+            LittleEndian::write_u32(&mut lengths[4..8], value_len as u32);
+            8
+        };
+        LittleEndian::write_u32(&mut lengths[padding_at..padding_at + 4], padding);
+        crc32::update(
+            crc32::checksum_ieee(&lengths[..padding_at + 4]),
+            &crc32::IEEE_TABLE,
+            data,
+        )
+    }
+    /// `from_flags` for flags read from a file, rejecting the ones this
+    /// version doesn't know.
+    pub fn checked_from_flags(flags: u16) -> io::Result<Self> {
+        let framing = Framing::from_flags(flags);
+        if flags & !KNOWN_FLAGS != 0 || framing.alignment_shift > MAX_ALIGNMENT_SHIFT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported data file flags {:#06x}", flags),
+            ));
+        }
+        Ok(framing)
+    }
+    pub fn flags(&self) -> u16 {
+        let mut flags = (self.alignment_shift as u16) << 8;
+        if self.varint_lengths {
+            flags |= FLAG_VARINT_LENGTHS;
+        }
+        if self.compressed_values {
+            flags |= FLAG_COMPRESSED_VALUES;
+        }
+        if self.hashed_keys {
+            flags |= FLAG_HASHED_KEYS;
+        }
+        if self.wide_values {
+            flags |= FLAG_WIDE_VALUES;
+        }
+        flags
+    }
+    pub fn alignment(&self) -> u64 {
+        1 << self.alignment_shift
+    }
+    /// Offset of the first record in a data file, right after the (padded) header.
+    pub fn records_start(&self) -> u64 {
+        HEADER_LEN.next_multiple_of(self.alignment())
+    }
+    /// Longest value a record can hold.
+    pub fn max_value_len(&self) -> u64 {
+        match self.wide_values {
+            true => u64::MAX,
+            false => u32::MAX as u64,
+        }
+    }
+    /// Bytes a record takes before padding.
+    fn unpadded_len(&self, key_len: u32, value_len: u64) -> u64 {
+        let lengths_len = match (self.varint_lengths, self.wide_values) {
+            (true, _) => varint_len(key_len as u64) + varint_len(value_len),
+            (false, false) => 8,
+            (false, true) => 12,
+        };
+        let padding_len_len = if self.alignment_shift == 0 { 0 } else { 4 };
+        4 + lengths_len + padding_len_len + key_len as u64 + value_len
+    }
+    /// Zero bytes written after a record so the next one starts aligned.
+    pub fn padding(&self, key_len: u32, value_len: u64) -> u32 {
+        if self.alignment_shift == 0 {
+            return 0;
+        }
+        let record_len = self.unpadded_len(key_len, value_len);
+        (record_len.next_multiple_of(self.alignment()) - record_len) as u32
+    }
+    /// Bytes a record takes on disk, framing and padding included.
+    pub fn record_len(&self, key_len: u32, value_len: u64) -> u64 {
+        self.unpadded_len(key_len, value_len) + self.padding(key_len, value_len) as u64
+    }
+    pub fn write_padding_len<W: Write>(&self, f: &mut W, padding: u32) -> io::Result<()> {
+        if self.alignment_shift == 0 {
+            return Ok(());
+        }
+        f.write_u32::<LittleEndian>(padding)
+    }
+    pub fn read_padding_len<R: Read>(&self, f: &mut R) -> io::Result<u32> {
+        if self.alignment_shift == 0 {
+            return Ok(0);
+        }
+        f.read_u32::<LittleEndian>()
+    }
+    /// Writes the lengths of a record, `value_len` has to be at most
+    /// `max_value_len`.
+    pub fn write_lengths<W: Write>(
+        &self,
+        f: &mut W,
+        key_len: u32,
+        value_len: u64,
+    ) -> io::Result<()> {
+        match (self.varint_lengths, self.wide_values) {
+            (true, _) => {
+                write_varint(f, key_len as u64)?;
+                write_varint(f, value_len)
+            }
+            (false, false) => {
+                f.write_u32::<LittleEndian>(key_len)?;
+                f.write_u32::<LittleEndian>(value_len as u32)
+            }
+            (false, true) => {
+                f.write_u32::<LittleEndian>(key_len)?;
+                f.write_u64::<LittleEndian>(value_len)
+            }
+        }
+    }
+    pub fn read_lengths<R: Read>(&self, f: &mut R) -> io::Result<(u32, u64)> {
+        let value_bits = if self.wide_values { 64 } else { 32 };
+        match (self.varint_lengths, self.wide_values) {
+            (true, _) => Ok((read_varint(f, 32)? as u32, read_varint(f, value_bits)?)),
+            (false, false) => Ok((
+                f.read_u32::<LittleEndian>()?,
+                f.read_u32::<LittleEndian>()? as u64,
+            )),
+            (false, true) => Ok((f.read_u32::<LittleEndian>()?, f.read_u64::<LittleEndian>()?)),
+        }
+    }
+}
+
+fn write_varint<W: Write>(f: &mut W, mut value: u64) -> io::Result<()> {
+    while value >= 0x80 {
+        f.write_u8((value as u8) | 0x80)?;
+        value >>= 7;
+    }
+    f.write_u8(value as u8)
+}
+
+fn varint_len(value: u64) -> u64 {
+    (64 - (value | 1).leading_zeros() as u64).div_ceil(7)
+}
+
+/// Reads a varint that has to fit in `bits` bits, 32 or 64.
+fn read_varint<R: Read>(f: &mut R, bits: u32) -> io::Result<u64> {
+    let max_len = bits.div_ceil(7);
+    let mut value: u64 = 0;
+    for shift in (0..max_len * 7).step_by(7) {
+        let byte = f.read_u8()?;
+        let part = ((byte & 0x7f) as u64)
+            .checked_shl(shift)
+            .filter(|part| part >> shift == (byte & 0x7f) as u64)
+            .filter(|part| bits == 64 || part >> bits == 0)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("varint overflows u{}", bits),
+                )
+            })?;
+        value |= part;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("varint longer than {} bytes", max_len),
+    ))
+}
+
+/// Random (version 4 UUID shaped) generation for a freshly created store.
+pub fn new_generation() -> Generation {
+    let mut generation = Generation::default();
+    Rng::new().fill(&mut generation);
+    generation[6] = (generation[6] & 0x0f) | 0x40;
+    generation[8] = (generation[8] & 0x3f) | 0x80;
+    generation
+}
+
+pub fn display_generation(generation: &Generation) -> String {
+    let hex: String = generation
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHeader {
+    pub magic: [u8; 4],
+    pub version: u16,
+    pub flags: u16,
+    pub generation: Generation,
+    pub data_len: u64,
+}
+
+impl FileHeader {
+    pub fn new(magic: &[u8; 4], generation: Generation) -> Self {
+        FileHeader {
+            magic: *magic,
+            version: FORMAT_VERSION,
+            flags: 0,
+            generation,
+            data_len: 0,
+        }
+    }
+    /// Header of a data file whose records are framed with `framing`.
+    pub fn for_data(generation: Generation, framing: Framing) -> Self {
+        FileHeader {
+            version: framing.version(),
+            flags: framing.flags(),
+            ..FileHeader::new(DATA_MAGIC, generation)
+        }
+    }
+    pub fn encode(&self) -> [u8; HEADER_LEN as usize] {
+        let mut buf = [0u8; HEADER_LEN as usize];
+        buf[0..4].copy_from_slice(&self.magic);
+        LittleEndian::write_u16(&mut buf[4..6], self.version);
+        LittleEndian::write_u16(&mut buf[6..8], self.flags);
+        buf[8..24].copy_from_slice(&self.generation);
+        LittleEndian::write_u64(&mut buf[24..32], self.data_len);
+        let checksum = crc32::checksum_ieee(&buf[..32]);
+        LittleEndian::write_u32(&mut buf[32..36], checksum);
+        buf
+    }
+    pub fn write_to<W: Write + Seek>(&self, f: &mut W) -> io::Result<()> {
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(&self.encode())
+    }
+    /// Header at the start of `f`, `None` for empty files and files from before the
+    /// header existed.
+    pub fn read_from<R: Read + Seek>(f: &mut R, magic: &[u8; 4]) -> io::Result<Option<Self>> {
+        f.seek(SeekFrom::Start(0))?;
+        let mut buf = [0u8; HEADER_LEN as usize];
+        let mut filled = 0;
+        while filled < buf.len() {
+            match f.read(&mut buf[filled..])? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        if filled < 4 || &buf[0..4] != magic {
+            return Ok(None);
+        }
+        if filled < buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file header is truncated",
+            ));
+        }
+        let checksum = crc32::checksum_ieee(&buf[..32]);
+        let saved_checksum = LittleEndian::read_u32(&buf[32..36]);
+        if checksum != saved_checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File header corruption encountered {:08x} != {:08x}",
+                    checksum, saved_checksum
+                ),
+            ));
+        }
+        let version = LittleEndian::read_u16(&buf[4..6]);
+        if version > FORMAT_VERSION {
+            return Err(ActionKvError::FormatTooNew { version }.into());
+        }
+        if version < OLDEST_FORMAT_VERSION {
+            return Err(ActionKvError::FormatTooOld { version }.into());
+        }
+        let mut generation = Generation::default();
+        generation.copy_from_slice(&buf[8..24]);
+        Ok(Some(FileHeader {
+            magic: *magic,
+            version,
+            flags: LittleEndian::read_u16(&buf[6..8]),
+            generation,
+            data_len: LittleEndian::read_u64(&buf[24..32]),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+    use std::io::Cursor;
+
+    #[rstest]
+    #[case(0)]
+    #[case(127)]
+    #[case(128)]
+    #[case(300_000)]
+    #[case(u32::MAX)]
+    fn test_varint_framing_round_trip(#[case] len: u32) {
+        let framing = Framing {
+            varint_lengths: true,
+            ..Framing::default()
+        };
+        let mut buf = Vec::new();
+        framing.write_lengths(&mut buf, len, 1).unwrap();
+        assert_eq!(framing.read_lengths(&mut buf.as_slice()).unwrap(), (len, 1));
+        assert_eq!(
+            buf.len() as u64,
+            framing.record_len(len, 1) - 4 - len as u64 - 1
+        );
+    }
+    #[rstest]
+    #[case(false, 0)]
+    #[case(false, u32::MAX as u64 + 1)]
+    #[case(false, u64::MAX >> 8)]
+    #[case(true, 300)]
+    #[case(true, u64::MAX >> 8)]
+    fn test_wide_values_round_trip(#[case] varint_lengths: bool, #[case] len: u64) {
+        let framing = Framing {
+            varint_lengths,
+            wide_values: true,
+            checksum_lengths: true,
+            ..Framing::default()
+        };
+        let mut buf = Vec::new();
+        framing.write_lengths(&mut buf, 3, len).unwrap();
+        assert_eq!(framing.read_lengths(&mut buf.as_slice()).unwrap(), (3, len));
+        assert_eq!(buf.len() as u64, framing.unpadded_len(3, len) - 4 - 3 - len);
+        assert!(Framing::from_flags(framing.flags()).wide_values);
+        assert_ne!(
+            framing.checksum(3, len, 0, b"foo"),
+            framing.checksum(3, len ^ 1 << 40, 0, b"foo")
+        );
+    }
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    fn test_padding_aligns_records(#[case] varint_lengths: bool) {
+        let framing = Framing {
+            varint_lengths,
+            alignment_shift: 9,
+            ..Framing::default()
+        };
+        assert_eq!(framing.records_start(), 512);
+        for (key_len, value_len) in [(0, 0), (3, 3), (200, 296), (1000, 1)] {
+            let mut header = Vec::new();
+            framing
+                .write_lengths(&mut header, key_len, value_len)
+                .unwrap();
+            let padding = framing.padding(key_len, value_len);
+            let record_len =
+                4 + header.len() as u64 + 4 + key_len as u64 + value_len + padding as u64;
+            assert_eq!(record_len % 512, 0);
+            assert!(padding < 512);
+        }
+        assert_eq!(Framing::from_flags(framing.flags()), framing);
+    }
+    #[rstest]
+    fn test_varint_rejects_overflow() {
+        let mut too_long: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0x7f];
+        assert!(read_varint(&mut too_long, 32).is_err());
+        let nine: &[u8] = &[0xff; 9];
+        assert!(read_varint(&mut [nine, &[0x02]].concat().as_slice(), 64).is_err());
+        assert_eq!(
+            read_varint(&mut [nine, &[0x01]].concat().as_slice(), 64).unwrap(),
+            u64::MAX
+        );
+    }
+    #[rstest]
+    fn test_header_round_trip() {
+        let mut header = FileHeader::new(INDEX_MAGIC, new_generation());
+        header.data_len = 1234;
+        let mut f = Cursor::new(Vec::new());
+        header.write_to(&mut f).unwrap();
+        assert_eq!(f.get_ref().len() as u64, HEADER_LEN);
+        assert_eq!(
+            FileHeader::read_from(&mut f, INDEX_MAGIC).unwrap(),
+            Some(header)
+        );
+        assert_eq!(FileHeader::read_from(&mut f, DATA_MAGIC).unwrap(), None);
+
+        f.get_mut()[10] ^= 1;
+        assert!(FileHeader::read_from(&mut f, INDEX_MAGIC).is_err());
+    }
+    #[rstest]
+    #[case(FORMAT_VERSION + 1, true)]
+    #[case(u16::MAX, true)]
+    #[case(OLDEST_FORMAT_VERSION - 1, false)]
+    fn test_unsupported_versions(#[case] version: u16, #[case] too_new: bool) {
+        let header = FileHeader {
+            version,
+            ..FileHeader::new(DATA_MAGIC, new_generation())
+        };
+        let mut f = Cursor::new(header.encode().to_vec());
+        let err = FileHeader::read_from(&mut f, DATA_MAGIC).unwrap_err();
+        match ActionKvError::from_io(&err) {
+            Some(ActionKvError::FormatTooNew { version: found }) => {
+                assert!(too_new && *found == version)
+            }
+            Some(ActionKvError::FormatTooOld { version: found }) => {
+                assert!(!too_new && *found == version)
+            }
+            _ => panic!("unexpected error {}", err),
+        }
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    fn test_checksum_covers_lengths(#[case] varint_lengths: bool) {
+        let framing = Framing {
+            varint_lengths,
+            checksum_lengths: true,
+            ..Framing::default()
+        };
+        let header = FileHeader::for_data(new_generation(), framing);
+        assert_eq!(header.version, FORMAT_VERSION);
+        assert_eq!(Framing::from_header(&header).unwrap(), framing);
+
+        let checksum = framing.checksum(3, 3, 0, b"foobar");
+        assert_ne!(framing.checksum(2, 4, 0, b"foobar"), checksum);
+        assert_ne!(framing.checksum(3, 3, 1, b"foobar"), checksum);
+        let legacy = Framing {
+            checksum_lengths: false,
+            ..framing
+        };
+        assert_eq!(
+            legacy.checksum(2, 4, 0, b"foobar"),
+            crc32::checksum_ieee(b"foobar")
+        );
+        let header = FileHeader::for_data(new_generation(), legacy);
+        assert_eq!(Framing::from_header(&header).unwrap(), legacy);
+    }
+}
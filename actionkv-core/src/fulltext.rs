@@ -0,0 +1,173 @@
+use crate::stats::OpClass;
+use crate::{is_internal_key, namespaced_key, ActionKV, ByteStr, ByteString, INDEX_KEY};
+use std::fmt::Debug;
+use std::io;
+
+/*
+    INVERTED INDEX LAYOUT
+    posting: +ft: | term_len | term | primary key  ->  1
+    every term of a value gets one posting, so all keys containing a term
+    are found with a single prefix lookup.
+*/
+const POSTING_PREFIX: &ByteStr = b"+ft:";
+
+fn posting_key(term: &ByteStr, key: &ByteStr) -> ByteString {
+    let mut posting = namespaced_key(POSTING_PREFIX, term);
+    posting.extend(key);
+    posting
+}
+
+/// Splits values into the terms they can be searched by.
+pub trait Tokenizer: Debug + Send {
+    fn tokenize(&self, value: &ByteStr) -> Vec<ByteString>;
+}
+
+/// Lowercased runs of ASCII letters and digits.
+#[derive(Debug, Default)]
+pub struct WordTokenizer;
+
+impl Tokenizer for WordTokenizer {
+    fn tokenize(&self, value: &ByteStr) -> Vec<ByteString> {
+        value
+            .split(|byte| !byte.is_ascii_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_ascii_lowercase())
+            .collect()
+    }
+}
+
+impl ActionKV {
+    /// Index every value written from now on with `tokenizer`, values already
+    /// stored are picked up by `rebuild_search_index`.
+    pub fn set_tokenizer(&mut self, tokenizer: Box<dyn Tokenizer>) {
+        self.tokenizer = Some(tokenizer);
+    }
+    /// Keys whose current value contains `term`, sorted. Keys the access
+    /// policy doesn't let be read are left out.
+    pub fn search(&mut self, term: &ByteStr) -> io::Result<Vec<ByteString>> {
+        let prefix = namespaced_key(POSTING_PREFIX, term);
+        Ok(self
+            .keys_with_prefix(&prefix)?
+            .into_iter()
+            .map(|posting| posting[prefix.len()..].to_vec())
+            .filter(|key| self.readable(key))
+            .collect())
+    }
+    /// Throws the inverted index away and indexes every live value again.
+    pub fn rebuild_search_index(&mut self) -> io::Result<()> {
+        self.op_class = OpClass::Maintenance;
+        for posting in self.keys_with_prefix(POSTING_PREFIX)? {
+            self.remove_(&posting)?;
+        }
+        if self.tokenizer.is_some() {
+            let entries = self.index_entries()?;
+            for (key, position) in entries {
+                if is_internal_key(&key) {
+                    continue;
+                }
+                let value = self.get_at(position, false)?.value;
+                self.index_terms(&key, &value)?;
+            }
+        }
+        self.store_index_on_disk(INDEX_KEY)
+    }
+    fn terms(&self, key: &ByteStr, value: &ByteStr) -> Vec<ByteString> {
+        match &self.tokenizer {
+            Some(tokenizer) if !is_internal_key(key) => {
+                let mut terms = tokenizer.tokenize(value);
+                terms.sort();
+                terms.dedup();
+                terms
+            }
+            _ => Vec::new(),
+        }
+    }
+    /// Keys of the postings of `value` stored under `key`.
+    pub(crate) fn postings(&self, key: &ByteStr, value: &ByteStr) -> Vec<ByteString> {
+        self.terms(key, value)
+            .iter()
+            .map(|term| posting_key(term, key))
+            .collect()
+    }
+    /// Keys of the postings of the value currently stored under `key`.
+    pub(crate) fn stale_postings(&mut self, key: &ByteStr) -> io::Result<Vec<ByteString>> {
+        if self.tokenizer.is_none() || is_internal_key(key) {
+            return Ok(Vec::new());
+        }
+        Ok(match self.value_of(key, true)? {
+            Some(old_value) => self.postings(key, &old_value),
+            None => Vec::new(),
+        })
+    }
+    /// Appends postings for `value`, persisting the index is left to the caller.
+    pub(crate) fn index_terms(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        for posting in self.postings(key, value) {
+            self.insert_(&posting, &[1])?;
+        }
+        Ok(())
+    }
+    /// Removes the postings of the value currently stored under `key`.
+    pub(crate) fn unindex_terms(&mut self, key: &ByteStr) -> io::Result<()> {
+        for posting in self.stale_postings(key)? {
+            self.remove_(&posting)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestCtx};
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_fulltext")
+    }
+    #[rstest]
+    #[serial]
+    fn test_search_follows_writes(mut ctx: TestCtx) {
+        ctx.test_file.set_tokenizer(Box::new(WordTokenizer));
+        ctx.test_file.insert(b"doc1", b"The quick fox").unwrap();
+        ctx.test_file.insert(b"doc2", b"a lazy fox").unwrap();
+        assert_eq!(
+            ctx.test_file.search(b"fox").unwrap(),
+            vec![b"doc1".to_vec(), b"doc2".to_vec()]
+        );
+
+        ctx.test_file.update(b"doc1", b"slow turtle").unwrap();
+        ctx.test_file.delete(b"doc2").unwrap();
+        assert!(ctx.test_file.search(b"fox").unwrap().is_empty());
+        assert_eq!(
+            ctx.test_file.search(b"turtle").unwrap(),
+            vec![b"doc1".to_vec()]
+        );
+    }
+    #[rstest]
+    #[serial]
+    fn test_rebuild_search_index(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"doc1", b"hello world").unwrap();
+        assert!(ctx.test_file.search(b"hello").unwrap().is_empty());
+
+        ctx.test_file.set_tokenizer(Box::new(WordTokenizer));
+        ctx.test_file.rebuild_search_index().unwrap();
+        assert_eq!(
+            ctx.test_file.search(b"hello").unwrap(),
+            vec![b"doc1".to_vec()]
+        );
+    }
+    #[rstest]
+    #[serial]
+    fn test_search_leaves_out_unreadable_keys(mut ctx: TestCtx) {
+        ctx.test_file.set_tokenizer(Box::new(WordTokenizer));
+        ctx.test_file.insert(b"public:doc", b"a fox").unwrap();
+        ctx.test_file.insert(b"secret:doc", b"the fox").unwrap();
+        ctx.test_file.options.access_policy = Some(testing::deny_secrets);
+        assert_eq!(
+            ctx.test_file.search(b"fox").unwrap(),
+            vec![b"public:doc".to_vec()]
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! Entry points for the fuzz targets in `fuzz/`, they hand attacker controlled
+//! bytes to the same decoders opening a store uses. Not a stable API.
+use crate::format::Framing;
+use crate::manifest::Manifest;
+use crate::{ActionKV, ByteStr, ByteString, FixintCodec, IndexCodec, KeyValuePair};
+use std::collections::HashMap;
+use std::io;
+
+/// One data file record framed as the data file header `flags` say.
+pub fn decode_record(bytes: &ByteStr, flags: u16) -> io::Result<KeyValuePair> {
+    let framing = Framing::checked_from_flags(flags)?;
+    ActionKV::process_records(&mut &bytes[..], framing)
+}
+
+/// The `+index` record at the start of the index file's records, decoded into
+/// the key directory.
+pub fn decode_index_record(bytes: &ByteStr) -> io::Result<HashMap<ByteString, u64>> {
+    let record = ActionKV::process_records(&mut &bytes[..], Framing::default())?;
+    FixintCodec.decode(&record.value)
+}
+
+/// A store's MANIFEST file, returning the ids of the dictionaries it holds.
+pub fn decode_manifest(bytes: &ByteStr) -> io::Result<Vec<u32>> {
+    let manifest = Manifest::decode(bytes)?;
+    Ok(manifest.dictionaries.iter().map(|(id, _)| *id).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+    use std::fs;
+
+    #[rstest]
+    fn test_corpus_seeds_decode() {
+        for seed in fs::read_dir("fuzz/corpus/process_records").unwrap() {
+            let bytes = fs::read(seed.unwrap().path()).unwrap();
+            let flags = u16::from_le_bytes([bytes[0], bytes[1]]);
+            decode_record(&bytes[2..], flags).unwrap();
+        }
+        for seed in fs::read_dir("fuzz/corpus/index").unwrap() {
+            decode_index_record(&fs::read(seed.unwrap().path()).unwrap()).unwrap();
+        }
+        for seed in fs::read_dir("fuzz/corpus/manifest").unwrap() {
+            decode_manifest(&fs::read(seed.unwrap().path()).unwrap()).unwrap();
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use crate::stats::OpClass;
+use crate::{ActionKV, ByteStr, ByteString, KeyEvent, Operation};
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Byte(u8),
+    /// `?`, exactly one byte.
+    AnyByte,
+    /// `*`, any run of bytes including none.
+    AnyRun,
+}
+
+/// Compiled key pattern: `*` matches any run of bytes, `?` a single byte and
+/// `\` makes the next byte literal, so `user:*:settings` matches the settings
+/// key of every user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    tokens: Vec<Token>,
+    prefix: ByteString,
+}
+
+impl Glob {
+    pub fn new(pattern: &ByteStr) -> Self {
+        let mut tokens = Vec::with_capacity(pattern.len());
+        let mut bytes = pattern.iter();
+        while let Some(&byte) = bytes.next() {
+            tokens.push(match byte {
+                b'*' if tokens.last() == Some(&Token::AnyRun) => continue,
+                b'*' => Token::AnyRun,
+                b'?' => Token::AnyByte,
+                b'\\' => Token::Byte(*bytes.next().unwrap_or(&b'\\')),
+                byte => Token::Byte(byte),
+            });
+        }
+        let prefix = tokens
+            .iter()
+            .map_while(|token| match token {
+                Token::Byte(byte) => Some(*byte),
+                _ => None,
+            })
+            .collect();
+        Glob { tokens, prefix }
+    }
+    /// Literal start every matching key shares, used to narrow scans down.
+    pub fn prefix(&self) -> &ByteStr {
+        &self.prefix
+    }
+    pub fn matches(&self, key: &ByteStr) -> bool {
+        // classic wildcard matching, backtracking only to the last `*`
+        let (mut t, mut k) = (0, 0);
+        let mut last_run: Option<(usize, usize)> = None;
+        while k < key.len() {
+            match self.tokens.get(t) {
+                Some(Token::AnyRun) => {
+                    last_run = Some((t, k));
+                    t += 1;
+                }
+                Some(Token::AnyByte) => {
+                    t += 1;
+                    k += 1;
+                }
+                Some(Token::Byte(byte)) if *byte == key[k] => {
+                    t += 1;
+                    k += 1;
+                }
+                _ => match last_run {
+                    Some((run_t, run_k)) => {
+                        last_run = Some((run_t, run_k + 1));
+                        t = run_t + 1;
+                        k = run_k + 1;
+                    }
+                    None => return false,
+                },
+            }
+        }
+        self.tokens[t..].iter().all(|token| *token == Token::AnyRun)
+    }
+}
+
+impl ActionKV {
+    /// Sorted live keys matching the glob `pattern`, leaving out the ones the
+    /// access policy doesn't let be read.
+    pub fn scan_glob(&mut self, pattern: &ByteStr) -> io::Result<Vec<ByteString>> {
+        let glob = Glob::new(pattern);
+        Ok(self
+            .user_keys(glob.prefix())?
+            .into_iter()
+            .filter(|key| glob.matches(key) && self.readable(key))
+            .collect())
+    }
+    /// Deletes every key matching the glob `pattern`, persisting the index once
+    /// at the end. Nothing is deleted unless every key may be. Returns how
+    /// many keys were deleted.
+    pub fn delete_glob(&mut self, pattern: &ByteStr) -> io::Result<usize> {
+        let keys = self.scan_glob(pattern)?;
+        for key in &keys {
+            self.authorize(Operation::Delete, key)?;
+        }
+        self.op_class = OpClass::Delete;
+        for key in &keys {
+            self.io_stats
+                .record_operation(OpClass::Delete, key.len() as u64);
+            self.unindex_terms(key)?;
+            self.remove_chunks(key)?;
+            self.remove_(key)?;
+        }
+        self.index_written()?;
+        let deleted = keys.len();
+        for key in keys {
+            self.notify(KeyEvent::Delete(key));
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestCtx};
+    use crate::Access;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_glob")
+    }
+    #[rstest]
+    #[case(b"user:*:settings", b"user:42:settings", true)]
+    #[case(b"user:*:settings", b"user:42:profile", false)]
+    #[case(b"user:*:settings", b"user::settings", true)]
+    #[case(b"a*b*c", b"aXbYbZc", true)]
+    #[case(b"a*b*c", b"aXbYcZ", false)]
+    #[case(b"k??", b"k12", true)]
+    #[case(b"k??", b"k1", false)]
+    #[case(b"lit\\*", b"lit*", true)]
+    #[case(b"lit\\*", b"litx", false)]
+    #[case(b"*", b"", true)]
+    fn test_glob_matches(#[case] pattern: &[u8], #[case] key: &[u8], #[case] expected: bool) {
+        assert_eq!(Glob::new(pattern).matches(key), expected);
+    }
+    #[rstest]
+    fn test_glob_prefix() {
+        assert_eq!(Glob::new(b"user:*:settings").prefix(), b"user:");
+        assert_eq!(Glob::new(b"*x").prefix(), b"");
+    }
+    #[rstest]
+    #[serial]
+    fn test_scan_and_delete_glob(mut ctx: TestCtx) {
+        let store = &mut ctx.test_file;
+        for key in [
+            "user:1:settings",
+            "user:2:settings",
+            "user:1:profile",
+            "other",
+        ] {
+            store.insert(key.as_bytes(), b"x").unwrap();
+        }
+        assert_eq!(
+            store.scan_glob(b"user:*:settings").unwrap(),
+            vec![b"user:1:settings".to_vec(), b"user:2:settings".to_vec()]
+        );
+        assert_eq!(store.delete_glob(b"user:*:settings").unwrap(), 2);
+        assert_eq!(store.scan_glob(b"*").unwrap().len(), 2);
+        assert_eq!(store.get(b"user:2:settings").unwrap(), None);
+    }
+    #[rstest]
+    #[serial]
+    fn test_glob_leaves_out_unreadable_keys(mut ctx: TestCtx) {
+        let store = &mut ctx.test_file;
+        testing::hide_secrets(store, 5);
+        let keys = store.scan_glob(b"*:?").unwrap();
+        assert_eq!(keys.len(), 5);
+        assert!(keys.iter().all(|key| key.starts_with(b"public:")));
+        store.options.access_policy = Some(|operation, key| match operation {
+            Operation::Delete if key.ends_with(b"4") => Access::Deny,
+            _ => Access::Allow,
+        });
+        assert_eq!(
+            store.delete_glob(b"public:*").unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(store.scan_glob(b"public:*").unwrap().len(), 5);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{
+    ActionKV, ActionKvError, ByteStr, ByteString, IoOp, KeyEvent, OpClass, Operation, StoreFile,
+};
+use std::io;
+
+/*
+    GROUPS
+    records that have to land together are preceded by a header record
+    telling how many follow, the postings, chunks and chunk tombstones the
+    writes bring along included. Without Options::index_checkpoints the index
+    save after the group is what commits it, a crash before drops all of
+    it. Replaying records after a checkpoint applies a group only once all
+    of its records were read, a group cut short is truncated away from its
+    header on. The header is never indexed, compaction drops it.
+    header: "+group" | records
+                       [u64 LE]
+*/
+pub(crate) const GROUP_KEY: &ByteStr = b"+group";
+
+/// Records of a group being replayed, held back until the last one is read.
+#[derive(Debug)]
+pub(crate) struct PendingGroup {
+    /// Where the header starts, replay is cut there if the group is torn.
+    pub start: u64,
+    left: u64,
+    records: Vec<(ByteString, u64, bool)>,
+}
+
+impl PendingGroup {
+    /// The group whose header at `start` holds `header`, `None` for an
+    /// empty one.
+    pub fn new(start: u64, header: &ByteStr) -> io::Result<Option<Self>> {
+        let left = header.try_into().map(u64::from_le_bytes).map_err(|_| {
+            ActionKvError::Serialization {
+                what: "group header",
+                reason: format!("{} bytes instead of 8", header.len()),
+            }
+        })?;
+        Ok((left > 0).then(|| PendingGroup {
+            start,
+            left,
+            records: Vec::new(),
+        }))
+    }
+    /// Holds back the record of `key` at `position`, returns the records of
+    /// the group once it is complete.
+    pub fn push(
+        &mut self,
+        key: ByteString,
+        position: u64,
+        tombstone: bool,
+    ) -> Option<Vec<(ByteString, u64, bool)>> {
+        self.records.push((key, position, tombstone));
+        self.left -= 1;
+        (self.left == 0).then(|| std::mem::take(&mut self.records))
+    }
+}
+
+impl ActionKV {
+    /// Writes `writes` as one group, `None` deletes the key. Keys have to be
+    /// canonical already, persisting the index is left to the caller.
+    pub(crate) fn write_group(
+        &mut self,
+        writes: &[(ByteString, Option<ByteString>)],
+    ) -> io::Result<()> {
+        self.writable()?;
+        for (key, value) in writes {
+            let operation = match value {
+                Some(_) => Operation::Write,
+                None => Operation::Delete,
+            };
+            self.authorize(operation, key)?;
+        }
+        // postings of the old values go first, before the writes move the
+        // index off them
+        let mut first = Vec::new();
+        for (key, _) in writes {
+            let postings = self.stale_postings(key)?;
+            first.extend(postings.into_iter().map(|posting| (posting, None)));
+        }
+        // values longer than Options::chunk_size are written in chunks as
+        // insert writes them, taking the place of their key's record
+        let mut records = Vec::with_capacity(writes.len());
+        // whole values go before the stale chunks, a plain record is found
+        // before a large value manifest
+        let mut last = Vec::new();
+        for (key, value) in writes {
+            match (value, self.options.chunk_size) {
+                (Some(value), Some(chunk_len)) if value.len() > chunk_len as usize => {
+                    records.extend(self.chunk_records(key, value, chunk_len.max(1))?);
+                }
+                _ => {
+                    records.push((key.clone(), value.clone()));
+                    let chunk_keys = self.chunk_keys(key)?;
+                    last.extend(chunk_keys.into_iter().map(|chunk_key| (chunk_key, None)));
+                }
+            }
+            if let Some(value) = value {
+                let postings = self.postings(key, value);
+                last.extend(postings.into_iter().map(|posting| (posting, Some(vec![1]))));
+            }
+        }
+        let parts = [first.as_slice(), records.as_slice(), last.as_slice()];
+        let start = self.appender.tail();
+        // where every key was and was deleted, to go back to if the group
+        // fails
+        let before: Vec<(ByteString, Option<u64>, Option<u64>)> = parts
+            .iter()
+            .flat_map(|part| part.iter())
+            .map(|(key, _)| {
+                let index_key = self.index_key(key).into_owned();
+                let deleted = self.write_log.deleted_at(&index_key);
+                (index_key, self.position(key), deleted)
+            })
+            .collect();
+        if let Err(err) = self.append_group(&parts) {
+            // records after a group cut short would be taken for the rest
+            // of it, so the group goes entirely
+            self.file_.set_len(start)?;
+            self.appender.reset(start);
+            for (key, position, deleted) in before {
+                let current = match position {
+                    Some(position) => self.index.insert(key.clone(), position),
+                    None => self.index.remove(&key),
+                };
+                self.index_moved(&key, current, position);
+                self.write_log.restore(key, deleted);
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+    /// Appends the records of `parts` as one group in a single write.
+    fn append_group(&mut self, parts: &[&[(ByteString, Option<ByteString>)]]) -> io::Result<()> {
+        self.appender.hold();
+        let records: usize = parts.iter().map(|part| part.len()).sum();
+        self.insert_(GROUP_KEY, &(records as u64).to_le_bytes())?;
+        self.index.remove(GROUP_KEY);
+        for (key, value) in parts.iter().flat_map(|part| part.iter()) {
+            match value {
+                Some(value) => self.insert_(key, value)?,
+                None => self.remove_(key)?,
+            }
+        }
+        self.inject(IoOp::Write, StoreFile::Data)?;
+        self.appender.release(&mut self.file_)
+    }
+    pub(crate) fn notify_group(&mut self, writes: Vec<(ByteString, Option<ByteString>)>) {
+        for (key, value) in writes {
+            self.notify(match value {
+                Some(_) => KeyEvent::Put(key),
+                None => KeyEvent::Delete(key),
+            });
+        }
+    }
+    fn commit_group(&mut self, writes: Vec<(ByteString, Option<ByteString>)>) -> io::Result<()> {
+        self.op_class = OpClass::Insert;
+        self.read_index()?;
+        self.write_group(&writes)?;
+        self.index_written()?;
+        self.notify_group(writes);
+        Ok(())
+    }
+    /// Exchanges the values of `a` and `b` in one step: a crash leaves
+    /// either both as they were or both swapped. A key without a value
+    /// takes the other one's and leaves it without.
+    pub fn swap(&mut self, a: &ByteStr, b: &ByteStr) -> io::Result<()> {
+        let a = self.canonical_key(a).into_owned();
+        let b = self.canonical_key(b).into_owned();
+        if a == b {
+            return Ok(());
+        }
+        let value_a = self.get(&a)?;
+        let value_b = self.get(&b)?;
+        let mut writes = Vec::new();
+        if value_a.is_some() || value_b.is_some() {
+            writes.push((a, value_b));
+            writes.push((b, value_a));
+        }
+        self.commit_group(writes)
+    }
+    /// Moves the value of `from` to `to` in one step, replacing what `to`
+    /// held: a crash leaves either both as they were or the value under
+    /// `to` alone. Returns whether `from` had a value to move.
+    pub fn move_value(&mut self, from: &ByteStr, to: &ByteStr) -> io::Result<bool> {
+        let from = self.canonical_key(from).into_owned();
+        let to = self.canonical_key(to).into_owned();
+        let Some(value) = self.get(&from)? else {
+            return Ok(false);
+        };
+        if from != to {
+            self.commit_group(vec![(to, Some(value)), (from, None)])?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::{IndexCheckpoints, Options};
+    use rstest::*;
+    use serial_test::serial;
+    use std::fs::OpenOptions;
+
+    #[rstest]
+    #[serial]
+    fn test_swap_and_move() {
+        let mut ctx = TestCtx::setup("test_group");
+        let store = &mut ctx.test_file;
+        store.insert(b"config:active", b"v41").unwrap();
+        store.insert(b"config:next", b"v42").unwrap();
+        let events = store.subscribe(b"config:*");
+        store.swap(b"config:active", b"config:next").unwrap();
+        assert_eq!(store.get(b"config:active").unwrap(), Some(b"v42".to_vec()));
+        assert_eq!(store.get(b"config:next").unwrap(), Some(b"v41".to_vec()));
+        assert_eq!(events.try_iter().count(), 2);
+
+        assert!(store.move_value(b"config:next", b"config:old").unwrap());
+        assert_eq!(store.get(b"config:next").unwrap(), None);
+        assert_eq!(store.get(b"config:old").unwrap(), Some(b"v41".to_vec()));
+        assert!(!store.move_value(b"config:next", b"config:old").unwrap());
+        store.swap(b"config:next", b"config:old").unwrap();
+        assert_eq!(store.get(b"config:next").unwrap(), Some(b"v41".to_vec()));
+        assert_eq!(store.get(b"config:old").unwrap(), None);
+        store.read_index().unwrap();
+        assert!(store.position(GROUP_KEY).is_none());
+
+        let store = ctx.reopen().unwrap();
+        assert_eq!(store.rebuild_index().unwrap(), 2);
+        assert_eq!(store.get(b"config:active").unwrap(), Some(b"v42".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_torn_group_is_not_replayed() {
+        let options = Options {
+            index_checkpoints: Some(IndexCheckpoints {
+                records: 100,
+                bytes: u64::MAX,
+            }),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_group", options.clone());
+        let store = &mut ctx.test_file;
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.flush_index().unwrap();
+        let before = store.appender.tail();
+        store.swap(b"a", b"b").unwrap();
+        let after = store.appender.tail();
+
+        // cut the last record of the group short, as a crash would
+        let data = OpenOptions::new()
+            .write(true)
+            .open("test_group/data")
+            .unwrap();
+        data.set_len(after - 1).unwrap();
+        let store = ctx.crash_and_reopen(options).unwrap();
+        assert_eq!(store.appender.tail(), before);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+}
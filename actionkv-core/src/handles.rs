@@ -0,0 +1,101 @@
+use crate::{ActionKV, Options};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Error for what a store opened on file handles can't do, as it needs the
+/// store's directory.
+pub(crate) fn no_directory(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} needs the store's directory, which a store opened on file handles doesn't have",
+            what
+        ),
+    )
+}
+
+impl ActionKV {
+    /// Opens a store on its already open data and index files instead of
+    /// its directory, for sandboxes (seccomp, capsicum, WASI) that hand out
+    /// preopened handles and forbid opening paths. `OwnedFd`s and cap-std
+    /// files convert into `File`s. Both handles need read access. Unless
+    /// `Options::read_only` is set, the index also needs write access and
+    /// the data file has to be opened for appending. Empty files are
+    /// initialized like `open` does.
+    ///
+    /// The store lives in those two files alone: compaction, checkpoints,
+    /// backups, scans and everything else that opens files of its own fails
+    /// with `ErrorKind::Unsupported`, as do compressed stores. With no
+    /// manifest to read it from `Options::key_canonicalization` is taken as
+    /// the store's, and nothing locks the files against other writers.
+    pub fn open_files(mut data: File, mut index: File, options: Options) -> io::Result<Self> {
+        let (layout, data_len) = if options.read_only {
+            ActionKV::read_only_layout(&mut data, &mut index)?
+        } else {
+            let replay = options.index_checkpoints.is_some();
+            let framing = options.framing()?;
+            let layout = ActionKV::check_headers(&mut data, &mut index, framing, replay, None)?;
+            let data_len = data.metadata()?.len();
+            (layout, data_len)
+        };
+        ActionKV::assemble(None, data, index, layout, data_len, options)
+    }
+    /// Directory of the store, an error for stores opened on file handles.
+    pub(crate) fn dir(&self, what: &str) -> io::Result<&Path> {
+        self.path.as_deref().ok_or_else(|| no_directory(what))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+    use serial_test::serial;
+    use std::fs::{self, OpenOptions};
+
+    #[rstest]
+    #[serial]
+    fn test_open_files() {
+        let _ = fs::remove_dir_all("test_handles");
+        fs::create_dir("test_handles").unwrap();
+        let data = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open("test_handles/data")
+            .unwrap();
+        let index = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open("test_handles/index")
+            .unwrap();
+        let mut store = ActionKV::open_files(data, index, Options::default()).unwrap();
+        store.insert(b"foo", b"bar").unwrap();
+        store.insert(b"foo", b"baz").unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"baz".to_vec()));
+        let err = store.compact().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        drop(store);
+
+        // the same files, opened the usual way
+        let mut store = ActionKV::open(Path::new("test_handles")).unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"baz".to_vec()));
+        drop(store);
+
+        let read_only = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let mut store = ActionKV::open_files(
+            File::open("test_handles/data").unwrap(),
+            File::open("test_handles/index").unwrap(),
+            read_only,
+        )
+        .unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"baz".to_vec()));
+        fs::remove_dir_all("test_handles").unwrap();
+    }
+}
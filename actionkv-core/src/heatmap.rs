@@ -0,0 +1,232 @@
+use crate::index_codec::{decode_entries, encode_entries};
+use crate::rng::Rng;
+use crate::{is_internal_key, ActionKV, ByteStr, ByteString};
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+const HEATMAP_KEY: &ByteStr = b"+heatmap";
+
+/// Settings of the sampling access tracker behind `Stats::hot_prefixes`.
+#[derive(Debug, Clone)]
+pub struct AccessTracking {
+    /// Fraction of accesses that get counted, 1.0 counts all of them.
+    pub sample_rate: f64,
+    /// A key's prefix ends right after the first occurrence of this byte...
+    pub delimiter: Option<u8>,
+    /// ...or after this many bytes, whichever comes first.
+    pub max_prefix_len: usize,
+    /// Counts start over every window, the finished window is persisted in the store.
+    pub window: Duration,
+    /// How many prefixes are reported.
+    pub top: usize,
+}
+
+impl Default for AccessTracking {
+    fn default() -> Self {
+        AccessTracking {
+            sample_rate: 0.01,
+            delimiter: Some(b':'),
+            max_prefix_len: 16,
+            window: Duration::from_secs(60),
+            top: 10,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AccessTracker {
+    config: AccessTracking,
+    window_start: Duration,
+    counts: HashMap<ByteString, u64>,
+    rng: Rng,
+}
+
+impl AccessTracker {
+    pub fn new(config: AccessTracking, now: Duration) -> Self {
+        AccessTracker {
+            config,
+            window_start: now,
+            counts: HashMap::new(),
+            rng: Rng::new(),
+        }
+    }
+    fn prefix<'a>(&self, key: &'a ByteStr) -> &'a ByteStr {
+        let mut end = key.len().min(self.config.max_prefix_len);
+        if let Some(delimiter) = self.config.delimiter {
+            if let Some(position) = key[..end].iter().position(|byte| *byte == delimiter) {
+                end = position + 1;
+            }
+        }
+        &key[..end]
+    }
+    /// Counts a sampled access to `key`, returning the hottest prefixes of the
+    /// previous window when this access started a new one.
+    pub fn record(&mut self, key: &ByteStr, now: Duration) -> Option<Vec<(ByteString, u64)>> {
+        let finished = self.roll(now);
+        if self.rng.next_f64() < self.config.sample_rate {
+            *self.counts.entry(self.prefix(key).to_vec()).or_insert(0) += 1;
+        }
+        finished
+    }
+    /// Starts a new window if the current one is over, returning the hottest
+    /// prefixes of the one that finished if anything was counted in it.
+    pub fn roll(&mut self, now: Duration) -> Option<Vec<(ByteString, u64)>> {
+        let mut finished = None;
+        if now.saturating_sub(self.window_start) >= self.config.window {
+            if !self.counts.is_empty() {
+                finished = Some(self.hottest());
+                self.counts.clear();
+            }
+            self.window_start = now;
+        }
+        finished
+    }
+    pub fn counts(&self) -> &HashMap<ByteString, u64> {
+        &self.counts
+    }
+    /// Estimated accesses per prefix in the current window, hottest first.
+    pub fn hottest(&self) -> Vec<(ByteString, u64)> {
+        let mut hottest: Vec<(ByteString, u64)> = self
+            .counts
+            .iter()
+            .map(|(prefix, count)| {
+                let estimate = (*count as f64 / self.config.sample_rate).round() as u64;
+                (prefix.clone(), estimate)
+            })
+            .collect();
+        hottest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        hottest.truncate(self.config.top);
+        hottest
+    }
+}
+
+impl ActionKV {
+    pub(crate) fn track_access(&mut self, key: &ByteStr) -> io::Result<()> {
+        if is_internal_key(key) {
+            return Ok(());
+        }
+        let now = self.clock.now();
+        let finished = match &mut self.access_tracker {
+            Some(tracker) => tracker.record(key, now),
+            None => None,
+        };
+        self.persist_window(finished)
+    }
+    /// Ends the tracking window if it is over, without waiting for the next
+    /// access to notice.
+    pub(crate) fn roll_access_window(&mut self) -> io::Result<()> {
+        let now = self.clock.now();
+        let finished = match &mut self.access_tracker {
+            Some(tracker) => tracker.roll(now),
+            None => None,
+        };
+        self.persist_window(finished)
+    }
+    fn persist_window(&mut self, finished: Option<Vec<(ByteString, u64)>>) -> io::Result<()> {
+        if let Some(window) = finished {
+            let encoded = encode_entries(
+                window
+                    .iter()
+                    .map(|(prefix, count)| (prefix.as_slice(), *count)),
+            );
+            self.put_internal(HEATMAP_KEY, &encoded)?;
+        }
+        Ok(())
+    }
+    /// Hottest prefixes of the last finished tracking window, as persisted by
+    /// whichever process last ran with `Options::access_tracking`.
+    pub fn recorded_hot_prefixes(&mut self) -> io::Result<Vec<(ByteString, u64)>> {
+        match self.get(HEATMAP_KEY)? {
+            Some(encoded) => decode_entries(&encoded),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::{ManualClock, Options};
+    use rstest::*;
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    fn tracking(clock: Arc<ManualClock>) -> Options {
+        Options {
+            access_tracking: Some(AccessTracking {
+                sample_rate: 1.0,
+                window: Duration::from_secs(60),
+                ..AccessTracking::default()
+            }),
+            clock: Some(clock),
+            ..Options::default()
+        }
+    }
+    #[rstest]
+    fn test_prefix_extraction() {
+        let tracking = AccessTracking {
+            max_prefix_len: 4,
+            ..AccessTracking::default()
+        };
+        let tracker = AccessTracker::new(tracking, Duration::ZERO);
+        assert_eq!(tracker.prefix(b"ab:cd"), b"ab:");
+        assert_eq!(tracker.prefix(b"abcdef:1"), b"abcd");
+        assert_eq!(tracker.prefix(b"a"), b"a");
+    }
+    #[rstest]
+    #[serial]
+    fn test_hot_prefixes() {
+        let clock = Arc::new(ManualClock::default());
+        let mut ctx = TestCtx::setup_with_options("test_heatmap", tracking(clock));
+        let store = &mut ctx.test_file;
+        store.insert(b"user:1", b"ann").unwrap();
+        store.insert(b"user:2", b"bob").unwrap();
+        store.get(b"user:1").unwrap();
+        store.insert(b"order:1", b"x").unwrap();
+        assert_eq!(
+            store.stats().hot_prefixes,
+            vec![(b"user:".to_vec(), 3), (b"order:".to_vec(), 1)]
+        );
+    }
+    #[rstest]
+    #[serial]
+    fn test_finished_window_is_persisted() {
+        let clock = Arc::new(ManualClock::default());
+        let mut ctx = TestCtx::setup_with_options("test_heatmap", tracking(clock.clone()));
+        let store = &mut ctx.test_file;
+        store.insert(b"user:1", b"ann").unwrap();
+        store.insert(b"user:2", b"bob").unwrap();
+        clock.advance(Duration::from_secs(59));
+        store.get(b"user:1").unwrap();
+        assert_eq!(store.recorded_hot_prefixes().unwrap(), vec![]);
+
+        clock.advance(Duration::from_secs(1));
+        store.get(b"user:1").unwrap();
+        assert_eq!(store.stats().hot_prefixes, vec![(b"user:".to_vec(), 1)]);
+        let reader = ctx.reopen().unwrap();
+        assert_eq!(
+            reader.recorded_hot_prefixes().unwrap(),
+            vec![(b"user:".to_vec(), 3)]
+        );
+    }
+    #[rstest]
+    #[serial]
+    fn test_tick_ends_window() {
+        let clock = Arc::new(ManualClock::default());
+        let mut ctx = TestCtx::setup_with_options("test_heatmap", tracking(clock.clone()));
+        let store = &mut ctx.test_file;
+        store.insert(b"user:1", b"ann").unwrap();
+        store.tick().unwrap();
+        assert_eq!(store.recorded_hot_prefixes().unwrap(), vec![]);
+
+        clock.advance(Duration::from_secs(60));
+        store.tick().unwrap();
+        assert_eq!(store.stats().hot_prefixes, vec![]);
+        assert_eq!(
+            store.recorded_hot_prefixes().unwrap(),
+            vec![(b"user:".to_vec(), 1)]
+        );
+    }
+}
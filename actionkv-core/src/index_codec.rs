@@ -0,0 +1,110 @@
+use crate::{ActionKvError, ByteStr, ByteString};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+
+/// Turns the key directory into the value of the `+index` record and back.
+/// Stores are written with one codec for their whole life, opening a store
+/// with a different one than it was created with fails to decode its index.
+pub trait IndexCodec: Debug + Send + Sync {
+    fn encode(&self, index: &HashMap<ByteString, u64>) -> ByteString;
+    fn decode(&self, bytes: &ByteStr) -> io::Result<HashMap<ByteString, u64>>;
+}
+
+/*
+    FIXINT INDEX LAYOUT
+    entries | key_len | key | offset | key_len | key | offset | ...
+    [u64;1]   [u64;1]   [u8]  [u64;1]
+    all little endian, which is what bincode 1 made of a HashMap<Vec<u8>, u64>
+    before it was dropped as a dependency, so older stores still decode. The
+    layout is the same whatever the byte order of the machine writing it,
+    a change to it would come with a new version in the index file header.
+*/
+/// The default codec, see the layout above.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FixintCodec;
+
+impl IndexCodec for FixintCodec {
+    fn encode(&self, index: &HashMap<ByteString, u64>) -> ByteString {
+        encode_entries(index.iter().map(|(key, offset)| (key.as_slice(), *offset)))
+    }
+    fn decode(&self, bytes: &ByteStr) -> io::Result<HashMap<ByteString, u64>> {
+        Ok(decode_entries(bytes)?.into_iter().collect())
+    }
+}
+
+/// `(bytes, u64)` pairs in the fixint layout.
+pub(crate) fn encode_entries<'a, I>(entries: I) -> ByteString
+where
+    I: ExactSizeIterator<Item = (&'a ByteStr, u64)>,
+{
+    let mut buf = ByteString::new();
+    // writes into a Vec can't fail
+    let _ = buf.write_u64::<LittleEndian>(entries.len() as u64);
+    for (key, value) in entries {
+        let _ = buf.write_u64::<LittleEndian>(key.len() as u64);
+        buf.extend(key);
+        let _ = buf.write_u64::<LittleEndian>(value);
+    }
+    buf
+}
+
+/// Inverse of `encode_entries`, bytes after the last entry are ignored.
+pub(crate) fn decode_entries(mut bytes: &ByteStr) -> io::Result<Vec<(ByteString, u64)>> {
+    let invalid = |reason: &str| -> io::Error {
+        ActionKvError::Serialization {
+            what: "index",
+            reason: reason.to_string(),
+        }
+        .into()
+    };
+    let count = bytes.read_u64::<LittleEndian>()?;
+    // every entry takes at least 16 bytes, a larger count is corrupt and
+    // mustn't be allocated for
+    if count > bytes.len() as u64 / 16 {
+        return Err(invalid("entry count exceeds the encoded length"));
+    }
+    let mut entries = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let key_len = bytes.read_u64::<LittleEndian>()?;
+        if key_len > bytes.len() as u64 {
+            return Err(invalid("key length exceeds the encoded length"));
+        }
+        let (key, rest) = bytes.split_at(key_len as usize);
+        bytes = rest;
+        let value = bytes.read_u64::<LittleEndian>()?;
+        entries.push((key.to_vec(), value));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    fn test_fixint_round_trip() {
+        let index: HashMap<ByteString, u64> =
+            [(b"foo".to_vec(), 36), (b"".to_vec(), u64::MAX)].into();
+        let encoded = FixintCodec.encode(&index);
+        assert_eq!(FixintCodec.decode(&encoded).unwrap(), index);
+        assert_eq!(encoded.len(), 8 + (8 + 3 + 8) + (8 + 8));
+    }
+    #[rstest]
+    fn test_fixint_layout() {
+        let index: HashMap<ByteString, u64> = [(b"ab".to_vec(), 7)].into();
+        let mut expected = vec![1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0];
+        expected.extend(b"ab");
+        expected.extend([7, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(FixintCodec.encode(&index), expected);
+    }
+    #[rstest]
+    #[case(&[])]
+    #[case(&[0xff; 8])]
+    #[case(&[1, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0])]
+    fn test_fixint_rejects_garbage(#[case] bytes: &[u8]) {
+        assert!(FixintCodec.decode(bytes).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use crate::format::Framing;
+use crate::inline::INLINE_KEY;
+use crate::key_hash::murmur3_128;
+use crate::{write_record, ActionKV, ActionKvError, ByteStr, ByteString, INDEX_KEY};
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+/*
+    INDEX DIGESTS
+    with Options::index_digests every saved index is followed in the index
+    file by a +digest record holding the number of keys it has and the
+    wrapping sum of a hash of every key and its offset:
+    keys  | hash
+    [u64]   [u64]    little endian
+    The sum doesn't depend on the order of the entries. Loading the index
+    checks it against the digest, and opening the store checks the digest
+    against a replay of the data file up to where the index was saved, so a
+    bug in saving or loading the index fails loudly instead of answering
+    wrong. Index files without the record aren't checked.
+*/
+pub(crate) const DIGEST_KEY: &ByteStr = b"+digest";
+const DIGEST_LEN: usize = 16;
+
+/// Key count and order independent hash of an index, see
+/// `Options::index_digests`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexDigest {
+    pub keys: u64,
+    pub hash: u64,
+}
+
+impl IndexDigest {
+    /// Digest of `index`, leaving out the entry of the saved index itself.
+    pub fn of(index: &HashMap<ByteString, u64>) -> Self {
+        let mut digest = IndexDigest::default();
+        for (key, position) in index {
+            if key.as_slice() != INDEX_KEY {
+                digest.add(key, *position);
+            }
+        }
+        digest
+    }
+    fn add(&mut self, key: &ByteStr, position: u64) {
+        let hash = murmur3_128(key);
+        let entry = LittleEndian::read_u64(&hash[..8])
+            ^ LittleEndian::read_u64(&hash[8..]).wrapping_mul(position | 1);
+        self.keys += 1;
+        self.hash = self.hash.wrapping_add(entry.wrapping_add(position));
+    }
+    pub(crate) fn encode(&self) -> [u8; DIGEST_LEN] {
+        let mut buf = [0; DIGEST_LEN];
+        LittleEndian::write_u64(&mut buf[..8], self.keys);
+        LittleEndian::write_u64(&mut buf[8..], self.hash);
+        buf
+    }
+    pub(crate) fn decode(bytes: &ByteStr) -> io::Result<Self> {
+        if bytes.len() != DIGEST_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("index digest is {} bytes long", bytes.len()),
+            ));
+        }
+        Ok(IndexDigest {
+            keys: LittleEndian::read_u64(&bytes[..8]),
+            hash: LittleEndian::read_u64(&bytes[8..]),
+        })
+    }
+}
+
+impl fmt::Display for IndexDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} keys, hash {:016x}", self.keys, self.hash)
+    }
+}
+
+impl ActionKV {
+    /// Digest saved after the index record at `index_position` whose value
+    /// is `value_len` bytes long, `None` if there is none or index deltas
+    /// follow it.
+    fn saved_digest(
+        &mut self,
+        index_position: u64,
+        value_len: usize,
+    ) -> io::Result<Option<IndexDigest>> {
+        let next = index_position
+            + Framing::default().record_len(INDEX_KEY.len() as u32, value_len as u64);
+        if next >= self.index_.metadata()?.len() {
+            return Ok(None);
+        }
+        let record = self.read_at(next, true, true)?;
+        if record.key != DIGEST_KEY {
+            return Ok(None);
+        }
+        // deltas appended after it, the digest only covers the index before them
+        let mut end =
+            next + Framing::default().record_len(DIGEST_KEY.len() as u32, DIGEST_LEN as u64);
+        let file_len = self.index_.metadata()?.len();
+        if end < file_len {
+            let inlined = self.read_at(end, true, true)?;
+            if inlined.key != INLINE_KEY {
+                return Ok(None);
+            }
+            end +=
+                Framing::default().record_len(INLINE_KEY.len() as u32, inlined.value.len() as u64);
+        }
+        if end < file_len {
+            return Ok(None);
+        }
+        IndexDigest::decode(&record.value).map(Some)
+    }
+    /// Checks the index just loaded from the record at `index_position`
+    /// against the digest saved along with it.
+    pub(crate) fn check_loaded_index(
+        &mut self,
+        index_position: u64,
+        value_len: usize,
+    ) -> io::Result<()> {
+        if !self.options.index_digests {
+            return Ok(());
+        }
+        let Some(saved) = self.saved_digest(index_position, value_len)? else {
+            return Ok(());
+        };
+        let loaded = IndexDigest::of(&self.index);
+        if loaded != saved {
+            return Err(ActionKvError::IndexDiverged {
+                saved,
+                found: loaded,
+                against: "the loaded index",
+            }
+            .into());
+        }
+        Ok(())
+    }
+    /// Before an opened store trusts its saved index, replays the sealed
+    /// segments and the data file up to `end`, where the index was saved, and
+    /// checks the result against the digest saved with the index.
+    pub(crate) fn check_saved_index(&mut self, end: u64) -> io::Result<()> {
+        if !self.options.index_digests || self.index_len <= self.index_start {
+            return Ok(());
+        }
+        let position = self.index_start;
+        let record = self.read_at(position, true, true)?;
+        let Some(saved) = self.saved_digest(position, record.value.len())? else {
+            return Ok(());
+        };
+        let mut replayed = HashMap::new();
+        self.replay_sealed(&mut replayed)?;
+        self.scan_into(self.data_start, end, &mut replayed)?;
+        let replayed = IndexDigest::of(&replayed);
+        if replayed != saved {
+            return Err(ActionKvError::IndexDiverged {
+                saved,
+                found: replayed,
+                against: "a replay of the data file",
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Index file bytes that make up the digest record of `index`.
+pub(crate) fn digest_record(index: &HashMap<ByteString, u64>) -> io::Result<ByteString> {
+    let mut buf = ByteString::new();
+    write_record(
+        &mut buf,
+        Framing::default(),
+        DIGEST_KEY,
+        &IndexDigest::of(index).encode(),
+    )?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::HEADER_LEN;
+    use crate::testing::TestCtx;
+    use crate::{IndexCheckpoints, IndexCodec, Options};
+    use rstest::*;
+    use serial_test::serial;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::Arc;
+
+    /// Drops the entry of one key when decoding, like a buggy codec would.
+    #[derive(Debug)]
+    struct LossyCodec;
+
+    impl IndexCodec for LossyCodec {
+        fn encode(&self, index: &HashMap<ByteString, u64>) -> ByteString {
+            crate::FixintCodec.encode(index)
+        }
+        fn decode(&self, bytes: &ByteStr) -> io::Result<HashMap<ByteString, u64>> {
+            let mut index = crate::FixintCodec.decode(bytes)?;
+            index.remove(b"lost".as_slice());
+            Ok(index)
+        }
+    }
+
+    fn diverged(err: &io::Error) -> Option<&'static str> {
+        match ActionKvError::from_io(err) {
+            Some(ActionKvError::IndexDiverged { against, .. }) => Some(against),
+            _ => None,
+        }
+    }
+
+    #[rstest]
+    #[serial]
+    fn test_index_digests() {
+        let options = Options {
+            index_digests: true,
+            index_checkpoints: Some(IndexCheckpoints {
+                records: 3,
+                bytes: u64::MAX,
+            }),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_index_digest", options.clone());
+        let store = &mut ctx.test_file;
+        for i in 0..10 {
+            store
+                .insert(format!("key:{}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        store.delete(b"key:3").unwrap();
+        store.compact().unwrap();
+        store.insert(b"lost", b"value").unwrap();
+        store.insert(b"after", b"compaction").unwrap();
+        store.flush_index().unwrap();
+        // replayed past the checkpoint on open
+        store.insert(b"unsaved", b"value").unwrap();
+        let store = ctx.crash_and_reopen(options.clone()).unwrap();
+        store.read_index().unwrap();
+        assert_eq!(store.get(b"unsaved").unwrap(), Some(b"value".to_vec()));
+
+        let store = ctx
+            .crash_and_reopen(Options {
+                index_codec: Some(Arc::new(LossyCodec)),
+                ..options.clone()
+            })
+            .unwrap();
+        let err = store.read_index().unwrap_err();
+        assert_eq!(diverged(&err), Some("the loaded index"));
+
+        // an index pointing at the wrong record, saved with a matching digest
+        let store = ctx.crash_and_reopen(options.clone()).unwrap();
+        store.read_index().unwrap();
+        let position = store.index[b"after".as_slice()];
+        store.index.insert(b"after".to_vec(), position + 1);
+        let mut buf = ByteString::new();
+        let index_as_bytes = crate::FixintCodec.encode(&store.index);
+        write_record(&mut buf, Framing::default(), INDEX_KEY, &index_as_bytes).unwrap();
+        buf.extend(digest_record(&store.index).unwrap());
+        let mut f = OpenOptions::new()
+            .write(true)
+            .open("test_index_digest/index")
+            .unwrap();
+        f.seek(SeekFrom::Start(HEADER_LEN)).unwrap();
+        f.write_all(&buf).unwrap();
+        let err = ctx.crash_and_reopen(options).unwrap_err();
+        assert_eq!(diverged(&err), Some("a replay of the data file"));
+        // nothing is checked without the option
+        assert!(ctx.crash_and_reopen(Options::default()).is_ok());
+    }
+}
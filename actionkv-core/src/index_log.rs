@@ -0,0 +1,338 @@
+use crate::format::{FileHeader, Framing, INDEX_MAGIC};
+use crate::index_digest::DIGEST_KEY;
+use crate::inline::INLINE_KEY;
+use crate::INDEX_KEY;
+use crate::{
+    is_corruption, write_record, ActionKV, ActionKvError, ByteStr, ByteString, IoOp, OpClass,
+    StoreFile,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufReader, Seek, SeekFrom, Write};
+
+/*
+    INDEX DELTAS
+    rewriting the whole index after every write costs as much as the index
+    is large. Instead a save appends the entries changed since the last one
+    to the index file, as a +delta record right after the index (and its
+    digest and inlined values), then rewrites the header with the new
+    data_len:
+    base  | data_len | key_len | key | position | key_len | key | position ...
+    [u32]   [u64]      [u32]     [u8]  [u64]      little endian
+    base is the crc32 of the encoded index the delta follows, so deltas an
+    index rewritten in place left behind it aren't taken for its own, and a
+    removed key has position u64::MAX. Loading the index applies the deltas
+    in order, up to the first one that is torn, follows another index or was
+    written against more data than the header says is committed, which a
+    crash between the two writes leaves behind and the writer cuts off. Once
+    the deltas take as many bytes as the index they follow, the next save
+    rewrites it whole.
+*/
+pub(crate) const DELTA_KEY: &ByteStr = b"+delta";
+const REMOVED: u64 = u64::MAX;
+
+/// How the in-memory index differs from the one in the index file.
+#[derive(Debug, Default)]
+pub(crate) struct IndexLog {
+    /// Keys whose entry changed since the index file last caught up, `None`
+    /// when that isn't known and the next save has to rewrite it whole.
+    changed: Option<HashSet<ByteString>>,
+    /// `Delta::base` of the saved index.
+    base: u32,
+    /// Bytes of the index file taken by the saved index, and after it.
+    base_len: u64,
+    deltas_len: u64,
+}
+
+impl IndexLog {
+    /// The index file holds the in-memory index, in `base_len` bytes of the
+    /// index record and what was saved along with it, followed by
+    /// `deltas_len` bytes of deltas.
+    pub(crate) fn in_sync(base: u32, base_len: u64, deltas_len: u64) -> Self {
+        IndexLog {
+            changed: Some(HashSet::new()),
+            base,
+            base_len,
+            deltas_len,
+        }
+    }
+    pub(crate) fn changed(&mut self, key: &ByteStr) {
+        if let Some(changed) = &mut self.changed {
+            changed.insert(key.to_vec());
+        }
+    }
+}
+
+/// Entries of the index written against `data_len` bytes of data, `None`
+/// for keys that were removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Delta {
+    /// Checksum of the encoded index this delta follows, see `Delta::base_of`.
+    pub base: u32,
+    pub data_len: u64,
+    pub entries: Vec<(ByteString, Option<u64>)>,
+}
+
+impl Delta {
+    pub(crate) fn base_of(index_as_bytes: &ByteStr) -> u32 {
+        crc32::checksum_ieee(index_as_bytes)
+    }
+    fn encode(&self) -> ByteString {
+        let mut buf = self.base.to_le_bytes().to_vec();
+        buf.extend(self.data_len.to_le_bytes());
+        for (key, position) in &self.entries {
+            buf.extend((key.len() as u32).to_le_bytes());
+            buf.extend(key);
+            buf.extend(position.unwrap_or(REMOVED).to_le_bytes());
+        }
+        buf
+    }
+    pub(crate) fn decode(bytes: &ByteStr) -> io::Result<Self> {
+        let malformed = || -> io::Error {
+            ActionKvError::Serialization {
+                what: "index delta",
+                reason: "an entry is cut short".to_string(),
+            }
+            .into()
+        };
+        let (base, rest) = bytes.split_at_checked(4).ok_or_else(malformed)?;
+        let (data_len, mut rest) = rest.split_at_checked(8).ok_or_else(malformed)?;
+        let mut entries = Vec::new();
+        while !rest.is_empty() {
+            let (key_len, after) = rest.split_at_checked(4).ok_or_else(malformed)?;
+            let key_len = LittleEndian::read_u32(key_len) as usize;
+            let (key, after) = after.split_at_checked(key_len).ok_or_else(malformed)?;
+            let (position, after) = after.split_at_checked(8).ok_or_else(malformed)?;
+            let position = match LittleEndian::read_u64(position) {
+                REMOVED => None,
+                position => Some(position),
+            };
+            entries.push((key.to_vec(), position));
+            rest = after;
+        }
+        Ok(Delta {
+            base: LittleEndian::read_u32(base),
+            data_len: LittleEndian::read_u64(data_len),
+            entries,
+        })
+    }
+    pub(crate) fn apply(self, index: &mut HashMap<ByteString, u64>) {
+        for (key, position) in self.entries {
+            match position {
+                Some(position) => index.insert(key, position),
+                None => index.remove(&key),
+            };
+        }
+    }
+}
+
+impl ActionKV {
+    /// Saves the index, appending what changed since it was last saved when
+    /// that is cheaper than rewriting it. Stores with `Options::index_digests`
+    /// or without file headers always rewrite it.
+    pub(crate) fn save_index(&mut self) -> io::Result<()> {
+        let log = &self.index_log;
+        let appendable = log.changed.is_some() && log.deltas_len < log.base_len;
+        match self.generation {
+            Some(generation) if appendable && !self.options.index_digests => {
+                self.append_index_delta(FileHeader::new(INDEX_MAGIC, generation))
+            }
+            _ => self.store_index_on_disk(INDEX_KEY),
+        }
+    }
+    fn append_index_delta(&mut self, mut header: FileHeader) -> io::Result<()> {
+        self.io_stats.record_operation(OpClass::Index, 0);
+        let tail = self.appender.tail();
+        let entries = self
+            .index_log
+            .changed
+            .iter()
+            .flatten()
+            .map(|key| (key.clone(), self.index.get(key).copied()))
+            .collect();
+        let delta = Delta {
+            base: self.index_log.base,
+            data_len: tail,
+            entries,
+        };
+        let mut buf = ByteString::new();
+        write_record(&mut buf, Framing::default(), DELTA_KEY, &delta.encode())?;
+        buf.extend(self.inline_record(false)?.unwrap_or_default());
+        self.torn_window_opened()?;
+        self.writable()?;
+        let end = self.index_len;
+        self.retrying(|store| {
+            store.inject(IoOp::Write, StoreFile::Index)?;
+            store.index_.seek(SeekFrom::Start(end))?;
+            if let Err(err) = store.index_.write_all(&buf) {
+                // a partial delta would hide the ones after it
+                store.index_.set_len(end)?;
+                return Err(err);
+            }
+            Ok(())
+        })?;
+        self.index_len += buf.len() as u64;
+        self.index_log.deltas_len += buf.len() as u64;
+        // a crash before this leaves the delta past what is committed, so
+        // it is cut off on load together with the records it points at
+        header.data_len = tail;
+        self.retrying(|store| {
+            store.inject(IoOp::Write, StoreFile::Index)?;
+            header.write_to(&mut store.index_)
+        })?;
+        self.io_stats.record_physical(
+            OpClass::Index,
+            buf.len() as u64 + header.encode().len() as u64,
+        );
+        self.index_log.changed = Some(HashSet::new());
+        self.inline_saved();
+        self.torn_window_closed();
+        self.unsaved_records = 0;
+        self.saved_tail = tail;
+        Ok(())
+    }
+    /// Applies the deltas following the index record at `position`, which
+    /// holds `index_as_bytes`, to the index just loaded from it. The writer
+    /// cuts off deltas that can't be applied. Read only stores apply the ones
+    /// past what they saw committed too, like they see a whole index saved
+    /// since.
+    pub(crate) fn load_index_deltas(
+        &mut self,
+        position: u64,
+        index_as_bytes: &ByteStr,
+    ) -> io::Result<()> {
+        self.inject(IoOp::Read, StoreFile::Index)?;
+        let start = position
+            + Framing::default().record_len(INDEX_KEY.len() as u32, index_as_bytes.len() as u64);
+        let base = Delta::base_of(index_as_bytes);
+        let tail = match self.options.read_only {
+            true => u64::MAX,
+            false => self.appender.tail(),
+        };
+        self.reset_inline();
+        let mut f = BufReader::new(&mut self.index_);
+        let mut end = f.seek(SeekFrom::Start(start))?;
+        // the digest and inlined values saved along with the index
+        let mut deltas_start = None;
+        let mut inlined = Vec::new();
+        while end < self.index_len {
+            let record = match ActionKV::process_records(&mut f, Framing::default()) {
+                Ok(record) => record,
+                // torn by a crash, or caught while the writer appends it
+                Err(err) if is_corruption(&err) => break,
+                Err(err) => return Err(err),
+            };
+            if record.key == DELTA_KEY {
+                let delta = Delta::decode(&record.value)?;
+                if delta.base != base || delta.data_len > tail {
+                    break;
+                }
+                delta.apply(&mut self.index);
+                deltas_start.get_or_insert(end);
+            } else if record.key == INLINE_KEY {
+                inlined.push(record.value);
+            } else if record.key != DIGEST_KEY {
+                break;
+            }
+            end = f.stream_position()?;
+        }
+        for record in inlined {
+            self.load_inline(&record)?;
+        }
+        if self.options.read_only {
+            return Ok(());
+        }
+        if end < self.index_len {
+            self.index_.set_len(end)?;
+            self.index_len = end;
+        }
+        let deltas_start = deltas_start.unwrap_or(end);
+        self.index_log = IndexLog::in_sync(base, deltas_start - position, end - deltas_start);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::Options;
+    use rstest::*;
+    use serial_test::serial;
+    use std::path::Path;
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let delta = Delta {
+            base: 7,
+            data_len: 1234,
+            entries: vec![(b"foo".to_vec(), Some(36)), (b"bar".to_vec(), None)],
+        };
+        assert_eq!(Delta::decode(&delta.encode()).unwrap(), delta);
+        assert!(Delta::decode(&delta.encode()[..20]).is_err());
+        assert!(Delta::decode(b"short").is_err());
+    }
+    #[rstest]
+    #[serial]
+    fn test_index_deltas() {
+        let path = Path::new("test_index_log");
+        let mut ctx = TestCtx::setup("test_index_log");
+        let store = &mut ctx.test_file;
+        let pairs = (0..20).map(|i| (format!("key:{:02}", i).into_bytes(), b"value".to_vec()));
+        store.bulk_load(pairs).unwrap();
+        let index_len = store.index_len;
+        store.insert(b"key:00", b"changed").unwrap();
+        store.delete(b"key:01").unwrap();
+        // two small deltas were appended instead of rewriting the index
+        let delta_len = store.index_len - index_len;
+        assert!(delta_len > 0);
+        assert!(delta_len < index_len / 2);
+        assert_eq!(store.stats().io.index.operations, 3);
+
+        let read_only = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let mut reopened = ActionKV::open_with_options(path, read_only.clone()).unwrap();
+        assert_eq!(reopened.get(b"key:00").unwrap(), Some(b"changed".to_vec()));
+        assert_eq!(reopened.get(b"key:01").unwrap(), None);
+        assert_eq!(reopened.get(b"key:19").unwrap(), Some(b"value".to_vec()));
+        drop(reopened);
+
+        // deltas are folded back into the index once they outgrow it
+        for _ in 0..100 {
+            store.insert(b"key:02", b"again").unwrap();
+        }
+        assert!(store.index_len < 2 * index_len + delta_len);
+        let mut reopened = ActionKV::open_with_options(path, read_only).unwrap();
+        assert_eq!(reopened.get(b"key:02").unwrap(), Some(b"again".to_vec()));
+        store.read_index().unwrap();
+        assert_eq!(reopened.index, store.index);
+    }
+    #[rstest]
+    #[serial]
+    fn test_uncommitted_delta_is_cut_off() {
+        let path = Path::new("test_index_log_crash");
+        let mut ctx = TestCtx::setup("test_index_log_crash");
+        let store = &mut ctx.test_file;
+        let pairs = (0..10).map(|i| (format!("key:{}", i).into_bytes(), b"value".to_vec()));
+        store.bulk_load(pairs).unwrap();
+        store.insert(b"foo", b"1").unwrap();
+        store.insert(b"bar", b"2").unwrap();
+        let header = std::fs::read(path.join("index")).unwrap()[..36].to_vec();
+        store.insert(b"foo", b"3").unwrap();
+        // the delta made it but the header rewrite didn't
+        let mut index = std::fs::read(path.join("index")).unwrap();
+        index[..36].copy_from_slice(&header);
+        std::fs::write(path.join("index"), &index).unwrap();
+
+        let reopened = ctx.crash_and_reopen(Options::default()).unwrap();
+        assert_eq!(reopened.get(b"foo").unwrap(), Some(b"1".to_vec()));
+        assert!(reopened.index_len < index.len() as u64);
+        reopened.insert(b"baz", b"4").unwrap();
+        let reopened = ctx.reopen().unwrap();
+        assert_eq!(reopened.get(b"foo").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(reopened.get(b"baz").unwrap(), Some(b"4".to_vec()));
+    }
+}
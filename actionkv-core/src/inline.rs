@@ -0,0 +1,323 @@
+use crate::format::Framing;
+use crate::{is_internal_key, write_record, ActionKV, ActionKvError, ByteStr, ByteString};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+/*
+    INLINE VALUES
+    with Options::inline_values, values up to that many bytes are kept in
+    memory next to the index along with the position of the record they came
+    from, and reads serve them from there as long as the index still points
+    at that record. They are saved in the index file as +inline records, so a
+    reopened store has them too: a full save writes all of them after the
+    index, a save appending a +delta only the ones that changed since, right
+    after it:
+    count | key_len | key | position | value_len | value | ...
+    [u64]   [u32]     [u8]  [u64]      [u32]       [u8]     all little endian
+    value_len u32::MAX drops the key's entry. Loading the index applies them
+    in order along with the deltas. An entry whose record the index no longer
+    points at, e.g. one saved before a crash and replayed over, is never
+    served. Stores written before kept them in a +inline record of the data
+    file, which is left for compaction to drop.
+*/
+pub(crate) const INLINE_KEY: &ByteStr = b"+inline";
+const REMOVED: u32 = u32::MAX;
+
+/// The inlined values by key, loaded along with the index.
+#[derive(Debug, Default)]
+pub(crate) struct InlineValues {
+    entries: HashMap<ByteString, (u64, ByteString)>,
+    /// Keys whose entry changed since the inlined values were last saved.
+    changed: HashSet<ByteString>,
+}
+
+impl InlineValues {
+    pub fn entries(&self) -> &HashMap<ByteString, (u64, ByteString)> {
+        &self.entries
+    }
+    /// The entries of `keys`, the ones without an entry as dropped.
+    fn encode<'k, I>(&self, keys: I) -> ByteString
+    where
+        I: ExactSizeIterator<Item = &'k ByteString>,
+    {
+        let mut buf = ByteString::new();
+        // writes into a Vec can't fail
+        let _ = buf.write_u64::<LittleEndian>(keys.len() as u64);
+        for key in keys {
+            let _ = buf.write_u32::<LittleEndian>(key.len() as u32);
+            buf.extend(key);
+            match self.entries.get(key) {
+                Some((position, value)) => {
+                    let _ = buf.write_u64::<LittleEndian>(*position);
+                    let _ = buf.write_u32::<LittleEndian>(value.len() as u32);
+                    buf.extend(value);
+                }
+                None => {
+                    let _ = buf.write_u64::<LittleEndian>(0);
+                    let _ = buf.write_u32::<LittleEndian>(REMOVED);
+                }
+            }
+        }
+        buf
+    }
+    /// Applies the entries of a `+inline` record.
+    fn apply(&mut self, mut bytes: &ByteStr) -> io::Result<()> {
+        let take = |len: u32, bytes: &mut &ByteStr| -> io::Result<ByteString> {
+            if len as usize > bytes.len() {
+                return Err(ActionKvError::Serialization {
+                    what: "inline values",
+                    reason: "an entry is cut short".to_string(),
+                }
+                .into());
+            }
+            let (taken, rest) = bytes.split_at(len as usize);
+            *bytes = rest;
+            Ok(taken.to_vec())
+        };
+        let count = bytes.read_u64::<LittleEndian>()?;
+        for _ in 0..count {
+            let key_len = bytes.read_u32::<LittleEndian>()?;
+            let key = take(key_len, &mut bytes)?;
+            let position = bytes.read_u64::<LittleEndian>()?;
+            match bytes.read_u32::<LittleEndian>()? {
+                REMOVED => {
+                    self.entries.remove(&key);
+                }
+                value_len => {
+                    let value = take(value_len, &mut bytes)?;
+                    self.entries.insert(key, (position, value));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ActionKV {
+    fn inlines(&self, key: &ByteStr, value: &ByteStr) -> bool {
+        match self.options.inline_values {
+            Some(max_len) => {
+                !value.is_empty() && value.len() <= max_len as usize && !is_internal_key(key)
+            }
+            None => false,
+        }
+    }
+    /// Inlined value of `key`, if it is the one of the record at `position`.
+    pub(crate) fn inline_value(
+        &mut self,
+        key: &ByteStr,
+        position: u64,
+    ) -> io::Result<Option<ByteString>> {
+        if self.options.inline_values.is_none() || is_internal_key(key) {
+            return Ok(None);
+        }
+        Ok(match self.inline.entries.get(key) {
+            Some((inlined_at, value)) if *inlined_at == position => Some(value.clone()),
+            _ => None,
+        })
+    }
+    /// Keeps `value`, the value of `key` in the record at `position`, in
+    /// memory if it is small enough, else forgets what was kept for `key`.
+    pub(crate) fn inline_put(&mut self, key: &ByteStr, position: u64, value: &ByteStr) {
+        if !self.inlines(key, value) {
+            return self.inline_remove(key);
+        }
+        match self.inline.entries.get(key) {
+            Some((inlined_at, inlined)) if *inlined_at == position && inlined == value => {}
+            _ => {
+                self.inline
+                    .entries
+                    .insert(key.to_vec(), (position, value.to_vec()));
+                // read only stores can't save them
+                if !self.options.read_only {
+                    self.inline.changed.insert(key.to_vec());
+                }
+            }
+        }
+    }
+    pub(crate) fn inline_remove(&mut self, key: &ByteStr) {
+        if self.inline.entries.remove(key).is_some() && !self.options.read_only {
+            self.inline.changed.insert(key.to_vec());
+        }
+    }
+    /// Forgets the entries whose record the index no longer points at, and
+    /// drops the `+inline` record older stores kept in the data file from the
+    /// index.
+    pub(crate) fn prune_inline(&mut self) {
+        let stale: Vec<ByteString> = self
+            .inline
+            .entries
+            .iter()
+            .filter(|(key, (position, _))| self.position(key) != Some(*position))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            self.inline_remove(&key);
+        }
+        if self.index.remove(INLINE_KEY).is_some() {
+            self.index_log.changed(INLINE_KEY);
+        }
+    }
+    /// The `+inline` record to save after the index, holding every entry if
+    /// `full`, else the ones that changed since the last save. `None` if
+    /// there is nothing to save. `inline_saved` has to follow once written.
+    pub(crate) fn inline_record(&self, full: bool) -> io::Result<Option<ByteString>> {
+        if self.options.inline_values.is_none() || self.options.read_only {
+            return Ok(None);
+        }
+        let value = match full {
+            true if self.inline.entries.is_empty() => return Ok(None),
+            true => self.inline.encode(self.inline.entries.keys()),
+            false if self.inline.changed.is_empty() => return Ok(None),
+            false => self.inline.encode(self.inline.changed.iter()),
+        };
+        let mut record = ByteString::new();
+        write_record(&mut record, Framing::default(), INLINE_KEY, &value)?;
+        Ok(Some(record))
+    }
+    pub(crate) fn inline_saved(&mut self) {
+        self.inline.changed.clear();
+    }
+    /// Starts over from the `+inline` records of the index file as it is
+    /// loaded, see `load_inline`.
+    pub(crate) fn reset_inline(&mut self) {
+        self.inline.entries.clear();
+    }
+    /// Applies a `+inline` record read from the index file.
+    pub(crate) fn load_inline(&mut self, record: &ByteStr) -> io::Result<()> {
+        self.inline.apply(record)
+    }
+    /// Hands the inlined values to a compaction about to move their
+    /// records, which puts back the ones it kept with `inline_moved`.
+    pub(crate) fn inline_take(&mut self) -> HashMap<ByteString, (u64, ByteString)> {
+        std::mem::take(&mut self.inline.entries)
+    }
+    /// Puts back the inlined value `taken` held for `key` if it was the one
+    /// of the record compaction moved from `from` to `to`.
+    pub(crate) fn inline_moved(
+        &mut self,
+        taken: &mut HashMap<ByteString, (u64, ByteString)>,
+        key: &ByteStr,
+        from: u64,
+        to: u64,
+    ) {
+        if let Some((position, value)) = taken.remove(key) {
+            if position == from {
+                self.inline.entries.insert(key.to_vec(), (to, value));
+            }
+        }
+    }
+    /// Follows the records of the inlined values to the positions `moved`
+    /// gives them, forgetting the ones it has none for.
+    pub(crate) fn inline_renumbered<F>(&mut self, moved: F)
+    where
+        F: Fn(u64) -> Option<u64>,
+    {
+        for (key, (position, value)) in self.inline_take() {
+            match moved(position) {
+                Some(to) if to == position => {
+                    self.inline.entries.insert(key, (to, value));
+                }
+                Some(to) => {
+                    self.inline.entries.insert(key.clone(), (to, value));
+                    self.inline.changed.insert(key);
+                }
+                None => {
+                    self.inline.changed.insert(key);
+                }
+            }
+        }
+    }
+    pub(crate) fn inline_dirty(&self) -> bool {
+        !self.inline.changed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault::{Fault, FaultInjector};
+    use crate::testing::TestCtx;
+    use crate::{IoOp, Options, StoreFile};
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[serial]
+    fn test_inline_values() {
+        let faults = FaultInjector::new();
+        let options = Options {
+            inline_values: Some(8),
+            faults: Some(faults.clone()),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_inline", options.clone());
+        let store = &mut ctx.test_file;
+        store.insert(b"flag", b"on").unwrap();
+        store.insert(b"big", b"more than eight").unwrap();
+        store.insert(b"gone", b"x").unwrap();
+        store.delete(b"gone").unwrap();
+        store.flush_index().unwrap();
+
+        // reads of tiny values don't touch the data file
+        let unreadable = Fault {
+            file: Some(StoreFile::Data),
+            times: None,
+            ..Fault::new(IoOp::Read, io::ErrorKind::Other)
+        };
+        faults.arm(unreadable.clone());
+        assert_eq!(store.get(b"flag").unwrap(), Some(b"on".to_vec()));
+        assert_eq!(store.get(b"gone").unwrap(), None);
+        assert!(store.get(b"big").is_err());
+        faults.clear();
+
+        store.insert(b"flag", b"off").unwrap();
+        store.compact().unwrap();
+        faults.arm(unreadable.clone());
+        assert_eq!(store.get(b"flag").unwrap(), Some(b"off".to_vec()));
+        faults.clear();
+
+        store.flush_index().unwrap();
+
+        // they come back with the index when the store is reopened
+        let faults = FaultInjector::new();
+        let store = ctx
+            .reopen_with_options(Options {
+                faults: Some(faults.clone()),
+                ..options
+            })
+            .unwrap();
+        store.read_index().unwrap();
+        faults.arm(unreadable);
+        assert_eq!(store.get(b"flag").unwrap(), Some(b"off".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_inline_values_stay_out_of_the_data_file() {
+        let data_len = |inline_values| {
+            let options = Options {
+                inline_values,
+                ..Options::default()
+            };
+            let mut ctx = TestCtx::setup_with_options("test_inline_growth", options);
+            let store = &mut ctx.test_file;
+            for i in 0..2000u32 {
+                store
+                    .insert(format!("key:{}", i).as_bytes(), b"tiny")
+                    .unwrap();
+            }
+            // reads of values already inlined don't make anything to save
+            store.flush_index().unwrap();
+            assert_eq!(store.get(b"key:7").unwrap(), Some(b"tiny".to_vec()));
+            assert!(!store.inline_dirty());
+            let index_len = std::fs::metadata("test_inline_growth/index").unwrap().len();
+            (store.appender.tail(), index_len)
+        };
+        let (plain, plain_index) = data_len(None);
+        let (inlined, inlined_index) = data_len(Some(8));
+        assert_eq!(inlined, plain);
+        // the inlined values only add their own bytes to the index file
+        assert!(inlined_index < 3 * plain_index + 2000 * 40);
+    }
+}
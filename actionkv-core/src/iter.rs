@@ -0,0 +1,183 @@
+use crate::{is_internal_key, ActionKV, ByteStr, ByteString, Operation};
+use std::io;
+use std::vec;
+
+/// Live pairs of a store sorted by key, see `ActionKV::iter`.
+#[derive(Debug)]
+pub struct Iter<'a> {
+    store: &'a mut ActionKV,
+    keys: Option<vec::IntoIter<ByteString>>,
+}
+
+/// `Iter` owning the store, see `ActionKV::into_iter`.
+#[derive(Debug)]
+pub struct IntoIter {
+    store: ActionKV,
+    keys: Option<vec::IntoIter<ByteString>>,
+}
+
+/// The pair of the next key in `keys` that still has a value, the keys are
+/// taken from the store the first time.
+fn next_pair(
+    store: &mut ActionKV,
+    keys: &mut Option<vec::IntoIter<ByteString>>,
+) -> Option<io::Result<(ByteString, ByteString)>> {
+    let keys = match keys {
+        Some(keys) => keys,
+        None => match store.keys() {
+            Ok(found) => keys.insert(found.into_iter()),
+            Err(err) => {
+                *keys = Some(Vec::new().into_iter());
+                return Some(Err(err));
+            }
+        },
+    };
+    for key in keys {
+        if !store.readable(&key) {
+            continue;
+        }
+        match store.current_value(&key) {
+            Ok(Some(value)) => return Some(Ok((key, value))),
+            Ok(None) => continue,
+            Err(err) => return Some(Err(err)),
+        }
+    }
+    None
+}
+
+impl Iterator for Iter<'_> {
+    type Item = io::Result<(ByteString, ByteString)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_pair(self.store, &mut self.keys)
+    }
+}
+
+impl Iterator for IntoIter {
+    type Item = io::Result<(ByteString, ByteString)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_pair(&mut self.store, &mut self.keys)
+    }
+}
+
+impl<'a> IntoIterator for &'a mut ActionKV {
+    type Item = io::Result<(ByteString, ByteString)>;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// Consumes the store, e.g. to migrate it into another one pair by pair.
+impl IntoIterator for ActionKV {
+    type Item = io::Result<(ByteString, ByteString)>;
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> IntoIter {
+        IntoIter {
+            store: self,
+            keys: None,
+        }
+    }
+}
+
+impl ActionKV {
+    /// Every live key sorted, values written in chunks included.
+    pub fn keys(&mut self) -> io::Result<Vec<ByteString>> {
+        self.authorize(Operation::Scan, b"")?;
+        self.user_keys(b"")
+    }
+    /// Live keys starting with `prefix` sorted, values written in chunks
+    /// included and the store's own keys left out. Operations going over the
+    /// pairs of the store take their keys from here and read each value with
+    /// `current_value`.
+    pub(crate) fn user_keys(&mut self, prefix: &ByteStr) -> io::Result<Vec<ByteString>> {
+        self.read_index()?;
+        let mut keys: Vec<ByteString> = self
+            .index_entries()?
+            .into_iter()
+            .map(|(key, _)| key)
+            .chain(self.chunked_keys())
+            .filter(|key| !is_internal_key(key) && key.starts_with(prefix))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+    /// Every live pair sorted by key. Only the keys are taken up front, each
+    /// value is read from the data file when its pair is asked for. A value
+    /// that can't be read is yielded as the error, the pairs after it follow.
+    /// Keys the access policy doesn't let be read are left out.
+    pub fn iter(&mut self) -> Iter<'_> {
+        Iter {
+            store: self,
+            keys: None,
+        }
+    }
+    /// Value of `key` as `get` reads it, without its access checks, callers
+    /// going over many keys check `readable` for each.
+    pub(crate) fn current_value(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        match self.value_of(key, !self.options.skip_checksums)? {
+            Some(value) => Ok(Some(value)),
+            None => self.chunked_value(key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, TestCtx};
+    use crate::{ByteString, Options};
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[case(Options::default())]
+    #[case(Options { hashed_keys: true, ..Options::default() })]
+    #[serial]
+    fn test_iter(#[case] options: Options) {
+        let options = Options {
+            chunk_size: Some(10),
+            ..options
+        };
+        let mut ctx = TestCtx::setup_with_options("test_iter", options.clone());
+        let store = &mut ctx.test_file;
+        let big: ByteString = (0..50u8).collect();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"c", b"3").unwrap();
+        store.insert(b"big", &big).unwrap();
+        store.delete(b"c").unwrap();
+        assert_eq!(
+            store.keys().unwrap(),
+            [b"a".to_vec(), b"b".to_vec(), b"big".to_vec()]
+        );
+        let expected = vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"2".to_vec()),
+            (b"big".to_vec(), big),
+        ];
+        let pairs: Vec<_> = store.iter().map(Result::unwrap).collect();
+        assert_eq!(pairs, expected);
+
+        let mut seen = Vec::new();
+        for pair in &mut *store {
+            seen.push(pair.unwrap());
+        }
+        assert_eq!(seen, expected);
+        let (store, _dir) = ctx.into_store();
+        let owned: Vec<_> = store.into_iter().map(Result::unwrap).collect();
+        assert_eq!(owned, expected);
+    }
+    #[rstest]
+    #[serial]
+    fn test_iter_leaves_out_unreadable_keys() {
+        let mut ctx = TestCtx::setup("test_iter");
+        testing::hide_secrets(&mut ctx.test_file, 5);
+        let keys: Vec<ByteString> = ctx.test_file.iter().map(|pair| pair.unwrap().0).collect();
+        assert_eq!(keys.len(), 5);
+        assert!(keys.iter().all(|key| key.starts_with(b"public:")));
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{ActionKV, ActionKvError, ByteStr};
+use serde_json::Value;
+use std::io;
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Field(String),
+    Index(usize),
+}
+
+fn invalid_path(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid json path {:?}", path),
+    )
+}
+
+/// Parses the `$.user.names[0]` subset of JSONPath: a root followed by
+/// `.field` and `[index]` steps.
+fn parse_path(path: &str) -> io::Result<Vec<Segment>> {
+    let mut rest = path.strip_prefix('$').ok_or_else(|| invalid_path(path))?;
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(field) = rest.strip_prefix('.') {
+            let end = field.find(['.', '[']).unwrap_or(field.len());
+            if end == 0 {
+                return Err(invalid_path(path));
+            }
+            segments.push(Segment::Field(field[..end].to_string()));
+            rest = &field[end..];
+        } else if let Some(index) = rest.strip_prefix('[') {
+            let end = index.find(']').ok_or_else(|| invalid_path(path))?;
+            let index_value = index[..end].parse().map_err(|_| invalid_path(path))?;
+            segments.push(Segment::Index(index_value));
+            rest = &index[end + 1..];
+        } else {
+            return Err(invalid_path(path));
+        }
+    }
+    Ok(segments)
+}
+
+fn lookup<'a>(mut value: &'a Value, segments: &[Segment]) -> Option<&'a Value> {
+    for segment in segments {
+        value = match segment {
+            Segment::Field(field) => value.get(field)?,
+            Segment::Index(index) => value.get(index)?,
+        };
+    }
+    Some(value)
+}
+
+fn lookup_mut<'a>(mut value: &'a mut Value, segments: &[Segment]) -> Option<&'a mut Value> {
+    for segment in segments {
+        value = match segment {
+            Segment::Field(field) => value.get_mut(field)?,
+            Segment::Index(index) => value.get_mut(index)?,
+        };
+    }
+    Some(value)
+}
+
+fn parse_document(value: &ByteStr) -> io::Result<Value> {
+    serde_json::from_slice(value).map_err(|err| {
+        ActionKvError::Serialization {
+            what: "json document",
+            reason: err.to_string(),
+        }
+        .into()
+    })
+}
+
+impl ActionKV {
+    /// Sub-field of the JSON document stored under `key`, `None` when either the key
+    /// or the path doesn't exist.
+    pub fn get_path(&mut self, key: &ByteStr, path: &str) -> io::Result<Option<Value>> {
+        let segments = parse_path(path)?;
+        let document = match self.get(key)? {
+            Some(value) => parse_document(&value)?,
+            None => return Ok(None),
+        };
+        Ok(lookup(&document, &segments).cloned())
+    }
+    /// Replaces the sub-field at `path` of the JSON document under `key` and writes
+    /// the document back. The last step of the path may name a new object field.
+    pub fn update_path(&mut self, key: &ByteStr, path: &str, new_value: Value) -> io::Result<()> {
+        let mut segments = parse_path(path)?;
+        let mut document = match self.get(key)? {
+            Some(value) => parse_document(&value)?,
+            None => return Err(ActionKvError::KeyNotFound { key: key.to_vec() }.into()),
+        };
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, "json path not found");
+        match segments.pop() {
+            None => document = new_value,
+            Some(last) => {
+                let parent = lookup_mut(&mut document, &segments).ok_or_else(not_found)?;
+                match (last, parent) {
+                    (Segment::Field(field), Value::Object(object)) => {
+                        object.insert(field, new_value);
+                    }
+                    (Segment::Index(index), Value::Array(array)) if index < array.len() => {
+                        array[index] = new_value;
+                    }
+                    _ => return Err(not_found()),
+                }
+            }
+        }
+        let encoded = serde_json::to_vec(&document)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.update(key, &encoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serde_json::json;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_json_path")
+    }
+    #[rstest]
+    #[case("$", vec![])]
+    #[case("$.user.name", vec![Segment::Field("user".into()), Segment::Field("name".into())])]
+    #[case("$.tags[1]", vec![Segment::Field("tags".into()), Segment::Index(1)])]
+    fn test_parse_path(#[case] path: &str, #[case] expected: Vec<Segment>) {
+        assert_eq!(parse_path(path).unwrap(), expected);
+    }
+    #[rstest]
+    #[case("user.name")]
+    #[case("$..name")]
+    #[case("$.tags[x]")]
+    fn test_parse_invalid_path(#[case] path: &str) {
+        assert!(parse_path(path).is_err());
+    }
+    #[rstest]
+    #[serial]
+    fn test_get_and_update_path(mut ctx: TestCtx) {
+        let document = json!({"user": {"name": "ann", "tags": ["a", "b"]}});
+        ctx.test_file
+            .insert(b"doc", &serde_json::to_vec(&document).unwrap())
+            .unwrap();
+        assert_eq!(
+            ctx.test_file.get_path(b"doc", "$.user.name").unwrap(),
+            Some(json!("ann"))
+        );
+        assert_eq!(ctx.test_file.get_path(b"doc", "$.user.age").unwrap(), None);
+
+        ctx.test_file
+            .update_path(b"doc", "$.user.tags[1]", json!("c"))
+            .unwrap();
+        ctx.test_file
+            .update_path(b"doc", "$.user.age", json!(42))
+            .unwrap();
+        assert_eq!(
+            ctx.test_file.get_path(b"doc", "$.user").unwrap(),
+            Some(json!({"name": "ann", "tags": ["a", "c"], "age": 42}))
+        );
+        assert!(ctx
+            .test_file
+            .update_path(b"doc", "$.missing.field", json!(1))
+            .is_err());
+
+        let err = ctx
+            .test_file
+            .update_path(b"nodoc", "$.a", json!(1))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(matches!(
+            ActionKvError::from(err),
+            ActionKvError::KeyNotFound { key } if key == b"nodoc"
+        ));
+        ctx.test_file.insert(b"text", b"not json").unwrap();
+        assert!(matches!(
+            ActionKvError::from(ctx.test_file.get_path(b"text", "$.a").unwrap_err()),
+            ActionKvError::Serialization { .. }
+        ));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{is_internal_key, ActionKV, ActionKvError, ByteStr, ByteString};
+use std::borrow::Cow;
+use std::io;
+
+/*
+    HASHED KEYS
+    stores created with Options::hashed_keys keep a 16 byte hash of every user
+    key in the index instead of the key itself, see index_key. The hash is
+    MurmurHash3 x64 128 with the top bit of the first byte set, so it never
+    starts with the `+` of the store's own keys, which stay as they are. The
+    full key is still in every record, reads compare it against the key asked
+    for, enumerating keys reads it back from the data file.
+*/
+pub const KEY_HASH_LEN: usize = 16;
+
+/// MurmurHash3 x64 128 with seed 0, h1 followed by h2, both little endian.
+pub(crate) fn murmur3_128(bytes: &ByteStr) -> [u8; KEY_HASH_LEN] {
+    const C1: u64 = 0x87c3_7b91_1142_53d5;
+    const C2: u64 = 0x4cf5_ad43_2745_937f;
+    fn fmix(mut k: u64) -> u64 {
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        k ^ (k >> 33)
+    }
+    fn u64_le(bytes: &[u8]) -> u64 {
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |value, byte| (value << 8) | *byte as u64)
+    }
+    let mix_k1 = |k1: u64| k1.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+    let mix_k2 = |k2: u64| k2.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+    let (mut h1, mut h2) = (0u64, 0u64);
+    let mut blocks = bytes.chunks_exact(16);
+    for block in &mut blocks {
+        h1 ^= mix_k1(u64_le(&block[..8]));
+        h1 = h1
+            .rotate_left(27)
+            .wrapping_add(h2)
+            .wrapping_mul(5)
+            .wrapping_add(0x52dc_e729);
+        h2 ^= mix_k2(u64_le(&block[8..]));
+        h2 = h2
+            .rotate_left(31)
+            .wrapping_add(h1)
+            .wrapping_mul(5)
+            .wrapping_add(0x3849_5ab5);
+    }
+    let tail = blocks.remainder();
+    if tail.len() > 8 {
+        h2 ^= mix_k2(u64_le(&tail[8..]));
+    }
+    if !tail.is_empty() {
+        h1 ^= mix_k1(u64_le(&tail[..tail.len().min(8)]));
+    }
+    h1 ^= bytes.len() as u64;
+    h2 ^= bytes.len() as u64;
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    h1 = fmix(h1);
+    h2 = fmix(h2);
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    let mut hash = [0; KEY_HASH_LEN];
+    hash[..8].copy_from_slice(&h1.to_le_bytes());
+    hash[8..].copy_from_slice(&h2.to_le_bytes());
+    hash
+}
+
+/// What the index of a store with (`hashed`) or without hashed keys holds
+/// for `key`.
+pub(crate) fn index_key(hashed: bool, key: &ByteStr) -> Cow<'_, ByteStr> {
+    if !hashed || is_internal_key(key) {
+        return Cow::Borrowed(key);
+    }
+    let mut hash = murmur3_128(key);
+    hash[0] |= 0x80;
+    Cow::Owned(hash.to_vec())
+}
+
+impl ActionKV {
+    /// `index_key` for this store.
+    pub(crate) fn index_key<'k>(&self, key: &'k ByteStr) -> Cow<'k, ByteStr> {
+        index_key(self.framing.hashed_keys, key)
+    }
+    /// Offset of the record holding the current value of `key`, not checked
+    /// against a hash collision yet.
+    pub(crate) fn position(&self, key: &ByteStr) -> Option<u64> {
+        self.index.get(self.index_key(key).as_ref()).copied()
+    }
+    /// Current value of `key`, its checksum checked if `verify`. With hashed
+    /// keys the record the index points at may belong to another key hashing
+    /// the same, which is an error.
+    pub(crate) fn value_of(
+        &mut self,
+        key: &ByteStr,
+        verify: bool,
+    ) -> io::Result<Option<ByteString>> {
+        let Some(position) = self.position(key) else {
+            return Ok(None);
+        };
+        if let Some(value) = self.inline_value(key, position)? {
+            return Ok(Some(value));
+        }
+        let mut record = self.read_at(position, false, verify)?;
+        record.value = self.decode_value(record.value)?;
+        if record.key != key {
+            return Err(ActionKvError::HashCollision {
+                key: key.to_vec(),
+                other: record.key,
+            }
+            .into());
+        }
+        self.inline_put(key, position, &record.value);
+        Ok(Some(record.value))
+    }
+    /// Every entry of the index with its real key, read back from the data
+    /// file for stores with hashed keys.
+    pub(crate) fn index_entries(&mut self) -> io::Result<Vec<(ByteString, u64)>> {
+        let entries: Vec<(ByteString, u64)> = self
+            .index
+            .iter()
+            .map(|(key, position)| (key.clone(), *position))
+            .collect();
+        if !self.framing.hashed_keys {
+            return Ok(entries);
+        }
+        let mut unhashed = Vec::with_capacity(entries.len());
+        for (key, position) in entries {
+            if is_internal_key(&key) {
+                unhashed.push((key, position));
+            } else {
+                unhashed.push((self.get_raw_at(position, false)?.key, position));
+            }
+        }
+        Ok(unhashed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::Options;
+    use rstest::*;
+    use serial_test::serial;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+    #[rstest]
+    fn test_murmur3_reference_values() {
+        assert_eq!(hex(&murmur3_128(b"")), "00000000000000000000000000000000");
+        assert_eq!(
+            hex(&murmur3_128(b"The quick brown fox jumps over the lazy dog")),
+            "6c1b07bc7bbc4be347939ac4a93c437a"
+        );
+        for i in 0..1000 {
+            let key = format!("key:{}", i);
+            assert!(!is_internal_key(&index_key(true, key.as_bytes())));
+        }
+        assert_eq!(index_key(true, b"+index"), &b"+index"[..]);
+    }
+    #[rstest]
+    #[serial]
+    fn test_hashed_keys() {
+        let options = Options {
+            hashed_keys: true,
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_key_hash", options);
+        let store = &mut ctx.test_file;
+        let long_key = vec![b'k'; 1000];
+        store.insert(&long_key, b"long").unwrap();
+        store.insert(b"foo", b"1").unwrap();
+        store.insert(b"bar", b"2").unwrap();
+        store.delete(b"bar").unwrap();
+        store.read_index().unwrap();
+        assert!(store.index.keys().all(|key| key.len() == KEY_HASH_LEN));
+        assert_eq!(store.get(&long_key).unwrap(), Some(b"long".to_vec()));
+        assert_eq!(store.sample(10).unwrap().len(), 2);
+
+        // point foo's entry at the record of the long key
+        let long_position = store.position(&long_key).unwrap();
+        let foo = store.index_key(b"foo").into_owned();
+        store.index.insert(foo, long_position);
+        let err = store.get(b"foo").unwrap_err();
+        assert!(matches!(
+            ActionKvError::from_io(&err),
+            Some(ActionKvError::HashCollision { .. })
+        ));
+
+        store.rebuild_index().unwrap();
+        store.compact().unwrap();
+        let store = ctx.reopen().unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"bar").unwrap(), None);
+        assert_eq!(
+            store.keys_with_prefix(b"").unwrap(),
+            vec![b"foo".to_vec(), long_key]
+        );
+    }
+}
@@ -0,0 +1,1884 @@
+// decoding reads bytes that may be corrupt or hostile, it has to fail with an
+// error rather than take the process down
+#![cfg_attr(
+    not(test),
+    deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)
+)]
+extern crate byteorder;
+extern crate crc;
+
+mod access;
+mod access_log;
+mod actor;
+mod admin_log;
+mod appender;
+#[cfg(feature = "arrow")]
+mod arrow;
+mod blake3;
+mod blob;
+mod canonical;
+mod checkpoint;
+mod chunked;
+mod clock;
+mod compaction;
+mod compression;
+#[cfg(feature = "csv")]
+mod csv_import;
+mod debug;
+#[cfg(feature = "json")]
+mod dump;
+mod emergency;
+mod error;
+mod fault;
+mod format;
+mod fulltext;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+mod glob;
+mod group;
+mod handles;
+mod heatmap;
+mod index_codec;
+mod index_digest;
+mod index_log;
+mod inline;
+mod iter;
+#[cfg(feature = "json")]
+mod json_path;
+mod key_hash;
+mod lock;
+mod log_iter;
+mod maintenance;
+mod manifest;
+mod memory;
+mod merkle;
+mod migrate;
+mod options;
+mod platform;
+mod prefetch;
+mod read_only;
+#[cfg(feature = "object-store")]
+mod remote;
+mod repair;
+mod retry;
+mod rng;
+mod scan;
+#[cfg(feature = "stream")]
+mod scan_stream;
+mod segment;
+mod shared;
+mod stats;
+mod timeseries;
+mod torn_trace;
+mod transaction;
+mod value_ref;
+#[cfg(feature = "regex")]
+mod value_search;
+mod verify;
+mod warm;
+mod watch;
+mod zset;
+
+pub use access::{Access, AccessPolicy, Operation};
+#[cfg(feature = "access-log")]
+pub use access_log::{AccessLog, AccessRecord, ACCESS_HASH_LEN};
+pub use actor::{Command, Reply, StoreHandle};
+pub use admin_log::{AdminAction, AdminEvent, ADMIN_LOG_FILE};
+pub use blob::BlobDigest;
+pub use canonical::KeyCanonicalization;
+pub use checkpoint::Quiesced;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use compaction::{
+    CompactionControl, CompactionFilter, CompactionProgress, CompactionReport, FilterDecision,
+    RecordMeta,
+};
+#[cfg(feature = "zstd")]
+pub use compression::Compression;
+#[cfg(feature = "csv")]
+pub use csv_import::{CsvEncoding, CsvImport};
+pub use debug::display_key;
+#[cfg(feature = "json")]
+pub use debug::{debug_data_file, DataFileWalk};
+pub use emergency::FlushOnPanic;
+pub use error::ActionKvError;
+#[cfg(feature = "fault-injection")]
+pub use fault::{Fault, FaultInjector};
+pub use fault::{IoOp, StoreFile};
+pub use format::{FORMAT_VERSION, OLDEST_FORMAT_VERSION};
+pub use fulltext::{Tokenizer, WordTokenizer};
+pub use glob::Glob;
+pub use heatmap::AccessTracking;
+pub use index_codec::{FixintCodec, IndexCodec};
+pub use index_digest::IndexDigest;
+pub use iter::{IntoIter, Iter};
+pub use log_iter::{LogIter, LogRecord};
+pub use memory::MemoryUsage;
+pub use merkle::{MerkleHash, MerkleTree};
+pub use migrate::MigrationProgress;
+pub use options::{IndexCheckpoints, Options, ParanoidChecks};
+pub use repair::RepairReport;
+pub use retry::{is_transient, RetryPolicy};
+#[cfg(feature = "stream")]
+pub use scan_stream::ScanStream;
+pub use segment::{SegmentFooter, SegmentScore};
+pub use shared::SharedStore;
+pub use stats::{CorruptionCounters, IoStats, OpClass, Stats, TagCounters, Tagged, WriteCounters};
+pub use timeseries::TimeSeries;
+#[cfg(feature = "torn-write-trace")]
+pub use torn_trace::{TornWindow, TornWriteTrace};
+pub use transaction::{Savepoint, Transaction, WriteOp};
+pub use value_ref::ValueRef;
+pub use verify::VerifyReport;
+pub use watch::KeyEvent;
+
+#[cfg(test)]
+mod crash_sim;
+#[cfg(test)]
+mod testing;
+
+use appender::Appender;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use compression::ValueCodec;
+use format::{FileHeader, Framing, Generation, DATA_MAGIC, HEADER_LEN, INDEX_MAGIC};
+use group::{PendingGroup, GROUP_KEY};
+use handles::no_directory;
+use heatmap::AccessTracker;
+use index_digest::DIGEST_KEY;
+use index_log::{Delta, DELTA_KEY};
+use inline::INLINE_KEY;
+use prefetch::ReadAhead;
+use rng::Rng;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+pub type ByteString = Vec<u8>;
+pub type ByteStr = [u8];
+const INDEX_KEY: &ByteStr = b"+index";
+const BULK_LOAD_BUFFER: usize = 8 << 20;
+const BULK_LOAD_BATCH: usize = 4096;
+/// Upper bound on what a record's length fields can make `process_records`
+/// allocate up front, longer records grow their buffer as bytes arrive.
+const RECORD_PREALLOC: u64 = 64 << 10;
+
+/// Writes one record framed with `framing`, returns how many bytes it took.
+pub(crate) fn write_record<W: Write>(
+    f: &mut W,
+    framing: Framing,
+    key: &ByteStr,
+    value: &ByteStr,
+) -> io::Result<u64> {
+    let key_len = key.len();
+    let value_len = value.len() as u64;
+    if u32::try_from(key_len).is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "keys are limited to 4 GiB",
+        ));
+    }
+    if value_len > framing.max_value_len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "values are limited to 4 GiB unless the store was created with Options::wide_values",
+        ));
+    }
+    let mut tmp = ByteString::with_capacity(key.len() + value.len());
+    tmp.extend(key);
+    tmp.extend(value);
+    let padding = framing.padding(key_len as u32, value_len);
+    let checksum = framing.checksum(key_len as u32, value_len, padding, &tmp);
+
+    let mut header = ByteString::with_capacity(20);
+    header.write_u32::<LittleEndian>(checksum)?;
+    framing.write_lengths(&mut header, key_len as u32, value_len)?;
+    framing.write_padding_len(&mut header, padding)?;
+    f.write_all(&header)?;
+    f.write_all(&tmp)?;
+    f.write_all(&vec![0; padding as usize])?;
+    Ok((header.len() + tmp.len()) as u64 + padding as u64)
+}
+
+/// Applies the records of `segment` read through `f` from `start` on to
+/// `index`, see `ActionKV::replay_into`, up to the first one that can't be
+/// read or doesn't end by `end`. Returns where that one starts.
+pub(crate) fn replay_records<R: Read + Seek>(
+    f: &mut R,
+    framing: Framing,
+    segment: u16,
+    start: u64,
+    end: u64,
+    index: &mut HashMap<ByteString, u64>,
+) -> io::Result<u64> {
+    let mut position = f.seek(SeekFrom::Start(start))?;
+    let mut group: Option<PendingGroup> = None;
+    loop {
+        let key_value = match ActionKV::process_records(f, framing) {
+            Ok(key_value) => key_value,
+            Err(err) if is_corruption(&err) => break,
+            Err(err) => return Err(err),
+        };
+        let next = f.stream_position()?;
+        if next > end {
+            break;
+        }
+        if key_value.key == GROUP_KEY {
+            group = PendingGroup::new(position, &key_value.value)?;
+            position = next;
+            continue;
+        }
+        let key = key_hash::index_key(framing.hashed_keys, &key_value.key).into_owned();
+        let tombstone = key_value.value.is_empty();
+        let at = segment::position(segment, position);
+        let records = match &mut group {
+            Some(pending) => match pending.push(key, at, tombstone) {
+                Some(records) => {
+                    group = None;
+                    records
+                }
+                None => Vec::new(),
+            },
+            None => vec![(key, at, tombstone)],
+        };
+        for (key, position, tombstone) in records {
+            if tombstone {
+                index.remove(&key);
+            } else {
+                index.insert(key, position);
+            }
+        }
+        position = next;
+    }
+    // a group cut short never happened
+    Ok(group.map_or(position, |pending| pending.start))
+}
+
+/// Walks the records of `segment` read through `f` from `start` on, leaving
+/// the position and value of the last one of `key` in `found`.
+fn find_record<R: Read + Seek>(
+    f: &mut R,
+    framing: Framing,
+    segment: u16,
+    start: u64,
+    key: &ByteStr,
+    found: &mut Option<(u64, ByteString)>,
+) -> io::Result<()> {
+    let mut position = f.seek(SeekFrom::Start(start))?;
+    loop {
+        let maybe_key_value = ActionKV::process_records(f, framing);
+        let key_value = match maybe_key_value {
+            Ok(kv) => kv,
+            Err(err) => match err.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    break;
+                }
+                _ => {
+                    let position = segment::position(segment, position);
+                    return Err(ActionKvError::at_offset(err, position));
+                }
+            },
+        };
+        if key == key_value.key {
+            *found = Some((segment::position(segment, position), key_value.value));
+        }
+        position = f.stream_position()?;
+    }
+    Ok(())
+}
+
+/// Fails unless `checksum`, computed from a record as read, matches the
+/// checksum saved in front of it.
+pub(crate) fn verify_checksum(checksum: u32, saved_checksum: u32) -> io::Result<()> {
+    if checksum != saved_checksum {
+        return Err(ActionKvError::Corruption {
+            offset: None,
+            expected: saved_checksum,
+            actual: checksum,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Records of one bulk load batch framed back to back, together with the
+/// offset of every key relative to the start of the batch.
+fn encode_batch(
+    framing: Framing,
+    values: Option<&ValueCodec>,
+    batch: Vec<(ByteString, ByteString)>,
+) -> io::Result<(ByteString, Vec<(ByteString, u64)>)> {
+    let mut buf = ByteString::new();
+    let mut keydir = Vec::with_capacity(batch.len());
+    for (key, value) in batch {
+        let offset = buf.len() as u64;
+        let value = match values {
+            Some(codec) => codec.encode(&value)?,
+            None => value,
+        };
+        write_record(&mut buf, framing, &key, &value)?;
+        keydir.push((key, offset));
+    }
+    Ok((buf, keydir))
+}
+
+/// Errors a crash in the middle of a write leaves behind: a record or header
+/// cut short or not matching its checksum.
+pub(crate) fn is_corruption(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Keys starting with `+` are reserved for the store's own bookkeeping.
+pub(crate) fn is_internal_key(key: &ByteStr) -> bool {
+    key.first() == Some(&b'+')
+}
+
+/// `prefix | name_len | name` where name_len is a big endian u32, so that
+/// no name can be a prefix of another one within the same namespace.
+pub(crate) fn namespaced_key(prefix: &ByteStr, name: &ByteStr) -> ByteString {
+    let mut key = ByteString::with_capacity(prefix.len() + 4 + name.len());
+    key.extend(prefix);
+    key.extend((name.len() as u32).to_be_bytes());
+    key.extend(name);
+    key
+}
+
+#[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_derive::Serialize, serde_derive::Deserialize)
+)]
+pub struct KeyValuePair {
+    pub key: ByteString,
+    pub value: ByteString,
+}
+
+#[derive(Debug)]
+pub struct ActionKV {
+    /// `None` for stores opened on file handles with `open_files`.
+    path: Option<PathBuf>,
+    file_: File,
+    index_: File,
+    appender: Appender,
+    /// Length of the index file as last written.
+    index_len: u64,
+    pub index: HashMap<ByteString, u64>,
+    tokenizer: Option<Box<dyn Tokenizer>>,
+    compaction_filter: Option<Box<dyn CompactionFilter>>,
+    compaction_progress: CompactionProgress,
+    compaction_control: CompactionControl,
+    options: Options,
+    generation: Option<Generation>,
+    data_start: u64,
+    index_start: u64,
+    framing: Framing,
+    io_stats: IoStats,
+    corruption: CorruptionCounters,
+    op_class: OpClass,
+    access_tracker: Option<AccessTracker>,
+    clock: Arc<dyn Clock>,
+    index_codec: Arc<dyn IndexCodec>,
+    /// Set for stores with compressed values.
+    values: Option<Arc<ValueCodec>>,
+    /// Records read by `get_ref` land here, reused from one read to the next.
+    read_buf: ByteString,
+    key_canonicalization: KeyCanonicalization,
+    subscribers: Vec<watch::Subscriber>,
+    /// Records written since the index was last saved.
+    unsaved_records: u64,
+    /// Where the data file ended when the index was last saved.
+    saved_tail: u64,
+    /// What changed in the index since it was last saved.
+    index_log: index_log::IndexLog,
+    /// Length of the data file as last compacted, or opened.
+    compacted_tail: u64,
+    write_log: transaction::WriteLog,
+    inline: inline::InlineValues,
+    segments: segment::Segments,
+    /// Held by writers opened on a directory for as long as they are open.
+    _writer: Option<lock::DirLock>,
+}
+
+/// Where the records of both files start and how the data file frames them.
+struct Layout {
+    generation: Option<Generation>,
+    data_start: u64,
+    index_start: u64,
+    framing: Framing,
+    /// The index file was unreadable and got reset, the index has to be
+    /// rebuilt from the data file.
+    rebuild_index: bool,
+    /// The data file goes on past where the saved index was written against,
+    /// the records from here on have to be replayed into the index.
+    replay_from: Option<u64>,
+    /// Records past where the saved index was written against were cut off.
+    truncated: bool,
+    /// The data file was sealed after the index was last saved, the index
+    /// still points into it for the records of the newest segment.
+    sealed_since_saved: bool,
+}
+
+/*
+    THIS IS BITCASK FILE FORMAT
+    both files start with a FileHeader (see format.rs) followed by records
+    checksum | key_len | value_len |     key      |     value
+    [u32;1]    [u32;1]   [u32;1]     [u8;key_len]   [u8;value_len]
+*/
+impl ActionKV {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        ActionKV::open_with_options(path, Options::default())
+    }
+    pub fn open_with_options(path: &Path, options: Options) -> io::Result<Self> {
+        let framing = options.framing()?;
+        let (_lock, writer, file_, index_, layout, data_len) = if options.read_only {
+            let lock = lock::shared(path)?;
+            let (file_, index_, layout, data_len) = ActionKV::open_files_read_only(path)?;
+            (lock, None, file_, index_, layout, data_len)
+        } else {
+            if !std::path::Path::new(&path).exists() {
+                std::fs::create_dir(path)?;
+            }
+            let writer = lock::writer(path)?;
+            // keeps read only opens out until recovery is done
+            let lock = lock::exclusive(path)?;
+            compaction::finish_interrupted(path)?;
+            segment::finish_interrupted(path)?;
+            let sealed_len = segment::newest_sealed_len(path)?;
+            let mut file_ = OpenOptions::new()
+                .read(true)
+                .create(true)
+                .append(true)
+                .open(path.join("data"))?;
+            let mut index_ = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path.join("index"))?;
+            let replay = options.index_checkpoints.is_some();
+            let layout =
+                ActionKV::check_headers(&mut file_, &mut index_, framing, replay, sealed_len)?;
+            let data_len = file_.metadata()?.len();
+            (lock, Some(writer), file_, index_, layout, data_len)
+        };
+        let mut store = ActionKV::assemble(Some(path), file_, index_, layout, data_len, options)?;
+        store._writer = writer;
+        Ok(store)
+    }
+    /// The store made of the opened `file_` and `index_`, recovering it if
+    /// `layout` says so. `path` is `None` for stores opened on file handles.
+    fn assemble(
+        path: Option<&Path>,
+        file_: File,
+        index_: File,
+        layout: Layout,
+        data_len: u64,
+        options: Options,
+    ) -> io::Result<Self> {
+        let clock = options
+            .clock
+            .clone()
+            .unwrap_or_else(|| Arc::new(SystemClock));
+        let index_codec = options
+            .index_codec
+            .clone()
+            .unwrap_or_else(|| Arc::new(FixintCodec));
+        let access_tracker = options
+            .access_tracking
+            .clone()
+            .map(|config| AccessTracker::new(config, clock.now()));
+        let Layout {
+            generation,
+            data_start,
+            index_start,
+            framing,
+            rebuild_index,
+            replay_from,
+            truncated,
+            sealed_since_saved,
+        } = layout;
+        let appender = Appender::new(data_len);
+        let index_len = index_.metadata()?.len();
+        let segments = segment::Segments::open(path, generation, options.read_only)?;
+        let values = match (framing.compressed_values, path) {
+            (true, Some(path)) => Some(Arc::new(ValueCodec::open(path, &options)?)),
+            (true, None) => return Err(no_directory("compressed values")),
+            (false, _) => None,
+        };
+        let key_canonicalization = match path {
+            Some(path) => KeyCanonicalization::open(
+                path,
+                options.key_canonicalization,
+                data_len <= data_start,
+                options.read_only,
+            )?,
+            None => options.key_canonicalization,
+        };
+        let mut index = HashMap::new();
+        if index_len > index_start {
+            // the persisted index is read lazily on first use
+            index.insert(INDEX_KEY.to_vec(), index_start);
+        }
+        let mut store = ActionKV {
+            path: path.map(Path::to_path_buf),
+            file_,
+            index_,
+            appender,
+            index_len,
+            index,
+            tokenizer: None,
+            compaction_filter: None,
+            compaction_progress: CompactionProgress::default(),
+            compaction_control: CompactionControl::default(),
+            options,
+            generation,
+            data_start,
+            index_start,
+            framing,
+            io_stats: IoStats::default(),
+            corruption: CorruptionCounters {
+                torn_truncations: truncated as u64,
+                ..CorruptionCounters::default()
+            },
+            op_class: OpClass::Insert,
+            access_tracker,
+            clock,
+            index_codec,
+            values,
+            read_buf: ByteString::new(),
+            key_canonicalization,
+            subscribers: Vec::new(),
+            unsaved_records: 0,
+            saved_tail: data_len,
+            index_log: index_log::IndexLog::default(),
+            compacted_tail: data_len,
+            write_log: transaction::WriteLog::default(),
+            inline: inline::InlineValues::default(),
+            segments,
+            _writer: None,
+        };
+        if let Some(id) = store.newest_segment().filter(|_| sealed_since_saved) {
+            // the deltas of the index were written against the sealed file
+            let (file, _) = store.record_file(segment::position(id, 0))?;
+            let sealed_len = file.metadata()?.len();
+            store.appender.reset(sealed_len);
+            let read = store.read_index();
+            store.appender.reset(data_len);
+            read?;
+            store.move_sealed_positions(id);
+            store.store_index_on_disk(INDEX_KEY)?;
+        }
+        if rebuild_index {
+            store.rebuild_index()?;
+        } else {
+            store.check_saved_index(replay_from.unwrap_or(data_len))?;
+            if let Some(start) = replay_from {
+                store.replay_since_checkpoint(start)?;
+            }
+        }
+        store.check_index(store.options.paranoid_checks)?;
+        Ok(store)
+    }
+    /// Makes sure the data and index file were written as a pair, stamping
+    /// brand new files with a fresh generation and `framing`. The generation is
+    /// `None` for stores created before files had headers. Records appended
+    /// after the index was saved are cut off, unless they are to be `replay`ed.
+    /// `sealed_len` is the length of the newest sealed segment.
+    fn check_headers(
+        file_: &mut File,
+        index_: &mut File,
+        framing: Framing,
+        replay: bool,
+        sealed_len: Option<u64>,
+    ) -> io::Result<Layout> {
+        let data_len = file_.metadata()?.len();
+        let index_len = index_.metadata()?.len();
+        let data_header = FileHeader::read_from(file_, DATA_MAGIC)?;
+        let (index_header, rebuild_index) = match FileHeader::read_from(index_, INDEX_MAGIC) {
+            Ok(header) => (header, false),
+            // a crash tore the header while the index was being rewritten,
+            // start the index over and rebuild it from the data file
+            Err(err) if data_header.is_some() && is_corruption(&err) => {
+                index_.set_len(0)?;
+                (None, true)
+            }
+            Err(err) => return Err(err),
+        };
+        let index_len = if rebuild_index { 0 } else { index_len };
+        let mismatched = |reason: String| -> io::Result<Layout> {
+            Err(ActionKvError::FilesMismatched { reason }.into())
+        };
+        let stamped = |data: &FileHeader| -> io::Result<Layout> {
+            let framing = Framing::from_header(data)?;
+            Ok(Layout {
+                generation: Some(data.generation),
+                data_start: framing.records_start(),
+                index_start: HEADER_LEN,
+                framing,
+                rebuild_index,
+                replay_from: None,
+                truncated: false,
+                sealed_since_saved: false,
+            })
+        };
+        match (data_header, index_header) {
+            (Some(data), Some(index)) => {
+                if data.generation != index.generation {
+                    return mismatched(format!(
+                        "data file generation is {} but index generation is {}",
+                        format::display_generation(&data.generation),
+                        format::display_generation(&index.generation)
+                    ));
+                }
+                let records_start = Framing::from_header(&data)?.records_start();
+                if index.data_len > data_len
+                    && data_len <= records_start
+                    && sealed_len == Some(index.data_len)
+                {
+                    // sealed, the index wasn't saved again yet
+                    return Ok(Layout {
+                        sealed_since_saved: true,
+                        ..stamped(&data)?
+                    });
+                }
+                if index.data_len > data_len {
+                    return mismatched(format!(
+                        "index was written against {} bytes of data but the data file has {}",
+                        index.data_len, data_len
+                    ));
+                }
+                // records appended after the index was last saved never got
+                // committed, a crash may have left them torn
+                let committed = index.data_len.max(records_start);
+                if committed < data_len && replay {
+                    return Ok(Layout {
+                        replay_from: Some(committed),
+                        ..stamped(&data)?
+                    });
+                }
+                if committed < data_len {
+                    file_.set_len(committed)?;
+                    return Ok(Layout {
+                        truncated: true,
+                        ..stamped(&data)?
+                    });
+                }
+                stamped(&data)
+            }
+            (Some(data), None) if index_len == 0 => {
+                FileHeader::new(INDEX_MAGIC, data.generation).write_to(index_)?;
+                stamped(&data)
+            }
+            (None, None) if data_len == 0 && index_len == 0 => {
+                let generation = format::new_generation();
+                let data = FileHeader::for_data(generation, framing);
+                data.write_to(file_)?;
+                let header_padding = framing.records_start() - HEADER_LEN;
+                file_.write_all(&vec![0; header_padding as usize])?;
+                FileHeader::new(INDEX_MAGIC, generation).write_to(index_)?;
+                stamped(&data)
+            }
+            (None, None) => Ok(Layout {
+                generation: None,
+                data_start: 0,
+                index_start: 0,
+                framing: Framing::default(),
+                rebuild_index: false,
+                replay_from: None,
+                truncated: false,
+                sealed_since_saved: false,
+            }),
+            (Some(_), None) => mismatched("index file has no generation stamp".to_string()),
+            (None, Some(index)) => mismatched(format!(
+                "index has generation {} but the data file has none",
+                format::display_generation(&index.generation)
+            )),
+        }
+    }
+    pub(crate) fn process_records<R: Read>(
+        f: &mut R,
+        framing: Framing,
+    ) -> io::Result<KeyValuePair> {
+        ActionKV::read_record(f, framing, true)
+    }
+    /// `process_records` leaving the checksum unchecked unless `verify`.
+    fn read_record<R: Read>(f: &mut R, framing: Framing, verify: bool) -> io::Result<KeyValuePair> {
+        let saved_checksum = f.read_u32::<LittleEndian>()?;
+        let (key_len, value_len) = framing.read_lengths(f)?;
+        let padding = framing.read_padding_len(f)?;
+        // wide value lengths can claim more than fits, which then can't be read
+        let data_len = (key_len as u64).saturating_add(value_len);
+        let mut data = ByteString::with_capacity(data_len.min(RECORD_PREALLOC) as usize);
+        {
+            f.by_ref().take(data_len).read_to_end(&mut data)?;
+        };
+        if data.len() as u64 != data_len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "record is cut short",
+            ));
+        }
+        io::copy(&mut f.by_ref().take(padding as u64), &mut io::sink())?;
+        if verify {
+            let checksum = framing.checksum(key_len, value_len, padding, &data);
+            verify_checksum(checksum, saved_checksum)?;
+        }
+        let value = data.split_off(key_len as usize);
+        let key = data;
+        Ok(KeyValuePair { key, value })
+    }
+    fn store_index_on_disk(&mut self, index_key: &ByteStr) -> io::Result<()> {
+        self.io_stats.record_operation(OpClass::Index, 0);
+        self.index.remove(index_key);
+        self.prune_inline();
+        let index_as_bytes = self.index_codec.encode(&self.index);
+        let mut trailer = match self.options.index_digests {
+            true => index_digest::digest_record(&self.index)?,
+            false => ByteString::new(),
+        };
+        trailer.extend(self.inline_record(true)?.unwrap_or_default());
+        self.torn_window_opened()?;
+        let index = std::mem::take(&mut self.index);
+        let written = self
+            .writable()
+            .and_then(|()| self.write_index_record(index_key, &index_as_bytes, &trailer));
+        if let Err(err) = written {
+            // keep serving from memory, the next write persists it again
+            self.index = index;
+            return Err(err);
+        }
+        self.inline_saved();
+        self.torn_window_closed();
+        self.unsaved_records = 0;
+        self.saved_tail = self.appender.tail();
+        self.index_log = index_log::IndexLog::default();
+        Ok(())
+    }
+    /// Saves the index after a write, unless `Options::index_checkpoints`
+    /// lets it wait for more writes, and seals the data file once it is
+    /// full, which saves it too.
+    fn index_written(&mut self) -> io::Result<()> {
+        if self.seal_if_full()? {
+            return Ok(());
+        }
+        if let Some(checkpoints) = self.options.index_checkpoints {
+            let bytes = self.appender.tail().saturating_sub(self.saved_tail);
+            if self.unsaved_records < checkpoints.records && bytes < checkpoints.bytes {
+                return Ok(());
+            }
+        }
+        self.save_index()
+    }
+    /// Saves the index if records were written since it was last saved,
+    /// which only happens with `Options::index_checkpoints`.
+    pub fn flush_index(&mut self) -> io::Result<()> {
+        if self.unsaved_records > 0 || self.inline_dirty() {
+            self.read_index()?;
+            self.save_index()?;
+        }
+        Ok(())
+    }
+    fn read_index(&mut self) -> io::Result<()> {
+        let maybe_index = self.index.get(INDEX_KEY);
+        if let Some(&position) = maybe_index {
+            let decoded = self.get_at(position, true).and_then(|key_value| {
+                let index = self.index_codec.decode(&key_value.value)?;
+                Ok((index, key_value.value))
+            });
+            match decoded {
+                Ok((index, index_as_bytes)) => {
+                    self.index = index;
+                    self.check_loaded_index(position, index_as_bytes.len())?;
+                    self.load_index_deltas(position, &index_as_bytes)?;
+                }
+                // caught while the writer was rewriting it
+                Err(err) if is_corruption(&err) && self.options.read_only => {
+                    return self.reread_torn_index(err);
+                }
+                // torn by a crash while it was being rewritten
+                Err(err) if is_corruption(&err) => {
+                    self.rebuild_index()?;
+                    return self.read_index();
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+    /// Recreates the index from scratch by scanning the data file, the last
+    /// record of every key wins and an empty value is a tombstone. The scan
+    /// stops at the first record that can't be read and the data file is
+    /// truncated there, as only a crash leaves a torn record behind.
+    /// Returns how many keys the index holds afterwards.
+    pub fn rebuild_index(&mut self) -> io::Result<usize> {
+        self.writable()?;
+        self.corruption.index_rebuilds += 1;
+        let mut index = HashMap::new();
+        self.replay_sealed(&mut index)?;
+        self.replay_into(self.data_start, &mut index)?;
+        index.remove(INDEX_KEY);
+        self.index = index;
+        self.segments.uncount();
+        let live = self
+            .index
+            .keys()
+            .filter(|key| !segment::is_footer_key(key))
+            .count();
+        self.store_index_on_disk(INDEX_KEY)?;
+        self.log_admin(
+            AdminAction::Repair,
+            &format!("rebuilt the index, {} keys", live),
+        )?;
+        Ok(live)
+    }
+    /// Brings the saved index up to date with the records appended after it
+    /// was saved, which `Options::index_checkpoints` leaves behind, instead of
+    /// scanning the whole data file.
+    fn replay_since_checkpoint(&mut self, start: u64) -> io::Result<()> {
+        self.read_index()?;
+        let mut index = std::mem::take(&mut self.index);
+        let replayed = self.replay_into(start, &mut index);
+        index.remove(INDEX_KEY);
+        self.index = index;
+        replayed?;
+        self.store_index_on_disk(INDEX_KEY)
+    }
+    /// Applies the records from `start` on to `index`, the last record of
+    /// every key wins and an empty value is a tombstone. Stops at the first
+    /// record that can't be read and truncates the data file there.
+    fn replay_into(&mut self, start: u64, index: &mut HashMap<ByteString, u64>) -> io::Result<()> {
+        let position = self.scan_into(start, u64::MAX, index)?;
+        if position < self.appender.tail() {
+            self.file_.set_len(position)?;
+            self.appender.reset(position);
+            self.corruption.torn_truncations += 1;
+        }
+        Ok(())
+    }
+    /// `replay_into` for the records ending by `end`, without touching the
+    /// data file. Returns where the scan stopped.
+    fn scan_into(
+        &mut self,
+        start: u64,
+        end: u64,
+        index: &mut HashMap<ByteString, u64>,
+    ) -> io::Result<u64> {
+        self.inject(IoOp::Read, StoreFile::Data)?;
+        let framing = self.framing;
+        let mut f = ReadAhead::new(&mut self.file_)?;
+        replay_records(&mut f, framing, segment::ACTIVE, start, end, index)
+    }
+    /// Reads back the records the index points at and makes sure they hold the
+    /// keys the index claims, see `Options::paranoid_checks`.
+    fn check_index(&mut self, checks: ParanoidChecks) -> io::Result<()> {
+        if checks == ParanoidChecks::Off {
+            return Ok(());
+        }
+        self.read_index()?;
+        let sample_size = match checks {
+            ParanoidChecks::Sample(sample_size) => sample_size.min(self.index.len()),
+            _ => self.index.len(),
+        };
+        let entries: Vec<(ByteString, u64)> = Rng::new()
+            .choose_multiple(self.index.iter(), sample_size)
+            .into_iter()
+            .map(|(key, position)| (key.clone(), *position))
+            .collect();
+        for (key, position) in entries {
+            let drift = |reason: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "index drift: key {:?} points at offset {} but {}",
+                        display_key(&key),
+                        position,
+                        reason
+                    ),
+                )
+            };
+            match self.get_at(position, false) {
+                Ok(record) if self.index_key(&record.key) == key => {}
+                Ok(record) => {
+                    return Err(drift(format!(
+                        "the record there holds key {:?}",
+                        display_key(&record.key)
+                    )))
+                }
+                Err(err) => return Err(drift(format!("the record there is unreadable: {}", err))),
+            }
+        }
+        Ok(())
+    }
+    fn insert_(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        self.writable()?;
+        let encoded = self.encode_value(value)?;
+        let framing = self.framing;
+        let tail = self.appender.tail();
+        let (position, physical_bytes) = self.retrying(|store| {
+            store.inject(IoOp::Write, StoreFile::Data)?;
+            match store
+                .appender
+                .append(&mut store.file_, framing, key, &encoded)
+            {
+                Err(err) if store.appender.tail() != tail => {
+                    // a partial record would hide whatever comes after it
+                    // from replay
+                    store.file_.set_len(tail)?;
+                    store.appender.reset(tail);
+                    Err(err)
+                }
+                appended => appended,
+            }
+        })?;
+        self.io_stats.record_physical(self.op_class, physical_bytes);
+        self.inline_put(key, position, value);
+        let key = self.index_key(key).into_owned();
+        self.index_log.changed(&key);
+        let counted = key != INDEX_KEY && !segment::is_footer_key(&key);
+        let replaced = self.index.insert(key, position);
+        if counted {
+            self.segments.moved(replaced, Some(position));
+        }
+        self.unsaved_records += 1;
+        Ok(())
+    }
+    /// Rewrites the index file with the header and a single record, followed
+    /// by the records in `trailer` (its digest, the inlined values), in one
+    /// write from its start.
+    fn write_index_record(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr,
+        trailer: &ByteStr,
+    ) -> io::Result<()> {
+        let mut buf = ByteString::new();
+        if let Some(generation) = self.generation {
+            let mut header = FileHeader::new(INDEX_MAGIC, generation);
+            header.data_len = self.appender.tail();
+            buf.extend(header.encode());
+        }
+        write_record(&mut buf, Framing::default(), key, value)?;
+        buf.extend(trailer);
+        let index_end = buf.len() as u64;
+        self.retrying(|store| {
+            store.inject(IoOp::Write, StoreFile::Index)?;
+            store.index_.seek(SeekFrom::Start(0))?;
+            store.index_.write_all(&buf)?;
+            if index_end < store.index_len {
+                // the index only ever occupies the start of its file, drop whatever
+                // an older (longer) index left behind it
+                store.index_.set_len(index_end)?;
+            }
+            Ok(())
+        })?;
+        self.index_len = index_end;
+        self.io_stats.record_physical(OpClass::Index, index_end);
+        self.index.insert(Vec::from(key), self.index_start);
+        Ok(())
+    }
+    fn get_at(&mut self, index: u64, get_index: bool) -> io::Result<KeyValuePair> {
+        let mut key_value = self.get_raw_at(index, get_index)?;
+        if !get_index {
+            key_value.value = self.decode_value(key_value.value)?;
+        }
+        Ok(key_value)
+    }
+    /// `get_at` leaving the value as it is stored.
+    fn get_raw_at(&mut self, index: u64, get_index: bool) -> io::Result<KeyValuePair> {
+        self.read_at(index, get_index, true)
+    }
+    /// `get_raw_at` skipping the checksum unless `verify`.
+    fn read_at(&mut self, index: u64, get_index: bool, verify: bool) -> io::Result<KeyValuePair> {
+        let file = if get_index {
+            StoreFile::Index
+        } else {
+            StoreFile::Data
+        };
+        let framing = if get_index {
+            Framing::default()
+        } else {
+            self.framing
+        };
+        self.retrying(|store| {
+            store.inject(IoOp::Read, file)?;
+            let (mut f, offset) = match get_index {
+                true => (BufReader::new(&mut store.index_), index),
+                false => {
+                    let (file, offset) = store.record_file(index)?;
+                    (BufReader::new(file), offset)
+                }
+            };
+            f.seek(SeekFrom::Start(offset))?;
+            ActionKV::read_record(&mut f, framing, verify)
+                .map_err(|err| ActionKvError::at_offset(err, index))
+        })
+        .inspect_err(|err| self.corruption.count(err))
+    }
+    #[cfg_attr(feature = "timed", timed::timed)]
+    pub fn load(&mut self) -> io::Result<()> {
+        let mut f = BufReader::new(&mut self.index_);
+        f.seek(SeekFrom::Start(self.index_start))?;
+        let mut base = None;
+        loop {
+            let result_key_value = ActionKV::process_records(&mut f, Framing::default());
+            let key_value = match result_key_value {
+                Ok(key_value) => key_value,
+                Err(err) => match err.kind() {
+                    io::ErrorKind::UnexpectedEof => {
+                        break;
+                    }
+                    _ => return Err(err),
+                },
+            };
+            if key_value.key == DIGEST_KEY || key_value.key == INLINE_KEY {
+                continue;
+            }
+            if key_value.key == DELTA_KEY {
+                let delta = Delta::decode(&key_value.value)?;
+                if base != Some(delta.base) || delta.data_len > self.appender.tail() {
+                    break;
+                }
+                delta.apply(&mut self.index);
+                continue;
+            }
+            self.index = self.index_codec.decode(&key_value.value)?;
+            base = Some(Delta::base_of(&key_value.value));
+        }
+        Ok(())
+    }
+    #[cfg_attr(feature = "timed", timed::timed)]
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        let key = self.canonical_key(key);
+        let key = key.as_ref();
+        self.logged(Operation::Write, key, |store| {
+            store.authorize(Operation::Write, key)?;
+            store.track_access(key)?;
+            store.op_class = OpClass::Insert;
+            store
+                .io_stats
+                .record_operation(OpClass::Insert, (key.len() + value.len()) as u64);
+            store.read_index()?;
+            store.unindex_terms(key)?;
+            store.put_value(key, value)?;
+            store.index_terms(key, value)?;
+            store.index_written()?;
+            store.notify(KeyEvent::Put(key.to_vec()));
+            Ok(())
+        })
+    }
+    /// Fast path for initial ingestion: appends every pair through one large
+    /// buffer, syncs the data file and persists the index once at the end instead
+    /// of after every record. Pre-sorted input gets duplicate keys collapsed for
+    /// free as they arrive next to each other, only the last value is written.
+    /// Returns how many records were written.
+    #[cfg_attr(feature = "timed", timed::timed)]
+    pub fn bulk_load<I>(&mut self, pairs: I) -> io::Result<u64>
+    where
+        I: IntoIterator<Item = (ByteString, ByteString)>,
+    {
+        self.bulk_load_parallel(pairs, 1)
+    }
+    /// `bulk_load` that cuts the input into batches and frames them on `workers`
+    /// threads at once. Batches are appended in input order and their keydirs
+    /// merged into the index in the same order, so later pairs still win.
+    #[cfg_attr(feature = "timed", timed::timed)]
+    pub fn bulk_load_parallel<I>(&mut self, pairs: I, workers: usize) -> io::Result<u64>
+    where
+        I: IntoIterator<Item = (ByteString, ByteString)>,
+    {
+        self.read_index()?;
+        self.inject(IoOp::Write, StoreFile::Data)?;
+        let start = self.appender.tail();
+        let canonicalization = self.key_canonicalization;
+        let watched = self.has_subscribers();
+        let mut loaded = Vec::new();
+        let pairs = pairs.into_iter().map(|(key, value)| {
+            let key = canonicalization.apply_owned(key);
+            if watched {
+                loaded.push(key.clone());
+            }
+            (key, value)
+        });
+        let (written, end) = match self.append_pairs(pairs, workers) {
+            Ok(appended) => appended,
+            Err(err) => {
+                // a partial batch may have made it, find the end again
+                self.appender.reset(self.file_.metadata()?.len());
+                return Err(err);
+            }
+        };
+        self.appender.reset(end);
+        self.io_stats
+            .record_physical(OpClass::BulkLoad, end - start);
+        self.inject(IoOp::Sync, StoreFile::Data)?;
+        self.file_.sync_data()?;
+        self.store_index_on_disk(INDEX_KEY)?;
+        self.seal_if_full()?;
+        if self.tokenizer.is_some() {
+            self.rebuild_search_index()?;
+        }
+        for key in loaded {
+            self.notify(KeyEvent::Put(key));
+        }
+        Ok(written)
+    }
+    /// Appends the records of `bulk_load_parallel` and adds them to the
+    /// in-memory index, returns how many were written and where the data file
+    /// ends now.
+    fn append_pairs<I>(&mut self, pairs: I, workers: usize) -> io::Result<(u64, u64)>
+    where
+        I: IntoIterator<Item = (ByteString, ByteString)>,
+    {
+        self.writable()?;
+        let policy = self.options.access_policy;
+        let framing = self.framing;
+        let values = self.values.clone();
+        let values = values.as_deref();
+        let workers = platform::workers(workers);
+        let mut pairs = pairs.into_iter().peekable();
+        let mut f = BufWriter::with_capacity(BULK_LOAD_BUFFER, &mut self.file_);
+        let mut position = self.appender.tail();
+        let mut written = 0;
+        loop {
+            let mut batches = Vec::with_capacity(workers);
+            while batches.len() < workers {
+                let mut batch = Vec::with_capacity(BULK_LOAD_BATCH);
+                while batch.len() < BULK_LOAD_BATCH {
+                    let (key, value) = match pairs.next() {
+                        Some(pair) => pair,
+                        None => break,
+                    };
+                    access::authorize(policy, Operation::Write, &key)?;
+                    self.io_stats
+                        .record_operation(OpClass::BulkLoad, (key.len() + value.len()) as u64);
+                    if !matches!(pairs.peek(), Some((next_key, _)) if *next_key == key) {
+                        batch.push((key, value));
+                    }
+                }
+                if batch.is_empty() {
+                    break;
+                }
+                batches.push(batch);
+            }
+            if batches.is_empty() {
+                break;
+            }
+            let encoded: Vec<io::Result<_>> = if batches.len() == 1 {
+                batches
+                    .into_iter()
+                    .map(|batch| encode_batch(framing, values, batch))
+                    .collect()
+            } else {
+                thread::scope(|scope| {
+                    let handles: Vec<_> = batches
+                        .into_iter()
+                        .map(|batch| scope.spawn(move || encode_batch(framing, values, batch)))
+                        .collect();
+                    handles
+                        .into_iter()
+                        .map(|handle| {
+                            handle.join().unwrap_or_else(|_| {
+                                Err(io::Error::other("bulk load worker panicked"))
+                            })
+                        })
+                        .collect()
+                })
+            };
+            for batch in encoded {
+                let (buf, keydir) = batch?;
+                f.write_all(&buf)?;
+                written += keydir.len() as u64;
+                for (key, offset) in keydir {
+                    let key = key_hash::index_key(framing.hashed_keys, &key).into_owned();
+                    let replaced = self.index.insert(key, position + offset);
+                    self.segments.moved(replaced, None);
+                }
+                position += buf.len() as u64;
+            }
+        }
+        f.flush()?;
+        Ok((written, position))
+    }
+    #[cfg_attr(feature = "timed", timed::timed)]
+    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let key = self.canonical_key(key);
+        let key = key.as_ref();
+        self.logged(Operation::Read, key, |store| {
+            store.authorize(Operation::Read, key)?;
+            store.track_access(key)?;
+            store.read_index()?;
+            let value = match store.value_of(key, !store.options.skip_checksums)? {
+                Some(value) => Some(value),
+                None => store.chunked_value(key)?,
+            };
+            if let Some(value) = &value {
+                store.io_stats.record_read(value.len() as u64);
+            }
+            Ok(value)
+        })
+    }
+    #[cfg_attr(feature = "timed", timed::timed)]
+    pub fn find(&mut self, key: &ByteStr) -> io::Result<Option<(u64, ByteString)>> {
+        let key = self.canonical_key(key);
+        let key = key.as_ref();
+        self.authorize(Operation::Read, key)?;
+        self.inject(IoOp::Read, StoreFile::Data)?;
+        let framing = self.framing;
+        let mut found_key_value: Option<(u64, ByteString)> = None;
+        for (id, path) in self.segment_files()? {
+            let mut f = ReadAhead::new(File::open(path)?)?;
+            find_record(
+                &mut f,
+                framing,
+                id,
+                self.data_start,
+                key,
+                &mut found_key_value,
+            )?;
+        }
+        let mut f = ReadAhead::new(&mut self.file_)?;
+        find_record(
+            &mut f,
+            framing,
+            segment::ACTIVE,
+            self.data_start,
+            key,
+            &mut found_key_value,
+        )?;
+        match found_key_value {
+            Some((position, value)) => Ok(Some((position, self.decode_value(value)?))),
+            None => Ok(None),
+        }
+    }
+    #[cfg_attr(feature = "timed", timed::timed)]
+    #[inline(always)]
+    pub fn delete(&mut self, key: &ByteStr) -> io::Result<()> {
+        let key = self.canonical_key(key);
+        let key = key.as_ref();
+        self.logged(Operation::Delete, key, |store| {
+            store.authorize(Operation::Delete, key)?;
+            store.track_access(key)?;
+            store.op_class = OpClass::Delete;
+            store
+                .io_stats
+                .record_operation(OpClass::Delete, key.len() as u64);
+            store.read_index()?;
+            store.unindex_terms(key)?;
+            store.remove_chunks(key)?;
+            store.remove_(key)?;
+            store.index_written()?;
+            store.notify(KeyEvent::Delete(key.to_vec()));
+            Ok(())
+        })
+    }
+    /// Writes bookkeeping the store keeps for itself under an internal key.
+    pub(crate) fn put_internal(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        self.op_class = OpClass::Maintenance;
+        self.read_index()?;
+        self.insert_(key, value)?;
+        self.index_written()
+    }
+    /// Appends a tombstone for `key` and drops it from the in-memory index,
+    /// persisting the index is left to the caller.
+    fn remove_(&mut self, key: &ByteStr) -> io::Result<()> {
+        let position = self.appender.tail();
+        self.insert_(key, b"")?;
+        self.inline_remove(key);
+        let key = self.index_key(key);
+        self.index_log.changed(&key);
+        if self.index.remove(key.as_ref()).is_some() && !is_internal_key(&key) {
+            self.write_log.deleted(key.into_owned(), position);
+        }
+        Ok(())
+    }
+    /// Sorted list of every live key starting with `prefix`.
+    pub(crate) fn keys_with_prefix(&mut self, prefix: &ByteStr) -> io::Result<Vec<ByteString>> {
+        self.read_index()?;
+        let mut keys: Vec<ByteString> = self
+            .index_entries()?
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+    /// Up to `n` distinct live keys picked uniformly at random, in no particular order.
+    /// Keys the access policy doesn't let be read are left out.
+    pub fn sample(&mut self, n: usize) -> io::Result<Vec<ByteString>> {
+        self.read_index()?;
+        // with hashed keys the policy can only be asked once they are read
+        let hashed_keys = self.framing.hashed_keys;
+        let entries = self
+            .index
+            .iter()
+            .filter(|(key, _)| !is_internal_key(key) && (hashed_keys || self.readable(key)));
+        let sample: Vec<(ByteString, u64)> = Rng::new()
+            .choose_multiple(entries, n)
+            .into_iter()
+            .map(|(key, position)| (key.clone(), *position))
+            .collect();
+        if !self.framing.hashed_keys {
+            return Ok(sample.into_iter().map(|(key, _)| key).collect());
+        }
+        let mut keys = Vec::with_capacity(sample.len());
+        for (_, position) in sample {
+            let key = self.get_raw_at(position, false)?.key;
+            if self.readable(&key) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+    /// `sample` returning the current value along with each key.
+    pub fn sample_pairs(&mut self, n: usize) -> io::Result<Vec<(ByteString, ByteString)>> {
+        let mut pairs = Vec::new();
+        for key in self.sample(n)? {
+            if let Some(value) = self.value_of(&key, true)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+    #[cfg_attr(feature = "timed", timed::timed)]
+    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        self.insert(key, value)?;
+        Ok(())
+    }
+    /// Read-modify-write of one key: `f` gets the current value and returns
+    /// the one to store, `None` deletes the key. Nothing else can write
+    /// between the read and the write, the store is borrowed for both (and
+    /// `SharedStore::update_with` holds its lock throughout). Returns the
+    /// value now stored.
+    pub fn update_with<F>(&mut self, key: &ByteStr, f: F) -> io::Result<Option<ByteString>>
+    where
+        F: FnOnce(Option<ByteString>) -> Option<ByteString>,
+    {
+        let old = self.get(key)?;
+        let existed = old.is_some();
+        let new = f(old);
+        match &new {
+            Some(value) => self.insert(key, value)?,
+            None if existed => self.delete(key)?,
+            None => {}
+        }
+        Ok(new)
+    }
+}
+
+impl Drop for ActionKV {
+    fn drop(&mut self) {
+        // nobody is left to report a failure to, the records stay in the
+        // data file either way
+        let _ = self.flush_index();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_foo")
+    }
+    #[rstest]
+    #[serial]
+    fn test_load(mut ctx: TestCtx) {
+        ctx.test_file.load().unwrap();
+        assert_eq!(ctx.test_file.index.len(), 0);
+        let key = b"foo";
+        let value = b"bar";
+        for i in 0..9 {
+            let key = format!("{:?}{}", key, i);
+            let new_key = key.as_bytes();
+            ctx.test_file
+                .insert(new_key, value)
+                .expect("Unable to insert key value pair into ActionKV file!");
+        }
+        ctx.test_file.load().unwrap();
+        assert_eq!(ctx.test_file.index.len(), 9);
+    }
+    #[rstest]
+    #[serial]
+    fn test_paranoid_checks_detect_index_drift(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"1").unwrap();
+        ctx.test_file.insert(b"bar", b"2").unwrap();
+        ctx.test_file.read_index().unwrap();
+        let bar_position = ctx.test_file.index[&b"bar".to_vec()];
+        ctx.test_file.index.insert(b"foo".to_vec(), bar_position);
+        ctx.test_file.store_index_on_disk(INDEX_KEY).unwrap();
+
+        let paranoid = Options {
+            paranoid_checks: ParanoidChecks::All,
+            ..Options::default()
+        };
+        let err = ctx.crash_and_reopen(paranoid).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("\"foo\""));
+        assert!(ctx.crash_and_reopen(Options::default()).is_ok());
+    }
+    #[rstest]
+    #[serial]
+    fn test_open_detects_mismatched_files(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        let data = Path::new("test_foo/data");
+        let data_len = std::fs::metadata(data).unwrap().len();
+        let truncated = OpenOptions::new().write(true).open(data).unwrap();
+        truncated.set_len(data_len - 1).unwrap();
+        let err = ctx.crash_and_reopen(Options::default()).unwrap_err();
+        assert!(matches!(
+            ActionKvError::from_io(&err),
+            Some(ActionKvError::FilesMismatched { .. })
+        ));
+
+        let replaced = FileHeader::new(DATA_MAGIC, format::new_generation());
+        std::fs::write(data, replaced.encode()).unwrap();
+        let err = ctx.crash_and_reopen(Options::default()).unwrap_err();
+        assert!(err.to_string().contains("generation"));
+    }
+    #[rstest]
+    #[serial]
+    fn test_varint_lengths() {
+        let options = Options {
+            varint_lengths: true,
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_foo", options);
+        let store = &mut ctx.test_file;
+        store.insert(b"foo", b"bar").unwrap();
+        store.insert(b"baz", &[7; 300]).unwrap();
+        let data_len = std::fs::metadata("test_foo/data").unwrap().len();
+        assert_eq!(data_len, HEADER_LEN + (6 + 6) + (4 + 1 + 2 + 3 + 300));
+
+        let store = ctx.reopen().unwrap();
+        assert_eq!(store.get(b"baz").unwrap(), Some(vec![7; 300]));
+        assert_eq!(
+            store.find(b"foo").unwrap(),
+            Some((HEADER_LEN, b"bar".to_vec()))
+        );
+    }
+    #[rstest]
+    #[serial]
+    fn test_wide_values() {
+        let options = Options {
+            wide_values: true,
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_foo", options);
+        let store = &mut ctx.test_file;
+        store.insert(b"foo", b"bar").unwrap();
+        let data_len = std::fs::metadata("test_foo/data").unwrap().len();
+        assert_eq!(data_len, HEADER_LEN + 16 + 6);
+
+        let store = ctx.reopen().unwrap();
+        assert!(store.framing.wide_values);
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+        assert_eq!(store.get_ref(b"foo").unwrap().unwrap().as_ref(), b"bar");
+        store.compact().unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+    }
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[serial]
+    fn test_checksum_covers_lengths(#[case] legacy_checksums: bool) {
+        let options = Options {
+            legacy_checksums,
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_foo", options);
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        let framing = ctx.test_file.framing;
+        assert_eq!(framing.checksum_lengths, !legacy_checksums);
+
+        // move a byte from the key to the value, the record stays as long
+        let mut data = std::fs::read("test_foo/data").unwrap();
+        let record = &mut data[HEADER_LEN as usize..];
+        record[4] = 2;
+        record[8] = 4;
+        let read = ActionKV::process_records(&mut &record[..], framing);
+        match legacy_checksums {
+            true => assert_eq!(read.unwrap().key, b"fo"),
+            false => assert!(is_corruption(&read.unwrap_err())),
+        }
+
+        let store = ctx.reopen().unwrap();
+        assert_eq!(store.framing, framing);
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_corruption_error(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        ctx.test_file.insert(b"baz", b"qux").unwrap();
+        ctx.test_file.read_index().unwrap();
+        let position = ctx.test_file.position(b"baz").unwrap();
+        let mut data = std::fs::read("test_foo/data").unwrap();
+        *data.last_mut().unwrap() ^= 0xff;
+        std::fs::write("test_foo/data", &data).unwrap();
+
+        let check = |err: io::Error| {
+            assert!(is_corruption(&err));
+            match ActionKvError::from_io(&err) {
+                Some(ActionKvError::Corruption {
+                    offset,
+                    expected,
+                    actual,
+                }) => {
+                    assert_eq!(*offset, Some(position));
+                    assert_ne!(expected, actual);
+                }
+                other => panic!("expected a corruption error, got {:?}", other),
+            }
+        };
+        check(ctx.test_file.get(b"baz").unwrap_err());
+        check(ctx.test_file.get_ref(b"baz").err().unwrap());
+        check(ctx.test_file.scan_filtered(b"", |_, _| true).unwrap_err());
+        check(ctx.test_file.iter().find_map(Result::err).unwrap());
+        check(
+            ctx.test_file
+                .log_iter()
+                .unwrap()
+                .find_map(Result::err)
+                .unwrap(),
+        );
+        assert_eq!(ctx.test_file.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+
+        // the error converts to and from the one the methods return
+        let err = ActionKvError::from(ctx.test_file.get(b"baz").unwrap_err());
+        assert!(matches!(
+            err,
+            ActionKvError::Corruption { offset: Some(offset), .. } if offset == position
+        ));
+        assert!(is_corruption(&io::Error::from(err)));
+        let err = ActionKvError::from(io::Error::new(io::ErrorKind::StorageFull, "full"));
+        assert!(matches!(&err, ActionKvError::Io(err) if err.kind() == io::ErrorKind::StorageFull));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::StorageFull);
+    }
+    #[rstest]
+    #[case(Framing::default())]
+    #[case(Framing { varint_lengths: true, ..Framing::default() })]
+    #[case(Framing { alignment_shift: 12, ..Framing::default() })]
+    #[case(Framing { checksum_lengths: true, ..Framing::default() })]
+    #[case(Framing { wide_values: true, checksum_lengths: true, ..Framing::default() })]
+    #[case(Framing { wide_values: true, varint_lengths: true, ..Framing::default() })]
+    fn test_process_records_never_panics(#[case] framing: Framing) {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(framing.flags() as u64);
+        let mut valid = Vec::new();
+        write_record(&mut valid, framing, b"key", b"value").unwrap();
+        for _ in 0..20_000 {
+            let bytes: ByteString = if rng.gen() {
+                let len = rng.gen_range(0..64);
+                (0..len).map(|_| rng.gen::<u8>()).collect()
+            } else {
+                let mut bytes = valid.clone();
+                let i = rng.gen_range(0..bytes.len());
+                bytes[i] = rng.gen();
+                bytes.truncate(rng.gen_range(0..=bytes.len()));
+                bytes
+            };
+            let _ = ActionKV::process_records(&mut bytes.as_slice(), framing);
+        }
+        // lengths claiming 8 GiB mustn't be allocated before they're read
+        let mut huge = vec![0; 4];
+        huge.extend([0xff; 8]);
+        let err = ActionKV::process_records(&mut huge.as_slice(), Framing::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+    #[rstest]
+    #[serial]
+    fn test_aligned_records() {
+        let options = Options {
+            alignment: Some(4096),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_foo", options);
+        let store = &mut ctx.test_file;
+        store.insert(b"foo", b"bar").unwrap();
+        store.insert(b"baz", &[7; 5000]).unwrap();
+        store.insert(b"qux", b"").unwrap();
+        assert_eq!(std::fs::metadata("test_foo/data").unwrap().len(), 4096 * 5);
+
+        let store = ctx.reopen().unwrap();
+        assert_eq!(store.get(b"baz").unwrap(), Some(vec![7; 5000]));
+        assert_eq!(store.find(b"qux").unwrap(), Some((4096 * 4, Vec::new())));
+
+        let odd = Options {
+            alignment: Some(1000),
+            ..Options::default()
+        };
+        let err = ActionKV::open_with_options(Path::new("test_foo_odd"), odd).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!Path::new("test_foo_odd").exists());
+    }
+    #[rstest]
+    #[serial]
+    fn test_sample(mut ctx: TestCtx) {
+        ctx.test_file.set_tokenizer(Box::new(WordTokenizer));
+        for i in 0..20u8 {
+            ctx.test_file.insert(&[b'k', i], &[i]).unwrap();
+        }
+        let keys = ctx.test_file.sample(5).unwrap();
+        assert_eq!(keys.len(), 5);
+        assert!(keys.iter().all(|key| key[0] == b'k'));
+        let mut all = ctx.test_file.sample(100).unwrap();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 20);
+        for (key, value) in ctx.test_file.sample_pairs(3).unwrap() {
+            assert_eq!(value, vec![key[1]]);
+        }
+    }
+    #[rstest]
+    #[case(Options::default())]
+    #[case(Options { hashed_keys: true, ..Options::default() })]
+    #[serial]
+    fn test_sample_leaves_out_unreadable_keys(#[case] options: Options) {
+        let mut ctx = TestCtx::setup_with_options("test_foo_sample", options);
+        testing::hide_secrets(&mut ctx.test_file, 10);
+        let keys = ctx.test_file.sample(100).unwrap();
+        assert_eq!(keys.len(), 10);
+        assert!(keys.iter().all(|key| key.starts_with(b"public:")));
+        let pairs = ctx.test_file.sample_pairs(100).unwrap();
+        assert!(pairs.iter().all(|(key, _)| key.starts_with(b"public:")));
+    }
+    #[rstest]
+    #[serial]
+    fn test_bulk_load(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"existing", b"value").unwrap();
+        let pairs = (0..1000u32)
+            .flat_map(|i| [(i, b"old"), (i, b"new")])
+            .map(|(i, value)| (format!("key{:04}", i).into_bytes(), value.to_vec()));
+        assert_eq!(ctx.test_file.bulk_load(pairs).unwrap(), 1000);
+
+        let data_len = std::fs::metadata("test_foo/data").unwrap().len();
+        assert_eq!(data_len, HEADER_LEN + (12 + 8 + 5) + 1000 * (12 + 7 + 3));
+        let store = ctx.reopen().unwrap();
+        assert_eq!(store.get(b"key0999").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(store.get(b"existing").unwrap(), Some(b"value".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_bulk_load_parallel(mut ctx: TestCtx) {
+        let pairs = (0..20_000u32).map(|i| {
+            let key = format!("key{}", i % 15_000).into_bytes();
+            (key, i.to_le_bytes().to_vec())
+        });
+        assert_eq!(ctx.test_file.bulk_load_parallel(pairs, 4).unwrap(), 20_000);
+
+        let store = ctx.reopen().unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 15_000);
+        for i in [0u32, 4_999, 5_000, 14_999] {
+            assert_eq!(
+                store.get(format!("key{}", i).as_bytes()).unwrap(),
+                Some((i + 15_000 * (i < 5_000) as u32).to_le_bytes().to_vec())
+            );
+        }
+    }
+    #[rstest]
+    #[serial]
+    fn test_insert_and_get(mut ctx: TestCtx) {
+        let key = b"foo";
+        let value = b"bar";
+        ctx.test_file
+            .insert(key, value)
+            .expect("Unable to insert key value pair into ActionKV file!");
+        let get_value = ctx
+            .test_file
+            .get(b"foo")
+            .expect("Unable to get value pair")
+            .expect("Didnt find value under that key");
+        let decode_value =
+            String::from_utf8(get_value).expect("unable to decode the value into string");
+        assert_eq!("bar", decode_value);
+    }
+
+    #[rstest]
+    #[serial]
+    fn test_get_at(mut ctx: TestCtx) {
+        let key = b"foo";
+        let value = b"bar";
+        ctx.test_file
+            .insert(key, value)
+            .expect("Unable to insert key value pair into ActionKV file!");
+        let get_value = ctx
+            .test_file
+            .get_at(HEADER_LEN, false)
+            .expect("Unable to get value pair");
+        let decode_value =
+            String::from_utf8(get_value.value).expect("unable to decode the value into string");
+        let decode_key =
+            String::from_utf8(get_value.key).expect("unable to decode the value into string");
+        assert_eq!("foo", decode_key);
+        assert_eq!("bar", decode_value);
+    }
+    #[rstest]
+    #[serial]
+    fn test_find(mut ctx: TestCtx) {
+        let key = b"foo";
+        let value = b"bar";
+        ctx.test_file
+            .insert(key, value)
+            .expect("Unable to insert key value pair into ActionKV file!");
+        let find_value = ctx
+            .test_file
+            .find(key)
+            .expect("Unable to get value pair")
+            .unwrap();
+        let decode_key =
+            String::from_utf8(find_value.1).expect("unable to decode the value into string");
+        assert_eq!("bar", decode_key);
+        assert_eq!(find_value.0, HEADER_LEN);
+    }
+    #[rstest]
+    #[serial]
+    fn test_delete(mut ctx: TestCtx) {
+        let key = b"foo";
+        let value = b"bar";
+        ctx.test_file
+            .insert(key, value)
+            .expect("Unable to insert key value pair into ActionKV file!");
+        let get_value = ctx
+            .test_file
+            .get(b"foo")
+            .expect("Unable to get value pair")
+            .expect("Didnt find value under that key");
+        let decode_value =
+            String::from_utf8(get_value).expect("unable to decode the value into string");
+        assert_eq!("bar", decode_value);
+        ctx.test_file
+            .delete(key)
+            .expect("unable to delete value at key");
+        let get_value = ctx.test_file.get(b"foo").expect("Unable to get value pair");
+        assert_eq!(get_value, None);
+    }
+    #[rstest]
+    #[serial]
+    fn test_update(mut ctx: TestCtx) {
+        let key = b"foo";
+        let value = b"bar";
+        ctx.test_file
+            .insert(key, value)
+            .expect("Unable to insert key value pair into ActionKV file!");
+        let get_value = ctx
+            .test_file
+            .get(b"foo")
+            .expect("Unable to get value pair")
+            .expect("Didnt find value under that key");
+        let decode_value =
+            String::from_utf8(get_value).expect("unable to decode the value into string");
+        assert_eq!("bar", decode_value);
+        ctx.test_file
+            .update(key, b"foo")
+            .expect("Unable to update value at the key");
+        let get_value = ctx
+            .test_file
+            .get(b"foo")
+            .expect("Unable to get value pair")
+            .expect("Didnt find value under that key");
+        let decode_value =
+            String::from_utf8(get_value).expect("unable to decode the value into string");
+        assert_eq!("foo", decode_value);
+    }
+    #[rstest]
+    #[serial]
+    fn test_update_with(mut ctx: TestCtx) {
+        let store = &mut ctx.test_file;
+        let bump = |old: Option<ByteString>| {
+            let n = old.map_or(0, |old| old[0]);
+            Some(vec![n + 1])
+        };
+        assert_eq!(store.update_with(b"hits", bump).unwrap(), Some(vec![1]));
+        assert_eq!(store.update_with(b"hits", bump).unwrap(), Some(vec![2]));
+        assert_eq!(store.get(b"hits").unwrap(), Some(vec![2]));
+        assert_eq!(store.update_with(b"hits", |_| None).unwrap(), None);
+        assert_eq!(store.get(b"hits").unwrap(), None);
+        assert_eq!(store.update_with(b"none", |_| None).unwrap(), None);
+    }
+    #[rstest]
+    #[serial]
+    fn test_index_checkpoints() {
+        let options = Options {
+            index_checkpoints: Some(IndexCheckpoints {
+                records: 10,
+                bytes: 1 << 20,
+            }),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_foo", options.clone());
+        for i in 0..9u8 {
+            ctx.test_file.insert(&[i], b"value").unwrap();
+        }
+        assert_eq!(ctx.test_file.stats().io.index.operations, 0);
+        assert_eq!(ctx.test_file.get(&[3]).unwrap(), Some(b"value".to_vec()));
+        ctx.test_file.delete(&[0]).unwrap();
+        assert_eq!(ctx.test_file.stats().io.index.operations, 1);
+
+        ctx.test_file.insert(b"big", &vec![7; 1 << 20]).unwrap();
+        assert_eq!(ctx.test_file.stats().io.index.operations, 2);
+        ctx.test_file.insert(b"late", b"1").unwrap();
+        assert_eq!(ctx.test_file.stats().io.index.operations, 2);
+        ctx.test_file.flush_index().unwrap();
+        let reopened = ctx.reopen().unwrap();
+        assert_eq!(reopened.get(b"late").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(reopened.get(&[0]).unwrap(), None);
+    }
+    #[rstest]
+    #[serial]
+    fn test_flash_profile() {
+        let mut ctx = TestCtx::setup_with_options("test_foo", Options::flash(4096));
+        for i in 0..100u8 {
+            ctx.test_file.insert(&[i], &[i; 100]).unwrap();
+        }
+        // about 11 KB of records, one index save per 4 KiB of them
+        assert_eq!(ctx.test_file.stats().io.index.operations, 2);
+        drop(ctx.test_file.quiesce().unwrap());
+        let reopened = ctx.reopen_with_options(Options::flash(4096)).unwrap();
+        assert!(reopened.framing.varint_lengths);
+        assert_eq!(reopened.get(&[99]).unwrap(), Some(vec![99; 100]));
+    }
+    #[rstest]
+    #[serial]
+    fn test_replay_since_checkpoint() {
+        let options = Options {
+            index_checkpoints: Some(IndexCheckpoints {
+                records: 100,
+                bytes: 1 << 20,
+            }),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_foo", options.clone());
+        let path = Path::new("test_foo");
+        let store = &mut ctx.test_file;
+        store.insert(b"saved", b"1").unwrap();
+        store.flush_index().unwrap();
+        store.insert(b"unsaved", b"2").unwrap();
+        store.delete(b"saved").unwrap();
+        let mut data = OpenOptions::new()
+            .append(true)
+            .open(path.join("data"))
+            .unwrap();
+        data.write_all(b"torn").unwrap();
+
+        // a crash: the index is never saved again
+        let store = ctx.crash_and_reopen(options).unwrap();
+        assert_eq!(store.get(b"unsaved").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"saved").unwrap(), None);
+        assert_eq!(store.stats().io.index.operations, 1);
+        store.insert(b"after", b"3").unwrap();
+        let store = ctx.reopen().unwrap();
+        assert_eq!(store.get(b"after").unwrap(), Some(b"3".to_vec()));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::{platform, ActionKvError};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/*
+    DIRECTORY LOCK
+    an advisory lock on the LOCK file of the store directory. The writer holds
+    it exclusively while it recovers from a crash on open and while compaction
+    renames the new files over, read only opens hold it shared while they read
+    the headers and the index, so they never see the data file of one
+    generation next to the index of another.
+    the writer also holds the WRITER file exclusively for as long as the store
+    is open, so a second writer fails instead of appending over the first.
+    it is a file of its own as readers have to get at LOCK meanwhile.
+*/
+const LOCK_FILE: &str = "LOCK";
+const WRITER_FILE: &str = "WRITER";
+
+/// Held until dropped, closing the file releases the lock.
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+fn lock_file(path: &Path) -> io::Result<File> {
+    let lock = path.join(LOCK_FILE);
+    match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock)
+    {
+        Ok(file) => Ok(file),
+        // readers may not be allowed to write to the directory
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => File::open(&lock),
+        Err(err) => Err(err),
+    }
+}
+
+pub(crate) fn exclusive(path: &Path) -> io::Result<DirLock> {
+    let file = lock_file(path)?;
+    platform::lock(&file, true)?;
+    Ok(DirLock { _file: file })
+}
+
+/// Fails with `ActionKvError::Locked` while another writer has `path` open.
+pub(crate) fn writer(path: &Path) -> io::Result<DirLock> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.join(WRITER_FILE))?;
+    match platform::try_lock(&file)? {
+        true => Ok(DirLock { _file: file }),
+        false => Err(ActionKvError::Locked.into()),
+    }
+}
+
+pub(crate) fn shared(path: &Path) -> io::Result<DirLock> {
+    let file = lock_file(path)?;
+    platform::lock(&file, false)?;
+    Ok(DirLock { _file: file })
+}
@@ -0,0 +1,214 @@
+use crate::access::{self, AccessPolicy};
+use crate::compression::ValueCodec;
+use crate::format::Framing;
+use crate::key_hash::index_key;
+use crate::prefetch::ReadAhead;
+use crate::segment;
+use crate::{ActionKV, ActionKvError, ByteString};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::vec;
+
+/// One record of the log as it was appended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Position of the record, its segment and the offset within it.
+    pub offset: u64,
+    /// Bytes the record takes on disk including its framing.
+    pub size: u64,
+    pub key: ByteString,
+    pub value: ByteString,
+    /// The record deletes its key, its value is empty.
+    pub tombstone: bool,
+    /// The index points at this record, it holds the key's current value.
+    pub current: bool,
+}
+
+/// Every record of the log in append order, see `ActionKV::log_iter`. Reads
+/// through file handles of its own, the sealed segments first and the data
+/// file last. Records appended after it was created aren't visited.
+#[derive(Debug)]
+pub struct LogIter<'a> {
+    /// The segments not started yet with where their records end.
+    segments: vec::IntoIter<(u16, PathBuf, u64)>,
+    f: Option<ReadAhead<File>>,
+    segment: u16,
+    framing: Framing,
+    values: Option<&'a ValueCodec>,
+    index: &'a HashMap<ByteString, u64>,
+    policy: Option<AccessPolicy>,
+    data_start: u64,
+    position: u64,
+    end: u64,
+}
+
+impl LogIter<'_> {
+    /// Starts reading the next segment, `false` once there are none left.
+    fn next_segment(&mut self) -> io::Result<bool> {
+        let Some((segment, path, end)) = self.segments.next() else {
+            self.f = None;
+            return Ok(false);
+        };
+        let mut f = ReadAhead::new(File::open(path)?)?;
+        f.seek(SeekFrom::Start(self.data_start))?;
+        self.f = Some(f);
+        self.segment = segment;
+        self.position = self.data_start;
+        self.end = end;
+        Ok(true)
+    }
+    /// The next record, whether it may be read or not.
+    fn read_next(&mut self) -> Option<io::Result<LogRecord>> {
+        while self.f.is_none() || self.position >= self.end {
+            match self.next_segment() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(err) => {
+                    self.segments = Vec::new().into_iter();
+                    self.f = None;
+                    return Some(Err(err));
+                }
+            }
+        }
+        let f = self.f.as_mut()?;
+        let offset = segment::position(self.segment, self.position);
+        let (record, next) = match ActionKV::process_records(f, self.framing)
+            .and_then(|record| Ok((record, f.stream_position()?)))
+        {
+            Ok(record) => record,
+            Err(err) => {
+                // a record that can't be read leaves no way to find the next one
+                self.segments = Vec::new().into_iter();
+                self.f = None;
+                return Some(Err(ActionKvError::at_offset(err, offset)));
+            }
+        };
+        let size = next - self.position;
+        self.position = next;
+        let value = match self.values {
+            Some(codec) => match codec.decode(record.value) {
+                Ok(value) => value,
+                Err(err) => return Some(Err(err)),
+            },
+            None => record.value,
+        };
+        Some(Ok(LogRecord {
+            offset,
+            size,
+            tombstone: value.is_empty(),
+            current: self
+                .index
+                .get(index_key(self.framing.hashed_keys, &record.key).as_ref())
+                == Some(&offset),
+            key: record.key,
+            value,
+        }))
+    }
+}
+
+impl Iterator for LogIter<'_> {
+    type Item = io::Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.read_next()? {
+                Ok(record) if !access::readable(self.policy, &record.key) => continue,
+                read => return Some(read),
+            }
+        }
+    }
+}
+
+impl ActionKV {
+    /// Iterates over the raw log: every record ever appended and not yet
+    /// compacted away, superseded values and tombstones included, in the
+    /// order they were written. For replication, analytics and forensics.
+    /// Records of keys the access policy doesn't let be read are skipped.
+    pub fn log_iter(&mut self) -> io::Result<LogIter<'_>> {
+        self.read_index()?;
+        let mut segments = Vec::new();
+        for (id, path) in self.segment_files()? {
+            let end = fs::metadata(&path)?.len();
+            segments.push((id, path, end));
+        }
+        let data = segment::segment_path(self.dir("log_iter")?, segment::ACTIVE);
+        segments.push((segment::ACTIVE, data, self.appender.tail()));
+        Ok(LogIter {
+            segments: segments.into_iter(),
+            f: None,
+            segment: segment::ACTIVE,
+            framing: self.framing,
+            values: self.values.as_deref(),
+            index: &self.index,
+            policy: self.options.access_policy,
+            data_start: self.data_start,
+            position: self.data_start,
+            end: self.data_start,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, TestCtx};
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_log_iter")
+    }
+    #[rstest]
+    #[serial]
+    fn test_log_iter_includes_history(mut ctx: TestCtx) {
+        let store = &mut ctx.test_file;
+        store.insert(b"foo", b"1").unwrap();
+        store.insert(b"bar", b"2").unwrap();
+        store.insert(b"foo", b"3").unwrap();
+        store.delete(b"bar").unwrap();
+
+        let records: Vec<_> = store
+            .log_iter()
+            .unwrap()
+            .map(|record| record.unwrap())
+            .collect();
+        let summary: Vec<(&[u8], &[u8], bool, bool)> = records
+            .iter()
+            .map(|record| {
+                (
+                    record.key.as_slice(),
+                    record.value.as_slice(),
+                    record.tombstone,
+                    record.current,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (&b"foo"[..], &b"1"[..], false, false),
+                (b"bar", b"2", false, false),
+                (b"foo", b"3", false, true),
+                (b"bar", b"", true, false),
+            ]
+        );
+        for pair in records.windows(2) {
+            assert_eq!(pair[0].offset + pair[0].size, pair[1].offset);
+        }
+    }
+    #[rstest]
+    #[serial]
+    fn test_log_iter_skips_unreadable_keys(mut ctx: TestCtx) {
+        testing::hide_secrets(&mut ctx.test_file, 5);
+        let keys: Vec<_> = ctx
+            .test_file
+            .log_iter()
+            .unwrap()
+            .map(|record| record.unwrap().key)
+            .collect();
+        assert_eq!(keys.len(), 5);
+        assert!(keys.iter().all(|key| key.starts_with(b"public:")));
+    }
+}
@@ -0,0 +1,74 @@
+use crate::{ActionKV, CompactionReport};
+use std::io;
+
+/*
+    MAINTENANCE
+    the store never starts threads of its own, everything it does happens
+    inside a call. Work that isn't tied to a write, like saving an index the
+    checkpoints let wait or compacting, happens when the host calls tick or
+    maintenance, e.g. a mobile app once it is sent to the background.
+*/
+
+impl ActionKV {
+    /// The cheap part of `maintenance`, fine to call often: ends an access
+    /// tracking window that is over. Does nothing on read only stores.
+    pub fn tick(&mut self) -> io::Result<()> {
+        if self.options.read_only {
+            return Ok(());
+        }
+        self.roll_access_window()
+    }
+    /// `tick`, then saves the index if writes wait for it, and compacts once
+    /// the log grew by `Options::compaction_trigger` bytes since it
+    /// was last compacted or opened. Returns the report of that compaction.
+    /// Does nothing on read only stores.
+    pub fn maintenance(&mut self) -> io::Result<Option<CompactionReport>> {
+        if self.options.read_only {
+            return Ok(None);
+        }
+        self.tick()?;
+        self.flush_index()?;
+        let Some(trigger) = self.options.compaction_trigger else {
+            return Ok(None);
+        };
+        let written = self.appender.tail() + self.segments.sealed_bytes;
+        if written.saturating_sub(self.compacted_tail) < trigger {
+            return Ok(None);
+        }
+        self.compact().map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestCtx;
+    use crate::{IndexCheckpoints, Options};
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[serial]
+    fn test_maintenance() {
+        let options = Options {
+            index_checkpoints: Some(IndexCheckpoints {
+                records: 1000,
+                bytes: u64::MAX,
+            }),
+            compaction_trigger: Some(1000),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_maintenance", options);
+        let store = &mut ctx.test_file;
+        store.insert(b"foo", b"1").unwrap();
+        assert_eq!(store.maintenance().unwrap(), None);
+        assert_eq!(store.unsaved_records, 0);
+
+        for _ in 0..10 {
+            store.insert(b"foo", &[0; 100]).unwrap();
+        }
+        let report = store.maintenance().unwrap().unwrap();
+        assert!(report.bytes_after < report.bytes_before);
+        assert_eq!(store.maintenance().unwrap(), None);
+        assert_eq!(store.get(b"foo").unwrap(), Some(vec![0; 100]));
+    }
+}
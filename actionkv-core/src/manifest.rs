@@ -0,0 +1,194 @@
+use crate::platform;
+use crate::{ByteStr, ByteString, KeyCanonicalization};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/*
+    MANIFEST
+    magic | version | entry_count | entries... | checksum
+    [u8;4]  [u16;1]   [u32;1]                   [u32;1]
+    entry:  tag | len | payload
+            [u16;1] [u32;1] [u8;len]
+    Store wide metadata that belongs to neither the data nor the index file,
+    written to a temporary file and renamed over, so it is replaced whole.
+    Entries with unknown tags are skipped, so older versions can still read
+    manifests of newer ones. checksum covers everything in front of it.
+*/
+pub const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+const MANIFEST_MAGIC: &[u8; 4] = b"AKVM";
+const MANIFEST_VERSION: u16 = 1;
+/// payload: dictionary id [u32;1] followed by the zstd dictionary
+const TAG_DICTIONARY: u16 = 1;
+/// payload: KeyCanonicalization steps [u8;1], one bit each
+const TAG_KEY_CANONICALIZATION: u16 = 2;
+/// payload: ids of the sealed segments [u16;n], oldest first
+const TAG_SEGMENTS: u16 = 3;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// zstd dictionaries compressed values may refer to, by dictionary id.
+    pub dictionaries: Vec<(u32, ByteString)>,
+    /// How keys of the store are normalized, `None` if they aren't.
+    pub key_canonicalization: Option<KeyCanonicalization>,
+    /// Ids of the sealed segments of the data file, oldest first. `None`
+    /// for stores that never sealed one since segments were recorded here,
+    /// their segments are told by their file names.
+    pub segments: Option<Vec<u16>>,
+}
+
+impl Manifest {
+    pub fn encode(&self) -> ByteString {
+        let mut buf = ByteString::new();
+        buf.extend(MANIFEST_MAGIC);
+        // writes into a Vec can't fail
+        let _ = buf.write_u16::<LittleEndian>(MANIFEST_VERSION);
+        let entries = self.dictionaries.len()
+            + self.key_canonicalization.is_some() as usize
+            + self.segments.is_some() as usize;
+        let _ = buf.write_u32::<LittleEndian>(entries as u32);
+        for (id, dictionary) in &self.dictionaries {
+            let _ = buf.write_u16::<LittleEndian>(TAG_DICTIONARY);
+            let _ = buf.write_u32::<LittleEndian>(4 + dictionary.len() as u32);
+            let _ = buf.write_u32::<LittleEndian>(*id);
+            buf.extend(dictionary);
+        }
+        if let Some(canonicalization) = self.key_canonicalization {
+            let _ = buf.write_u16::<LittleEndian>(TAG_KEY_CANONICALIZATION);
+            let _ = buf.write_u32::<LittleEndian>(1);
+            buf.push(canonicalization.to_bits());
+        }
+        if let Some(segments) = &self.segments {
+            let _ = buf.write_u16::<LittleEndian>(TAG_SEGMENTS);
+            let _ = buf.write_u32::<LittleEndian>(2 * segments.len() as u32);
+            for id in segments {
+                let _ = buf.write_u16::<LittleEndian>(*id);
+            }
+        }
+        let checksum = crc32::checksum_ieee(&buf);
+        let _ = buf.write_u32::<LittleEndian>(checksum);
+        buf
+    }
+    pub fn decode(bytes: &ByteStr) -> io::Result<Self> {
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+        if bytes.len() < 14 || &bytes[..4] != MANIFEST_MAGIC {
+            return Err(invalid("not a manifest".to_string()));
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        let saved_checksum = LittleEndian::read_u32(checksum);
+        let checksum = crc32::checksum_ieee(body);
+        if checksum != saved_checksum {
+            return Err(invalid(format!(
+                "Manifest corruption encountered {:08x} != {:08x}",
+                checksum, saved_checksum
+            )));
+        }
+        let mut f = &body[4..];
+        let version = f.read_u16::<LittleEndian>()?;
+        if version > MANIFEST_VERSION {
+            return Err(invalid(format!("unsupported manifest version {}", version)));
+        }
+        let entries = f.read_u32::<LittleEndian>()?;
+        let mut manifest = Manifest::default();
+        for _ in 0..entries {
+            let tag = f.read_u16::<LittleEndian>()?;
+            let len = f.read_u32::<LittleEndian>()? as usize;
+            if len > f.len() {
+                return Err(invalid("manifest entry is cut short".to_string()));
+            }
+            let (mut payload, rest) = f.split_at(len);
+            f = rest;
+            if tag == TAG_DICTIONARY {
+                let id = payload.read_u32::<LittleEndian>()?;
+                manifest.dictionaries.push((id, payload.to_vec()));
+            } else if tag == TAG_KEY_CANONICALIZATION {
+                let bits = payload.read_u8()?;
+                manifest.key_canonicalization = Some(KeyCanonicalization::from_bits(bits));
+            } else if tag == TAG_SEGMENTS {
+                let mut segments = Vec::with_capacity(len / 2);
+                while !payload.is_empty() {
+                    segments.push(payload.read_u16::<LittleEndian>()?);
+                }
+                manifest.segments = Some(segments);
+            }
+        }
+        Ok(manifest)
+    }
+    /// The manifest of the store at `path`, empty if it has none.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        match fs::read(path.join(MANIFEST_FILE)) {
+            Ok(bytes) => Manifest::decode(&bytes),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(err) => Err(err),
+        }
+    }
+    /// Durably replaces the manifest of the store at `path`.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.join(MANIFEST_TMP);
+        let mut f = File::create(&tmp)?;
+        f.write_all(&self.encode())?;
+        f.sync_all()?;
+        fs::rename(&tmp, path.join(MANIFEST_FILE))?;
+        platform::sync_dir(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    fn test_manifest_round_trip() {
+        let manifest = Manifest {
+            dictionaries: vec![(7, b"dict".to_vec()), (9, Vec::new())],
+            key_canonicalization: Some(KeyCanonicalization {
+                trim: true,
+                ..KeyCanonicalization::default()
+            }),
+            segments: Some(vec![3, 4]),
+        };
+        let encoded = manifest.encode();
+        assert_eq!(Manifest::decode(&encoded).unwrap(), manifest);
+
+        let mut corrupt = encoded.clone();
+        corrupt[12] ^= 1;
+        assert!(Manifest::decode(&corrupt).is_err());
+        assert!(Manifest::decode(&encoded[..10]).is_err());
+    }
+    #[rstest]
+    fn test_manifest_layout() {
+        let manifest = Manifest {
+            dictionaries: vec![(0x0102_0304, b"d".to_vec())],
+            key_canonicalization: None,
+            segments: None,
+        };
+        let mut expected = b"AKVM".to_vec();
+        expected.extend([1, 0, 1, 0, 0, 0]);
+        expected.extend([1, 0, 5, 0, 0, 0, 4, 3, 2, 1, b'd']);
+        let checksum = crc32::checksum_ieee(&expected);
+        expected.extend(checksum.to_le_bytes());
+        assert_eq!(manifest.encode(), expected);
+    }
+    #[rstest]
+    fn test_manifest_skips_unknown_tags() {
+        let mut bytes = b"AKVM".to_vec();
+        bytes.extend([1, 0, 2, 0, 0, 0]);
+        bytes.extend([0xff, 0xff, 2, 0, 0, 0, 0xaa, 0xbb]);
+        bytes.extend([2, 0, 1, 0, 0, 0, 0b100]);
+        let checksum = crc32::checksum_ieee(&bytes);
+        bytes.extend(checksum.to_le_bytes());
+        let manifest = Manifest::decode(&bytes).unwrap();
+        assert!(manifest.dictionaries.is_empty());
+        assert_eq!(
+            manifest.key_canonicalization,
+            Some(KeyCanonicalization {
+                lowercase_ascii: true,
+                ..KeyCanonicalization::default()
+            })
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{ActionKV, ByteString};
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+
+/// Control bytes the hash table keeps past its buckets, one SIMD group.
+const TABLE_GROUP_WIDTH: usize = 16;
+
+/// Estimated heap bytes the store holds, see `ActionKV::memory_usage`. The
+/// store keeps no caches or bloom filters, everything else it knows is read
+/// from disk when asked for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The in-memory index: its hash table and the keys in it.
+    pub keydir: u64,
+    /// Keys deleted since the last compaction, remembered to catch
+    /// transactions conflicting with the deletes.
+    pub write_log: u64,
+    /// Buffers reused from one record to the next.
+    pub buffers: u64,
+    /// zstd dictionaries of stores with `Options::compression`.
+    pub dictionaries: u64,
+    /// Prefix counts of `Options::access_tracking`.
+    pub access_tracking: u64,
+    /// Values kept in memory with `Options::inline_values`.
+    pub inline_values: u64,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.keydir
+            + self.write_log
+            + self.buffers
+            + self.dictionaries
+            + self.access_tracking
+            + self.inline_values
+    }
+    /// Estimated bytes the keydir of a store with `keys` keys of
+    /// `average_key_len` bytes takes, for sizing a store before it is
+    /// loaded. Stores with `Options::hashed_keys` keep 16 byte keys.
+    pub fn estimate_keydir(keys: u64, average_key_len: u64) -> u64 {
+        let buckets = match keys {
+            0 => 0,
+            // the table grows once it is 7/8 full
+            keys => (keys as usize * 8).div_ceil(7).next_power_of_two(),
+        };
+        (table_bytes::<u64>(buckets) as u64).saturating_add(keys.saturating_mul(average_key_len))
+    }
+}
+
+/// Bytes of a hash table of `buckets` entries from keys to `V`.
+fn table_bytes<V>(buckets: usize) -> usize {
+    match buckets {
+        0 => 0,
+        buckets => buckets * (mem::size_of::<(ByteString, V)>() + 1) + TABLE_GROUP_WIDTH,
+    }
+}
+
+/// Bytes of `map` with the keys it holds.
+fn map_bytes<V>(map: &HashMap<ByteString, V>) -> u64 {
+    let buckets = match map.capacity() {
+        0 => 0,
+        // capacity is 7/8 of the buckets, rounded down
+        capacity => (capacity * 8 / 7).next_power_of_two(),
+    };
+    let keys: usize = map.keys().map(|key| key.capacity()).sum();
+    (table_bytes::<V>(buckets) + keys) as u64
+}
+
+impl ActionKV {
+    /// Estimated heap bytes the store holds at most between operations, to
+    /// budget memory for it. Every operation has the keydir loaded, it is
+    /// loaded here too and walked, so this costs as much as the index is big.
+    pub fn memory_usage(&mut self) -> io::Result<MemoryUsage> {
+        self.read_index()?;
+        Ok(MemoryUsage {
+            keydir: map_bytes(&self.index),
+            write_log: map_bytes(self.write_log.deleted_keys()),
+            buffers: (self.read_buf.capacity() + self.appender.buffered()) as u64,
+            dictionaries: self
+                .values
+                .as_ref()
+                .map_or(0, |values| values.dictionary_bytes() as u64),
+            access_tracking: self
+                .access_tracker
+                .as_ref()
+                .map_or(0, |tracker| map_bytes(tracker.counts())),
+            inline_values: {
+                let entries = self.inline.entries();
+                let values: usize = entries.values().map(|(_, value)| value.capacity()).sum();
+                map_bytes(entries) + values as u64
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[serial]
+    fn test_memory_usage() {
+        let mut ctx = TestCtx::setup("test_memory");
+        let store = &mut ctx.test_file;
+        let empty = store.memory_usage().unwrap();
+        let pairs: Vec<_> = (0..10_000)
+            .map(|i| (format!("key:{:06}", i).into_bytes(), b"value".to_vec()))
+            .collect();
+        store.bulk_load(pairs).unwrap();
+        let loaded = store.memory_usage().unwrap();
+        assert!(loaded.keydir > empty.keydir);
+        assert_eq!(loaded.write_log, 0);
+        assert!(loaded.total() >= loaded.keydir);
+
+        // the estimate is in the same ballpark as what the keydir took
+        let estimate = MemoryUsage::estimate_keydir(10_000, 10);
+        assert!(estimate * 2 > loaded.keydir && estimate < loaded.keydir * 2);
+
+        store.delete(b"key:000001").unwrap();
+        assert!(store.memory_usage().unwrap().write_log > 0);
+        store.compact().unwrap();
+        assert_eq!(store.memory_usage().unwrap().write_log, 0);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::key_hash::{murmur3_128, KEY_HASH_LEN};
+use crate::{ActionKV, ByteStr, ByteString, KeyValuePair};
+use std::io;
+
+/*
+    MERKLE TREE
+    live user keys are split into 2^depth buckets by the leading bits of the
+    MurmurHash3 of the key, so two replicas holding the same keys put them in
+    the same buckets whatever order they were written in. A leaf is the
+    wrapping sum of the hashes of `key_len | key | value` of its keys, an
+    inner node the hash of its two children. Comparing two trees from the
+    root down only descends into nodes that differ, the buckets reached are
+    the only ones that need syncing.
+    encoded: depth | leaves
+             [u8;1]  [[u8;16];2^depth]
+*/
+const MAX_MERKLE_DEPTH: u8 = 24;
+
+pub type MerkleHash = [u8; KEY_HASH_LEN];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    depth: u8,
+    /// Level by level from the root, the children of node i are 2i+1 and 2i+2.
+    nodes: Vec<MerkleHash>,
+}
+
+fn check_depth(depth: u8) -> io::Result<()> {
+    if depth > MAX_MERKLE_DEPTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "merkle tree depth is limited to {}, got {}",
+                MAX_MERKLE_DEPTH, depth
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn entry_hash(key: &ByteStr, value: &ByteStr) -> u128 {
+    let mut entry = ByteString::with_capacity(4 + key.len() + value.len());
+    entry.extend((key.len() as u32).to_le_bytes());
+    entry.extend(key);
+    entry.extend(value);
+    u128::from_le_bytes(murmur3_128(&entry))
+}
+
+impl MerkleTree {
+    /// Bucket `key` falls into in a tree of `depth` levels below the root.
+    pub fn bucket_of(key: &ByteStr, depth: u8) -> u32 {
+        let hash = murmur3_128(key);
+        let top = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]);
+        top.checked_shr(32 - depth as u32).unwrap_or(0)
+    }
+    /// Completes a tree from its leaves, whose count has to be a power of two.
+    fn from_leaves(leaves: Vec<MerkleHash>) -> Self {
+        let depth = leaves.len().trailing_zeros() as u8;
+        let first_leaf = leaves.len() - 1;
+        let mut nodes = vec![MerkleHash::default(); first_leaf];
+        nodes.extend(leaves);
+        for i in (0..first_leaf).rev() {
+            let mut children = [0u8; 2 * KEY_HASH_LEN];
+            children[..KEY_HASH_LEN].copy_from_slice(&nodes[2 * i + 1]);
+            children[KEY_HASH_LEN..].copy_from_slice(&nodes[2 * i + 2]);
+            nodes[i] = murmur3_128(&children);
+        }
+        MerkleTree { depth, nodes }
+    }
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+    /// Equal for two stores holding the same keys and values.
+    pub fn root(&self) -> MerkleHash {
+        self.nodes[0]
+    }
+    pub fn leaves(&self) -> &[MerkleHash] {
+        &self.nodes[(1 << self.depth) - 1..]
+    }
+    /// Buckets whose keys differ between the stores the two trees were built
+    /// from, in ascending order. Both trees need the same depth.
+    pub fn diff(&self, other: &MerkleTree) -> io::Result<Vec<u32>> {
+        if self.depth != other.depth {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "merkle trees of depth {} and {} can't be compared",
+                    self.depth, other.depth
+                ),
+            ));
+        }
+        let first_leaf = (1 << self.depth) - 1;
+        let mut buckets = Vec::new();
+        let mut pending = vec![0];
+        while let Some(i) = pending.pop() {
+            if self.nodes[i] == other.nodes[i] {
+                continue;
+            }
+            if i >= first_leaf {
+                buckets.push((i - first_leaf) as u32);
+            } else {
+                // right first, so buckets come off the stack in order
+                pending.push(2 * i + 2);
+                pending.push(2 * i + 1);
+            }
+        }
+        Ok(buckets)
+    }
+    /// Only the leaves are encoded, the inner nodes follow from them.
+    pub fn encode(&self) -> ByteString {
+        let leaves = self.leaves();
+        let mut buf = ByteString::with_capacity(1 + leaves.len() * KEY_HASH_LEN);
+        buf.push(self.depth);
+        for leaf in leaves {
+            buf.extend(leaf);
+        }
+        buf
+    }
+    pub fn decode(bytes: &ByteStr) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+        let (&depth, leaves) = bytes
+            .split_first()
+            .ok_or_else(|| invalid("merkle tree is empty"))?;
+        check_depth(depth).map_err(|err| invalid(&err.to_string()))?;
+        if leaves.len() != KEY_HASH_LEN << depth {
+            return Err(invalid("merkle tree has the wrong number of leaves"));
+        }
+        let leaves = leaves
+            .chunks_exact(KEY_HASH_LEN)
+            .map(|leaf| {
+                let mut hash = MerkleHash::default();
+                hash.copy_from_slice(leaf);
+                hash
+            })
+            .collect();
+        Ok(MerkleTree::from_leaves(leaves))
+    }
+}
+
+impl ActionKV {
+    /// Merkle tree over every live key and value, with `2^depth` buckets for
+    /// a `depth` of up to 24. Replicas exchange trees, `MerkleTree::diff` them
+    /// and only sync the buckets that differ with `merkle_bucket`. Reads the
+    /// whole store, leaving out the keys the access policy doesn't let be
+    /// read.
+    pub fn merkle_tree(&mut self, depth: u8) -> io::Result<MerkleTree> {
+        check_depth(depth)?;
+        self.read_index()?;
+        let mut sums = vec![0u128; 1 << depth];
+        for key in self.user_keys(b"")? {
+            if !self.readable(&key) {
+                continue;
+            }
+            if let Some(value) = self.current_value(&key)? {
+                let bucket = MerkleTree::bucket_of(&key, depth) as usize;
+                sums[bucket] = sums[bucket].wrapping_add(entry_hash(&key, &value));
+            }
+        }
+        let leaves = sums.into_iter().map(u128::to_le_bytes).collect();
+        Ok(MerkleTree::from_leaves(leaves))
+    }
+    /// Every live key and value in `bucket` of a tree of `depth`, sorted by
+    /// key. Keys one replica has in a differing bucket and the other doesn't
+    /// were deleted on one side or never reached it.
+    pub fn merkle_bucket(&mut self, depth: u8, bucket: u32) -> io::Result<Vec<KeyValuePair>> {
+        check_depth(depth)?;
+        self.read_index()?;
+        let keys: Vec<ByteString> = self
+            .user_keys(b"")?
+            .into_iter()
+            .filter(|key| MerkleTree::bucket_of(key, depth) == bucket && self.readable(key))
+            .collect();
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.current_value(&key)? {
+                pairs.push(KeyValuePair { key, value });
+            }
+        }
+        Ok(pairs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestCtx};
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[serial]
+    fn test_merkle_diff_finds_changed_buckets() {
+        let mut primary = TestCtx::setup("test_merkle");
+        let mut replica = TestCtx::setup("test_merkle_replica");
+        let keys: Vec<ByteString> = (0..200)
+            .map(|i| format!("key:{}", i).into_bytes())
+            .collect();
+        for key in &keys {
+            primary.test_file.insert(key, b"value").unwrap();
+        }
+        for key in keys.iter().rev() {
+            replica.test_file.insert(key, b"value").unwrap();
+        }
+        let depth = 4;
+        let tree = primary.test_file.merkle_tree(depth).unwrap();
+        assert_eq!(tree, replica.test_file.merkle_tree(depth).unwrap());
+        assert_eq!(tree.leaves().len(), 16);
+
+        replica.test_file.update(b"key:7", b"changed").unwrap();
+        replica.test_file.delete(b"key:42").unwrap();
+        let replica_tree = replica.test_file.merkle_tree(depth).unwrap();
+        assert_ne!(tree.root(), replica_tree.root());
+        let mut expected = vec![
+            MerkleTree::bucket_of(b"key:7", depth),
+            MerkleTree::bucket_of(b"key:42", depth),
+        ];
+        expected.sort();
+        expected.dedup();
+        assert_eq!(tree.diff(&replica_tree).unwrap(), expected);
+
+        let bucket = MerkleTree::bucket_of(b"key:42", depth);
+        let on_primary = primary.test_file.merkle_bucket(depth, bucket).unwrap();
+        let on_replica = replica.test_file.merkle_bucket(depth, bucket).unwrap();
+        assert!(on_primary.iter().any(|pair| pair.key == b"key:42"));
+        assert!(!on_replica.iter().any(|pair| pair.key == b"key:42"));
+
+        let decoded = MerkleTree::decode(&replica_tree.encode()).unwrap();
+        assert_eq!(decoded, replica_tree);
+        assert!(tree
+            .diff(&primary.test_file.merkle_tree(2).unwrap())
+            .is_err());
+        assert!(primary.test_file.merkle_tree(MAX_MERKLE_DEPTH + 1).is_err());
+    }
+    #[rstest]
+    #[serial]
+    fn test_merkle_leaves_out_unreadable_keys() {
+        let mut ctx = TestCtx::setup("test_merkle");
+        let mut public = TestCtx::setup("test_merkle_replica");
+        testing::hide_secrets(&mut ctx.test_file, 50);
+        for i in 0..50u8 {
+            public
+                .test_file
+                .insert(format!("public:{}", i).as_bytes(), &[i])
+                .unwrap();
+        }
+        let tree = ctx.test_file.merkle_tree(3).unwrap();
+        assert_eq!(tree, public.test_file.merkle_tree(3).unwrap());
+        for bucket in 0..8 {
+            let pairs = ctx.test_file.merkle_bucket(3, bucket).unwrap();
+            assert!(pairs.iter().all(|pair| pair.key.starts_with(b"public:")));
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use crate::stats::OpClass;
+use crate::{ActionKV, ByteStr, ByteString, INDEX_KEY};
+use std::io;
+
+/// Last key a migration got through, committed together with the index so an
+/// interrupted migration picks up right after it.
+const MIGRATION_KEY: &ByteStr = b"+migrate";
+const MIGRATION_BATCH: u64 = 1000;
+
+/// Passed to the progress callback after every committed batch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MigrationProgress {
+    /// Keys gone through, including the ones done before a resume.
+    pub done: u64,
+    pub total: u64,
+    /// Keys whose value the transform changed in this run.
+    pub rewritten: u64,
+}
+
+impl ActionKV {
+    /// Rewrites every live value through `transform`, `None` leaves a value
+    /// as it is. See `migrate_values_with_progress`.
+    pub fn migrate_values<F>(&mut self, transform: F) -> io::Result<MigrationProgress>
+    where
+        F: FnMut(&ByteStr, ByteString) -> Option<ByteString>,
+    {
+        self.migrate_values_with_progress(transform, |_| {})
+    }
+    /// `migrate_values` reporting to `progress` as it goes. Keys are migrated
+    /// in sorted order and the position is committed with every batch, so when
+    /// the process dies halfway, calling this again with the same transform
+    /// continues where it stopped without transforming any value twice.
+    pub fn migrate_values_with_progress<F, P>(
+        &mut self,
+        mut transform: F,
+        mut progress: P,
+    ) -> io::Result<MigrationProgress>
+    where
+        F: FnMut(&ByteStr, ByteString) -> Option<ByteString>,
+        P: FnMut(MigrationProgress),
+    {
+        self.op_class = OpClass::Maintenance;
+        self.read_index()?;
+        let resume_after = self.value_of(MIGRATION_KEY, true)?;
+        let keys = self.user_keys(b"")?;
+        let start = match &resume_after {
+            Some(last) => keys.partition_point(|key| key <= last),
+            None => 0,
+        };
+        let mut state = MigrationProgress {
+            done: start as u64,
+            total: keys.len() as u64,
+            rewritten: 0,
+        };
+        for key in &keys[start..] {
+            let Some(value) = self.current_value(key)? else {
+                continue;
+            };
+            if let Some(new_value) = transform(key, value.clone()) {
+                if new_value != value {
+                    self.unindex_terms(key)?;
+                    self.put_value(key, &new_value)?;
+                    self.index_terms(key, &new_value)?;
+                    self.io_stats.record_operation(
+                        OpClass::Maintenance,
+                        (key.len() + new_value.len()) as u64,
+                    );
+                    state.rewritten += 1;
+                }
+            }
+            state.done += 1;
+            if state.done.is_multiple_of(MIGRATION_BATCH) {
+                self.insert_(MIGRATION_KEY, key)?;
+                self.store_index_on_disk(INDEX_KEY)?;
+                self.read_index()?;
+                progress(state);
+            }
+        }
+        self.index.remove(MIGRATION_KEY);
+        self.store_index_on_disk(INDEX_KEY)?;
+        progress(state);
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::Options;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_migrate")
+    }
+    #[rstest]
+    #[serial]
+    fn test_migrate_values(mut ctx: TestCtx) {
+        let pairs = (0..2500u32).map(|i| (format!("key{:04}", i).into_bytes(), b"v1".to_vec()));
+        ctx.test_file.bulk_load(pairs).unwrap();
+        ctx.test_file.insert(b"keep", b"v0").unwrap();
+        let mut reports = Vec::new();
+        let state = ctx
+            .test_file
+            .migrate_values_with_progress(
+                |_, value| (value == b"v1").then(|| b"v2".to_vec()),
+                |state| reports.push(state.done),
+            )
+            .unwrap();
+        assert_eq!(state.rewritten, 2500);
+        assert_eq!(reports, vec![1000, 2000, 2501]);
+
+        let store = ctx.reopen().unwrap();
+        assert_eq!(store.get(b"key2499").unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(store.get(b"keep").unwrap(), Some(b"v0".to_vec()));
+        assert_eq!(store.get(MIGRATION_KEY).unwrap(), None);
+    }
+    #[rstest]
+    #[serial]
+    fn test_interrupted_migration_resumes(mut ctx: TestCtx) {
+        let pairs = (0..1500u32).map(|i| (format!("key{:04}", i).into_bytes(), vec![0]));
+        ctx.test_file.bulk_load(pairs).unwrap();
+        let increment = |_: &ByteStr, value: ByteString| Some(vec![value[0] + 1]);
+        let crashed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            ctx.test_file
+                .migrate_values_with_progress(increment, |_| panic!("crash"))
+        }));
+        assert!(crashed.is_err());
+
+        let store = ctx.crash_and_reopen(Options::default()).unwrap();
+        let state = store.migrate_values(increment).unwrap();
+        assert_eq!(state.rewritten, 500);
+        for key in [b"key0000", b"key0999", b"key1000", b"key1499"] {
+            assert_eq!(store.get(key).unwrap(), Some(vec![1]));
+        }
+    }
+    #[rstest]
+    #[serial]
+    fn test_migrate_chunked_values() {
+        let options = Options {
+            chunk_size: Some(10),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_migrate", options);
+        let store = &mut ctx.test_file;
+        store.insert(b"big", &[1; 50]).unwrap();
+        store.insert(b"small", &[1]).unwrap();
+        store.insert(b"grows", &[1]).unwrap();
+        let state = store
+            .migrate_values(|key, mut value| {
+                value.iter_mut().for_each(|byte| *byte += 1);
+                if key == b"grows" {
+                    value.resize(30, 2);
+                }
+                Some(value)
+            })
+            .unwrap();
+        assert_eq!((state.total, state.rewritten), (3, 3));
+        assert_eq!(store.get(b"big").unwrap(), Some(vec![2; 50]));
+        assert_eq!(store.get(b"small").unwrap(), Some(vec![2]));
+        assert_eq!(store.get(b"grows").unwrap(), Some(vec![2; 30]));
+        store.read_index().unwrap();
+        assert!(store.position(b"grows").is_none());
+    }
+}
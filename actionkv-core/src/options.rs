@@ -0,0 +1,218 @@
+#[cfg(any(test, feature = "access-log"))]
+use crate::access_log::AccessLog;
+#[cfg(feature = "zstd")]
+use crate::compression::Compression;
+#[cfg(any(test, feature = "fault-injection"))]
+use crate::fault::FaultInjector;
+use crate::format::{Framing, MAX_ALIGNMENT_SHIFT};
+#[cfg(any(test, feature = "torn-write-trace"))]
+use crate::torn_trace::TornWriteTrace;
+use crate::{
+    AccessPolicy, AccessTracking, ByteString, Clock, IndexCodec, KeyCanonicalization, RetryPolicy,
+};
+use std::io;
+use std::sync::Arc;
+
+/// How much of the index `open` cross-checks against the data file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParanoidChecks {
+    #[default]
+    Off,
+    /// Check this many randomly picked index entries.
+    Sample(usize),
+    /// Check every index entry.
+    All,
+}
+
+/// How often the index is saved, see `Options::index_checkpoints`. It is
+/// saved as soon as either threshold is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexCheckpoints {
+    /// Records written since the index was last saved.
+    pub records: u64,
+    /// Bytes appended to the data file since the index was last saved.
+    pub bytes: u64,
+}
+
+/// Settings used by `ActionKV::open_with_options`, `Options::default()` is what `open` uses.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Verify on open that index offsets point at intact records holding the
+    /// indexed key, failing instead of serving wrong values after index drift.
+    pub paranoid_checks: ParanoidChecks,
+    /// Frame data records of a newly created store with varint encoded lengths,
+    /// which shrinks the 12 byte record header to as little as 6 bytes for small
+    /// keys and values. Existing stores keep the framing they were created with.
+    pub varint_lengths: bool,
+    /// Pad data records of a newly created store so each one starts on a multiple
+    /// of this many bytes (a power of two, e.g. 4096), as direct I/O and block level
+    /// dedup need. Existing stores keep the alignment they were created with.
+    pub alignment: Option<u32>,
+    /// Frame data records of a newly created store with u64 value lengths, so
+    /// a single value can be larger than 4 GiB, e.g. media files or model
+    /// weights. Costs 4 bytes per record without `varint_lengths`. Existing
+    /// stores keep the framing they were created with.
+    pub wide_values: bool,
+    /// Don't check record checksums on `get` and `get_ref`, for filesystems
+    /// that checksum data themselves, as the CRC dominates the cost of reading
+    /// large values. Scans, compaction and `verify` still check every record.
+    pub skip_checksums: bool,
+    /// Create the store in format version 1, whose record checksums cover
+    /// only the key and value, so versions of this library from before the
+    /// lengths were checksummed too can open it. Existing stores keep the
+    /// version they were created with, compaction included.
+    pub legacy_checksums: bool,
+    /// Open an existing store for reading only, next to its writer and any
+    /// number of other readers. Writes fail with `ActionKvError::ReadOnly`, records
+    /// the writer adds later show up after `ActionKV::refresh`. Without it,
+    /// opening a store another writer has open fails with `ActionKvError::Locked`.
+    pub read_only: bool,
+    /// Keep a 16 byte hash of every key of a newly created store in the index
+    /// instead of the key, which saves memory when keys are long. Reads compare
+    /// the key stored in the record, and anything listing keys reads them back
+    /// from the data file. Existing stores keep the mode they were created with.
+    pub hashed_keys: bool,
+    /// Save the index once enough was written since it was last saved
+    /// instead of after every write, which bounds how much saving the
+    /// index costs per write. `None` saves it after every write. Dropping
+    /// the store and `flush_index` save what is left, after a crash `open`
+    /// replays the records written after the last save, the thresholds also
+    /// bound how long that takes. Without this, `open` drops such records.
+    pub index_checkpoints: Option<IndexCheckpoints>,
+    /// Save the key count and a hash of the index along with it, check the
+    /// index against them whenever it is loaded, and before trusting a
+    /// saved index on open replay the data file up to where it was saved
+    /// and check that too. Catches bugs in saving and loading the index,
+    /// e.g. a broken `index_codec`, with `ActionKvError::IndexDiverged` instead
+    /// of wrong answers, at the cost of reading the whole data file on open.
+    pub index_digests: bool,
+    /// Split values longer than this many bytes into chunk records and a
+    /// manifest record when inserting them, so a value is never limited to
+    /// what fits one record. `get` puts them back together, `get_to` streams
+    /// them. Scans, exports and other reads over the whole store skip chunked
+    /// values. Can change from one open to the next.
+    pub chunk_size: Option<u32>,
+    /// Keep values of up to this many bytes in memory next to the index,
+    /// so reads of small values like flags and settings don't touch the
+    /// data file. They are saved in the index file along with the index,
+    /// only the changed ones when the index is saved as a delta, and all of
+    /// them when it is rewritten, which suits a few thousand small values
+    /// better than millions. Can change from one open to the next.
+    pub inline_values: Option<u32>,
+    /// Seal the data file once it grew past this many bytes and start a new
+    /// one, so the log is kept in files of about this size: `data.0001`,
+    /// `data.0002` and so on, then `data`. Sealed segments are never written
+    /// again, `compact_segments` merges them without touching the data file
+    /// and `compact` folds them into it. `None` keeps a single data file, as
+    /// do stores opened on file handles. Can change from one open to the
+    /// next.
+    pub segment_size: Option<u64>,
+    /// Share of a sealed segment's bytes that has to be overwritten values
+    /// and tombstones for `compact_segments` to merge it, see
+    /// `ActionKV::segment_scores`. 0.0 merges every segment with any
+    /// garbage at all.
+    pub segment_garbage_ratio: f64,
+    /// Caps what `compact` reads and writes at this many bytes a second, so
+    /// it doesn't starve other users of a shared disk. `None` runs it flat
+    /// out, `set_compaction_rate` changes it on an open store.
+    pub compaction_rate: Option<u64>,
+    /// Compact in `maintenance` once the data file grew by this many bytes
+    /// since it was last compacted or opened, `None` leaves compacting to
+    /// explicit `compact` calls.
+    pub compaction_trigger: Option<u64>,
+    /// Key prefixes whose records `compact` writes next to each other, one
+    /// contiguous region per prefix, so scans of them read the data file
+    /// sequentially. A key goes with the first prefix it starts with.
+    pub key_groups: Vec<ByteString>,
+    /// Normalize keys before writing and looking them up. Recorded in the
+    /// manifest of a newly created store, existing stores keep the
+    /// canonicalization they were created with.
+    pub key_canonicalization: KeyCanonicalization,
+    /// Sample accesses to find the hottest key prefixes, reported by `stats()`.
+    pub access_tracking: Option<AccessTracking>,
+    /// Source of the current time, `None` uses the system clock.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// How the index is persisted, `None` uses `FixintCodec`. Has to stay the
+    /// same for the life of a store.
+    pub index_codec: Option<Arc<dyn IndexCodec>>,
+    /// Asked before every get, insert, delete and prefix scan, and for every
+    /// key a transaction, `swap`, `move_value` or bulk load writes, so code
+    /// embedding the store can keep plugins to their own keys. A denied
+    /// operation fails with `ActionKvError::AccessDenied` before anything is
+    /// written. The store's own bookkeeping isn't checked.
+    pub access_policy: Option<AccessPolicy>,
+    /// Reports every get, insert, delete and prefix scan with its latency
+    /// and a salted hash of the key, for audits that mustn't see the keys.
+    #[cfg(any(test, feature = "access-log"))]
+    pub access_log: Option<AccessLog>,
+    /// Retry reads and writes that fail with a transient error, see
+    /// `is_transient`, after a growing pause. Once the attempts are used
+    /// up the call fails with `ActionKvError::RetriesExhausted`. `None` fails
+    /// on the first error.
+    pub retry: Option<RetryPolicy>,
+    /// zstd compress the values of a newly created store, and of any store
+    /// once it is compacted, against a dictionary every compaction trains
+    /// from a sample of the live values.
+    #[cfg(feature = "zstd")]
+    pub compression: Option<Compression>,
+    /// Makes chosen reads, writes and syncs fail, for testing error handling.
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub faults: Option<FaultInjector>,
+    /// Records the torn-write windows of the store and can crash it in one,
+    /// for testing and learning about crash recovery.
+    #[cfg(any(test, feature = "torn-write-trace"))]
+    pub torn_write_trace: Option<TornWriteTrace>,
+}
+
+impl Options {
+    /// Preset for flash and SD card storage with `erase_block` byte erase
+    /// blocks, cutting down on what is written over and over. Records get
+    /// varint lengths, and the index is saved once per erase block of
+    /// appended data instead of after every write, `open` replays what came after the last save. Records
+    /// aren't padded to the erase block, which would multiply what is
+    /// written, and `bulk_load` is still the way to hand the device large
+    /// coalesced writes.
+    pub fn flash(erase_block: u32) -> Self {
+        Options {
+            varint_lengths: true,
+            index_checkpoints: Some(IndexCheckpoints {
+                records: u64::MAX,
+                bytes: erase_block.max(1) as u64,
+            }),
+            ..Options::default()
+        }
+    }
+    /// Record framing for a store created with these options.
+    pub(crate) fn framing(&self) -> io::Result<Framing> {
+        let alignment_shift = match self.alignment {
+            None => 0,
+            Some(alignment)
+                if alignment.is_power_of_two()
+                    && alignment.trailing_zeros() <= MAX_ALIGNMENT_SHIFT as u32 =>
+            {
+                alignment.trailing_zeros() as u8
+            }
+            Some(alignment) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "alignment must be a power of two up to {}, got {}",
+                        1u32 << MAX_ALIGNMENT_SHIFT,
+                        alignment
+                    ),
+                ))
+            }
+        };
+        Ok(Framing {
+            varint_lengths: self.varint_lengths,
+            alignment_shift,
+            #[cfg(feature = "zstd")]
+            compressed_values: self.compression.is_some(),
+            #[cfg(not(feature = "zstd"))]
+            compressed_values: false,
+            hashed_keys: self.hashed_keys,
+            wide_values: self.wide_values,
+            checksum_lengths: !self.legacy_checksums,
+        })
+    }
+}
@@ -0,0 +1,58 @@
+use std::fs::{File, TryLockError};
+use std::io;
+use std::path::Path;
+
+/*
+    PLATFORM
+    WASI (wasm32-wasip1/p2) has no file locks, can't always sync directories
+    and, without the threads proposal, can't spawn threads. The store then
+    runs unlocked, syncs files only and does parallel work on the calling
+    thread. A WASI runtime hands a store to a single instance, which takes
+    the place of the lock.
+*/
+
+/// Threads parallel work may be split between.
+pub(crate) fn workers(requested: usize) -> usize {
+    if cfg!(all(target_os = "wasi", not(target_feature = "atomics"))) {
+        return 1;
+    }
+    requested.max(1)
+}
+
+/// Makes renames and new files in the directory `path` durable, where the
+/// platform can.
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    let synced = File::open(path).and_then(|dir| dir.sync_all());
+    match synced {
+        Err(_) if cfg!(target_os = "wasi") => Ok(()),
+        synced => synced,
+    }
+}
+
+/// Locks `file` exclusively, or shared unless `exclusive`. Platforms without
+/// file locks go without.
+pub(crate) fn lock(file: &File, exclusive: bool) -> io::Result<()> {
+    let locked = match exclusive {
+        true => file.lock(),
+        false => file.lock_shared(),
+    };
+    match locked {
+        Err(err) if err.kind() == io::ErrorKind::Unsupported && cfg!(target_os = "wasi") => Ok(()),
+        locked => locked,
+    }
+}
+
+/// Locks `file` exclusively unless someone else holds a lock on it, `false`
+/// if they do. Platforms without file locks go without.
+pub(crate) fn try_lock(file: &File) -> io::Result<bool> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(err))
+            if err.kind() == io::ErrorKind::Unsupported && cfg!(target_os = "wasi") =>
+        {
+            Ok(true)
+        }
+        Err(TryLockError::Error(err)) => Err(err),
+    }
+}
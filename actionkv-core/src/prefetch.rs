@@ -0,0 +1,128 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Size of the first read after a seek, what `BufReader` reads every time.
+const MIN_READAHEAD: usize = 8 << 10;
+/// The most a sequential scan reads ahead in one go.
+const MAX_READAHEAD: usize = 4 << 20;
+
+/// Buffered reader for scans over the data file. Every time it finds its
+/// buffer used up by reads that followed each other, it doubles how much it
+/// reads next, so long scans end up issuing few large reads, which is what
+/// spinning disks and network filesystems need to keep up. Seeks into the
+/// buffered bytes keep them, any other seek starts over at the smallest read.
+#[derive(Debug)]
+pub(crate) struct ReadAhead<R> {
+    inner: R,
+    buf: Vec<u8>,
+    /// Bytes of `buf` already handed out.
+    consumed: usize,
+    /// Where the inner reader is, right behind the end of `buf`.
+    inner_position: u64,
+    window: usize,
+}
+
+impl<R: Read + Seek> ReadAhead<R> {
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let inner_position = inner.stream_position()?;
+        Ok(ReadAhead {
+            inner,
+            buf: Vec::new(),
+            consumed: 0,
+            inner_position,
+            window: MIN_READAHEAD,
+        })
+    }
+    fn buffered(&self) -> &[u8] {
+        &self.buf[self.consumed..]
+    }
+    fn position(&self) -> u64 {
+        self.inner_position - self.buffered().len() as u64
+    }
+    fn fill(&mut self) -> io::Result<()> {
+        // the whole buffer got read, the scan is sequential so far
+        if !self.buf.is_empty() {
+            self.window = (self.window * 2).min(MAX_READAHEAD);
+        }
+        self.buf.clear();
+        self.consumed = 0;
+        let read = self
+            .inner
+            .by_ref()
+            .take(self.window as u64)
+            .read_to_end(&mut self.buf)?;
+        self.inner_position += read as u64;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for ReadAhead<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.buffered().is_empty() {
+            self.fill()?;
+        }
+        let buffered = self.buffered();
+        let len = buffered.len().min(out.len());
+        out[..len].copy_from_slice(&buffered[..len]);
+        self.consumed += len;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for ReadAhead<R> {
+    fn seek(&mut self, target: SeekFrom) -> io::Result<u64> {
+        let position = self.position();
+        let target = match target {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+            SeekFrom::End(_) => None,
+        };
+        let buf_start = self.inner_position - self.buf.len() as u64;
+        match target {
+            Some(target) if target >= buf_start && target <= self.inner_position => {
+                self.consumed = (target - buf_start) as usize;
+                Ok(target)
+            }
+            _ => {
+                let offset = match target {
+                    Some(target) => SeekFrom::Start(target),
+                    None => SeekFrom::End(0),
+                };
+                self.inner_position = self.inner.seek(offset)?;
+                self.buf.clear();
+                self.consumed = 0;
+                self.window = MIN_READAHEAD;
+                Ok(self.inner_position)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+    use std::io::Cursor;
+
+    #[rstest]
+    fn test_read_ahead_grows_while_sequential() {
+        let bytes: Vec<u8> = (0..MAX_READAHEAD * 3).map(|i| i as u8).collect();
+        let mut f = ReadAhead::new(Cursor::new(bytes.clone())).unwrap();
+        let mut all = Vec::new();
+        f.read_to_end(&mut all).unwrap();
+        assert_eq!(all, bytes);
+        assert_eq!(f.window, MAX_READAHEAD);
+
+        f.seek(SeekFrom::Start(100)).unwrap();
+        assert_eq!(f.window, MIN_READAHEAD);
+        let mut record = [0; 10];
+        f.read_exact(&mut record).unwrap();
+        assert_eq!(record, bytes[100..110]);
+        // within the buffer, nothing is read again
+        assert_eq!(f.seek(SeekFrom::Start(500)).unwrap(), 500);
+        assert_eq!(f.seek(SeekFrom::Current(-10)).unwrap(), 490);
+        assert_eq!(f.stream_position().unwrap(), 490);
+        assert_eq!(f.inner_position, 100 + MIN_READAHEAD as u64);
+        f.read_exact(&mut record).unwrap();
+        assert_eq!(record, bytes[490..500]);
+    }
+}
@@ -0,0 +1,316 @@
+use crate::format::{FileHeader, Framing, DATA_MAGIC, HEADER_LEN, INDEX_MAGIC};
+use crate::prefetch::ReadAhead;
+use crate::segment;
+use crate::{format, lock, ActionKV, ActionKvError, Layout, Options, INDEX_KEY};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/*
+    READ ONLY OPENS
+    any number of stores opened with Options::read_only can read a directory
+    next to the one opened for writing. They never write, recover or repair
+    anything and only see records the writer's index was saved for. Appends
+    show up after `refresh`, which reloads the index, and a compaction by the
+    writer is noticed by the index carrying a new generation, the files are
+    reopened then, as they are when the writer sealed or merged segments.
+*/
+/// How often a read only store rereads an index that the writer was caught
+/// rewriting before giving up.
+const TORN_INDEX_RETRIES: usize = 20;
+const TORN_INDEX_BACKOFF: Duration = Duration::from_millis(1);
+
+impl ActionKV {
+    /// The data and index file of the store at `path` opened for reading,
+    /// together with where their records start and how much of the data file
+    /// the index covers.
+    pub(crate) fn open_files_read_only(path: &Path) -> io::Result<(File, File, Layout, u64)> {
+        let mut file_ = File::open(path.join("data"))?;
+        let mut index_ = File::open(path.join("index"))?;
+        let (layout, data_len) = ActionKV::read_only_layout(&mut file_, &mut index_)?;
+        Ok((file_, index_, layout, data_len))
+    }
+    /// Layout of a store's files, left as they are, and the length of the
+    /// data file the index covers.
+    pub(crate) fn read_only_layout(
+        file_: &mut File,
+        index_: &mut File,
+    ) -> io::Result<(Layout, u64)> {
+        let data_header = FileHeader::read_from(file_, DATA_MAGIC)?;
+        let index_header = FileHeader::read_from(index_, INDEX_MAGIC)?;
+        match (data_header, index_header) {
+            (Some(data), Some(index)) if data.generation == index.generation => {
+                let framing = Framing::from_header(&data)?;
+                let layout = Layout {
+                    generation: Some(data.generation),
+                    data_start: framing.records_start(),
+                    index_start: HEADER_LEN,
+                    framing,
+                    rebuild_index: false,
+                    replay_from: None,
+                    truncated: false,
+                    sealed_since_saved: false,
+                };
+                let data_len = index.data_len.max(framing.records_start());
+                Ok((layout, data_len))
+            }
+            (None, None) => {
+                let data_len = file_.metadata()?.len();
+                let layout = Layout {
+                    generation: None,
+                    data_start: 0,
+                    index_start: 0,
+                    framing: Framing::default(),
+                    rebuild_index: false,
+                    replay_from: None,
+                    truncated: false,
+                    sealed_since_saved: false,
+                };
+                Ok((layout, data_len))
+            }
+            (data, index) => Err(ActionKvError::FilesMismatched {
+                reason: format!(
+                    "data file generation is {} but index generation is {}, open the \
+                     store for writing once to recover it",
+                    data.map_or("missing".to_string(), |header| {
+                        format::display_generation(&header.generation)
+                    }),
+                    index.map_or("missing".to_string(), |header| {
+                        format::display_generation(&header.generation)
+                    }),
+                ),
+            }
+            .into()),
+        }
+    }
+    /// Opens the store at `path` read only as it was when its log ended at
+    /// `log_offset`, a position as `log_iter` reports them, to look at what
+    /// it held back then. The saved index is the starting point when it is
+    /// older than that, else the log is scanned from its start. Records of
+    /// the past that compaction got rid of are gone for good. `refresh`
+    /// brings it to the present.
+    pub fn open_at(path: &Path, log_offset: u64) -> io::Result<Self> {
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let mut store = ActionKV::open_with_options(path, options)?;
+        let checkpoint = store.appender.tail();
+        let (segment, log_offset) = match segment::split(log_offset) {
+            // past the sealed segments, in the data file
+            (id, offset) if id > store.newest_segment().unwrap_or(segment::ACTIVE) => {
+                (segment::ACTIVE, offset)
+            }
+            split => split,
+        };
+        let (start, mut index) = if segment == segment::ACTIVE && log_offset >= checkpoint {
+            store.read_index()?;
+            (checkpoint, std::mem::take(&mut store.index))
+        } else {
+            (store.data_start, HashMap::new())
+        };
+        if segment != segment::ACTIVE || log_offset < checkpoint {
+            for (id, path) in store.segment_files()? {
+                let end = match id {
+                    id if id == segment => log_offset,
+                    id if segment != segment::ACTIVE && id > segment => break,
+                    _ => u64::MAX,
+                };
+                let mut f = ReadAhead::new(File::open(path)?)?;
+                crate::replay_records(&mut f, store.framing, id, start, end, &mut index)?;
+            }
+        }
+        let log_offset = match segment {
+            segment::ACTIVE => log_offset,
+            // the data file was written after
+            _ => start,
+        };
+        let end = store.scan_into(start, log_offset, &mut index)?;
+        index.remove(INDEX_KEY);
+        store.index = index;
+        store.appender.reset(end);
+        Ok(store)
+    }
+    /// Fails for stores opened with `Options::read_only`.
+    pub(crate) fn writable(&self) -> io::Result<()> {
+        if self.options.read_only {
+            return Err(ActionKvError::ReadOnly.into());
+        }
+        self.not_crashed()
+    }
+    /// Catches a store opened with `Options::read_only` up with the writer:
+    /// the index is reloaded if records were appended since, the files are
+    /// reopened if a compaction replaced them. Returns whether anything
+    /// changed, stores opened for writing are always current.
+    pub fn refresh(&mut self) -> io::Result<bool> {
+        if !self.options.read_only {
+            return Ok(false);
+        }
+        let path = self.dir("refresh")?.to_path_buf();
+        let lock = lock::shared(&path)?;
+        let header = FileHeader::read_from(&mut File::open(path.join("index"))?, INDEX_MAGIC)?;
+        match header {
+            Some(header) if Some(header.generation) == self.generation => {
+                let data_len = header.data_len.max(self.data_start);
+                if self.segments_changed(&path)? {
+                    // the data file was sealed, the handle is to a segment now
+                    drop(lock);
+                    self.reopen(&path)?;
+                    return Ok(true);
+                }
+                if data_len == self.appender.tail() {
+                    return Ok(false);
+                }
+                self.appender.reset(data_len);
+                self.index_len = self.index_.metadata()?.len();
+                // the persisted index is read lazily on first use
+                self.index = HashMap::from([(INDEX_KEY.to_vec(), self.index_start)]);
+                self.segments.uncount();
+            }
+            _ => {
+                drop(lock);
+                self.reopen(&path)?;
+            }
+        }
+        Ok(true)
+    }
+    /// Replaces the store with the store at `path` opened anew, keeping what
+    /// was set on it.
+    fn reopen(&mut self, path: &Path) -> io::Result<()> {
+        let mut reopened = ActionKV::open_with_options(path, self.options.clone())?;
+        reopened.tokenizer = self.tokenizer.take();
+        reopened.compaction_filter = self.compaction_filter.take();
+        reopened.io_stats = std::mem::take(&mut self.io_stats);
+        reopened.corruption.add(&self.corruption);
+        *self = reopened;
+        Ok(())
+    }
+    /// The writer rewrites the index in place, a read only store reading it
+    /// at the same time sees a torn record. Retries after the writer had time
+    /// to finish, `err` is what the first attempt failed with.
+    pub(crate) fn reread_torn_index(&mut self, mut err: io::Error) -> io::Result<()> {
+        for _ in 0..TORN_INDEX_RETRIES {
+            thread::sleep(TORN_INDEX_BACKOFF);
+            self.refresh()?;
+            let position = self.index_start;
+            match self.get_at(position, true).and_then(|key_value| {
+                let index = self.index_codec.decode(&key_value.value)?;
+                Ok((index, key_value.value))
+            }) {
+                Ok((index, index_as_bytes)) => {
+                    self.index = index;
+                    return self.load_index_deltas(position, &index_as_bytes);
+                }
+                Err(retry_err) if crate::is_corruption(&retry_err) => err = retry_err,
+                Err(retry_err) => return Err(retry_err),
+            }
+        }
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestCtx;
+    use crate::{ActionKV, ActionKvError, Options};
+    use rstest::*;
+    use serial_test::serial;
+    use std::path::Path;
+
+    fn read_only() -> Options {
+        Options {
+            read_only: true,
+            ..Options::default()
+        }
+    }
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_read_only")
+    }
+    #[rstest]
+    #[serial]
+    fn test_open_at(mut ctx: TestCtx) {
+        let path = Path::new("test_read_only");
+        ctx.test_file.insert(b"foo", b"1").unwrap();
+        let first = ctx.test_file.appender.tail();
+        ctx.test_file.insert(b"foo", b"2").unwrap();
+        ctx.test_file.insert(b"bar", b"3").unwrap();
+        let second = ctx.test_file.appender.tail();
+        ctx.test_file.delete(b"foo").unwrap();
+
+        for (offset, foo, bar) in [
+            (first, Some(b"1".to_vec()), None),
+            (first + 3, Some(b"1".to_vec()), None),
+            (second, Some(b"2".to_vec()), Some(b"3".to_vec())),
+            (u64::MAX, None, Some(b"3".to_vec())),
+        ] {
+            let mut past = ActionKV::open_at(path, offset).unwrap();
+            assert_eq!(past.get(b"foo").unwrap(), foo);
+            assert_eq!(past.get(b"bar").unwrap(), bar);
+            assert!(past.insert(b"foo", b"4").is_err());
+        }
+    }
+    #[rstest]
+    #[serial]
+    fn test_readers_follow_the_writer(mut ctx: TestCtx) {
+        let path = Path::new("test_read_only");
+        ctx.test_file.insert(b"foo", b"1").unwrap();
+        let mut first = ActionKV::open_with_options(path, read_only()).unwrap();
+        let mut second = ActionKV::open_with_options(path, read_only()).unwrap();
+        assert_eq!(first.get(b"foo").unwrap(), Some(b"1".to_vec()));
+
+        ctx.test_file.insert(b"foo", b"2").unwrap();
+        ctx.test_file.insert(b"bar", b"3").unwrap();
+        assert_eq!(first.get(b"foo").unwrap(), Some(b"1".to_vec()));
+        assert!(first.refresh().unwrap());
+        assert!(!first.refresh().unwrap());
+        assert_eq!(first.get(b"foo").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(second.get(b"bar").unwrap(), Some(b"3".to_vec()));
+
+        ctx.test_file.delete(b"bar").unwrap();
+        ctx.test_file.compact().unwrap();
+        assert!(first.refresh().unwrap());
+        assert_eq!(first.get(b"foo").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(first.get(b"bar").unwrap(), None);
+
+        let err = first.insert(b"foo", b"4").unwrap_err();
+        assert!(matches!(
+            ActionKvError::from_io(&err),
+            Some(ActionKvError::ReadOnly)
+        ));
+        assert!(first.compact().is_err());
+        assert_eq!(ctx.test_file.get(b"foo").unwrap(), Some(b"2".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_one_writer_at_a_time(mut ctx: TestCtx) {
+        let path = Path::new("test_read_only");
+        ctx.test_file.insert(b"foo", b"1").unwrap();
+        let err = ActionKV::open(path).unwrap_err();
+        assert!(matches!(
+            ActionKvError::from_io(&err),
+            Some(ActionKvError::Locked)
+        ));
+        // readers still get in next to the writer
+        let mut reader = ActionKV::open_with_options(path, read_only()).unwrap();
+        assert_eq!(reader.get(b"foo").unwrap(), Some(b"1".to_vec()));
+        ctx.test_file.compact().unwrap();
+        assert!(ActionKV::open(path).is_err());
+
+        let (store, _dir) = ctx.into_store();
+        drop(store);
+        let mut writer = ActionKV::open(path).unwrap();
+        assert_eq!(writer.get(b"foo").unwrap(), Some(b"1".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_read_only_open_needs_a_store() {
+        assert!(
+            ActionKV::open_with_options(Path::new("test_read_only_missing"), read_only()).is_err()
+        );
+        assert!(!Path::new("test_read_only_missing").exists());
+    }
+}
@@ -0,0 +1,224 @@
+use crate::manifest::MANIFEST_FILE;
+use crate::{ActionKV, AdminAction};
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, PutPayload};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Objects are moved in parts of this size, so files of any size pass through
+/// a bounded amount of memory.
+const PART_SIZE: usize = 8 << 20;
+/// Scratch file in the store directory for dumps on their way to or from the
+/// object store.
+const TRANSFER_FILE: &str = "transfer.tmp";
+/// Object listing the sealed segments of a backup, one file name a line.
+const SEGMENTS_OBJECT: &str = "segments";
+
+fn object_error(err: object_store::Error) -> io::Error {
+    match err {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, err),
+        err => io::Error::other(err),
+    }
+}
+
+/// Uploads the first `len` bytes of `file` to `location`, one part at a time.
+async fn upload(
+    store: &dyn ObjectStore,
+    location: &ObjectPath,
+    file: &Path,
+    len: u64,
+) -> io::Result<()> {
+    let mut multipart = store.put_multipart(location).await.map_err(object_error)?;
+    let mut source = File::open(file)?.take(len);
+    loop {
+        let mut part = Vec::with_capacity(PART_SIZE);
+        (&mut source)
+            .take(PART_SIZE as u64)
+            .read_to_end(&mut part)?;
+        if part.is_empty() {
+            break;
+        }
+        if let Err(err) = multipart.put_part(PutPayload::from(part)).await {
+            let _ = multipart.abort().await;
+            return Err(object_error(err));
+        }
+    }
+    multipart.complete().await.map_err(object_error)?;
+    Ok(())
+}
+
+async fn download(store: &dyn ObjectStore, location: &ObjectPath, file: &Path) -> io::Result<()> {
+    let len = store.head(location).await.map_err(object_error)?.size;
+    let mut target = File::create(file)?;
+    let mut offset = 0;
+    while offset < len {
+        let end = (offset + PART_SIZE as u64).min(len);
+        let part = store
+            .get_range(location, offset..end)
+            .await
+            .map_err(object_error)?;
+        target.write_all(&part)?;
+        offset = end;
+    }
+    target.sync_all()
+}
+
+impl ActionKV {
+    /// Uploads a consistent copy of the store's files under `prefix` of any
+    /// `object_store` backend (local, S3, GCS, Azure...), see `restore_from`.
+    pub async fn backup_to(
+        &mut self,
+        store: &dyn ObjectStore,
+        prefix: &ObjectPath,
+    ) -> io::Result<()> {
+        let data_len = self.file_.metadata()?.len();
+        let index_len = self.index_.metadata()?.len();
+        let mut sealed = Vec::new();
+        for (_, file) in self.segment_files()? {
+            let len = fs::metadata(&file)?.len();
+            if let Some(name) = file.file_name().and_then(|name| name.to_str()) {
+                sealed.push((name.to_string(), file.clone(), len));
+            }
+        }
+        let quiesced = self.quiesce()?;
+        let path = quiesced.path();
+        let manifest = path.join(MANIFEST_FILE);
+        if manifest.exists() {
+            let len = fs::metadata(&manifest)?.len();
+            upload(store, &prefix.child(MANIFEST_FILE), &manifest, len).await?;
+        }
+        if !sealed.is_empty() {
+            let names: Vec<&str> = sealed.iter().map(|(name, _, _)| name.as_str()).collect();
+            let listed = PutPayload::from(names.join("\n").into_bytes());
+            store
+                .put(&prefix.child(SEGMENTS_OBJECT), listed)
+                .await
+                .map_err(object_error)?;
+        }
+        for (name, file, len) in &sealed {
+            upload(store, &prefix.child(name.as_str()), file, *len).await?;
+        }
+        upload(store, &prefix.child("data"), &path.join("data"), data_len).await?;
+        upload(
+            store,
+            &prefix.child("index"),
+            &path.join("index"),
+            index_len,
+        )
+        .await
+    }
+    /// Downloads a backup made by `backup_to` into the new directory `path`
+    /// and opens it.
+    pub async fn restore_from(
+        store: &dyn ObjectStore,
+        prefix: &ObjectPath,
+        path: &Path,
+    ) -> io::Result<ActionKV> {
+        fs::create_dir(path)?;
+        let manifest = path.join(MANIFEST_FILE);
+        match download(store, &prefix.child(MANIFEST_FILE), &manifest).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+        let listed = match store.get(&prefix.child(SEGMENTS_OBJECT)).await {
+            Ok(listed) => listed.bytes().await.map_err(object_error)?.to_vec(),
+            Err(object_store::Error::NotFound { .. }) => Vec::new(),
+            Err(err) => return Err(object_error(err)),
+        };
+        let listed = String::from_utf8(listed)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        for name in listed.lines() {
+            if !name.starts_with("data.") || name.contains(['/', '\\']) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("backup lists {:?} as a segment", name),
+                ));
+            }
+            download(store, &prefix.child(name), &path.join(name)).await?;
+        }
+        download(store, &prefix.child("data"), &path.join("data")).await?;
+        download(store, &prefix.child("index"), &path.join("index")).await?;
+        let restored = ActionKV::open(path)?;
+        restored.log_admin(AdminAction::Restore, &format!("from backup {}", prefix))?;
+        Ok(restored)
+    }
+    /// `export_jsonl` into the object at `location`. Returns how many pairs
+    /// were written.
+    pub async fn export_to(
+        &mut self,
+        store: &dyn ObjectStore,
+        location: &ObjectPath,
+    ) -> io::Result<u64> {
+        let transfer = self.dir("transfers")?.join(TRANSFER_FILE);
+        let written = self.export_jsonl(BufWriter::new(File::create(&transfer)?))?;
+        let len = fs::metadata(&transfer)?.len();
+        let uploaded = upload(store, location, &transfer, len).await;
+        fs::remove_file(&transfer)?;
+        uploaded.map(|_| written)
+    }
+    /// `import_jsonl` from the object at `location`. Returns how many records
+    /// were written.
+    pub async fn import_from(
+        &mut self,
+        store: &dyn ObjectStore,
+        location: &ObjectPath,
+    ) -> io::Result<u64> {
+        let transfer = self.dir("transfers")?.join(TRANSFER_FILE);
+        let imported = match download(store, location, &transfer).await {
+            Ok(()) => self.import_jsonl(BufReader::new(File::open(&transfer)?)),
+            Err(err) => Err(err),
+        };
+        let _ = fs::remove_file(&transfer);
+        imported
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use futures_executor::block_on;
+    use object_store::memory::InMemory;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_remote")
+    }
+    #[rstest]
+    #[serial]
+    fn test_backup_and_restore(mut ctx: TestCtx) {
+        let bucket = InMemory::new();
+        let prefix = ObjectPath::from("backups/1");
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        block_on(ctx.test_file.backup_to(&bucket, &prefix)).unwrap();
+        ctx.test_file.insert(b"foo", b"baz").unwrap();
+
+        let restored = Path::new("test_remote/restored");
+        let mut store = block_on(ActionKV::restore_from(&bucket, &prefix, restored)).unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_export_and_import(mut ctx: TestCtx) {
+        let bucket = InMemory::new();
+        let dump = ObjectPath::from("dumps/all.jsonl");
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        assert_eq!(
+            block_on(ctx.test_file.export_to(&bucket, &dump)).unwrap(),
+            1
+        );
+
+        ctx.test_file.delete(b"foo").unwrap();
+        assert_eq!(
+            block_on(ctx.test_file.import_from(&bucket, &dump)).unwrap(),
+            1
+        );
+        assert_eq!(ctx.test_file.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+        let missing = ObjectPath::from("dumps/missing.jsonl");
+        let err = block_on(ctx.test_file.import_from(&bucket, &missing)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{ActionKV, AdminAction, ByteString};
+use std::io;
+
+/// Deepest Merkle tree `repair_from` compares, 64K buckets.
+const MAX_REPAIR_DEPTH: u8 = 16;
+/// Keys per bucket `repair_from` aims for.
+const KEYS_PER_BUCKET: usize = 64;
+
+/// What `repair_from` changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Merkle buckets that differed between the two stores.
+    pub buckets: u64,
+    /// Keys copied over because this store didn't have them.
+    pub added: Vec<ByteString>,
+    /// Keys whose value was replaced by the one of the other store.
+    pub updated: Vec<ByteString>,
+    /// Keys only this store has, left alone: they were either deleted from
+    /// the other store or never made it there.
+    pub only_here: Vec<ByteString>,
+}
+
+impl ActionKV {
+    /// Anti-entropy repair: compares Merkle trees of both stores and copies
+    /// every key of `other` that is missing here or has another value, from
+    /// the buckets that differ only. Running it both ways brings two replicas
+    /// in sync, apart from deletes, which look the same as keys that never
+    /// made it across and are reported instead.
+    pub fn repair_from(&mut self, other: &mut ActionKV) -> io::Result<RepairReport> {
+        self.writable()?;
+        self.read_index()?;
+        other.read_index()?;
+        let keys = self.index.len().max(other.index.len());
+        let depth = ((keys / KEYS_PER_BUCKET).max(1).ilog2() as u8).min(MAX_REPAIR_DEPTH);
+        let differing = self.merkle_tree(depth)?.diff(&other.merkle_tree(depth)?)?;
+        let mut report = RepairReport {
+            buckets: differing.len() as u64,
+            ..RepairReport::default()
+        };
+        for bucket in differing {
+            let mut here = self.merkle_bucket(depth, bucket)?.into_iter().peekable();
+            for theirs in other.merkle_bucket(depth, bucket)? {
+                // both sides are sorted by key
+                while let Some(ours) = here.next_if(|ours| ours.key < theirs.key) {
+                    report.only_here.push(ours.key);
+                }
+                match here.next_if(|ours| ours.key == theirs.key) {
+                    Some(ours) if ours.value == theirs.value => continue,
+                    Some(_) => report.updated.push(theirs.key.clone()),
+                    None => report.added.push(theirs.key.clone()),
+                }
+                self.insert(&theirs.key, &theirs.value)?;
+            }
+            report.only_here.extend(here.map(|ours| ours.key));
+        }
+        self.log_admin(
+            AdminAction::Repair,
+            &format!(
+                "from a replica, {} buckets differed, {} added, {} updated, {} only here",
+                report.buckets,
+                report.added.len(),
+                report.updated.len(),
+                report.only_here.len()
+            ),
+        )?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestCtx;
+    use crate::Options;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[serial]
+    fn test_repair_from() {
+        let mut primary = TestCtx::setup("test_repair");
+        let mut replica = TestCtx::setup("test_repair_replica");
+        for i in 0..500 {
+            let key = format!("key:{}", i).into_bytes();
+            primary.test_file.insert(&key, b"value").unwrap();
+            replica.test_file.insert(&key, b"value").unwrap();
+        }
+        primary.test_file.insert(b"new", b"1").unwrap();
+        primary.test_file.update(b"key:3", b"changed").unwrap();
+        replica.test_file.insert(b"stale", b"2").unwrap();
+
+        let report = replica
+            .test_file
+            .repair_from(&mut primary.test_file)
+            .unwrap();
+        assert_eq!(report.added, vec![b"new".to_vec()]);
+        assert_eq!(report.updated, vec![b"key:3".to_vec()]);
+        assert_eq!(report.only_here, vec![b"stale".to_vec()]);
+        assert!(report.buckets >= 1 && report.buckets <= 3);
+        assert_eq!(
+            replica.test_file.get(b"key:3").unwrap(),
+            Some(b"changed".to_vec())
+        );
+
+        replica.test_file.delete(b"stale").unwrap();
+        let again = replica
+            .test_file
+            .repair_from(&mut primary.test_file)
+            .unwrap();
+        assert_eq!(again, Default::default());
+    }
+    #[rstest]
+    #[serial]
+    fn test_repair_copies_chunked_values() {
+        let options = Options {
+            chunk_size: Some(10),
+            ..Options::default()
+        };
+        let mut primary = TestCtx::setup_with_options("test_repair", options);
+        let mut replica = TestCtx::setup("test_repair_replica");
+        let big: Vec<u8> = (0..95).collect();
+        primary.test_file.insert(b"big", &big).unwrap();
+        primary.test_file.insert(b"small", b"1").unwrap();
+
+        let report = replica
+            .test_file
+            .repair_from(&mut primary.test_file)
+            .unwrap();
+        assert_eq!(report.added, vec![b"big".to_vec(), b"small".to_vec()]);
+        assert_eq!(replica.test_file.get(b"big").unwrap(), Some(big));
+        let again = replica
+            .test_file
+            .repair_from(&mut primary.test_file)
+            .unwrap();
+        assert_eq!(again, Default::default());
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{ActionKV, ActionKvError};
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+/// How reads and writes retry failures that tend to go away on their own,
+/// see `Options::retry`. Syncs are never retried: after a failed `fsync`
+/// the kernel may have dropped the dirty pages, and a second one succeeding
+/// says nothing about them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How often a call is tried, the first try included.
+    pub attempts: u32,
+    /// Wait before the first retry, doubled before every retry after it.
+    pub backoff: Duration,
+    /// Longest wait between two tries.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 4,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Whether `err` is worth trying again: interrupted and would-block calls,
+/// a busy device, and the timeouts soft mounted network file systems
+/// return while the server is unreachable. Errors of the store itself are
+/// never transient, its `TimedOut` included.
+pub fn is_transient(err: &io::Error) -> bool {
+    ActionKvError::from_io(err).is_none()
+        && matches!(
+            err.kind(),
+            io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::ResourceBusy
+        )
+}
+
+impl ActionKV {
+    /// Runs `op` until it succeeds, fails with an error that isn't transient
+    /// or used up the attempts of `Options::retry`, which fails with
+    /// `ActionKvError::RetriesExhausted`. `op` has to leave the store as it found
+    /// it when it fails.
+    pub(crate) fn retrying<T>(
+        &mut self,
+        mut op: impl FnMut(&mut ActionKV) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let Some(policy) = self.options.retry else {
+            return op(self);
+        };
+        let mut backoff = policy.backoff;
+        let mut attempts = 1;
+        loop {
+            match op(self) {
+                Err(err) if is_transient(&err) => {
+                    if attempts >= policy.attempts {
+                        return Err(ActionKvError::RetriesExhausted {
+                            attempts,
+                            last: err,
+                        }
+                        .into());
+                    }
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2).min(policy.max_backoff);
+                    attempts += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault::{Fault, FaultInjector};
+    use crate::testing::TestCtx;
+    use crate::{IoOp, Options, StoreFile};
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[serial]
+    fn test_retry() {
+        let faults = FaultInjector::new();
+        let mut ctx = TestCtx::setup_with_options(
+            "test_retry",
+            Options {
+                retry: Some(RetryPolicy {
+                    attempts: 3,
+                    backoff: Duration::from_millis(1),
+                    max_backoff: Duration::from_millis(2),
+                }),
+                faults: Some(faults.clone()),
+                ..Options::default()
+            },
+        );
+        let store = &mut ctx.test_file;
+        faults.arm(Fault {
+            times: Some(2),
+            ..Fault::new(IoOp::Write, io::ErrorKind::Interrupted)
+        });
+        store.insert(b"foo", b"bar").unwrap();
+        assert_eq!(faults.armed(), 0);
+        let would_block = Fault {
+            file: Some(StoreFile::Data),
+            times: Some(2),
+            ..Fault::new(IoOp::Read, io::ErrorKind::WouldBlock)
+        };
+        faults.arm(would_block.clone());
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+        faults.arm(would_block);
+        assert_eq!(&*store.get_ref(b"foo").unwrap().unwrap(), b"bar");
+
+        // past the cap the last error comes back with its kind
+        faults.arm(Fault {
+            file: Some(StoreFile::Data),
+            times: Some(3),
+            ..Fault::new(IoOp::Read, io::ErrorKind::TimedOut)
+        });
+        let err = store.get(b"foo").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(matches!(
+            ActionKvError::from_io(&err),
+            Some(ActionKvError::RetriesExhausted { attempts: 3, .. })
+        ));
+
+        // other errors fail right away
+        faults.arm(Fault {
+            file: Some(StoreFile::Data),
+            ..Fault::new(IoOp::Write, io::ErrorKind::StorageFull)
+        });
+        let err = store.insert(b"foo", b"baz").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(ActionKvError::from_io(&err).is_none());
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Small non-cryptographic generator (splitmix64) for sampling and fresh
+/// generations, so the store doesn't need the rand crate. Every instance
+/// starts from a different seed.
+#[derive(Debug, Clone)]
+pub(crate) struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new() -> Self {
+        static INSTANCES: AtomicU64 = AtomicU64::new(0);
+        // RandomState is seeded from the OS once per thread and stepped for
+        // every instance, the time and counter keep seeds apart anyway
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(INSTANCES.fetch_add(1, Ordering::Relaxed));
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        Rng {
+            state: hasher.finish(),
+        }
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    /// Uniform in `[0, bound)`, `bound` must not be 0.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        // widening multiply, the bias is negligible for the bounds used here
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+    pub fn fill(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            let random = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    }
+    /// Up to `n` items of `items` picked uniformly at random (reservoir
+    /// sampling), in no particular order.
+    pub fn choose_multiple<T, I: IntoIterator<Item = T>>(&mut self, items: I, n: usize) -> Vec<T> {
+        let mut chosen = Vec::with_capacity(n);
+        for (seen, item) in items.into_iter().enumerate() {
+            if chosen.len() < n {
+                chosen.push(item);
+            } else {
+                let slot = self.below(seen as u64 + 1) as usize;
+                if slot < n {
+                    chosen[slot] = item;
+                }
+            }
+        }
+        chosen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    fn test_choose_multiple() {
+        let mut rng = Rng::new();
+        assert_eq!(rng.choose_multiple(0..3, 5).len(), 3);
+        let mut chosen = rng.choose_multiple(0..100, 10);
+        chosen.sort();
+        chosen.dedup();
+        assert_eq!(chosen.len(), 10);
+        assert!(chosen.iter().all(|i| *i < 100));
+    }
+    #[rstest]
+    fn test_instances_differ() {
+        assert_ne!(Rng::new().next_u64(), Rng::new().next_u64());
+        let f = Rng::new().next_f64();
+        assert!((0.0..1.0).contains(&f));
+    }
+}
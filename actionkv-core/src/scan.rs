@@ -0,0 +1,378 @@
+use crate::access::{self, AccessPolicy};
+use crate::chunked::ChunkedValue;
+use crate::compression::ValueCodec;
+use crate::format::Framing;
+use crate::platform;
+use crate::segment::{self, SegmentReader};
+use crate::{
+    is_internal_key, ActionKV, ActionKvError, ByteStr, ByteString, KeyValuePair, Operation,
+};
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::thread;
+
+/// The record at `position` with its value decoded.
+pub(crate) fn read_record(
+    segments: &mut SegmentReader,
+    framing: Framing,
+    values: Option<&ValueCodec>,
+    position: u64,
+) -> io::Result<KeyValuePair> {
+    let mut record = ActionKV::process_records(segments.at(position)?, framing)
+        .map_err(|err| ActionKvError::at_offset(err, position))?;
+    if let Some(codec) = values {
+        record.value = codec.decode(record.value)?;
+    }
+    Ok(record)
+}
+
+/// The keys a scan hands on: the ones starting with `prefix` that the access
+/// policy lets be read.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ScanKeys<'a> {
+    prefix: &'a ByteStr,
+    policy: Option<AccessPolicy>,
+}
+
+impl ScanKeys<'_> {
+    pub(crate) fn admit(&self, key: &ByteStr) -> bool {
+        key.starts_with(self.prefix) && access::readable(self.policy, key)
+    }
+}
+
+/// Hands the key and decoded value of every record at `positions` with a key
+/// `keys` admits to `visit`, then the values in `chunked` put back together,
+/// reading the segments of the store in `dir` through file handles of its
+/// own.
+pub(crate) fn visit_records<F>(
+    dir: &Path,
+    framing: Framing,
+    values: Option<&ValueCodec>,
+    positions: &[u64],
+    chunked: &[ChunkedValue],
+    keys: ScanKeys,
+    mut visit: F,
+) -> io::Result<()>
+where
+    F: FnMut(ByteString, ByteString) -> io::Result<()>,
+{
+    let mut segments = SegmentReader::open(dir)?;
+    for position in positions {
+        let record = read_record(&mut segments, framing, values, *position)?;
+        // with hashed keys only the record tells what the key is
+        if !keys.admit(&record.key) {
+            continue;
+        }
+        visit(record.key, record.value)?;
+    }
+    for value in chunked {
+        if !keys.admit(&value.key) {
+            continue;
+        }
+        let assembled = value.assemble(|position| {
+            Ok(read_record(&mut segments, framing, values, position)?.value)
+        })?;
+        visit(value.key.clone(), assembled)?;
+    }
+    Ok(())
+}
+
+impl ActionKV {
+    /// The keys under `prefix` a scan of this store hands on.
+    pub(crate) fn scan_keys<'a>(&self, prefix: &'a ByteStr) -> ScanKeys<'a> {
+        ScanKeys {
+            prefix,
+            policy: self.options.access_policy,
+        }
+    }
+    /// Positions of the live records that may have a key starting with
+    /// `prefix`, in file order so they are read sequentially.
+    pub(crate) fn prefix_positions(&mut self, prefix: &ByteStr) -> io::Result<Vec<u64>> {
+        self.authorize(Operation::Scan, prefix)?;
+        self.read_index()?;
+        let hashed_keys = self.framing.hashed_keys;
+        // hashed keys can't be matched here, the footers rule out segments
+        let skipped = match hashed_keys {
+            true => self.segments_without(prefix)?,
+            false => HashSet::new(),
+        };
+        let mut positions: Vec<u64> = self
+            .index
+            .iter()
+            .filter(|(key, _)| !is_internal_key(key) && (hashed_keys || key.starts_with(prefix)))
+            .map(|(_, position)| *position)
+            .filter(|position| !skipped.contains(&segment::split(*position).0))
+            .collect();
+        positions.sort();
+        Ok(positions)
+    }
+    /// Live pairs whose key starts with `prefix` and for which `filter`,
+    /// given the key and value, returns true, sorted by key. Values are
+    /// read and filtered one at a time, only the matching ones are kept.
+    /// Keys the access policy doesn't let be read are left out.
+    pub fn scan_filtered<F>(
+        &mut self,
+        prefix: &ByteStr,
+        mut filter: F,
+    ) -> io::Result<Vec<KeyValuePair>>
+    where
+        F: FnMut(&ByteStr, &ByteStr) -> bool,
+    {
+        self.logged(Operation::Scan, prefix, |store| {
+            let positions = store.prefix_positions(prefix)?;
+            let chunked = store.chunked_values(prefix)?;
+            let mut matching = Vec::new();
+            visit_records(
+                store.dir("scans")?,
+                store.framing,
+                store.values.as_deref(),
+                &positions,
+                &chunked,
+                store.scan_keys(prefix),
+                |key, value| {
+                    if filter(&key, &value) {
+                        matching.push(KeyValuePair { key, value });
+                    }
+                    Ok(())
+                },
+            )?;
+            matching.sort_by(|a, b| a.key.cmp(&b.key));
+            Ok(matching)
+        })
+    }
+    /// Folds every live pair whose key starts with `prefix` into `init` with
+    /// `f`, e.g. to sum, count or take the maximum of values. Pairs come in
+    /// no particular order, the ones the access policy doesn't let be read
+    /// are left out.
+    pub fn fold<A, F>(&mut self, prefix: &ByteStr, init: A, mut f: F) -> io::Result<A>
+    where
+        F: FnMut(A, &ByteStr, &ByteStr) -> A,
+    {
+        self.logged(Operation::Scan, prefix, |store| {
+            let positions = store.prefix_positions(prefix)?;
+            let chunked = store.chunked_values(prefix)?;
+            let mut acc = Some(init);
+            visit_records(
+                store.dir("scans")?,
+                store.framing,
+                store.values.as_deref(),
+                &positions,
+                &chunked,
+                store.scan_keys(prefix),
+                |key, value| {
+                    acc = acc.take().map(|acc| f(acc, &key, &value));
+                    Ok(())
+                },
+            )?;
+            acc.ok_or_else(|| io::Error::other("fold lost its accumulator"))
+        })
+    }
+    /// `fold` with the records split between `workers` threads, each folding
+    /// its share into an accumulator of its own from `init`. The results are
+    /// merged with `combine`, in the order of the shares in the data file,
+    /// with the values written in chunks folded by one more thread last.
+    pub fn fold_parallel<A, I, F, C>(
+        &mut self,
+        prefix: &ByteStr,
+        workers: usize,
+        init: I,
+        f: F,
+        mut combine: C,
+    ) -> io::Result<A>
+    where
+        A: Send,
+        I: Fn() -> A + Sync,
+        F: Fn(A, &ByteStr, &ByteStr) -> A + Sync,
+        C: FnMut(A, A) -> A,
+    {
+        let workers = platform::workers(workers);
+        if workers == 1 {
+            let folded = self.fold(prefix, init(), f)?;
+            return Ok(combine(init(), folded));
+        }
+        let positions = self.prefix_positions(prefix)?;
+        let chunked = self.chunked_values(prefix)?;
+        let dir = self.dir("scans")?;
+        let framing = self.framing;
+        let values = self.values.as_deref();
+        let keys = self.scan_keys(prefix);
+        let chunk_len = positions.len().div_ceil(workers).max(1);
+        let fold_share = |positions: &[u64], chunked: &[ChunkedValue]| {
+            let mut acc = Some(init());
+            visit_records(
+                dir,
+                framing,
+                values,
+                positions,
+                chunked,
+                keys,
+                |key, value| {
+                    acc = acc.take().map(|acc| f(acc, &key, &value));
+                    Ok(())
+                },
+            )?;
+            acc.ok_or_else(|| io::Error::other("fold lost its accumulator"))
+        };
+        let shares = positions
+            .chunks(chunk_len)
+            .map(|share| (share, &[][..]))
+            .chain((!chunked.is_empty()).then_some((&[][..], &chunked[..])));
+        thread::scope(|scope| {
+            let handles: Vec<_> = shares
+                .map(|(positions, chunked)| {
+                    let fold_share = &fold_share;
+                    scope.spawn(move || fold_share(positions, chunked))
+                })
+                .collect();
+            let mut result = init();
+            for handle in handles {
+                let folded = handle
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("fold worker panicked")))?;
+                result = combine(result, folded);
+            }
+            Ok(result)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, TestCtx};
+    use crate::Options;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[case(Options::default())]
+    #[case(Options { hashed_keys: true, ..Options::default() })]
+    #[serial]
+    fn test_scan_filtered(#[case] options: Options) {
+        let mut ctx = TestCtx::setup_with_options("test_scan", options);
+        let store = &mut ctx.test_file;
+        for i in 0..50u32 {
+            store
+                .insert(format!("user:{:02}", i).as_bytes(), &i.to_le_bytes())
+                .unwrap();
+            store
+                .insert(format!("order:{:02}", i).as_bytes(), &i.to_le_bytes())
+                .unwrap();
+        }
+        store.delete(b"user:47").unwrap();
+
+        let over_44 = |_: &[u8], value: &[u8]| value[0] > 44;
+        let keys: Vec<Vec<u8>> = store
+            .scan_filtered(b"user:", over_44)
+            .unwrap()
+            .into_iter()
+            .map(|pair| pair.key)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                b"user:45".to_vec(),
+                b"user:46".to_vec(),
+                b"user:48".to_vec(),
+                b"user:49".to_vec()
+            ]
+        );
+        let all = store.scan_filtered(b"", |_, _| true).unwrap();
+        assert_eq!(all.len(), 99);
+        assert_eq!(all[0].key, b"order:00");
+        assert_eq!(all[0].value, 0u32.to_le_bytes());
+    }
+    #[rstest]
+    #[serial]
+    fn test_fold() {
+        let mut ctx = TestCtx::setup("test_scan");
+        let store = &mut ctx.test_file;
+        for i in 1..=100u64 {
+            store
+                .insert(format!("sale:{:03}", i).as_bytes(), &i.to_le_bytes())
+                .unwrap();
+        }
+        store.insert(b"other", &1000u64.to_le_bytes()).unwrap();
+        let amount = |value: &[u8]| u64::from_le_bytes(value.try_into().unwrap());
+
+        let (count, sum) = store
+            .fold(b"sale:", (0, 0), |(count, sum), _, value| {
+                (count + 1, sum + amount(value))
+            })
+            .unwrap();
+        assert_eq!((count, sum), (100, 5050));
+        let max = store
+            .fold_parallel(
+                b"",
+                4,
+                || 0,
+                |max, _, value| amount(value).max(max),
+                u64::max,
+            )
+            .unwrap();
+        assert_eq!(max, 1000);
+        let sum = store
+            .fold_parallel(
+                b"sale:",
+                3,
+                || 0,
+                |sum, _, value| sum + amount(value),
+                |a, b| a + b,
+            )
+            .unwrap();
+        assert_eq!(sum, 5050);
+    }
+    #[rstest]
+    #[serial]
+    fn test_scans_take_chunked_values_along() {
+        let options = Options {
+            chunk_size: Some(10),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_scan", options);
+        let store = &mut ctx.test_file;
+        let big = vec![7u8; 95];
+        store.insert(b"k:small", &[1]).unwrap();
+        store.insert(b"k:big", &big).unwrap();
+        store.insert(b"other", &big).unwrap();
+
+        let pairs = store.scan_filtered(b"k:", |_, _| true).unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!((&pairs[0].key[..], &pairs[0].value), (&b"k:big"[..], &big));
+        let bytes = |sum: usize, _: &[u8], value: &[u8]| sum + value.len();
+        assert_eq!(store.fold(b"k:", 0, bytes).unwrap(), 96);
+        let parallel = store
+            .fold_parallel(b"", 2, || 0, bytes, |a, b| a + b)
+            .unwrap();
+        assert_eq!(parallel, 191);
+        assert_eq!(
+            store.scan_glob(b"k:*").unwrap(),
+            vec![b"k:big".to_vec(), b"k:small".to_vec()]
+        );
+        assert_eq!(store.delete_glob(b"*big").unwrap(), 1);
+        assert_eq!(store.get(b"k:big").unwrap(), None);
+        assert_eq!(store.fold(b"", 0, bytes).unwrap(), 96);
+    }
+    #[rstest]
+    #[serial]
+    fn test_scans_leave_out_unreadable_keys() {
+        let options = Options {
+            chunk_size: Some(2),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_scan", options);
+        let store = &mut ctx.test_file;
+        store.insert(b"secret:big", &[9; 10]).unwrap();
+        testing::hide_secrets(store, 5);
+        assert_eq!(store.scan_filtered(b"", |_, _| true).unwrap().len(), 5);
+        let public = |count: usize, key: &[u8], _: &[u8]| {
+            assert!(key.starts_with(b"public:"));
+            count + 1
+        };
+        assert_eq!(store.fold(b"", 0, public).unwrap(), 5);
+        let parallel = store
+            .fold_parallel(b"", 3, || 0, public, |a, b| a + b)
+            .unwrap();
+        assert_eq!(parallel, 5);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::access::{self, AccessPolicy};
+use crate::chunked::ChunkedValue;
+use crate::compression::ValueCodec;
+use crate::format::Framing;
+use crate::scan::read_record;
+use crate::segment::SegmentReader;
+use crate::{ActionKV, ByteStr, ByteString, KeyValuePair};
+use futures_core::Stream;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::vec;
+
+/// Live pairs under a prefix, read one record at a time as they are asked
+/// for. It reads through file handles of its own and doesn't borrow the
+/// store, so it can be handed to e.g. an HTTP response body. Pairs come in
+/// data file order, as of when the scan started, values written in chunks
+/// last.
+#[derive(Debug)]
+pub struct ScanStream {
+    segments: SegmentReader,
+    framing: Framing,
+    values: Option<Arc<ValueCodec>>,
+    positions: vec::IntoIter<u64>,
+    chunked: vec::IntoIter<ChunkedValue>,
+    prefix: ByteString,
+    policy: Option<AccessPolicy>,
+}
+
+impl ScanStream {
+    fn read(&mut self, position: u64) -> io::Result<Option<KeyValuePair>> {
+        let record = read_record(
+            &mut self.segments,
+            self.framing,
+            self.values.as_deref(),
+            position,
+        )?;
+        // with hashed keys only the record tells what the key is
+        if !record.key.starts_with(&self.prefix) || !access::readable(self.policy, &record.key) {
+            return Ok(None);
+        }
+        Ok(Some(record))
+    }
+    fn assemble(&mut self, chunked: ChunkedValue) -> io::Result<KeyValuePair> {
+        let value = chunked.assemble(|position| {
+            let values = self.values.as_deref();
+            Ok(read_record(&mut self.segments, self.framing, values, position)?.value)
+        })?;
+        Ok(KeyValuePair {
+            key: chunked.key,
+            value,
+        })
+    }
+}
+
+impl Iterator for ScanStream {
+    type Item = io::Result<KeyValuePair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(position) = self.positions.next() {
+            match self.read(position) {
+                Ok(Some(pair)) => return Some(Ok(pair)),
+                Ok(None) => continue,
+                Err(err) => {
+                    // the file can't be trusted past a failed read
+                    self.positions = Vec::new().into_iter();
+                    self.chunked = Vec::new().into_iter();
+                    return Some(Err(err));
+                }
+            }
+        }
+        let chunked = self.chunked.next()?;
+        Some(self.assemble(chunked))
+    }
+}
+
+/// Every poll reads the next record from disk right away, nothing is read
+/// ahead of what the consumer asks for beyond the read-ahead buffer.
+impl Stream for ScanStream {
+    type Item = io::Result<KeyValuePair>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.get_mut().next())
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.positions.len() + self.chunked.len()))
+    }
+}
+
+impl ActionKV {
+    /// `scan_filtered` without the filter as a `Stream`, for async consumers
+    /// that shouldn't have to buffer the whole result. Keys the access policy
+    /// doesn't let be read are left out.
+    pub fn scan_stream(&mut self, prefix: &ByteStr) -> io::Result<ScanStream> {
+        let positions = self.prefix_positions(prefix)?;
+        let mut chunked = self.chunked_values(prefix)?;
+        chunked.retain(|value| self.readable(&value.key));
+        Ok(ScanStream {
+            segments: SegmentReader::open(self.dir("scans")?)?,
+            framing: self.framing,
+            values: self.values.clone(),
+            positions: positions.into_iter(),
+            chunked: chunked.into_iter(),
+            prefix: prefix.to_vec(),
+            policy: self.options.access_policy,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[serial]
+    fn test_scan_stream() {
+        let mut ctx = TestCtx::setup("test_scan_stream");
+        let store = &mut ctx.test_file;
+        store.insert(b"user:1", b"ann").unwrap();
+        store.insert(b"order:1", b"x").unwrap();
+        store.insert(b"user:2", b"bob").unwrap();
+        store.insert(b"user:1", b"amy").unwrap();
+        store.options.chunk_size = Some(2);
+        store.insert(b"user:4", b"dora").unwrap();
+        let stream = store.scan_stream(b"user:").unwrap();
+        // the store stays usable while the stream is around
+        store.insert(b"user:3", b"cat").unwrap();
+        let pairs: Vec<_> = futures_executor::block_on_stream(stream)
+            .map(|pair| pair.unwrap())
+            .map(|pair| (pair.key, pair.value))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (b"user:2".to_vec(), b"bob".to_vec()),
+                (b"user:1".to_vec(), b"amy".to_vec()),
+                (b"user:4".to_vec(), b"dora".to_vec()),
+            ]
+        );
+    }
+}
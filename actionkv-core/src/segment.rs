@@ -0,0 +1,1233 @@
+use crate::format::{FileHeader, Generation, DATA_MAGIC, HEADER_LEN};
+use crate::manifest::Manifest;
+use crate::prefetch::ReadAhead;
+use crate::{
+    is_internal_key, lock, platform, write_record, ActionKV, ByteStr, ByteString, CompactionReport,
+    OpClass, INDEX_KEY,
+};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/*
+    SEGMENTS
+    with Options::segment_size the data file is sealed once it grows past that
+    size: it is renamed to data.<id>, e.g. data.0001, and a new data file with
+    the same header takes its place. Sealed segments are never written again.
+    A position packs the segment in its top 16 bits and the offset within it
+    below, segment 0 being the data file, so the positions of a store that
+    never sealed one are plain offsets. The data file is the only one ever
+    written to, the sealed segments are the ones the manifest records, so
+    open never has to guess which file is which from what is on disk.
+    Sealing moves the positions pointing into the data file to the id it
+    was sealed as:
+      1. the index is saved, the data file synced, data.next is written
+         with the header
+      2. data is renamed to data.<id>
+      3. the manifest records data.<id>, which seals it
+      4. data.next is renamed to data
+      5. the index is saved with its positions moved
+    A crash in 1 leaves data.next behind, it is removed on open. A crash in
+    2 or 3 leaves data missing and data.<id> unrecorded, it is renamed back
+    to data. In 4 data is missing next to a recorded data.<id>, data.next is
+    put in place. A crash in 5 leaves an index written against the length
+    of data.<id> next to an empty data file, open moves the positions then.
+    Any other data.<id> the manifest doesn't record is left from a merge
+    and removed on open. Stores whose manifest doesn't record segments yet
+    go by the file names, until they next seal one.
+    Segments belong to the generation of the data file: compaction copies
+    them into its new data file, the ones a crash left behind after it are
+    removed on open. compact_segments merges the sealed segments with the
+    most garbage into a new one and leaves the data file alone. Which ones
+    it picks goes by the records of each segment the index points at,
+    counted once and then kept up to date by every write that moves an
+    index entry, against the records its footer says it holds.
+
+    SEGMENT FOOTERS
+    the last record of a sealed or merged segment, under +segment<id>, sums
+    up what it holds so scans and compaction can leave it alone unread
+        has_range | min_len | min | max_len | max | keys | records | live | created
+        [u8;1]      [u32;1]         [u32;1]         [u64;1] [u64;1] [u64;1] [u64;1]
+    min and max are the smallest and largest user keys with a record in it,
+    left out with has_range 0 when there are none. keys counts those keys,
+    records the records before the footer, live the ones of them the index
+    pointed at when it was written. created is in milliseconds since the Unix
+    epoch by the store's clock. The index points at the footer like at any
+    other record, so rebuilding it finds the footers again; segments sealed
+    before there were footers have none.
+*/
+/// Bits of a position below the segment id.
+const SEGMENT_SHIFT: u32 = 48;
+const OFFSET_MASK: u64 = (1 << SEGMENT_SHIFT) - 1;
+/// Segment id of the data file being appended to.
+pub(crate) const ACTIVE: u16 = 0;
+const NEXT_DATA: &str = "data.next";
+/// A merged segment while it is written.
+const MERGED_SUFFIX: &str = ".merge";
+/// Key of a segment's footer, followed by its id as a big endian u16.
+const FOOTER_PREFIX: &ByteStr = b"+segment";
+
+/// Position of the record at `offset` of `segment`.
+pub(crate) fn position(segment: u16, offset: u64) -> u64 {
+    ((segment as u64) << SEGMENT_SHIFT) | offset
+}
+
+/// Segment and offset of the record at `position`.
+pub(crate) fn split(position: u64) -> (u16, u64) {
+    ((position >> SEGMENT_SHIFT) as u16, position & OFFSET_MASK)
+}
+
+pub(crate) fn segment_path(dir: &Path, segment: u16) -> PathBuf {
+    match segment {
+        ACTIVE => dir.join("data"),
+        id => dir.join(format!("data.{:04}", id)),
+    }
+}
+
+pub(crate) fn footer_key(segment: u16) -> ByteString {
+    let mut key = FOOTER_PREFIX.to_vec();
+    key.extend(segment.to_be_bytes());
+    key
+}
+
+pub(crate) fn is_footer_key(key: &ByteStr) -> bool {
+    key.len() == FOOTER_PREFIX.len() + 2 && key.starts_with(FOOTER_PREFIX)
+}
+
+/// What a sealed segment holds, see SEGMENT FOOTERS.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SegmentFooter {
+    /// Smallest and largest user key with a record in the segment, `None`
+    /// if it has none.
+    pub key_range: Option<(ByteString, ByteString)>,
+    /// User keys with a record in the segment.
+    pub keys: u64,
+    /// Records in the segment, the footer left out.
+    pub records: u64,
+    /// Records the index pointed at when the segment was sealed or merged.
+    pub live: u64,
+    /// When the segment was sealed or merged, since the Unix epoch.
+    pub created: Duration,
+}
+
+impl SegmentFooter {
+    /// Footer of a segment sealed or merged `now`, with nothing counted yet.
+    fn new(now: Duration) -> Self {
+        SegmentFooter {
+            // milliseconds is all it keeps
+            created: Duration::from_millis(now.as_millis() as u64),
+            ..SegmentFooter::default()
+        }
+    }
+    fn add_key(&mut self, key: &ByteStr) {
+        self.keys += 1;
+        self.key_range = match self.key_range.take() {
+            None => Some((key.to_vec(), key.to_vec())),
+            Some((min, max)) if key < min.as_slice() => Some((key.to_vec(), max)),
+            Some((min, max)) if key > max.as_slice() => Some((min, key.to_vec())),
+            range => range,
+        };
+    }
+    /// Whether the segment may hold a record of a user key starting with
+    /// `prefix`.
+    pub fn may_hold(&self, prefix: &ByteStr) -> bool {
+        match &self.key_range {
+            None => false,
+            Some((min, max)) => {
+                max.as_slice() >= prefix && (min.as_slice() <= prefix || min.starts_with(prefix))
+            }
+        }
+    }
+    pub(crate) fn encode(&self) -> ByteString {
+        let mut buf = ByteString::new();
+        // writes into a Vec can't fail
+        match &self.key_range {
+            Some((min, max)) => {
+                buf.push(1);
+                for key in [min, max] {
+                    let _ = buf.write_u32::<LittleEndian>(key.len() as u32);
+                    buf.extend(key);
+                }
+            }
+            None => buf.push(0),
+        }
+        for count in [self.keys, self.records, self.live] {
+            let _ = buf.write_u64::<LittleEndian>(count);
+        }
+        let _ = buf.write_u64::<LittleEndian>(self.created.as_millis() as u64);
+        buf
+    }
+    pub(crate) fn decode(mut bytes: &ByteStr) -> io::Result<Self> {
+        fn key(bytes: &mut &ByteStr) -> io::Result<ByteString> {
+            let len = bytes.read_u32::<LittleEndian>()? as usize;
+            if len > bytes.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "segment footer is cut short",
+                ));
+            }
+            let (key, rest) = bytes.split_at(len);
+            *bytes = rest;
+            Ok(key.to_vec())
+        }
+        let key_range = match bytes.read_u8()? {
+            0 => None,
+            _ => Some((key(&mut bytes)?, key(&mut bytes)?)),
+        };
+        Ok(SegmentFooter {
+            key_range,
+            keys: bytes.read_u64::<LittleEndian>()?,
+            records: bytes.read_u64::<LittleEndian>()?,
+            live: bytes.read_u64::<LittleEndian>()?,
+            created: Duration::from_millis(bytes.read_u64::<LittleEndian>()?),
+        })
+    }
+}
+
+/// How much of a sealed segment is garbage, what `compact_segments` picks
+/// the segments it merges by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SegmentScore {
+    pub id: u16,
+    /// Bytes of records in the segment, its footer included.
+    pub bytes: u64,
+    /// Records in the segment, `None` for segments sealed before they had
+    /// footers.
+    pub records: Option<u64>,
+    /// Records in the segment the index points at.
+    pub live: u64,
+    /// Estimated bytes of overwritten values and tombstones, the share of
+    /// `bytes` the records no longer pointed at make up.
+    pub dead_bytes: u64,
+}
+
+impl SegmentScore {
+    fn new(id: u16, bytes: u64, records: Option<u64>, live: u64) -> Self {
+        let dead_bytes = match records {
+            Some(records) if records > 0 => {
+                (bytes as u128 * records.saturating_sub(live) as u128 / records as u128) as u64
+            }
+            // nothing to go by, merging gives it a footer
+            _ => bytes,
+        };
+        SegmentScore {
+            id,
+            bytes,
+            records,
+            live,
+            dead_bytes,
+        }
+    }
+    /// Share of the segment's bytes that is garbage, 1.0 for segments
+    /// without a footer.
+    pub fn dead_ratio(&self) -> f64 {
+        match self.bytes {
+            0 => 1.0,
+            bytes => self.dead_bytes as f64 / bytes as f64,
+        }
+    }
+}
+
+fn generation_of(path: &Path) -> io::Result<Option<Generation>> {
+    let header = FileHeader::read_from(&mut File::open(path)?, DATA_MAGIC)?;
+    Ok(header.map(|header| header.generation))
+}
+
+/// Ids of the segments sealed in `dir` of the data file's `generation`,
+/// oldest first: the ones the manifest records, or going by the file names
+/// if it records none. Segments of another generation are left from before
+/// a compaction, unrecorded ones from a merge, both are removed unless
+/// `read_only`.
+fn sealed_ids(dir: &Path, generation: Generation, read_only: bool) -> io::Result<Vec<u16>> {
+    let recorded = Manifest::read(dir)?.segments;
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        // a merge a crash cut short
+        if !read_only
+            && name
+                .to_str()
+                .is_some_and(|name| name.ends_with(MERGED_SUFFIX))
+        {
+            fs::remove_file(dir.join(name))?;
+            continue;
+        }
+        let Some(id) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("data."))
+            .filter(|id| id.len() >= 4 && id.bytes().all(|byte| byte.is_ascii_digit()))
+            .and_then(|id| id.parse::<u16>().ok())
+            .filter(|id| *id != ACTIVE)
+        else {
+            continue;
+        };
+        let path = segment_path(dir, id);
+        let sealed = recorded
+            .as_ref()
+            .is_none_or(|recorded| recorded.contains(&id));
+        if sealed && generation_of(&path)? == Some(generation) {
+            ids.push(id);
+        } else if !read_only {
+            fs::remove_file(path)?;
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// The id of a data file renamed to data.<id> in `dir` but not recorded as
+/// sealed yet, the highest one if several are.
+fn unrecorded_id(dir: &Path, recorded: &[u16]) -> io::Result<Option<u16>> {
+    let mut unrecorded = None;
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let id = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("data."))
+            .filter(|id| id.len() >= 4 && id.bytes().all(|byte| byte.is_ascii_digit()))
+            .and_then(|id| id.parse::<u16>().ok())
+            .filter(|id| *id != ACTIVE && !recorded.contains(id));
+        unrecorded = unrecorded.max(id);
+    }
+    Ok(unrecorded)
+}
+
+/// Records `ids` as the sealed segments in the manifest of the store in
+/// `dir`.
+fn record_sealed(dir: &Path, ids: &[u16]) -> io::Result<()> {
+    let mut manifest = Manifest::read(dir)?;
+    manifest.segments = Some(ids.to_vec());
+    manifest.write(dir)
+}
+
+/// Length of the newest segment sealed in `dir`, for `open` to tell an
+/// index saved before the data file was sealed.
+pub(crate) fn newest_sealed_len(dir: &Path) -> io::Result<Option<u64>> {
+    let Some(generation) = generation_of(&dir.join("data")).ok().flatten() else {
+        return Ok(None);
+    };
+    match sealed_ids(dir, generation, true)?.last() {
+        Some(id) => Ok(Some(fs::metadata(segment_path(dir, *id))?.len())),
+        None => Ok(None),
+    }
+}
+
+/// Completes or rolls back a sealing that was interrupted by a crash.
+pub(crate) fn finish_interrupted(dir: &Path) -> io::Result<()> {
+    let next = dir.join(NEXT_DATA);
+    if !next.exists() {
+        return Ok(());
+    }
+    if dir.join("data").exists() {
+        return fs::remove_file(next);
+    }
+    let recorded = Manifest::read(dir)?.segments;
+    match recorded.map(|recorded| unrecorded_id(dir, &recorded)) {
+        // renamed but not sealed yet, it still is the data file
+        Some(unrecorded) => match unrecorded? {
+            Some(id) => {
+                fs::rename(segment_path(dir, id), dir.join("data"))?;
+                fs::remove_file(next)?;
+            }
+            None => fs::rename(next, dir.join("data"))?,
+        },
+        None => fs::rename(next, dir.join("data"))?,
+    }
+    platform::sync_dir(dir)
+}
+
+/// The sealed segments of a store, oldest first, the ones read from so far
+/// kept open.
+#[derive(Debug, Default)]
+pub(crate) struct Segments {
+    ids: Vec<u16>,
+    files: HashMap<u16, File>,
+    /// The footers read so far.
+    footers: HashMap<u16, SegmentFooter>,
+    /// Records of each segment the index points at, `None` until they are
+    /// counted, then kept up to date as keys are overwritten and deleted.
+    live: Option<HashMap<u16, u64>>,
+    /// Bytes of records in each segment scored so far.
+    lens: HashMap<u16, u64>,
+    /// Bytes sealed since the store was last compacted or opened.
+    pub sealed_bytes: u64,
+}
+
+impl Segments {
+    /// The sealed segments of the store in `dir`, none for stores without a
+    /// directory or a generation to tell them by.
+    pub(crate) fn open(
+        dir: Option<&Path>,
+        generation: Option<Generation>,
+        read_only: bool,
+    ) -> io::Result<Self> {
+        let (Some(dir), Some(generation)) = (dir, generation) else {
+            return Ok(Segments::default());
+        };
+        let mut segments = Segments {
+            ids: sealed_ids(dir, generation, read_only)?,
+            ..Segments::default()
+        };
+        if read_only {
+            // the writer may merge them away before they are first read
+            for id in segments.ids.clone() {
+                segments.file(dir, id)?;
+            }
+        }
+        Ok(segments)
+    }
+    pub(crate) fn ids(&self) -> &[u16] {
+        &self.ids
+    }
+    fn next_id(&self) -> io::Result<u16> {
+        match self.ids.last() {
+            None => Ok(1),
+            Some(id) => id
+                .checked_add(1)
+                .ok_or_else(|| io::Error::other("out of segment ids, compact the store")),
+        }
+    }
+    /// The open file of the sealed `segment` of the store in `dir`.
+    fn file(&mut self, dir: &Path, segment: u16) -> io::Result<&mut File> {
+        match self.files.entry(segment) {
+            Entry::Occupied(file) => Ok(file.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(File::open(segment_path(dir, segment))?)),
+        }
+    }
+    /// Counts an index entry moving from `from` to `to` towards the live
+    /// records of the segments they are in.
+    pub(crate) fn moved(&mut self, from: Option<u64>, to: Option<u64>) {
+        let Some(live) = &mut self.live else {
+            return;
+        };
+        if let Some((segment, _)) = from.map(split).filter(|(segment, _)| *segment != ACTIVE) {
+            let count = live.entry(segment).or_default();
+            *count = count.saturating_sub(1);
+        }
+        if let Some((segment, _)) = to.map(split).filter(|(segment, _)| *segment != ACTIVE) {
+            *live.entry(segment).or_default() += 1;
+        }
+    }
+    /// Forgets the live records counted, after the index was replaced.
+    pub(crate) fn uncount(&mut self) {
+        self.live = None;
+    }
+    /// The scores of the segments whose footer, length and live records
+    /// are known, highest dead ratio first.
+    fn scores(&self) -> Vec<SegmentScore> {
+        let Some(live) = &self.live else {
+            return Vec::new();
+        };
+        let mut scores: Vec<SegmentScore> = self
+            .ids
+            .iter()
+            .filter_map(|id| {
+                Some(SegmentScore::new(
+                    *id,
+                    *self.lens.get(id)?,
+                    self.footers.get(id).map(|footer| footer.records),
+                    live.get(id).copied().unwrap_or(0),
+                ))
+            })
+            .collect();
+        scores.sort_by(|a, b| b.dead_ratio().total_cmp(&a.dead_ratio()));
+        scores
+    }
+    /// Forgets every sealed segment, after removing their files from `dir`.
+    fn remove_all(&mut self, dir: &Path) -> io::Result<()> {
+        self.files.clear();
+        self.footers.clear();
+        self.lens.clear();
+        self.live = None;
+        for id in std::mem::take(&mut self.ids) {
+            fs::remove_file(segment_path(dir, id))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads records by position through file handles of its own, for scans
+/// that mustn't move the store's. Segments are opened as they are needed.
+#[derive(Debug)]
+pub(crate) struct SegmentReader {
+    dir: PathBuf,
+    files: HashMap<u16, ReadAhead<File>>,
+}
+
+impl SegmentReader {
+    pub(crate) fn open(dir: &Path) -> io::Result<Self> {
+        let active = ReadAhead::new(File::open(segment_path(dir, ACTIVE))?)?;
+        Ok(SegmentReader {
+            dir: dir.to_path_buf(),
+            files: HashMap::from([(ACTIVE, active)]),
+        })
+    }
+    /// The reader of the segment holding `position`, at the record there.
+    pub(crate) fn at(&mut self, position: u64) -> io::Result<&mut ReadAhead<File>> {
+        let (segment, offset) = split(position);
+        let f = match self.files.entry(segment) {
+            Entry::Occupied(file) => file.into_mut(),
+            Entry::Vacant(entry) => entry.insert(ReadAhead::new(File::open(segment_path(
+                &self.dir, segment,
+            ))?)?),
+        };
+        f.seek(SeekFrom::Start(offset))?;
+        Ok(f)
+    }
+}
+
+impl ActionKV {
+    /// The file holding the record at `position` and where in it the record
+    /// starts.
+    pub(crate) fn record_file(&mut self, position: u64) -> io::Result<(&mut File, u64)> {
+        match split(position) {
+            (ACTIVE, offset) => Ok((&mut self.file_, offset)),
+            (segment, offset) => {
+                let dir = self.dir("sealed segments")?.to_path_buf();
+                Ok((self.segments.file(&dir, segment)?, offset))
+            }
+        }
+    }
+    /// Every segment's file in the order it was written, the data file last.
+    pub(crate) fn segment_files(&self) -> io::Result<Vec<(u16, PathBuf)>> {
+        if self.segments.ids().is_empty() {
+            return Ok(Vec::new());
+        }
+        let dir = self.dir("sealed segments")?;
+        Ok(self
+            .segments
+            .ids()
+            .iter()
+            .map(|id| (*id, segment_path(dir, *id)))
+            .collect())
+    }
+    /// Bytes the sealed segments take on disk.
+    pub(crate) fn sealed_len(&self) -> io::Result<u64> {
+        let mut len = 0;
+        for (_, path) in self.segment_files()? {
+            len += fs::metadata(path)?.len();
+        }
+        Ok(len)
+    }
+    /// Bytes of records in the data file and the sealed segments.
+    pub(crate) fn log_bytes(&self) -> io::Result<u64> {
+        let mut bytes = self.appender.tail() - self.data_start;
+        for (_, path) in self.segment_files()? {
+            bytes += fs::metadata(path)?.len().saturating_sub(self.data_start);
+        }
+        Ok(bytes)
+    }
+    /// Applies the records of every sealed segment to `index`, oldest first.
+    pub(crate) fn replay_sealed(&mut self, index: &mut HashMap<ByteString, u64>) -> io::Result<()> {
+        for (id, path) in self.segment_files()? {
+            let mut f = ReadAhead::new(File::open(path)?)?;
+            crate::replay_records(&mut f, self.framing, id, self.data_start, u64::MAX, index)?;
+        }
+        Ok(())
+    }
+    /// Seals the data file once it grew past `Options::segment_size`,
+    /// returns whether it did. Stores opened on file handles never seal.
+    pub(crate) fn seal_if_full(&mut self) -> io::Result<bool> {
+        let sealable = self.path.is_some() && self.generation.is_some();
+        match self.options.segment_size {
+            Some(size) if sealable && self.appender.tail() >= size => {
+                self.seal()?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+    /// Seals the data file as the next segment and starts a new one.
+    fn seal(&mut self) -> io::Result<()> {
+        let dir = self.dir("sealing")?.to_path_buf();
+        // files without a header can't be told apart from other generations
+        let Some(generation) = self.generation else {
+            return Ok(());
+        };
+        let id = self.segments.next_id()?;
+        self.read_index()?;
+        let footer = self.active_footer(&dir)?;
+        self.insert_(&footer_key(id), &footer.encode())?;
+        // nothing in the data file is left for open to replay
+        self.store_index_on_disk(INDEX_KEY)?;
+        self.file_.sync_all()?;
+        let next = dir.join(NEXT_DATA);
+        let mut f = File::create(&next)?;
+        FileHeader::for_data(generation, self.framing).write_to(&mut f)?;
+        f.write_all(&vec![0; (self.data_start - HEADER_LEN) as usize])?;
+        f.sync_all()?;
+        drop(f);
+
+        let lock = lock::exclusive(&dir)?;
+        fs::rename(dir.join("data"), segment_path(&dir, id))?;
+        platform::sync_dir(&dir)?;
+        let mut sealed = self.segments.ids().to_vec();
+        sealed.push(id);
+        record_sealed(&dir, &sealed)?;
+        fs::rename(&next, dir.join("data"))?;
+        platform::sync_dir(&dir)?;
+        drop(lock);
+        self.file_ = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(dir.join("data"))?;
+        self.segments.sealed_bytes += self.appender.tail();
+        let sealed_len = self.appender.tail() - self.data_start;
+        self.appender.reset(self.data_start);
+        self.segments.ids.push(id);
+        let live = footer.live;
+        self.segments.footers.insert(id, footer);
+        // saving it left only its position in memory
+        self.read_index()?;
+        self.move_sealed_positions(id);
+        self.segments.lens.insert(id, sealed_len);
+        if let Some(counts) = &mut self.segments.live {
+            counts.insert(id, live);
+        }
+        // the deletes it remembers point into the data file
+        self.write_log.compacted();
+        self.store_index_on_disk(INDEX_KEY)
+    }
+    /// Footer of the records the data file in `dir` holds.
+    fn active_footer(&self, dir: &Path) -> io::Result<SegmentFooter> {
+        let mut footer = SegmentFooter::new(self.clock.now());
+        let mut keys = HashSet::new();
+        let mut f = ReadAhead::new(File::open(segment_path(dir, ACTIVE))?)?;
+        let mut position = f.seek(SeekFrom::Start(self.data_start))?;
+        while position < self.appender.tail() {
+            let record = ActionKV::process_records(&mut f, self.framing)?;
+            position = f.stream_position()?;
+            footer.records += 1;
+            if !is_internal_key(&record.key) && keys.insert(record.key.clone()) {
+                footer.add_key(&record.key);
+            }
+        }
+        footer.live = self
+            .index
+            .iter()
+            .filter(|(key, position)| {
+                *key != INDEX_KEY && !is_footer_key(key) && split(**position).0 == ACTIVE
+            })
+            .count() as u64;
+        Ok(footer)
+    }
+    /// The footer of the sealed `segment`, `None` if it was sealed before
+    /// segments had footers.
+    pub(crate) fn segment_footer(&mut self, segment: u16) -> io::Result<Option<SegmentFooter>> {
+        if let Some(footer) = self.segments.footers.get(&segment) {
+            return Ok(Some(footer.clone()));
+        }
+        self.read_index()?;
+        let Some(position) = self.index.get(&footer_key(segment)).copied() else {
+            return Ok(None);
+        };
+        let footer = SegmentFooter::decode(&self.get_at(position, false)?.value)?;
+        self.segments.footers.insert(segment, footer.clone());
+        Ok(Some(footer))
+    }
+    /// The footer of every sealed segment, oldest first, for telling what
+    /// the segments hold without reading them.
+    pub fn segment_footers(&mut self) -> io::Result<Vec<(u16, Option<SegmentFooter>)>> {
+        let mut footers = Vec::new();
+        for id in self.segments.ids().to_vec() {
+            footers.push((id, self.segment_footer(id)?));
+        }
+        Ok(footers)
+    }
+    /// The sealed segments whose footer says they hold no user key starting
+    /// with `prefix`.
+    pub(crate) fn segments_without(&mut self, prefix: &ByteStr) -> io::Result<HashSet<u16>> {
+        let mut without = HashSet::new();
+        for (id, footer) in self.segment_footers()? {
+            if footer.is_some_and(|footer| !footer.may_hold(prefix)) {
+                without.insert(id);
+            }
+        }
+        Ok(without)
+    }
+    /// Scores every sealed segment by how much of it is garbage, highest
+    /// dead ratio first. The first call counts the records of each segment
+    /// the index points at, later ones keep to the counts overwrites and
+    /// deletes keep up to date. `Stats::segments` has the last scores.
+    pub fn segment_scores(&mut self) -> io::Result<Vec<SegmentScore>> {
+        self.read_index()?;
+        if self.segments.live.is_none() {
+            let mut live = HashMap::new();
+            for (key, position) in &self.index {
+                let segment = split(*position).0;
+                if key != INDEX_KEY && !is_footer_key(key) && segment != ACTIVE {
+                    *live.entry(segment).or_default() += 1;
+                }
+            }
+            self.segments.live = Some(live);
+        }
+        for id in self.segments.ids().to_vec() {
+            if !self.segments.lens.contains_key(&id) {
+                let path = segment_path(self.dir("sealed segments")?, id);
+                let len = fs::metadata(path)?.len().saturating_sub(self.data_start);
+                self.segments.lens.insert(id, len);
+            }
+            self.segment_footer(id)?;
+        }
+        Ok(self.segments.scores())
+    }
+    /// The scores of the segments `segment_scores` knows about.
+    pub(crate) fn known_segment_scores(&self) -> Vec<SegmentScore> {
+        self.segments.scores()
+    }
+    /// Keeps the live records counted per segment up to date with the index
+    /// entry of `key` moving from `from` to `to`.
+    pub(crate) fn index_moved(&mut self, key: &ByteStr, from: Option<u64>, to: Option<u64>) {
+        if key != INDEX_KEY && !is_footer_key(key) {
+            self.segments.moved(from, to);
+        }
+    }
+    /// `position` in the order the log was written, which the positions of
+    /// the data file don't keep once segments were sealed before it.
+    pub(crate) fn log_position(&self, position: u64) -> u64 {
+        match split(position) {
+            (ACTIVE, offset) => {
+                let active = self.segments.next_id().unwrap_or(u16::MAX);
+                crate::segment::position(active, offset)
+            }
+            _ => position,
+        }
+    }
+    /// Points the index entries into the data file at the segment `id` it
+    /// was sealed as.
+    pub(crate) fn move_sealed_positions(&mut self, id: u16) {
+        for (key, position) in self.index.iter_mut() {
+            if key != INDEX_KEY && split(*position).0 == ACTIVE {
+                *position = crate::segment::position(id, *position);
+            }
+        }
+        self.inline_renumbered(|position| match split(position) {
+            (ACTIVE, offset) => Some(crate::segment::position(id, offset)),
+            _ => Some(position),
+        });
+    }
+    /// Whether the segments sealed in `dir` are no longer the ones the store
+    /// opened, for read only stores to notice the writer sealing or merging.
+    pub(crate) fn segments_changed(&self, dir: &Path) -> io::Result<bool> {
+        match self.generation {
+            Some(generation) => Ok(sealed_ids(dir, generation, true)? != self.segments.ids()),
+            None => Ok(false),
+        }
+    }
+    /// The id of the newest sealed segment.
+    pub(crate) fn newest_segment(&self) -> Option<u16> {
+        self.segments.ids().last().copied()
+    }
+    /// Removes the sealed segments after a compaction copied their live
+    /// records into the new data file.
+    pub(crate) fn drop_segments(&mut self) -> io::Result<()> {
+        self.segments.sealed_bytes = 0;
+        if self.segments.ids().is_empty() {
+            return Ok(());
+        }
+        let dir = self.dir("sealed segments")?.to_path_buf();
+        self.segments.remove_all(&dir)?;
+        platform::sync_dir(&dir)?;
+        record_sealed(&dir, &[])
+    }
+    /// Merges the sealed segments with the most garbage into one holding
+    /// only their records the index points at, without touching the data
+    /// file, which keeps taking writes as before. Segments are picked by
+    /// `segment_scores`, highest dead ratio first, those below
+    /// `Options::segment_garbage_ratio` or without garbage left alone, until
+    /// their live records would fill `Options::segment_size`. Overwritten
+    /// values are dropped, as are tombstones unless an older segment is
+    /// left that may still hold the deleted key. The compaction filter
+    /// isn't run.
+    pub fn compact_segments(&mut self) -> io::Result<CompactionReport> {
+        self.writable()?;
+        self.read_index()?;
+        if self.segments.ids().is_empty() {
+            return Ok(CompactionReport::default());
+        }
+        let dir = self.dir("segment compaction")?.to_path_buf();
+        let Some(generation) = self.generation else {
+            return Ok(CompactionReport::default());
+        };
+        let mut victims = Vec::new();
+        let mut live_bytes = 0;
+        for score in self.segment_scores()? {
+            let ratio = score.dead_ratio();
+            if ratio == 0.0 || ratio < self.options.segment_garbage_ratio {
+                break;
+            }
+            let live = score.bytes - score.dead_bytes;
+            if !victims.is_empty()
+                && self
+                    .options
+                    .segment_size
+                    .is_some_and(|size| live_bytes + live > size)
+            {
+                break;
+            }
+            live_bytes += live;
+            victims.push(score.id);
+        }
+        if victims.is_empty() {
+            return Ok(CompactionReport::default());
+        }
+        victims.sort();
+        self.op_class = OpClass::Maintenance;
+        let merged_id = self.segments.next_id()?;
+        let mut report = CompactionReport::default();
+        self.prune_inline();
+
+        let framing = self.framing;
+        let merged = dir.join(format!("data.{:04}{}", merged_id, MERGED_SUFFIX));
+        let mut f = BufWriter::new(File::create(&merged)?);
+        FileHeader::for_data(generation, framing).write_to(&mut f)?;
+        f.write_all(&vec![0; (self.data_start - HEADER_LEN) as usize])?;
+        let mut offset = self.data_start;
+        let mut moved = HashMap::new();
+        let mut tombstones = HashSet::new();
+        let mut footer = SegmentFooter::new(self.clock.now());
+        for id in &victims {
+            let path = segment_path(&dir, *id);
+            let len = fs::metadata(&path)?.len();
+            report.bytes_before += len;
+            // a delete only matters to the records of older segments
+            let keep_tombstones = self
+                .segments
+                .ids()
+                .iter()
+                .any(|older| older < id && !victims.contains(older));
+            let mut segment = ReadAhead::new(File::open(&path)?)?;
+            let mut at = segment.seek(SeekFrom::Start(self.data_start))?;
+            while at < len {
+                // the value stays encoded as it is
+                let record = ActionKV::process_records(&mut segment, framing)?;
+                let from = position(*id, at);
+                at = segment.stream_position()?;
+                let key = self.index_key(&record.key).into_owned();
+                let current = self.index.get(&key) == Some(&from);
+                let deleted = keep_tombstones
+                    && record.value.is_empty()
+                    && !is_internal_key(&record.key)
+                    && !self.index.contains_key(&key)
+                    && tombstones.insert(key.clone());
+                if is_footer_key(&record.key) || !(current || deleted) {
+                    report.dropped += 1;
+                    continue;
+                }
+                if current {
+                    moved.insert(key, (from, position(merged_id, offset)));
+                }
+                if !is_internal_key(&record.key) {
+                    footer.add_key(&record.key);
+                }
+                offset += write_record(&mut f, framing, &record.key, &record.value)?;
+                report.kept += 1;
+            }
+        }
+        footer.records = report.kept;
+        footer.live = moved.len() as u64;
+        let footer_at = position(merged_id, offset);
+        offset += write_record(
+            &mut f,
+            framing,
+            &footer_key(merged_id),
+            &self.encode_value(&footer.encode())?,
+        )?;
+        f.flush()?;
+        f.get_ref().sync_all()?;
+        drop(f);
+        report.bytes_after = offset;
+
+        let lock = lock::exclusive(&dir)?;
+        fs::rename(&merged, segment_path(&dir, merged_id))?;
+        platform::sync_dir(&dir)?;
+        // the index still points into the merged segments until it is saved
+        let mut sealed = self.segments.ids().to_vec();
+        sealed.push(merged_id);
+        record_sealed(&dir, &sealed)?;
+        let inline_moved: HashMap<u64, u64> = moved.values().copied().collect();
+        for (key, (_, to)) in moved {
+            self.index.insert(key, to);
+        }
+        for id in &victims {
+            self.index.remove(&footer_key(*id));
+            self.segments.footers.remove(id);
+            self.segments.lens.remove(id);
+            self.segments.files.remove(id);
+            if let Some(live) = &mut self.segments.live {
+                live.remove(id);
+            }
+        }
+        self.index.insert(footer_key(merged_id), footer_at);
+        self.inline_renumbered(|position| match split(position) {
+            (segment, _) if segment == ACTIVE || !victims.contains(&segment) => Some(position),
+            _ => inline_moved.get(&position).copied(),
+        });
+        if let Some(live) = &mut self.segments.live {
+            live.insert(merged_id, footer.live);
+        }
+        self.segments.footers.insert(merged_id, footer);
+        self.segments
+            .lens
+            .insert(merged_id, report.bytes_after - self.data_start);
+        self.segments.ids.retain(|id| !victims.contains(id));
+        self.segments.ids.push(merged_id);
+        self.write_log.compacted();
+        self.store_index_on_disk(INDEX_KEY)?;
+        record_sealed(&dir, self.segments.ids())?;
+        // the index no longer points into them, a crash before they are gone
+        // leaves unrecorded segments open removes
+        for id in victims {
+            fs::remove_file(segment_path(&dir, id))?;
+        }
+        platform::sync_dir(&dir)?;
+        drop(lock);
+        self.io_stats
+            .record_physical(OpClass::Maintenance, report.bytes_after);
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::{ActionKV, Options};
+    use rstest::*;
+    use serial_test::serial;
+
+    fn segmented() -> Options {
+        Options {
+            segment_size: Some(256),
+            ..Options::default()
+        }
+    }
+    fn value(i: u8, round: u8) -> ByteString {
+        format!("value {} of round {}", i, round).into_bytes()
+    }
+    /// Writes keys 0..20 twice and deletes the odd ones, sealing a segment
+    /// every few records.
+    fn fill(store: &mut ActionKV) {
+        for round in 0..2 {
+            for i in 0..20u8 {
+                store.insert(&[b'k', i], &value(i, round)).unwrap();
+            }
+        }
+        for i in (1..20u8).step_by(2) {
+            store.delete(&[b'k', i]).unwrap();
+        }
+    }
+    fn assert_filled(store: &mut ActionKV) {
+        for i in 0..20u8 {
+            let expected = (i % 2 == 0).then(|| value(i, 1));
+            assert_eq!(store.get(&[b'k', i]).unwrap(), expected);
+        }
+    }
+    #[rstest]
+    #[serial]
+    fn test_segments_rotate() {
+        let path = Path::new("test_segments_rotate");
+        let mut ctx = TestCtx::setup_with_options("test_segments_rotate", segmented());
+        let store = &mut ctx.test_file;
+        fill(store);
+        let ids = store.segments.ids().to_vec();
+        assert!(ids.len() > 3);
+        assert!(ids.iter().all(|id| segment_path(path, *id).exists()));
+        assert!(store.appender.tail() < 256);
+        assert_filled(store);
+
+        assert_eq!(store.scan_filtered(b"k", |_, _| true).unwrap().len(), 10);
+        // a footer closes every segment
+        let records = 50 + ids.len() as u64;
+        assert_eq!(store.log_iter().unwrap().count() as u64, records);
+        let current: Vec<_> = store
+            .log_iter()
+            .unwrap()
+            .map(Result::unwrap)
+            .filter(|record| record.current && !is_footer_key(&record.key))
+            .collect();
+        assert_eq!(current.len(), 10);
+        assert_eq!(store.verify().unwrap().records, records);
+        assert_eq!(store.verify_fast().unwrap().records, records);
+        assert_eq!(store.verify_deep(3, |_, _| {}).unwrap().records, records);
+
+        let reopened = ctx.reopen_with_options(segmented()).unwrap();
+        assert_eq!(reopened.segments.ids(), ids);
+        assert_filled(reopened);
+        assert_eq!(reopened.rebuild_index().unwrap(), 10);
+        assert_filled(reopened);
+        // without a size nothing new is sealed, the old segments are read
+        let single = ctx.reopen().unwrap();
+        single.insert(b"k\x00", b"latest").unwrap();
+        assert_eq!(single.get(b"k\x00").unwrap(), Some(b"latest".to_vec()));
+        assert_eq!(single.get(b"k\x02").unwrap(), Some(value(2, 1)));
+    }
+    #[rstest]
+    #[serial]
+    fn test_compact_segments() {
+        let path = Path::new("test_compact_segments");
+        let mut ctx = TestCtx::setup_with_options("test_compact_segments", segmented());
+        let store = &mut ctx.test_file;
+        fill(store);
+        let sealed = store.segments.ids().to_vec();
+        let tail = store.appender.tail();
+        assert!(store.stats().segments.is_empty());
+        let scores = store.segment_scores().unwrap();
+        assert_eq!(store.stats().segments, scores);
+        assert_eq!(scores.len(), sealed.len());
+        assert!(scores
+            .windows(2)
+            .all(|pair| pair[0].dead_ratio() >= pair[1].dead_ratio()));
+        // the first round was overwritten whole
+        assert_eq!(scores[0].dead_ratio(), 1.0);
+        assert_eq!(scores[0].live, 0);
+
+        let report = store.compact_segments().unwrap();
+        assert!(report.bytes_after < report.bytes_before);
+        let merged_id = sealed[sealed.len() - 1] + 1;
+        let ids = store.segments.ids().to_vec();
+        assert_eq!(ids.last(), Some(&merged_id));
+        let survivors = &ids[..ids.len() - 1];
+        assert!(survivors.len() + 1 < sealed.len());
+        for id in &sealed {
+            assert_eq!(segment_path(path, *id).exists(), survivors.contains(id));
+        }
+        // survivors are the ones with the least garbage
+        let merged_ratio = scores
+            .iter()
+            .filter(|score| !survivors.contains(&score.id))
+            .map(SegmentScore::dead_ratio)
+            .fold(f64::MAX, f64::min);
+        assert!(scores
+            .iter()
+            .filter(|score| survivors.contains(&score.id))
+            .all(|score| score.dead_ratio() <= merged_ratio));
+        assert!(store
+            .stats()
+            .segments
+            .iter()
+            .any(|score| score.id == merged_id));
+        // the data file is left alone
+        assert_eq!(store.appender.tail(), tail);
+        assert_filled(store);
+        assert!(store.verify().is_ok());
+        let read_only = Options {
+            read_only: true,
+            ..segmented()
+        };
+        let mut reader = ActionKV::open_with_options(path, read_only.clone()).unwrap();
+        assert_eq!(reader.segments.ids(), ids);
+        assert_filled(&mut reader);
+        store.rebuild_index().unwrap();
+        assert_filled(store);
+
+        store.compact().unwrap();
+        assert!(store.segments.ids().is_empty());
+        assert!(!segment_path(path, merged_id).exists());
+        assert_filled(store);
+        let mut reader = ActionKV::open_with_options(path, read_only).unwrap();
+        assert_filled(&mut reader);
+    }
+    #[rstest]
+    #[serial]
+    fn test_compact_segments_keeps_deletes_of_older_segments() {
+        let options = Options {
+            segment_garbage_ratio: 0.5,
+            ..segmented()
+        };
+        let mut ctx = TestCtx::setup_with_options(
+            "test_compact_segments_keeps_deletes_of_older_segments",
+            options,
+        );
+        let store = &mut ctx.test_file;
+        for i in 0..10u8 {
+            store.insert(&[b'k', i], &value(i, 0)).unwrap();
+        }
+        store.delete(b"k\x00").unwrap();
+        for round in 0..10 {
+            store.insert(b"filler", &value(0, round)).unwrap();
+        }
+        let first = store.segments.ids()[0];
+        let scores = store.segment_scores().unwrap();
+        let first_score = scores.iter().find(|score| score.id == first).unwrap();
+        assert!(first_score.dead_ratio() > 0.0 && first_score.dead_ratio() < 0.5);
+
+        store.compact_segments().unwrap();
+        // the segment with the delete was merged, the older one it deletes
+        // from wasn't
+        assert!(store.segments.ids().contains(&first));
+        store.rebuild_index().unwrap();
+        assert_eq!(store.get(b"k\x00").unwrap(), None);
+        assert_eq!(store.get(b"k\x01").unwrap(), Some(value(1, 0)));
+        assert_eq!(store.get(b"filler").unwrap(), Some(value(0, 9)));
+    }
+    #[rstest]
+    #[serial]
+    fn test_segment_footers() {
+        let options = Options {
+            hashed_keys: true,
+            ..segmented()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_segment_footers", options.clone());
+        let store = &mut ctx.test_file;
+        for i in 0..20u8 {
+            store.insert(&[b'a', i], &value(i, 0)).unwrap();
+        }
+        for i in 0..20u8 {
+            store.insert(&[b'b', i], &value(i, 0)).unwrap();
+        }
+        let footers = store.segment_footers().unwrap();
+        assert!(footers.len() > 2);
+        let footers: Vec<SegmentFooter> = footers
+            .into_iter()
+            .map(|(_, footer)| footer.unwrap())
+            .collect();
+        let first = &footers[0];
+        assert_eq!(
+            first.key_range,
+            Some((b"a\x00".to_vec(), [b'a', first.keys as u8 - 1].to_vec()))
+        );
+        assert_eq!(first.records, first.keys);
+        assert_eq!(first.live, first.keys);
+        assert!(first.created > Duration::ZERO);
+        assert!(first.may_hold(b"a") && !first.may_hold(b"b"));
+        assert!(footers.iter().any(|footer| !footer.may_hold(b"a")));
+        // the segments holding only b keys aren't read by a scan of a
+        let skipped = store.segments_without(b"a").unwrap();
+        assert!(!skipped.is_empty());
+        let positions = store.prefix_positions(b"a").unwrap();
+        assert!(positions
+            .iter()
+            .all(|position| !skipped.contains(&split(*position).0)));
+        assert_eq!(store.scan_filtered(b"a", |_, _| true).unwrap().len(), 20);
+        assert_eq!(store.scan_filtered(b"b", |_, _| true).unwrap().len(), 20);
+
+        // footers are found again by a rebuilt index, the merged segment
+        // gets one of its own
+        let reopened = ctx.reopen_with_options(options).unwrap();
+        reopened.rebuild_index().unwrap();
+        assert_eq!(
+            reopened.segment_footers().unwrap()[0].1.as_ref(),
+            Some(&footers[0])
+        );
+        reopened.delete(b"a\x00").unwrap();
+        reopened.compact_segments().unwrap();
+        let merged = reopened.segment_footers().unwrap();
+        assert_eq!(merged.len(), footers.len());
+        let merged = merged[merged.len() - 1].1.clone().unwrap();
+        assert_eq!(merged.records, footers[0].records - 1);
+        assert_eq!(merged.records, merged.live);
+        assert_eq!(merged.key_range.unwrap().0, b"a\x01".to_vec());
+        reopened.read_index().unwrap();
+        assert_eq!(
+            reopened
+                .index
+                .keys()
+                .filter(|key| is_footer_key(key))
+                .count(),
+            footers.len()
+        );
+        reopened.compact().unwrap();
+        reopened.read_index().unwrap();
+        assert!(!reopened.index.keys().any(|key| is_footer_key(key)));
+        assert_eq!(reopened.scan_filtered(b"", |_, _| true).unwrap().len(), 39);
+    }
+    #[rstest]
+    #[serial]
+    fn test_sealing_interrupted() {
+        let path = Path::new("test_sealing_interrupted");
+        let mut ctx = TestCtx::setup("test_sealing_interrupted");
+        let store = &mut ctx.test_file;
+        fill(store);
+        let data_start = store.data_start as usize;
+
+        // a crash after the renames, before the index was saved again
+        fs::rename(path.join("data"), segment_path(path, 1)).unwrap();
+        let header = fs::read(segment_path(path, 1)).unwrap();
+        fs::write(path.join("data"), &header[..data_start]).unwrap();
+        let reopened = ctx.crash_and_reopen(Options::default()).unwrap();
+        assert_eq!(reopened.segments.ids(), [1]);
+        assert_filled(reopened);
+        reopened.insert(b"k\x01", b"back").unwrap();
+        let reopened = ctx.reopen().unwrap();
+        assert_eq!(reopened.get(b"k\x01").unwrap(), Some(b"back".to_vec()));
+
+        // a crash between the renames, and one before them
+        fs::rename(path.join("data"), path.join(NEXT_DATA)).unwrap();
+        let reopened = ctx.crash_and_reopen(Options::default()).unwrap();
+        assert_eq!(reopened.get(b"k\x01").unwrap(), Some(b"back".to_vec()));
+        fs::write(path.join(NEXT_DATA), &header[..data_start]).unwrap();
+        let reopened = ctx.crash_and_reopen(Options::default()).unwrap();
+        assert!(!path.join(NEXT_DATA).exists());
+        assert_eq!(reopened.get(b"k\x01").unwrap(), Some(b"back".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_sealing_recorded_in_manifest() {
+        let path = Path::new("test_sealing_recorded_in_manifest");
+        let mut ctx = TestCtx::setup_with_options("test_sealing_recorded_in_manifest", segmented());
+        let store = &mut ctx.test_file;
+        fill(store);
+        let ids = store.segments.ids().to_vec();
+        assert_eq!(Manifest::read(path).unwrap().segments, Some(ids.clone()));
+        let next = ids[ids.len() - 1] + 1;
+        let data_start = store.data_start as usize;
+        let data = fs::read(path.join("data")).unwrap();
+
+        // a crash after data was renamed, before the manifest sealed it
+        fs::rename(path.join("data"), segment_path(path, next)).unwrap();
+        fs::write(path.join(NEXT_DATA), &data[..data_start]).unwrap();
+        let reopened = ctx.crash_and_reopen(segmented()).unwrap();
+        assert_eq!(reopened.segments.ids(), ids);
+        assert!(!segment_path(path, next).exists());
+        assert!(!path.join(NEXT_DATA).exists());
+        assert_eq!(fs::read(path.join("data")).unwrap(), data);
+        assert_filled(reopened);
+
+        // a crash after the manifest sealed it, before data.next took its place
+        fs::rename(path.join("data"), segment_path(path, next)).unwrap();
+        fs::write(path.join(NEXT_DATA), &data[..data_start]).unwrap();
+        let mut sealed = ids.clone();
+        sealed.push(next);
+        record_sealed(path, &sealed).unwrap();
+        let reopened = ctx.crash_and_reopen(segmented()).unwrap();
+        assert_eq!(reopened.segments.ids(), sealed);
+        assert_eq!(fs::read(path.join("data")).unwrap(), &data[..data_start]);
+        assert_filled(reopened);
+
+        // a segment the manifest doesn't record is left from a merge
+        fs::copy(segment_path(path, next), segment_path(path, next + 5)).unwrap();
+        let reopened = ctx.crash_and_reopen(segmented()).unwrap();
+        assert_eq!(reopened.segments.ids(), sealed);
+        assert!(!segment_path(path, next + 5).exists());
+        assert_filled(reopened);
+        reopened.compact_segments().unwrap();
+        let merged = reopened.segments.ids().to_vec();
+        assert_eq!(Manifest::read(path).unwrap().segments, Some(merged));
+        reopened.compact().unwrap();
+        assert_eq!(Manifest::read(path).unwrap().segments, Some(Vec::new()));
+        assert_filled(reopened);
+    }
+    #[rstest]
+    #[serial]
+    fn test_read_only_follows_sealing() {
+        let path = Path::new("test_read_only_follows_sealing");
+        let mut ctx = TestCtx::setup_with_options("test_read_only_follows_sealing", segmented());
+        ctx.test_file.insert(b"k\x00", b"first").unwrap();
+        let read_only = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let mut reader = ActionKV::open_with_options(path, read_only).unwrap();
+        fill(&mut ctx.test_file);
+        assert!(reader.refresh().unwrap());
+        assert_filled(&mut reader);
+        ctx.test_file.compact_segments().unwrap();
+        assert!(reader.refresh().unwrap());
+        assert_filled(&mut reader);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::{ActionKV, ActionKvError, ByteStr, ByteString};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+type SharedResult = Result<Option<ByteString>, ActionKvError>;
+
+/// A read of one key that callers asking for the same key wait on instead
+/// of reading it themselves.
+#[derive(Debug, Default)]
+struct Flight {
+    result: Mutex<Option<SharedResult>>,
+    done: Condvar,
+}
+
+/// Coalesces concurrent reads of the same key: the first caller reads, the
+/// ones arriving while it does get its result.
+#[derive(Debug, Default)]
+pub(crate) struct SingleFlight {
+    flights: Mutex<HashMap<ByteString, Arc<Flight>>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Ends the flight of the leading caller, failing it for the waiters if
+/// the read never finished, e.g. because it panicked.
+struct Landing<'a> {
+    flights: &'a SingleFlight,
+    key: &'a ByteStr,
+    flight: Arc<Flight>,
+    result: Option<SharedResult>,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        lock(&self.flights.flights).remove(self.key);
+        let result = self
+            .result
+            .take()
+            .unwrap_or_else(|| Err(io::Error::other("the read was abandoned").into()));
+        *lock(&self.flight.result) = Some(result);
+        self.flight.done.notify_all();
+    }
+}
+
+impl SingleFlight {
+    /// The result of `read`, or of the read of `key` already running. Every
+    /// caller gets the error it failed with, as the store's own error.
+    pub fn run<F>(&self, key: &ByteStr, read: F) -> SharedResult
+    where
+        F: FnOnce() -> io::Result<Option<ByteString>>,
+    {
+        let (flight, leader) = {
+            let mut flights = lock(&self.flights);
+            match flights.get(key) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = Arc::new(Flight::default());
+                    flights.insert(key.to_vec(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+        if leader {
+            let mut landing = Landing {
+                flights: self,
+                key,
+                flight,
+                result: None,
+            };
+            let result = read().map_err(ActionKvError::from);
+            landing.result = Some(match &result {
+                Ok(value) => Ok(value.clone()),
+                Err(err) => Err(err.duplicate()),
+            });
+            return result;
+        }
+        let mut result = lock(&flight.result);
+        loop {
+            match &*result {
+                Some(Ok(value)) => return Ok(value.clone()),
+                Some(Err(err)) => return Err(err.duplicate()),
+                None => {
+                    result = flight
+                        .done
+                        .wait(result)
+                        .unwrap_or_else(PoisonError::into_inner)
+                }
+            }
+        }
+    }
+}
+
+/// A store shared between threads. Reads of the same key by several
+/// threads at once are coalesced into one, so a cold key everybody asks
+/// for at the same moment is only read from disk once.
+#[derive(Debug, Clone)]
+pub struct SharedStore {
+    store: Arc<Mutex<ActionKV>>,
+    flights: Arc<SingleFlight>,
+}
+
+impl SharedStore {
+    pub fn new(store: ActionKV) -> Self {
+        SharedStore {
+            store: Arc::new(Mutex::new(store)),
+            flights: Arc::new(SingleFlight::default()),
+        }
+    }
+    /// `ActionKV::get`, coalesced with the reads of `key` already running.
+    /// The callers sharing a read all get its error.
+    pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>, ActionKvError> {
+        self.flights.run(key, || self.with(|store| store.get(key)))
+    }
+    /// `ActionKV::update_with` under the store's lock, so concurrent
+    /// updates of the same key can't lose one another.
+    pub fn update_with<F>(&self, key: &ByteStr, f: F) -> Result<Option<ByteString>, ActionKvError>
+    where
+        F: FnOnce(Option<ByteString>) -> Option<ByteString>,
+    {
+        Ok(self.with(|store| store.update_with(key, f))?)
+    }
+    /// Runs `f` with the store to itself, for everything besides `get`.
+    pub fn with<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut ActionKV) -> R,
+    {
+        f(&mut lock(&self.store))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serial_test::serial;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    #[rstest]
+    fn test_single_flight() {
+        let flights = SingleFlight::default();
+        let reads = AtomicUsize::new(0);
+        let start = Barrier::new(8);
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        start.wait();
+                        flights.run(b"cold", || {
+                            reads.fetch_add(1, Ordering::SeqCst);
+                            thread::sleep(Duration::from_millis(200));
+                            Ok(Some(b"value".to_vec()))
+                        })
+                    })
+                })
+                .collect();
+            for handle in handles {
+                assert_eq!(handle.join().unwrap().unwrap(), Some(b"value".to_vec()));
+            }
+        });
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        let failed = flights.run(b"cold", || Err(io::Error::other("disk on fire")));
+        assert!(matches!(failed, Err(ActionKvError::Io(err)) if err.to_string() == "disk on fire"));
+        // the callers waiting on a failed read get the store's error too
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        flights.run(b"locked", || {
+                            thread::sleep(Duration::from_millis(100));
+                            Err(ActionKvError::Locked.into())
+                        })
+                    })
+                })
+                .collect();
+            for handle in handles {
+                assert!(matches!(handle.join().unwrap(), Err(ActionKvError::Locked)));
+            }
+        });
+        assert_eq!(flights.run(b"cold", || Ok(None)).unwrap(), None);
+    }
+    #[rstest]
+    #[serial]
+    fn test_shared_store() {
+        let (store, _dir) = TestCtx::setup("test_shared").into_store();
+        let shared = SharedStore::new(store);
+        shared.with(|store| store.insert(b"foo", b"bar")).unwrap();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let shared = shared.clone();
+                scope.spawn(move || {
+                    assert_eq!(shared.get(b"foo").unwrap(), Some(b"bar".to_vec()));
+                });
+            }
+        });
+        assert_eq!(shared.get(b"nope").unwrap(), None);
+
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                let shared = shared.clone();
+                scope.spawn(move || {
+                    for _ in 0..10 {
+                        shared
+                            .update_with(b"count", |old| {
+                                let n = old.map_or(0, |old| old[0]);
+                                Some(vec![n + 1])
+                            })
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(shared.get(b"count").unwrap(), Some(vec![80]));
+    }
+}
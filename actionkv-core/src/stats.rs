@@ -0,0 +1,375 @@
+use crate::rng::Rng;
+use crate::segment::SegmentScore;
+use crate::{is_internal_key, ActionKV, ActionKvError, ByteStr, ByteString, CompactionProgress};
+use std::collections::HashMap;
+use std::io;
+use std::ops::{Deref, DerefMut};
+
+/// Records read back by `approximate_size` to estimate the average record size.
+const SIZE_SAMPLE: usize = 64;
+
+/// What a write was done for, writes are accounted per class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpClass {
+    Insert,
+    Delete,
+    BulkLoad,
+    /// Saves of the persisted index, whole or as a delta.
+    Index,
+    /// Writes the store does on its own behalf, e.g. rebuilding the search index.
+    Maintenance,
+}
+
+/// `logical_bytes` is what callers asked to store (keys and values),
+/// `physical_bytes` is what actually hit the files (framing, padding, index...).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteCounters {
+    pub operations: u64,
+    pub logical_bytes: u64,
+    pub physical_bytes: u64,
+}
+
+/// What the operations done under one tag cost, see `ActionKV::with_tag`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TagCounters {
+    /// Writes of every class together.
+    pub writes: WriteCounters,
+    /// Values read by `get` and `get_ref`, and their bytes.
+    pub reads: u64,
+    pub read_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub insert: WriteCounters,
+    pub delete: WriteCounters,
+    pub bulk_load: WriteCounters,
+    pub index: WriteCounters,
+    pub maintenance: WriteCounters,
+    /// Counters of the operations done under each tag.
+    pub tags: HashMap<String, TagCounters>,
+    /// Tag operations are counted under right now.
+    tag: Option<String>,
+}
+
+impl IoStats {
+    pub fn class(&self, class: OpClass) -> &WriteCounters {
+        match class {
+            OpClass::Insert => &self.insert,
+            OpClass::Delete => &self.delete,
+            OpClass::BulkLoad => &self.bulk_load,
+            OpClass::Index => &self.index,
+            OpClass::Maintenance => &self.maintenance,
+        }
+    }
+    fn class_mut(&mut self, class: OpClass) -> &mut WriteCounters {
+        match class {
+            OpClass::Insert => &mut self.insert,
+            OpClass::Delete => &mut self.delete,
+            OpClass::BulkLoad => &mut self.bulk_load,
+            OpClass::Index => &mut self.index,
+            OpClass::Maintenance => &mut self.maintenance,
+        }
+    }
+    fn classes(&self) -> [&WriteCounters; 5] {
+        [
+            &self.insert,
+            &self.delete,
+            &self.bulk_load,
+            &self.index,
+            &self.maintenance,
+        ]
+    }
+    pub fn logical_bytes(&self) -> u64 {
+        self.classes().iter().map(|class| class.logical_bytes).sum()
+    }
+    pub fn physical_bytes(&self) -> u64 {
+        self.classes()
+            .iter()
+            .map(|class| class.physical_bytes)
+            .sum()
+    }
+    /// Physical bytes written per logical byte, `None` until something was written.
+    pub fn write_amplification(&self) -> Option<f64> {
+        match self.logical_bytes() {
+            0 => None,
+            logical => Some(self.physical_bytes() as f64 / logical as f64),
+        }
+    }
+    fn tagged(&mut self) -> Option<&mut TagCounters> {
+        let tag = self.tag.as_ref()?;
+        Some(self.tags.entry(tag.clone()).or_default())
+    }
+    pub(crate) fn record_operation(&mut self, class: OpClass, logical_bytes: u64) {
+        let counters = self.class_mut(class);
+        counters.operations += 1;
+        counters.logical_bytes += logical_bytes;
+        if let Some(tagged) = self.tagged() {
+            tagged.writes.operations += 1;
+            tagged.writes.logical_bytes += logical_bytes;
+        }
+    }
+    pub(crate) fn record_physical(&mut self, class: OpClass, physical_bytes: u64) {
+        self.class_mut(class).physical_bytes += physical_bytes;
+        if let Some(tagged) = self.tagged() {
+            tagged.writes.physical_bytes += physical_bytes;
+        }
+    }
+    pub(crate) fn record_read(&mut self, bytes: u64) {
+        if let Some(tagged) = self.tagged() {
+            tagged.reads += 1;
+            tagged.read_bytes += bytes;
+        }
+    }
+}
+
+/// Returned by `with_tag`, it stands in for the store and counts what is
+/// done through it under its tag until it is dropped.
+#[derive(Debug)]
+pub struct Tagged<'a> {
+    store: &'a mut ActionKV,
+    previous: Option<String>,
+}
+
+impl Deref for Tagged<'_> {
+    type Target = ActionKV;
+
+    fn deref(&self) -> &ActionKV {
+        self.store
+    }
+}
+
+impl DerefMut for Tagged<'_> {
+    fn deref_mut(&mut self) -> &mut ActionKV {
+        self.store
+    }
+}
+
+impl Drop for Tagged<'_> {
+    fn drop(&mut self) {
+        self.store.io_stats.tag = self.previous.take();
+    }
+}
+
+/// Damage the store ran into, which keeps climbing on a disk going bad.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CorruptionCounters {
+    /// Records that failed their checksum when read or verified.
+    pub checksum_failures: u64,
+    /// Times the data file was cut back to its last intact record, on open
+    /// or while replaying it after a crash.
+    pub torn_truncations: u64,
+    /// Times the index was rebuilt from the data file, on request or
+    /// because the saved one was torn.
+    pub index_rebuilds: u64,
+}
+
+impl CorruptionCounters {
+    /// Counts `err` if it is a failed checksum.
+    pub(crate) fn count(&mut self, err: &io::Error) {
+        if let Some(ActionKvError::Corruption { .. }) = ActionKvError::from_io(err) {
+            self.checksum_failures += 1;
+        }
+    }
+    pub(crate) fn add(&mut self, other: &CorruptionCounters) {
+        self.checksum_failures += other.checksum_failures;
+        self.torn_truncations += other.torn_truncations;
+        self.index_rebuilds += other.index_rebuilds;
+    }
+}
+
+/// Counters collected since the store was opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub io: IoStats,
+    /// Estimated accesses per key prefix in the current tracking window, hottest
+    /// first. Empty unless `Options::access_tracking` is set.
+    pub hot_prefixes: Vec<(ByteString, u64)>,
+    pub compaction: CompactionProgress,
+    pub corruption: CorruptionCounters,
+    /// How much of each sealed segment is garbage, highest dead ratio
+    /// first, as `segment_scores` last scored them and kept up to date
+    /// since. Empty until it was called on the store.
+    pub segments: Vec<SegmentScore>,
+}
+
+impl ActionKV {
+    pub fn stats(&self) -> Stats {
+        Stats {
+            io: self.io_stats.clone(),
+            hot_prefixes: self
+                .access_tracker
+                .as_ref()
+                .map(|tracker| tracker.hottest())
+                .unwrap_or_default(),
+            compaction: self.compaction_progress(),
+            corruption: self.corruption,
+            segments: self.known_segment_scores(),
+        }
+    }
+    pub fn reset_stats(&mut self) {
+        self.io_stats = IoStats {
+            tag: self.io_stats.tag.take(),
+            ..IoStats::default()
+        };
+        self.corruption = CorruptionCounters::default();
+    }
+    /// Attributes the operations done through the returned guard to `tag`
+    /// in `Stats::io.tags`, so components sharing a store can tell whose
+    /// load is whose. Tags nest, the innermost one counts.
+    pub fn with_tag(&mut self, tag: &str) -> Tagged<'_> {
+        let previous = self.io_stats.tag.replace(tag.to_string());
+        Tagged {
+            store: self,
+            previous,
+        }
+    }
+    /// Estimated bytes the live records of keys starting with `prefix` take in
+    /// the data file, framing included. The keys are counted exactly from the
+    /// index, their average size is taken from a random sample of them, so this
+    /// stays cheap for prefixes covering millions of keys.
+    pub fn approximate_size(&mut self, prefix: &ByteStr) -> io::Result<u64> {
+        self.read_index()?;
+        let mut count = 0;
+        // hashed keys are read back from the data file to match the prefix
+        let entries = self.index_entries()?;
+        let positions = entries
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix) && !is_internal_key(key))
+            .inspect(|_| count += 1)
+            .map(|(_, position)| *position);
+        let sample = Rng::new().choose_multiple(positions, SIZE_SAMPLE);
+        if sample.is_empty() {
+            return Ok(0);
+        }
+        let mut sampled_bytes = 0;
+        for position in &sample {
+            let record = self.get_raw_at(*position, false)?;
+            sampled_bytes += self
+                .framing
+                .record_len(record.key.len() as u32, record.value.len() as u64);
+        }
+        Ok(sampled_bytes * count / sample.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::HEADER_LEN;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_stats")
+    }
+    #[rstest]
+    #[serial]
+    fn test_write_accounting(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        ctx.test_file.delete(b"foo").unwrap();
+        let pairs = vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"2".to_vec()),
+        ];
+        ctx.test_file.bulk_load(pairs).unwrap();
+
+        let io = ctx.test_file.stats().io;
+        assert_eq!(
+            io.insert,
+            WriteCounters {
+                operations: 1,
+                logical_bytes: 6,
+                physical_bytes: 18,
+            }
+        );
+        assert_eq!(io.delete.logical_bytes, 3);
+        assert_eq!(io.delete.physical_bytes, 15);
+        assert_eq!(io.bulk_load.operations, 2);
+        assert_eq!(io.bulk_load.physical_bytes, 2 * 14);
+        assert_eq!(io.index.operations, 3);
+        let index_len = std::fs::metadata("test_stats/index").unwrap().len();
+        assert!(io.index.physical_bytes > 3 * index_len - 3 * HEADER_LEN);
+        assert!(io.write_amplification().unwrap() > 1.0);
+
+        ctx.test_file.reset_stats();
+        assert_eq!(ctx.test_file.stats().io.write_amplification(), None);
+    }
+    #[rstest]
+    #[serial]
+    fn test_tags(mut ctx: TestCtx) {
+        let store = &mut ctx.test_file;
+        store.insert(b"untagged", b"x").unwrap();
+        {
+            let mut ingest = store.with_tag("ingest");
+            ingest.insert(b"foo", b"bar").unwrap();
+            ingest.insert(b"baz", b"qux").unwrap();
+            let mut api = ingest.with_tag("api");
+            api.get(b"foo").unwrap();
+            api.get(b"nope").unwrap();
+            assert_eq!(&*api.get_ref(b"baz").unwrap().unwrap(), b"qux");
+        }
+        store.get(b"foo").unwrap();
+        let tags = store.stats().io.tags;
+        assert_eq!(tags.len(), 2);
+        let ingest = tags["ingest"];
+        assert_eq!(ingest.writes.operations, 2 + 2);
+        assert_eq!(ingest.writes.logical_bytes, 12);
+        assert!(ingest.writes.physical_bytes > 2 * 18);
+        assert_eq!(ingest.reads, 0);
+        assert_eq!((tags["api"].reads, tags["api"].read_bytes), (2, 6));
+        assert_eq!(tags["api"].writes, WriteCounters::default());
+
+        let mut tagged = store.with_tag("ingest");
+        tagged.reset_stats();
+        tagged.insert(b"foo", b"bar").unwrap();
+        assert_eq!(tagged.stats().io.tags["ingest"].writes.logical_bytes, 6);
+    }
+    #[rstest]
+    #[serial]
+    fn test_corruption_counters(mut ctx: TestCtx) {
+        let store = &mut ctx.test_file;
+        store.insert(b"foo", b"bar").unwrap();
+        store.insert(b"baz", b"qux").unwrap();
+        let mut data = std::fs::read("test_stats/data").unwrap();
+        *data.last_mut().unwrap() ^= 0xff;
+        std::fs::write("test_stats/data", &data).unwrap();
+        assert!(store.get(b"baz").is_err());
+        assert!(store.verify().is_err());
+        assert_eq!(store.rebuild_index().unwrap(), 1);
+        assert_eq!(
+            store.stats().corruption,
+            CorruptionCounters {
+                checksum_failures: 2,
+                torn_truncations: 1,
+                index_rebuilds: 1,
+            }
+        );
+
+        // cut off on open
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open("test_stats/data")
+            .unwrap();
+        std::io::Write::write_all(&mut f, b"torn").unwrap();
+        let reopened = ctx.reopen().unwrap();
+        assert_eq!(reopened.stats().corruption.torn_truncations, 1);
+        reopened.reset_stats();
+        assert_eq!(reopened.stats().corruption, CorruptionCounters::default());
+    }
+    #[rstest]
+    #[serial]
+    fn test_approximate_size(mut ctx: TestCtx) {
+        let pairs = (0..1000u32).map(|i| (format!("big:{:04}", i).into_bytes(), vec![0; 100]));
+        ctx.test_file.bulk_load(pairs).unwrap();
+        ctx.test_file.insert(b"small:1", b"x").unwrap();
+        assert_eq!(
+            ctx.test_file.approximate_size(b"big:").unwrap(),
+            1000 * (12 + 8 + 100)
+        );
+        assert_eq!(ctx.test_file.approximate_size(b"small:").unwrap(), 12 + 8);
+        assert_eq!(ctx.test_file.approximate_size(b"none:").unwrap(), 0);
+    }
+}
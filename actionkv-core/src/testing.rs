@@ -0,0 +1,73 @@
+use crate::{Access, ActionKV, ByteStr, Operation, Options};
+use std::fs::remove_dir_all;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub(crate) struct TestCtx {
+    pub test_file: ActionKV,
+    dir: TestDir,
+}
+/// Removes the directory of a test once dropped.
+pub(crate) struct TestDir(PathBuf);
+impl TestCtx {
+    pub fn setup(path: &str) -> Self {
+        TestCtx::setup_with_options(path, Options::default())
+    }
+    pub fn setup_with_options(path: &str, options: Options) -> Self {
+        Self {
+            test_file: ActionKV::open_with_options(Path::new(path), options)
+                .expect("Unable to open file!"),
+            dir: TestDir(PathBuf::from(path)),
+        }
+    }
+    /// The store to move somewhere else, with the guard of its directory.
+    pub fn into_store(self) -> (ActionKV, TestDir) {
+        (self.test_file, self.dir)
+    }
+    /// Closes the store as dropping it would and opens the directory again
+    /// with `options`.
+    pub fn reopen_with_options(&mut self, options: Options) -> io::Result<&mut ActionKV> {
+        self.test_file.flush_index()?;
+        self.crash_and_reopen(options)
+    }
+    pub fn reopen(&mut self) -> io::Result<&mut ActionKV> {
+        self.reopen_with_options(Options::default())
+    }
+    /// Opens the directory again with `options` in place of the open store,
+    /// like a process starting after the one holding it died: the open store
+    /// lets go of the writer lock and saves nothing more. It stays in place
+    /// if the directory doesn't open.
+    pub fn crash_and_reopen(&mut self, options: Options) -> io::Result<&mut ActionKV> {
+        self.test_file._writer = None;
+        let reopened = ActionKV::open_with_options(&self.dir.0, options)?;
+        std::mem::forget(std::mem::replace(&mut self.test_file, reopened));
+        Ok(&mut self.test_file)
+    }
+}
+/// Access policy of a principal that may do nothing with `secret` keys.
+pub fn deny_secrets(_: &Operation, key: &ByteStr) -> Access {
+    match key.starts_with(b"secret") {
+        true => Access::Deny,
+        false => Access::Allow,
+    }
+}
+/// Writes `public:0..n` and `secret:0..n`, each key's value being its
+/// number, then takes the `secret` keys away with `deny_secrets`.
+pub fn hide_secrets(store: &mut ActionKV, n: u8) {
+    for i in 0..n {
+        store
+            .insert(format!("public:{}", i).as_bytes(), &[i])
+            .unwrap();
+        store
+            .insert(format!("secret:{}", i).as_bytes(), &[i])
+            .unwrap();
+    }
+    store.options.access_policy = Some(deny_secrets);
+}
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        if self.0.exists() {
+            remove_dir_all(&self.0).expect("failed to del folder");
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::{display_key, namespaced_key, ActionKV, AdminAction, ByteStr, ByteString};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::io;
+
+/*
+    TIME SERIES LAYOUT
+    point:     +ts: | series_len | series | timestamp  ->  value
+    retention: +tr: | series_len | series              ->  retention
+    timestamp is a big endian u64 so the points of one series sort by time.
+*/
+const POINT_PREFIX: &ByteStr = b"+ts:";
+const RETENTION_PREFIX: &ByteStr = b"+tr:";
+
+fn point_key(series: &ByteStr, timestamp: u64) -> ByteString {
+    let mut key = namespaced_key(POINT_PREFIX, series);
+    key.extend(timestamp.to_be_bytes());
+    key
+}
+
+/// Timestamp at the end of a point key, `None` for keys that aren't
+/// `prefix` followed by 8 bytes.
+fn point_timestamp(key: &ByteStr, prefix: &ByteStr) -> Option<u64> {
+    let timestamp = key.strip_prefix(prefix)?;
+    (timestamp.len() == 8).then(|| BigEndian::read_u64(timestamp))
+}
+
+/// Append-mostly view over one series of `(timestamp, value)` points.
+///
+/// Timestamps are plain `u64`s, the unit (seconds, millis, ...) is up to the caller
+/// as long as retention and `enforce_retention` use the same one. Every `compact`
+/// enforces retention as of the store clock in milliseconds, so series with a
+/// retention are stamped in milliseconds, as `append_now` does.
+pub struct TimeSeries<'a> {
+    store: &'a mut ActionKV,
+    series: ByteString,
+}
+
+impl<'a> TimeSeries<'a> {
+    pub fn append(&mut self, timestamp: u64, value: &ByteStr) -> io::Result<()> {
+        self.store
+            .insert(&point_key(&self.series, timestamp), value)
+    }
+    /// Appends `value` stamped with the store clock in milliseconds since the
+    /// Unix epoch, returns the timestamp used.
+    pub fn append_now(&mut self, value: &ByteStr) -> io::Result<u64> {
+        let timestamp = self.store.clock.now().as_millis() as u64;
+        self.append(timestamp, value)?;
+        Ok(timestamp)
+    }
+    /// Points with `from <= timestamp < to`, oldest first.
+    pub fn range(&mut self, from: u64, to: u64) -> io::Result<Vec<(u64, ByteString)>> {
+        let prefix = namespaced_key(POINT_PREFIX, &self.series);
+        let keys: Vec<(u64, ByteString)> = self
+            .store
+            .keys_with_prefix(&prefix)?
+            .into_iter()
+            .filter_map(|key| Some((point_timestamp(&key, &prefix)?, key)))
+            .collect();
+        let start = keys.partition_point(|(timestamp, _)| *timestamp < from);
+        let end = keys.partition_point(|(timestamp, _)| *timestamp < to);
+        let mut points = Vec::with_capacity(end.saturating_sub(start));
+        for (timestamp, key) in &keys[start..end] {
+            if let Some(value) = self.store.get(key)? {
+                points.push((*timestamp, value));
+            }
+        }
+        Ok(points)
+    }
+    /// Keep only points younger than `retention`, `None` keeps everything.
+    /// The older ones are dropped by the next `compact`, or right away by
+    /// `enforce_retention`.
+    pub fn set_retention(&mut self, retention: Option<u64>) -> io::Result<()> {
+        let key = namespaced_key(RETENTION_PREFIX, &self.series);
+        match retention {
+            Some(retention) => self.store.insert(&key, &retention.to_le_bytes())?,
+            None => self.store.delete(&key)?,
+        }
+        let retention = retention.map_or("none".to_string(), |retention| retention.to_string());
+        self.store.log_admin(
+            AdminAction::Config,
+            &format!(
+                "retention of series {:?} {}",
+                display_key(&self.series),
+                retention
+            ),
+        )
+    }
+    pub fn retention(&mut self) -> io::Result<Option<u64>> {
+        let key = namespaced_key(RETENTION_PREFIX, &self.series);
+        Ok(self
+            .store
+            .get(&key)?
+            .map(|retention| LittleEndian::read_u64(&retention)))
+    }
+}
+
+impl ActionKV {
+    pub fn time_series(&mut self, series: &ByteStr) -> TimeSeries<'_> {
+        TimeSeries {
+            store: self,
+            series: series.to_vec(),
+        }
+    }
+    /// Drops every point older than its series retention as of `now`,
+    /// returns how many points were removed.
+    pub fn enforce_retention(&mut self, now: u64) -> io::Result<usize> {
+        let mut removed = 0;
+        for retention_key in self.keys_with_prefix(RETENTION_PREFIX)? {
+            let retention = match self.get(&retention_key)? {
+                Some(retention) if retention.len() == 8 => LittleEndian::read_u64(&retention),
+                Some(retention) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("series retention is {} bytes instead of 8", retention.len()),
+                    ))
+                }
+                None => continue,
+            };
+            let Some(series) = retention_key.get(RETENTION_PREFIX.len() + 4..) else {
+                continue;
+            };
+            let cutoff = now.saturating_sub(retention);
+            let expired = self.time_series(series).range(0, cutoff)?;
+            for (timestamp, _) in expired {
+                self.delete(&point_key(series, timestamp))?;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            self.log_admin(
+                AdminAction::Purge,
+                &format!("{} time series points past their retention", removed),
+            )?;
+        }
+        Ok(removed)
+    }
+    /// `enforce_retention` as of the store clock, for series whose timestamps
+    /// and retention are in milliseconds like `append_now` writes them.
+    pub fn enforce_retention_now(&mut self) -> io::Result<usize> {
+        let now = self.clock.now().as_millis() as u64;
+        self.enforce_retention(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestCtx;
+    use crate::{ManualClock, Options};
+    use rstest::*;
+    use serial_test::serial;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_timeseries")
+    }
+    #[rstest]
+    #[serial]
+    fn test_append_and_range(mut ctx: TestCtx) {
+        let mut cpu = ctx.test_file.time_series(b"cpu");
+        for (timestamp, value) in [(300, b"30"), (100, b"10"), (200, b"20")] {
+            cpu.append(timestamp, value).unwrap();
+        }
+        ctx.test_file
+            .time_series(b"mem")
+            .append(150, b"1G")
+            .unwrap();
+
+        let points = ctx.test_file.time_series(b"cpu").range(100, 300).unwrap();
+        assert_eq!(points, vec![(100, b"10".to_vec()), (200, b"20".to_vec())]);
+    }
+    #[rstest]
+    #[serial]
+    fn test_enforce_retention(mut ctx: TestCtx) {
+        let mut cpu = ctx.test_file.time_series(b"cpu");
+        cpu.set_retention(Some(50)).unwrap();
+        for timestamp in [10, 60, 90] {
+            cpu.append(timestamp, b"x").unwrap();
+        }
+        ctx.test_file.time_series(b"mem").append(10, b"x").unwrap();
+
+        assert_eq!(ctx.test_file.enforce_retention(100).unwrap(), 1);
+        let points = ctx
+            .test_file
+            .time_series(b"cpu")
+            .range(0, u64::MAX)
+            .unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(
+            ctx.test_file
+                .time_series(b"mem")
+                .range(0, 100)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+    #[rstest]
+    #[serial]
+    fn test_retention_follows_clock() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1000)));
+        let options = Options {
+            clock: Some(clock.clone()),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_timeseries", options);
+        let mut cpu = ctx.test_file.time_series(b"cpu");
+        cpu.set_retention(Some(60_000)).unwrap();
+        assert_eq!(cpu.append_now(b"x").unwrap(), 1_000_000);
+        clock.advance(Duration::from_secs(30));
+        ctx.test_file.time_series(b"cpu").append_now(b"y").unwrap();
+
+        clock.advance(Duration::from_secs(31));
+        assert_eq!(ctx.test_file.enforce_retention_now().unwrap(), 1);
+        let points = ctx
+            .test_file
+            .time_series(b"cpu")
+            .range(0, u64::MAX)
+            .unwrap();
+        assert_eq!(points, vec![(1_030_000, b"y".to_vec())]);
+    }
+    #[rstest]
+    #[serial]
+    fn test_compaction_enforces_retention() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1000)));
+        let options = Options {
+            clock: Some(clock.clone()),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_timeseries", options);
+        let mut cpu = ctx.test_file.time_series(b"cpu");
+        cpu.set_retention(Some(60_000)).unwrap();
+        cpu.append_now(b"old").unwrap();
+        clock.advance(Duration::from_secs(45));
+        ctx.test_file
+            .time_series(b"cpu")
+            .append_now(b"new")
+            .unwrap();
+        ctx.test_file
+            .time_series(b"mem")
+            .append_now(b"kept")
+            .unwrap();
+        clock.advance(Duration::from_secs(30));
+
+        ctx.test_file.compact().unwrap();
+        let points = ctx
+            .test_file
+            .time_series(b"cpu")
+            .range(0, u64::MAX)
+            .unwrap();
+        assert_eq!(points, vec![(1_045_000, b"new".to_vec())]);
+        assert_eq!(
+            ctx.test_file
+                .time_series(b"mem")
+                .range(0, u64::MAX)
+                .unwrap()
+                .len(),
+            1
+        );
+        // the expired point is gone from the data file too
+        let old = ctx
+            .test_file
+            .log_iter()
+            .unwrap()
+            .any(|record| record.unwrap().value == b"old");
+        assert!(!old);
+    }
+}
@@ -0,0 +1,220 @@
+use crate::ActionKV;
+use std::io;
+#[cfg(any(test, feature = "torn-write-trace"))]
+use std::ops::Range;
+#[cfg(any(test, feature = "torn-write-trace"))]
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/*
+    TORN-WRITE WINDOWS
+    between a record being appended to the data file and the index that
+    points at it being persisted, a crash leaves records on disk that the
+    saved index doesn't know about. open then drops them, or replays them
+    with Options::index_checkpoints. A window spans the data file bytes
+    written since the last index save, it opens with the first of them and
+    closes once the index is saved.
+*/
+
+/// Data file bytes a crash would have left behind the persisted index.
+#[cfg(any(test, feature = "torn-write-trace"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TornWindow {
+    pub data: Range<u64>,
+    /// Whether the index covering `data` made it to disk.
+    pub closed: bool,
+}
+
+#[cfg(any(test, feature = "torn-write-trace"))]
+#[derive(Debug, Default)]
+struct TraceState {
+    windows: Vec<TornWindow>,
+    crash_in: Option<usize>,
+    crashed: bool,
+}
+
+/// Shared handle to the torn-write windows of a store, see
+/// `Options::torn_write_trace`. It can also crash the store in a chosen
+/// window: the index save closing it fails and the handle refuses every
+/// write after, as if the process died there, so a test can reopen the
+/// directory and check what recovery makes of it.
+#[cfg(any(test, feature = "torn-write-trace"))]
+#[derive(Debug, Clone, Default)]
+pub struct TornWriteTrace {
+    state: Arc<Mutex<TraceState>>,
+}
+
+#[cfg(any(test, feature = "torn-write-trace"))]
+impl TornWriteTrace {
+    pub fn new() -> Self {
+        TornWriteTrace::default()
+    }
+    fn lock(&self) -> MutexGuard<'_, TraceState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Every window seen so far, oldest first.
+    pub fn windows(&self) -> Vec<TornWindow> {
+        self.lock().windows.clone()
+    }
+    /// Crashes the store in window `n`, counting from the first one in
+    /// `windows`, instead of saving the index that would close it.
+    pub fn crash_in_window(&self, n: usize) {
+        self.lock().crash_in = Some(n);
+    }
+    pub fn crashed(&self) -> bool {
+        self.lock().crashed
+    }
+    /// Records the window `data` about to be closed by an index save,
+    /// failing if the store is to crash in it.
+    fn opened(&self, data: Range<u64>) -> io::Result<()> {
+        let mut state = self.lock();
+        if state.crashed {
+            return Err(crashed());
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        match state.windows.last_mut() {
+            // the save closing it failed before, the window grew since
+            Some(window) if !window.closed && window.data.start == data.start => window.data = data,
+            _ => state.windows.push(TornWindow {
+                data,
+                closed: false,
+            }),
+        }
+        if state.crash_in == Some(state.windows.len() - 1) {
+            state.crashed = true;
+            return Err(crashed());
+        }
+        Ok(())
+    }
+    fn closed(&self) {
+        if let Some(window) = self.lock().windows.last_mut() {
+            window.closed = true;
+        }
+    }
+    fn alive(&self) -> io::Result<()> {
+        match self.lock().crashed {
+            true => Err(crashed()),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "torn-write-trace"))]
+fn crashed() -> io::Error {
+    io::Error::other("simulated crash in a torn-write window")
+}
+
+impl ActionKV {
+    /// Called before the index is saved, with the data file bytes it is
+    /// the first to cover.
+    #[cfg(any(test, feature = "torn-write-trace"))]
+    pub(crate) fn torn_window_opened(&self) -> io::Result<()> {
+        match &self.options.torn_write_trace {
+            Some(trace) => trace.opened(self.saved_tail..self.appender.tail()),
+            None => Ok(()),
+        }
+    }
+    #[cfg(any(test, feature = "torn-write-trace"))]
+    pub(crate) fn torn_window_closed(&self) {
+        if let Some(trace) = &self.options.torn_write_trace {
+            trace.closed();
+        }
+    }
+    /// Fails writes to a store that crashed in a torn-write window.
+    #[cfg(any(test, feature = "torn-write-trace"))]
+    pub(crate) fn not_crashed(&self) -> io::Result<()> {
+        match &self.options.torn_write_trace {
+            Some(trace) => trace.alive(),
+            None => Ok(()),
+        }
+    }
+    #[cfg(not(any(test, feature = "torn-write-trace")))]
+    #[inline(always)]
+    pub(crate) fn torn_window_opened(&self) -> io::Result<()> {
+        Ok(())
+    }
+    #[cfg(not(any(test, feature = "torn-write-trace")))]
+    #[inline(always)]
+    pub(crate) fn torn_window_closed(&self) {}
+    #[cfg(not(any(test, feature = "torn-write-trace")))]
+    #[inline(always)]
+    pub(crate) fn not_crashed(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use crate::{IndexCheckpoints, Options};
+    use rstest::*;
+    use serial_test::serial;
+    use std::fs;
+
+    #[rstest]
+    #[serial]
+    fn test_crash_in_torn_window() {
+        let trace = TornWriteTrace::new();
+        let mut ctx = TestCtx::setup_with_options(
+            "test_torn",
+            Options {
+                torn_write_trace: Some(trace.clone()),
+                ..Options::default()
+            },
+        );
+        ctx.test_file.insert(b"a", b"1").unwrap();
+        ctx.test_file.insert(b"b", b"2").unwrap();
+        let windows = trace.windows();
+        assert_eq!(windows.len(), 2);
+        assert!(windows.iter().all(|window| window.closed));
+        assert_eq!(windows[0].data.end, windows[1].data.start);
+
+        trace.crash_in_window(2);
+        assert!(ctx.test_file.insert(b"c", b"3").is_err());
+        assert!(trace.crashed());
+        assert!(ctx.test_file.insert(b"d", b"4").is_err());
+        let torn = trace.windows()[2].clone();
+        assert!(!torn.closed);
+        assert_eq!(fs::metadata("test_torn/data").unwrap().len(), torn.data.end);
+
+        // the record in the window is dropped, everything before it kept
+        let store = ctx.crash_and_reopen(Options::default()).unwrap();
+        assert_eq!(
+            fs::metadata("test_torn/data").unwrap().len(),
+            torn.data.start
+        );
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), None);
+    }
+    #[rstest]
+    #[serial]
+    fn test_crash_in_torn_window_is_replayed() {
+        let trace = TornWriteTrace::new();
+        let options = Options {
+            index_checkpoints: Some(IndexCheckpoints {
+                records: 3,
+                bytes: u64::MAX,
+            }),
+            torn_write_trace: Some(trace.clone()),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_torn", options);
+        trace.crash_in_window(0);
+        ctx.test_file.insert(b"a", b"1").unwrap();
+        ctx.test_file.insert(b"b", b"2").unwrap();
+        assert!(ctx.test_file.insert(b"c", b"3").is_err());
+
+        let options = Options {
+            index_checkpoints: Some(IndexCheckpoints {
+                records: 3,
+                bytes: u64::MAX,
+            }),
+            ..Options::default()
+        };
+        let store = ctx.crash_and_reopen(options).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+}
@@ -0,0 +1,608 @@
+use crate::{ActionKV, ActionKvError, ByteStr, ByteString, IoOp, OpClass, StoreFile};
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+/*
+    ISOLATION
+    transactions get snapshot isolation as of where the data file ended
+    when they began. A key whose record lies past that, or that was deleted
+    since, was written by someone else: reading it through the transaction
+    fails with ActionKvError::Conflict instead of seeing the newer value, and so
+    does committing a write to it, the first transaction to commit a key
+    wins. Compaction moves every record, transactions begun before it
+    conflict on every key.
+
+    DURABILITY
+    a commit is acknowledged once its group was synced to the data file,
+    and without Options::index_checkpoints the index saved after it as
+    well. The last record of a group is what commits it, a group cut short
+    by a crash is discarded when the store is opened. commit_all syncs once
+    for several transactions.
+*/
+
+/// Where keys were deleted, as the index no longer tells, and how often
+/// the store was compacted.
+#[derive(Debug, Default)]
+pub(crate) struct WriteLog {
+    deleted: HashMap<ByteString, u64>,
+    compactions: u64,
+}
+
+impl WriteLog {
+    pub fn deleted(&mut self, key: ByteString, position: u64) {
+        self.deleted.insert(key, position);
+    }
+    pub fn deleted_keys(&self) -> &HashMap<ByteString, u64> {
+        &self.deleted
+    }
+    pub fn deleted_at(&self, key: &ByteStr) -> Option<u64> {
+        self.deleted.get(key).copied()
+    }
+    /// Puts back where `key` was deleted before a write that was undone,
+    /// `None` if it wasn't.
+    pub fn restore(&mut self, key: ByteString, position: Option<u64>) {
+        match position {
+            Some(position) => self.deleted.insert(key, position),
+            None => self.deleted.remove(&key),
+        };
+    }
+    /// Forgets the deletes, every transaction begun before conflicts now.
+    pub fn compacted(&mut self) {
+        self.deleted = HashMap::new();
+        self.compactions += 1;
+    }
+}
+
+/// Writes buffered to be committed together with `ActionKV::commit`, as
+/// one group: a crash leaves all of them or none. A transaction doesn't
+/// borrow the store, nothing is written until it is committed and
+/// dropping it abandons it. One begun with a deadline is abandoned once
+/// the store's clock passes it, see `ActionKV::transaction_until`.
+#[derive(Debug)]
+pub struct Transaction {
+    /// The data file length it sees the store as of.
+    snapshot: u64,
+    compactions: u64,
+    /// Time since the Unix epoch by the store's clock.
+    deadline: Option<Duration>,
+    /// Every write in the order it was made, `None` deletes the key.
+    writes: Vec<(ByteString, Option<ByteString>)>,
+    savepoints: HashMap<String, usize>,
+}
+
+/// One write of `ActionKV::write_batch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    Insert(ByteString, ByteString),
+    Delete(ByteString),
+}
+
+/// A point in a transaction `Transaction::rollback_to` can go back to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Savepoint(String);
+
+impl Transaction {
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) {
+        self.writes.push((key.to_vec(), Some(value.to_vec())));
+    }
+    pub fn delete(&mut self, key: &ByteStr) {
+        self.writes.push((key.to_vec(), None));
+    }
+    /// Value of `key` as the transaction would leave it, its own writes
+    /// over what `store` held when it began. Fails with `ActionKvError::Conflict`
+    /// if `key` was written since.
+    pub fn get(&self, store: &mut ActionKV, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        store.within(self.deadline)?;
+        let canonical = store.canonical_key(key).into_owned();
+        let written = self
+            .writes
+            .iter()
+            .rev()
+            .find(|(written, _)| store.canonical_key(written).as_ref() == canonical.as_slice());
+        if let Some((_, value)) = written {
+            return Ok(value.clone());
+        }
+        store.read_index()?;
+        store.unchanged(self, &canonical)?;
+        store.get(key)
+    }
+    /// Marks the writes made so far under `name`, replacing an older
+    /// savepoint of that name.
+    pub fn savepoint(&mut self, name: &str) -> Savepoint {
+        self.savepoints.insert(name.to_string(), self.writes.len());
+        Savepoint(name.to_string())
+    }
+    /// Undoes the writes made since `savepoint`, which stays usable. The
+    /// savepoints set after it are forgotten.
+    pub fn rollback_to(&mut self, savepoint: &Savepoint) -> io::Result<()> {
+        let len = *self.savepoints.get(&savepoint.0).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no savepoint {:?} in this transaction", savepoint.0),
+            )
+        })?;
+        self.writes.truncate(len);
+        self.savepoints.retain(|_, set_at| *set_at <= len);
+        Ok(())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+    pub fn deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+}
+
+impl ActionKV {
+    pub fn transaction(&self) -> Transaction {
+        Transaction {
+            snapshot: self.log_position(self.appender.tail()),
+            compactions: self.write_log.compactions,
+            deadline: None,
+            writes: Vec::new(),
+            savepoints: HashMap::new(),
+        }
+    }
+    /// A transaction that has to be committed within `timeout` by the
+    /// store's clock. Past that, reads through it and its commit fail with
+    /// `ActionKvError::DeadlineExceeded` and its writes are dropped, so a caller
+    /// that got stuck half way can't commit stale writes later.
+    pub fn transaction_until(&self, timeout: Duration) -> Transaction {
+        Transaction {
+            deadline: Some(self.clock.now().saturating_add(timeout)),
+            ..self.transaction()
+        }
+    }
+    fn within(&self, deadline: Option<Duration>) -> io::Result<()> {
+        match deadline {
+            Some(deadline) if self.clock.now() > deadline => {
+                Err(ActionKvError::DeadlineExceeded.into())
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Fails if `key` was written since `tx` began, the index has to be
+    /// read already.
+    fn unchanged(&self, tx: &Transaction, key: &ByteStr) -> io::Result<()> {
+        let index_key = self.index_key(key);
+        let deleted = self.write_log.deleted_at(&index_key);
+        let written = [self.position(key), deleted]
+            .into_iter()
+            .flatten()
+            .map(|position| self.log_position(position))
+            .max();
+        if tx.compactions != self.write_log.compactions
+            || written.is_some_and(|position| position >= tx.snapshot)
+        {
+            return Err(ActionKvError::Conflict { key: key.to_vec() }.into());
+        }
+        Ok(())
+    }
+    /// Writes everything `tx` holds as one group, only the last write of
+    /// every key makes it, and makes it durable. Fails with
+    /// `ActionKvError::Conflict`, writing nothing, if one of the keys was written
+    /// since `tx` began. If the sync fails the commit may or may not have
+    /// made it to disk.
+    pub fn commit(&mut self, tx: Transaction) -> io::Result<()> {
+        self.commit_all(vec![tx]).pop().unwrap_or(Ok(()))
+    }
+    /// Group commit: writes each of `txs` as its own group, in order, and
+    /// syncs once for all of them. Returns the result of every commit, a
+    /// transaction that fails doesn't keep the others from committing.
+    pub fn commit_all(&mut self, txs: Vec<Transaction>) -> Vec<io::Result<()>> {
+        let mut committed = Vec::new();
+        let mut results = Vec::with_capacity(txs.len());
+        for tx in txs {
+            results.push(
+                self.write_transaction(tx)
+                    .map(|writes| committed.push(writes)),
+            );
+        }
+        if committed.is_empty() {
+            return results;
+        }
+        if let Err(err) = self.sync_commits() {
+            for result in results.iter_mut().filter(|result| result.is_ok()) {
+                *result = Err(io::Error::new(err.kind(), err.to_string()));
+            }
+            return results;
+        }
+        for writes in committed {
+            self.notify_group(writes);
+        }
+        results
+    }
+    fn sync_commits(&mut self) -> io::Result<()> {
+        self.inject(IoOp::Sync, StoreFile::Data)?;
+        self.file_.sync_data()?;
+        self.index_written()?;
+        if self.options.index_checkpoints.is_none() {
+            self.inject(IoOp::Sync, StoreFile::Index)?;
+            self.index_.sync_data()?;
+        }
+        Ok(())
+    }
+    /// Applies `ops` in order for ingesting many writes at once: they are
+    /// appended as one group in a single write, synced once and followed by
+    /// a single index save, instead of paying for each write on its own. A
+    /// crash leaves all of them or none, only the last write of every key
+    /// makes it. Unlike a commit nothing is checked for conflicts.
+    pub fn write_batch(&mut self, ops: &[WriteOp]) -> io::Result<()> {
+        let mut tx = self.transaction();
+        for op in ops {
+            match op {
+                WriteOp::Insert(key, value) => {
+                    self.io_stats
+                        .record_operation(OpClass::Insert, (key.len() + value.len()) as u64);
+                    tx.insert(key, value);
+                }
+                WriteOp::Delete(key) => {
+                    self.io_stats
+                        .record_operation(OpClass::Delete, key.len() as u64);
+                    tx.delete(key);
+                }
+            }
+        }
+        self.commit(tx)
+    }
+    /// Writes the group of `tx` without syncing it, returns its writes.
+    fn write_transaction(
+        &mut self,
+        mut tx: Transaction,
+    ) -> io::Result<Vec<(ByteString, Option<ByteString>)>> {
+        self.within(tx.deadline)?;
+        self.read_index()?;
+        let mut writes: Vec<(ByteString, Option<ByteString>)> = Vec::new();
+        let mut slots = HashMap::new();
+        for (key, value) in std::mem::take(&mut tx.writes) {
+            let key = self.canonical_key(&key).into_owned();
+            match slots.get(&key) {
+                Some(&slot) => writes[slot] = (key, value),
+                None => {
+                    slots.insert(key.clone(), writes.len());
+                    writes.push((key, value));
+                }
+            }
+        }
+        for (key, _) in &writes {
+            self.unchanged(&tx, key)?;
+        }
+        if !writes.is_empty() {
+            self.op_class = OpClass::Insert;
+            self.write_group(&writes)?;
+        }
+        Ok(writes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault::{Fault, FaultInjector};
+    use crate::testing::TestCtx;
+    use crate::{IndexCheckpoints, ManualClock, Options, WordTokenizer};
+    use rstest::*;
+    use serial_test::serial;
+    use std::fs::{self, OpenOptions};
+    use std::sync::Arc;
+
+    #[rstest]
+    #[serial]
+    fn test_savepoints() {
+        let mut ctx = TestCtx::setup("test_transaction");
+        let store = &mut ctx.test_file;
+        store.insert(b"stock", b"10").unwrap();
+        let mut tx = store.transaction();
+        tx.insert(b"order:1", b"placed");
+        let placed = tx.savepoint("placed");
+        tx.insert(b"stock", b"9");
+        let reserved = tx.savepoint("reserved");
+        tx.delete(b"order:1");
+        assert_eq!(tx.get(store, b"order:1").unwrap(), None);
+        tx.rollback_to(&placed).unwrap();
+        assert_eq!(tx.get(store, b"stock").unwrap(), Some(b"10".to_vec()));
+        assert!(tx.rollback_to(&reserved).is_err());
+        tx.insert(b"stock", b"8");
+        tx.rollback_to(&placed).unwrap();
+        tx.insert(b"stock", b"7");
+        assert_eq!(store.get(b"order:1").unwrap(), None);
+        store.commit(tx).unwrap();
+        assert_eq!(store.get(b"order:1").unwrap(), Some(b"placed".to_vec()));
+        assert_eq!(store.get(b"stock").unwrap(), Some(b"7".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_transaction_deadline() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1000)));
+        let options = Options {
+            clock: Some(clock.clone()),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_transaction", options);
+        let store = &mut ctx.test_file;
+        let mut tx = store.transaction_until(Duration::from_secs(5));
+        tx.insert(b"a", b"1");
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(tx.get(store, b"a").unwrap(), Some(b"1".to_vec()));
+        store.commit(tx).unwrap();
+
+        let mut tx = store.transaction_until(Duration::from_secs(5));
+        tx.insert(b"a", b"2");
+        clock.advance(Duration::from_secs(6));
+        let err = tx.get(store, b"a").unwrap_err();
+        assert!(matches!(
+            ActionKvError::from_io(&err),
+            Some(ActionKvError::DeadlineExceeded)
+        ));
+        let err = store.commit(tx).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_first_committer_wins() {
+        let mut ctx = TestCtx::setup("test_transaction");
+        let store = &mut ctx.test_file;
+        store.insert(b"a", b"0").unwrap();
+        store.insert(b"b", b"0").unwrap();
+        let mut first = store.transaction();
+        let mut second = store.transaction();
+        first.insert(b"a", b"1");
+        second.insert(b"a", b"2");
+        second.insert(b"b", b"2");
+        store.commit(first).unwrap();
+        let err = store.commit(second).unwrap_err();
+        assert!(
+            matches!(ActionKvError::from_io(&err), Some(ActionKvError::Conflict { key }) if key == b"a")
+        );
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"0".to_vec()));
+
+        // deletes conflict too, outside of transactions as well
+        let mut tx = store.transaction();
+        assert_eq!(tx.get(store, b"b").unwrap(), Some(b"0".to_vec()));
+        store.delete(b"b").unwrap();
+        assert!(matches!(
+            ActionKvError::from_io(&tx.get(store, b"b").unwrap_err()),
+            Some(ActionKvError::Conflict { .. })
+        ));
+        tx.insert(b"b", b"3");
+        assert!(store.commit(tx).is_err());
+
+        // keys nobody else touched commit fine
+        let mut tx = store.transaction();
+        store.insert(b"c", b"0").unwrap();
+        tx.insert(b"a", b"4");
+        store.commit(tx).unwrap();
+
+        let mut tx = store.transaction();
+        tx.insert(b"a", b"5");
+        store.compact().unwrap();
+        assert!(store.commit(tx).is_err());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"4".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_commit_all() {
+        let mut ctx = TestCtx::setup("test_transaction");
+        let store = &mut ctx.test_file;
+        let mut first = store.transaction();
+        let mut second = store.transaction();
+        let empty = store.transaction();
+        first.insert(b"a", b"1");
+        second.insert(b"a", b"2");
+        second.insert(b"b", b"2");
+        let results = store.commit_all(vec![first, second, empty]);
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            ActionKvError::from_io(results[1].as_ref().unwrap_err()),
+            Some(ActionKvError::Conflict { .. })
+        ));
+        assert!(results[2].is_ok());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+    }
+    #[rstest]
+    #[serial]
+    fn test_failed_commit_leaves_nothing_behind() {
+        let faults = FaultInjector::new();
+        let options = Options {
+            faults: Some(faults.clone()),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_transaction", options);
+        let store = &mut ctx.test_file;
+        store.insert(b"a", b"0").unwrap();
+        let tail = store.appender.tail();
+
+        // the header and the first record make it, the second doesn't
+        let mut fault = Fault::new(IoOp::Write, io::ErrorKind::StorageFull);
+        fault.file = Some(StoreFile::Data);
+        fault.skip = 2;
+        faults.arm(fault);
+        let mut tx = store.transaction();
+        tx.insert(b"a", b"1");
+        tx.insert(b"b", b"1");
+        assert!(store.commit(tx).is_err());
+        assert_eq!(store.appender.tail(), tail);
+        assert_eq!(fs::metadata("test_transaction/data").unwrap().len(), tail);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"0".to_vec()));
+
+        faults.arm(Fault::new(IoOp::Sync, io::ErrorKind::Other));
+        let mut tx = store.transaction();
+        tx.insert(b"b", b"2");
+        assert!(store.commit(tx).is_err());
+
+        let mut tx = store.transaction();
+        tx.insert(b"a", b"3");
+        store.commit(tx).unwrap();
+        store.insert(b"c", b"3").unwrap();
+        let store = ctx.crash_and_reopen(Options::default()).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_write_batch() {
+        let faults = FaultInjector::new();
+        let options = Options {
+            faults: Some(faults.clone()),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_transaction", options);
+        let store = &mut ctx.test_file;
+        store.insert(b"a", b"0").unwrap();
+        store.reset_stats();
+        let insert = |key: &[u8], value: &[u8]| WriteOp::Insert(key.to_vec(), value.to_vec());
+        store
+            .write_batch(&[
+                insert(b"a", b"1"),
+                insert(b"b", b"1"),
+                WriteOp::Delete(b"a".to_vec()),
+                insert(b"c", b"1"),
+                insert(b"c", b"2"),
+            ])
+            .unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert_eq!(store.get(b"b").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"2".to_vec()));
+        let io = store.stats().io;
+        assert_eq!((io.insert.operations, io.delete.operations), (4, 1));
+        assert_eq!(io.index.operations, 1);
+
+        // the records are held back and written at once, failing that
+        // leaves nothing behind
+        let tail = store.appender.tail();
+        faults.arm(Fault {
+            file: Some(StoreFile::Data),
+            skip: 3,
+            ..Fault::new(IoOp::Write, io::ErrorKind::StorageFull)
+        });
+        assert!(store
+            .write_batch(&[insert(b"a", b"2"), insert(b"b", b"2")])
+            .is_err());
+        assert_eq!(faults.armed(), 0);
+        assert_eq!(fs::metadata("test_transaction/data").unwrap().len(), tail);
+        assert_eq!(store.get(b"b").unwrap(), Some(b"1".to_vec()));
+
+        let store = ctx.crash_and_reopen(Options::default()).unwrap();
+        assert_eq!(store.get(b"b").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"2".to_vec()));
+    }
+    #[rstest]
+    #[serial]
+    fn test_write_batch_takes_its_postings_along() {
+        let faults = FaultInjector::new();
+        let checkpoints = Some(IndexCheckpoints {
+            records: 100,
+            bytes: u64::MAX,
+        });
+        let options = Options {
+            faults: Some(faults.clone()),
+            index_checkpoints: checkpoints,
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_transaction", options);
+        let store = &mut ctx.test_file;
+        store.set_tokenizer(Box::new(WordTokenizer));
+        store.insert(b"a", b"red apple").unwrap();
+        store.flush_index().unwrap();
+        let tail = store.appender.tail();
+        let batch = [
+            WriteOp::Insert(b"a".to_vec(), b"green pear".to_vec()),
+            WriteOp::Insert(b"b".to_vec(), b"blue".to_vec()),
+        ];
+
+        // the header, the two stale postings and both values are written,
+        // the first new posting isn't
+        faults.arm(Fault {
+            file: Some(StoreFile::Data),
+            skip: 5,
+            ..Fault::new(IoOp::Write, io::ErrorKind::StorageFull)
+        });
+        assert!(store.write_batch(&batch).is_err());
+        assert_eq!(fs::metadata("test_transaction/data").unwrap().len(), tail);
+        assert_eq!(store.search(b"red").unwrap(), [b"a".to_vec()]);
+        assert!(store.search(b"green").unwrap().is_empty());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"red apple".to_vec()));
+
+        // a crash cutting the group short on disk takes its postings too
+        store.write_batch(&batch).unwrap();
+        assert_eq!(store.search(b"green").unwrap(), [b"a".to_vec()]);
+        let after = store.appender.tail();
+        let data = OpenOptions::new()
+            .write(true)
+            .open("test_transaction/data")
+            .unwrap();
+        data.set_len(after - 1).unwrap();
+        let store = ctx
+            .crash_and_reopen(Options {
+                index_checkpoints: checkpoints,
+                ..Options::default()
+            })
+            .unwrap();
+        assert_eq!(store.appender.tail(), tail);
+        assert_eq!(store.search(b"red").unwrap(), [b"a".to_vec()]);
+        assert!(store.search(b"green").unwrap().is_empty());
+        assert_eq!(store.get(b"b").unwrap(), None);
+    }
+    #[rstest]
+    #[serial]
+    fn test_write_batch_chunks_large_values() {
+        let options = Options {
+            chunk_size: Some(10),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_transaction", options.clone());
+        let store = &mut ctx.test_file;
+        let big: ByteString = (0..100).collect();
+        store.insert(b"a", b"1").unwrap();
+        store
+            .write_batch(&[
+                WriteOp::Insert(b"a".to_vec(), big.clone()),
+                WriteOp::Insert(b"b".to_vec(), b"2".to_vec()),
+            ])
+            .unwrap();
+        store.read_index().unwrap();
+        assert!(store.position(b"a").is_none());
+        assert_eq!(store.get(b"a").unwrap(), Some(big.clone()));
+
+        let store = ctx.reopen_with_options(options).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(big));
+        store
+            .write_batch(&[WriteOp::Insert(b"a".to_vec(), b"small".to_vec())])
+            .unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"small".to_vec()));
+        assert!(store.chunked_keys().is_empty());
+    }
+    #[rstest]
+    #[serial]
+    fn test_failed_batch_leaves_no_conflicts() {
+        let faults = FaultInjector::new();
+        let options = Options {
+            faults: Some(faults.clone()),
+            ..Options::default()
+        };
+        let mut ctx = TestCtx::setup_with_options("test_transaction", options);
+        let store = &mut ctx.test_file;
+        store.insert(b"a", b"1").unwrap();
+        // the header and the delete of a are written, b isn't
+        faults.arm(Fault {
+            file: Some(StoreFile::Data),
+            skip: 2,
+            ..Fault::new(IoOp::Write, io::ErrorKind::StorageFull)
+        });
+        let batch = [
+            WriteOp::Delete(b"a".to_vec()),
+            WriteOp::Insert(b"b".to_vec(), b"2".to_vec()),
+        ];
+        assert!(store.write_batch(&batch).is_err());
+
+        let mut tx = store.transaction();
+        assert_eq!(tx.get(store, b"a").unwrap(), Some(b"1".to_vec()));
+        tx.insert(b"a", b"3");
+        store.commit(tx).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+    }
+}
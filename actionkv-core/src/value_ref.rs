@@ -0,0 +1,149 @@
+use crate::fault::{IoOp, StoreFile};
+use crate::format::Framing;
+use crate::{verify_checksum, ActionKV, ActionKvError, ByteStr, ByteString, Operation};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::mem;
+use std::ops::{Deref, Range};
+
+/// Bytes read in one go when fetching a record, enough for most records to
+/// take a single read.
+const READ_AHEAD: u64 = 4 << 10;
+
+/// A value borrowed from the store, see `ActionKV::get_ref`. It holds on to
+/// the store, which can be used again once it is dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueRef<'a> {
+    bytes: Cow<'a, ByteStr>,
+}
+
+impl ValueRef<'_> {
+    pub fn into_owned(self) -> ByteString {
+        self.bytes.into_owned()
+    }
+}
+
+impl Deref for ValueRef<'_> {
+    type Target = ByteStr;
+
+    fn deref(&self) -> &ByteStr {
+        &self.bytes
+    }
+}
+
+impl AsRef<ByteStr> for ValueRef<'_> {
+    fn as_ref(&self) -> &ByteStr {
+        &self.bytes
+    }
+}
+
+/// Reads the record at `position` into `buf`, which keeps its allocation
+/// from one call to the next. Returns where its key and value are in `buf`.
+fn read_record_at(
+    f: &mut File,
+    position: u64,
+    framing: Framing,
+    verify: bool,
+    buf: &mut ByteString,
+) -> io::Result<(Range<usize>, Range<usize>)> {
+    f.seek(SeekFrom::Start(position))?;
+    buf.clear();
+    f.by_ref().take(READ_AHEAD).read_to_end(buf)?;
+    let mut header = &buf[..];
+    let saved_checksum = header.read_u32::<LittleEndian>()?;
+    let (key_len, value_len) = framing.read_lengths(&mut header)?;
+    let padding = framing.read_padding_len(&mut header)?;
+    let key_start = buf.len() - header.len();
+    let value_start = key_start + key_len as usize;
+    let end = value_start.saturating_add(usize::try_from(value_len).unwrap_or(usize::MAX));
+    if buf.len() < end {
+        f.by_ref().take((end - buf.len()) as u64).read_to_end(buf)?;
+    }
+    if buf.len() < end {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "record is cut short",
+        ));
+    }
+    if verify {
+        let checksum = framing.checksum(key_len, value_len, padding, &buf[key_start..end]);
+        verify_checksum(checksum, saved_checksum)?;
+    }
+    Ok((key_start..value_start, value_start..end))
+}
+
+impl ActionKV {
+    /// `get` without copying the value out: the record is read into a buffer
+    /// the store reuses and the value is returned as a view into it. Values
+    /// of stores with compression are decompressed into a buffer of their own.
+    pub fn get_ref(&mut self, key: &ByteStr) -> io::Result<Option<ValueRef<'_>>> {
+        let key = self.canonical_key(key);
+        let key = key.as_ref();
+        self.authorize(Operation::Read, key)?;
+        self.track_access(key)?;
+        self.read_index()?;
+        let Some(position) = self.position(key) else {
+            return Ok(None);
+        };
+        let framing = self.framing;
+        let verify = !self.options.skip_checksums;
+        let (key_range, value_range) = self
+            .retrying(|store| {
+                store.inject(IoOp::Read, StoreFile::Data)?;
+                let mut buf = mem::take(&mut store.read_buf);
+                let read = store
+                    .record_file(position)
+                    .and_then(|(f, offset)| read_record_at(f, offset, framing, verify, &mut buf));
+                store.read_buf = buf;
+                read.map_err(|err| ActionKvError::at_offset(err, position))
+            })
+            .inspect_err(|err| self.corruption.count(err))?;
+        let record_key = &self.read_buf[key_range];
+        if record_key != key {
+            return Err(ActionKvError::HashCollision {
+                key: key.to_vec(),
+                other: record_key.to_vec(),
+            }
+            .into());
+        }
+        let value = &self.read_buf[value_range];
+        let bytes = match &self.values {
+            Some(codec) => Cow::Owned(codec.decode(value.to_vec())?),
+            None => Cow::Borrowed(value),
+        };
+        self.io_stats.record_read(bytes.len() as u64);
+        Ok(Some(ValueRef { bytes }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestCtx;
+    use crate::Options;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[rstest]
+    #[case(Options::default())]
+    #[case(Options { varint_lengths: true, alignment: Some(512), ..Options::default() })]
+    #[serial]
+    fn test_get_ref(#[case] options: Options) {
+        let mut ctx = TestCtx::setup_with_options("test_value_ref", options);
+        let store = &mut ctx.test_file;
+        let large = vec![7; 10_000];
+        store.insert(b"foo", b"bar").unwrap();
+        store.insert(b"large", &large).unwrap();
+
+        assert_eq!(&*store.get_ref(b"foo").unwrap().unwrap(), b"bar");
+        assert_eq!(
+            store.get_ref(b"large").unwrap().unwrap().into_owned(),
+            large
+        );
+        assert_eq!(&*store.get_ref(b"foo").unwrap().unwrap(), b"bar");
+        assert_eq!(store.get_ref(b"missing").unwrap(), None);
+        store.delete(b"foo").unwrap();
+        assert_eq!(store.get_ref(b"foo").unwrap(), None);
+    }
+}
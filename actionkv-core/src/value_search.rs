@@ -0,0 +1,142 @@
+use crate::access::{self, AccessPolicy};
+use crate::compression::ValueCodec;
+use crate::format::Framing;
+use crate::platform;
+use crate::segment::SegmentReader;
+use crate::{is_internal_key, ActionKV, ActionKvError, ByteString};
+use regex::Regex;
+use std::io;
+use std::path::Path;
+use std::thread;
+
+/// Keys of the records at `entries` whose value is UTF-8 text matching `regex`
+/// and that `policy` lets be read, read through file handles of its own so
+/// several of these can run at once.
+fn grep_records(
+    dir: &Path,
+    policy: Option<AccessPolicy>,
+    framing: Framing,
+    values: Option<&ValueCodec>,
+    entries: &[(ByteString, u64)],
+    regex: &Regex,
+) -> io::Result<Vec<ByteString>> {
+    let mut segments = SegmentReader::open(dir)?;
+    let mut matching = Vec::new();
+    for (_, position) in entries {
+        let mut record = ActionKV::process_records(segments.at(*position)?, framing)
+            .map_err(|err| ActionKvError::at_offset(err, *position))?;
+        // the record has the key itself, the index may only have its hash
+        if !access::readable(policy, &record.key) {
+            continue;
+        }
+        if let Some(codec) = values {
+            record.value = codec.decode(record.value)?;
+        }
+        if std::str::from_utf8(&record.value).is_ok_and(|value| regex.is_match(value)) {
+            matching.push(record.key);
+        }
+    }
+    Ok(matching)
+}
+
+impl ActionKV {
+    /// Keys whose current value is UTF-8 text matching the regular expression
+    /// `pattern`, sorted. Every live value is read, nothing is indexed for it.
+    /// Keys the access policy doesn't let be read are left out.
+    pub fn search_values(&mut self, pattern: &str) -> io::Result<Vec<ByteString>> {
+        self.search_values_parallel(pattern, 1)
+    }
+    /// `search_values` with the records split between `workers` threads.
+    pub fn search_values_parallel(
+        &mut self,
+        pattern: &str,
+        workers: usize,
+    ) -> io::Result<Vec<ByteString>> {
+        let regex =
+            Regex::new(pattern).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.read_index()?;
+        let mut entries: Vec<(ByteString, u64)> = self
+            .index
+            .iter()
+            .filter(|(key, _)| !is_internal_key(key))
+            .map(|(key, position)| (key.clone(), *position))
+            .collect();
+        // reading in file order keeps every worker's reads sequential
+        entries.sort_by_key(|(_, position)| *position);
+        let dir = self.dir("value searches")?;
+        let policy = self.options.access_policy;
+        let framing = self.framing;
+        let values = self.values.as_deref();
+        let chunk_len = entries.len().div_ceil(platform::workers(workers)).max(1);
+        if entries.len() <= chunk_len {
+            let mut matching = grep_records(dir, policy, framing, values, &entries, &regex)?;
+            matching.sort();
+            return Ok(matching);
+        }
+        let mut matching = thread::scope(|scope| {
+            let handles: Vec<_> = entries
+                .chunks(chunk_len)
+                .map(|chunk| {
+                    scope.spawn(|| grep_records(dir, policy, framing, values, chunk, &regex))
+                })
+                .collect();
+            let mut matching = Vec::new();
+            for handle in handles {
+                let found = handle
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("value search worker panicked")))?;
+                matching.extend(found);
+            }
+            Ok::<_, io::Error>(matching)
+        })?;
+        matching.sort();
+        Ok(matching)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, TestCtx};
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_value_search")
+    }
+    #[rstest]
+    #[serial]
+    fn test_search_values(mut ctx: TestCtx) {
+        for i in 0..100u32 {
+            let value = format!(
+                "order {} status={}",
+                i,
+                ["ok", "failed"][(i % 7 == 0) as usize]
+            );
+            ctx.test_file
+                .insert(format!("order:{:03}", i).as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+        ctx.test_file
+            .insert(b"binary", b"\xffstatus=failed")
+            .unwrap();
+        let failed = ctx.test_file.search_values("status=fail").unwrap();
+        assert_eq!(failed.len(), 15);
+        assert_eq!(failed[0], b"order:000".to_vec());
+        assert_eq!(
+            ctx.test_file
+                .search_values_parallel("status=fail", 4)
+                .unwrap(),
+            failed
+        );
+        assert!(ctx.test_file.search_values("(").is_err());
+    }
+    #[rstest]
+    #[serial]
+    fn test_search_values_leaves_out_unreadable_keys(mut ctx: TestCtx) {
+        testing::hide_secrets(&mut ctx.test_file, 5);
+        let found = ctx.test_file.search_values_parallel(".", 2).unwrap();
+        assert_eq!(found.len(), 5);
+        assert!(found.iter().all(|key| key.starts_with(b"public:")));
+    }
+}
@@ -0,0 +1,445 @@
+use crate::fault::{IoOp, StoreFile};
+use crate::format::Framing;
+use crate::prefetch::ReadAhead;
+use crate::segment::{position, split, SegmentReader, ACTIVE};
+use crate::{display_key, platform, ActionKV, ActionKvError, ByteString, ParanoidChecks};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+/// Bytes a verification pass, or a `verify_deep` worker, checks between two
+/// progress reports.
+const PROGRESS_STEP: u64 = 1 << 20;
+
+/// Outcome of a `verify` pass that found nothing wrong.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Records read from the data file, superseded ones and tombstones included.
+    pub records: u64,
+    pub bytes: u64,
+    /// Index entries checked against the records they point at.
+    pub index_entries: u64,
+}
+
+impl ActionKV {
+    /// Checks the whole store: every record of the data file against its
+    /// checksum, whatever `Options::skip_checksums` says, then every index
+    /// entry against the record it points at. Fails at the first problem.
+    pub fn verify(&mut self) -> io::Result<VerifyReport> {
+        self.verify_with_progress(|_, _| {})
+    }
+    /// `verify` calling `progress` with the bytes checked so far and the
+    /// total as it goes.
+    pub fn verify_with_progress<P>(&mut self, mut progress: P) -> io::Result<VerifyReport>
+    where
+        P: FnMut(u64, u64),
+    {
+        self.read_index()?;
+        self.inject(IoOp::Read, StoreFile::Data)?;
+        let framing = self.framing;
+        let (start, end) = (self.data_start, self.data_end()?);
+        let total = self.verified_bytes()?;
+        let mut done = 0;
+        progress(done, total);
+        let mut report = VerifyReport::default();
+        let mut checked = |bytes| {
+            done += bytes;
+            progress(done, total);
+        };
+        let walked = walk_records(
+            &mut self.file_,
+            framing,
+            ACTIVE,
+            start,
+            end,
+            read_record,
+            &mut checked,
+        );
+        let mut walked = walked.map(|starts| starts.len() as u64);
+        for (id, path) in self.segment_files()? {
+            let Ok(records) = walked else {
+                break;
+            };
+            let mut f = File::open(path)?;
+            let end = f.metadata()?.len();
+            walked = walk_records(&mut f, framing, id, start, end, read_record, &mut checked)
+                .map(|starts| records + starts.len() as u64);
+        }
+        report.records = walked.inspect_err(|err| self.corruption.count(err))?;
+        report.bytes = total;
+        self.check_index(ParanoidChecks::All)?;
+        report.index_entries = self.index.len() as u64;
+        Ok(report)
+    }
+    /// Quick health check: walks the record framing of the data file, the
+    /// values are skipped and their checksums left alone, then checks every
+    /// index entry against the record it points at.
+    pub fn verify_fast(&mut self) -> io::Result<VerifyReport> {
+        self.verify_fast_with_progress(|_, _| {})
+    }
+    /// `verify_fast` calling `progress` with the bytes walked so far and the
+    /// total as it goes.
+    pub fn verify_fast_with_progress<P>(&mut self, mut progress: P) -> io::Result<VerifyReport>
+    where
+        P: FnMut(u64, u64),
+    {
+        self.read_index()?;
+        let total = self.verified_bytes()?;
+        let mut done = 0;
+        progress(done, total);
+        let starts = self.record_starts(&mut |bytes| {
+            done += bytes;
+            progress(done, total);
+        })?;
+        self.check_index_framing(&starts)?;
+        Ok(VerifyReport {
+            records: starts.len() as u64,
+            bytes: total,
+            index_entries: self.index.len() as u64,
+        })
+    }
+    /// `verify` with the checksums of the records checked by `workers`
+    /// threads, each reading its own stretch of the log. `progress` is
+    /// called with the bytes checked so far and the total as they go.
+    pub fn verify_deep<P>(&mut self, workers: usize, mut progress: P) -> io::Result<VerifyReport>
+    where
+        P: FnMut(u64, u64),
+    {
+        self.read_index()?;
+        let dir = self.dir("deep verification")?.to_path_buf();
+        let starts = self.record_starts(&mut |_| {})?;
+        let total = self.verified_bytes()?;
+        let framing = self.framing;
+        let chunk_len = starts.len().div_ceil(platform::workers(workers)).max(1);
+        let (checked, reports) = mpsc::channel();
+        thread::scope(|scope| {
+            let handles: Vec<_> = starts
+                .chunks(chunk_len)
+                .map(|chunk| {
+                    let checked = checked.clone();
+                    let dir = &dir;
+                    scope.spawn(move || check_records(dir, framing, chunk, checked))
+                })
+                .collect();
+            drop(checked);
+            let mut done = 0;
+            for bytes in reports {
+                done += bytes;
+                progress(done, total);
+            }
+            for handle in handles {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("verify worker panicked")))?;
+            }
+            Ok::<_, io::Error>(())
+        })?;
+        self.check_index(ParanoidChecks::All)?;
+        Ok(VerifyReport {
+            records: starts.len() as u64,
+            bytes: total,
+            index_entries: self.index.len() as u64,
+        })
+    }
+    /// `check_index` without the checksums: every index entry has to point
+    /// at the start of a record holding its key.
+    fn check_index_framing(&mut self, starts: &[u64]) -> io::Result<()> {
+        let entries: Vec<(ByteString, u64)> = self
+            .index
+            .iter()
+            .map(|(key, position)| (key.clone(), *position))
+            .collect();
+        for (key, position) in entries {
+            let drift = |reason: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "index drift: key {:?} points at offset {} but {}",
+                        display_key(&key),
+                        position,
+                        reason
+                    ),
+                )
+            };
+            if starts.binary_search(&position).is_err() {
+                return Err(drift("no record starts there"));
+            }
+            let record = self.read_at(position, false, false)?;
+            if self.index_key(&record.key) != key {
+                return Err(drift("the record there holds another key"));
+            }
+        }
+        Ok(())
+    }
+    /// Where the records of the data file end for verification. A read only
+    /// store sees as far as the writer's saved index, the rest of the file
+    /// is checked as well: a torn record there is reported rather than cut
+    /// off as a writer open would.
+    fn data_end(&self) -> io::Result<u64> {
+        match self.options.read_only {
+            true => Ok(self.file_.metadata()?.len().max(self.appender.tail())),
+            false => Ok(self.appender.tail()),
+        }
+    }
+    /// Bytes of the log up to `data_end`.
+    fn verified_bytes(&self) -> io::Result<u64> {
+        Ok(self.log_bytes()? + self.data_end()? - self.appender.tail())
+    }
+    /// Positions of every record in the data file and the sealed segments,
+    /// found by their framing alone, sorted. `walked` is given the bytes
+    /// moved past as they add up.
+    fn record_starts(&mut self, walked: &mut dyn FnMut(u64)) -> io::Result<Vec<u64>> {
+        self.inject(IoOp::Read, StoreFile::Data)?;
+        let framing = self.framing;
+        let (start, end) = (self.data_start, self.data_end()?);
+        let mut starts = walk_records(
+            &mut self.file_,
+            framing,
+            ACTIVE,
+            start,
+            end,
+            skip_record,
+            walked,
+        )?;
+        for (id, path) in self.segment_files()? {
+            let mut f = File::open(path)?;
+            let end = f.metadata()?.len();
+            starts.extend(walk_records(
+                &mut f,
+                framing,
+                id,
+                start,
+                end,
+                skip_record,
+                walked,
+            )?);
+        }
+        Ok(starts)
+    }
+}
+
+/// Moves through the records of `segment` from `start` to `end` with `read`,
+/// which leaves `f` at the end of the record it is given. `walked` is given
+/// the bytes moved past every `PROGRESS_STEP` and at the end. Returns the
+/// positions of the records.
+fn walk_records<R: Read + Seek>(
+    f: R,
+    framing: Framing,
+    segment: u16,
+    start: u64,
+    end: u64,
+    read: fn(&mut ReadAhead<R>, Framing) -> io::Result<()>,
+    walked: &mut dyn FnMut(u64),
+) -> io::Result<Vec<u64>> {
+    let mut starts = Vec::new();
+    let mut f = ReadAhead::new(f)?;
+    let mut offset = f.seek(SeekFrom::Start(start))?;
+    let mut reported = offset;
+    while offset < end {
+        let at = position(segment, offset);
+        let next = read(&mut f, framing)
+            .and_then(|_| f.stream_position())
+            .and_then(|next| match next <= end {
+                true => Ok(next),
+                false => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "record is cut short",
+                )),
+            })
+            .map_err(|err| at_offset(at, err))?;
+        starts.push(at);
+        offset = next;
+        if offset - reported >= PROGRESS_STEP {
+            walked(offset - reported);
+            reported = offset;
+        }
+    }
+    if offset > reported {
+        walked(offset - reported);
+    }
+    Ok(starts)
+}
+
+fn at_offset(position: u64, err: io::Error) -> io::Error {
+    // the store's errors keep their variant, with the offset filled in
+    if ActionKvError::from_io(&err).is_some() {
+        return ActionKvError::at_offset(err, position);
+    }
+    io::Error::new(
+        err.kind(),
+        format!("record at offset {}: {}", position, err),
+    )
+}
+
+/// Reads the record `f` is at, checking its checksum.
+fn read_record<R: Read>(f: &mut R, framing: Framing) -> io::Result<()> {
+    ActionKV::process_records(f, framing).map(|_| ())
+}
+
+/// Moves `f` past the record it is at without reading its key and value.
+fn skip_record<R: Read + Seek>(f: &mut R, framing: Framing) -> io::Result<()> {
+    f.read_u32::<LittleEndian>()?;
+    let (key_len, value_len) = framing.read_lengths(f)?;
+    let padding = framing.read_padding_len(f)?;
+    let skip = (key_len as u64)
+        .saturating_add(value_len)
+        .saturating_add(padding as u64);
+    let skip = i64::try_from(skip)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "record length out of range"))?;
+    f.seek(SeekFrom::Current(skip))?;
+    Ok(())
+}
+
+/// Checks the checksums of the records at `starts` in the segments of the
+/// store in `dir`, reporting the bytes checked to `checked`.
+fn check_records(
+    dir: &Path,
+    framing: Framing,
+    starts: &[u64],
+    checked: mpsc::Sender<u64>,
+) -> io::Result<()> {
+    let mut segments = SegmentReader::open(dir)?;
+    let mut unreported = 0;
+    for &position in starts {
+        let f = segments.at(position)?;
+        ActionKV::process_records(f, framing).map_err(|err| at_offset(position, err))?;
+        unreported += f.stream_position()? - split(position).1;
+        if unreported >= PROGRESS_STEP {
+            let _ = checked.send(unreported);
+            unreported = 0;
+        }
+    }
+    let _ = checked.send(unreported);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::HEADER_LEN;
+    use crate::testing::TestCtx;
+    use crate::{ActionKV, ActionKvError, Options};
+    use rstest::*;
+    use serial_test::serial;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_verify")
+    }
+    #[rstest]
+    #[serial]
+    fn test_verify_ignores_skip_checksums(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        ctx.test_file.insert(b"foo", b"baz").unwrap();
+        let report = ctx.test_file.verify().unwrap();
+        assert_eq!((report.records, report.index_entries), (2, 1));
+
+        // the second record starts after the 18 bytes of the first one,
+        // overwrite the last byte of its value
+        let second = HEADER_LEN + 18;
+        let mut data = OpenOptions::new()
+            .write(true)
+            .open("test_verify/data")
+            .unwrap();
+        data.seek(SeekFrom::Start(second + 12 + 5)).unwrap();
+        data.write_all(b"X").unwrap();
+        assert!(ctx.test_file.get(b"foo").is_err());
+        let options = Options {
+            skip_checksums: true,
+            ..Options::default()
+        };
+        let store = ctx.reopen_with_options(options).unwrap();
+        assert_eq!(store.get(b"foo").unwrap(), Some(b"baX".to_vec()));
+        assert_eq!(&*store.get_ref(b"foo").unwrap().unwrap(), b"baX");
+        let err = store.verify().unwrap_err();
+        assert!(err.to_string().contains(&format!("offset {}", second)));
+        assert!(matches!(
+            ActionKvError::from_io(&err),
+            Some(ActionKvError::Corruption { offset: Some(offset), .. }) if *offset == second
+        ));
+
+        // the framing is fine, only the checksum tells
+        assert_eq!(store.verify_fast().unwrap().records, 2);
+        store.read_index().unwrap();
+        store.index.insert(b"foo".to_vec(), second + 1);
+        assert!(store.verify_fast().is_err());
+        store.index.insert(b"foo".to_vec(), second);
+        let err = store.verify_deep(2, |_, _| {}).unwrap_err();
+        assert!(err.to_string().contains(&format!("offset {}", second)));
+    }
+    #[rstest]
+    #[serial]
+    fn test_verify_deep(mut ctx: TestCtx) {
+        for i in 0..1000u32 {
+            ctx.test_file
+                .insert(format!("key:{}", i).as_bytes(), &[7; 100])
+                .unwrap();
+        }
+        let report = ctx.test_file.verify().unwrap();
+        let mut last = (0, 0);
+        let deep = ctx
+            .test_file
+            .verify_deep(4, |done, total| {
+                assert!(done > last.0 && done <= total);
+                last = (done, total);
+            })
+            .unwrap();
+        assert_eq!(deep, report);
+        assert_eq!(last, (report.bytes, report.bytes));
+        assert_eq!(ctx.test_file.verify_fast().unwrap(), report);
+    }
+    #[rstest]
+    #[serial]
+    fn test_verify_progress(mut ctx: TestCtx) {
+        for i in 0..1000u32 {
+            ctx.test_file
+                .insert(format!("key:{}", i).as_bytes(), &[7; 100])
+                .unwrap();
+        }
+        let mut calls = Vec::new();
+        let report = ctx
+            .test_file
+            .verify_with_progress(|done, total| calls.push((done, total)))
+            .unwrap();
+        assert_eq!(calls.first(), Some(&(0, report.bytes)));
+        assert_eq!(calls.last(), Some(&(report.bytes, report.bytes)));
+        assert!(calls.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        calls.clear();
+        let fast = ctx
+            .test_file
+            .verify_fast_with_progress(|done, total| calls.push((done, total)))
+            .unwrap();
+        assert_eq!(fast, report);
+        assert_eq!(calls.first(), Some(&(0, report.bytes)));
+        assert_eq!(calls.last(), Some(&(report.bytes, report.bytes)));
+    }
+    #[rstest]
+    #[serial]
+    fn test_read_only_verify_reports_a_torn_tail(mut ctx: TestCtx) {
+        ctx.test_file.insert(b"foo", b"bar").unwrap();
+        let report = ctx.test_file.verify().unwrap();
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let mut reader = ActionKV::open_with_options(Path::new("test_verify"), options).unwrap();
+        assert_eq!(reader.verify().unwrap(), report);
+
+        // half a record, as a crash in the middle of an append leaves it
+        let mut data = OpenOptions::new()
+            .append(true)
+            .open("test_verify/data")
+            .unwrap();
+        data.write_all(&[1, 2, 3, 4, 5, 0, 0, 0]).unwrap();
+        let len = data.metadata().unwrap().len();
+        assert!(reader.verify().is_err());
+        assert!(reader.verify_fast().is_err());
+        assert!(reader.verify_deep(2, |_, _| {}).is_err());
+        assert_eq!(fs::metadata("test_verify/data").unwrap().len(), len);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::platform;
+use crate::segment::SegmentReader;
+use crate::{ActionKV, ActionKvError, ByteStr, ByteString};
+use std::io;
+use std::thread;
+
+/// How many records a warming thread read, and the small values among them
+/// with the positions of their records.
+type Warmed = (u64, Vec<(ByteString, u64, ByteString)>);
+
+impl ActionKV {
+    /// Reads the live records of every key starting with one of `prefixes`
+    /// on as many threads as there are cores, so they are in the OS page
+    /// cache before the first request asks for them, and keeps the values
+    /// `Options::inline_values` allows in memory. An empty prefix warms the
+    /// whole store. Returns how many records were read.
+    pub fn warm(&mut self, prefixes: &[&ByteStr]) -> io::Result<u64> {
+        let mut positions = Vec::new();
+        for prefix in prefixes {
+            positions.extend(self.prefix_positions(prefix)?);
+        }
+        positions.sort();
+        positions.dedup();
+        let dir = self.dir("warming")?;
+        let framing = self.framing;
+        let values = self.values.as_deref();
+        let inline_values = self.options.inline_values;
+        let workers = platform::workers(thread::available_parallelism().map_or(1, usize::from));
+        let chunk_len = positions.len().div_ceil(workers).max(1);
+        let warmed: Vec<io::Result<Warmed>> = thread::scope(|scope| {
+            let handles: Vec<_> = positions
+                .chunks(chunk_len)
+                .map(|chunk| {
+                    scope.spawn(move || -> io::Result<Warmed> {
+                        let mut segments = SegmentReader::open(dir)?;
+                        let mut read = 0;
+                        let mut small = Vec::new();
+                        for position in chunk {
+                            let record =
+                                ActionKV::process_records(segments.at(*position)?, framing)
+                                    .map_err(|err| ActionKvError::at_offset(err, *position))?;
+                            // with hashed keys only the record tells what the key is
+                            if !prefixes.iter().any(|prefix| record.key.starts_with(prefix)) {
+                                continue;
+                            }
+                            read += 1;
+                            let value = match values {
+                                Some(codec) => codec.decode(record.value)?,
+                                None => record.value,
+                            };
+                            if inline_values.is_some_and(|max_len| value.len() <= max_len as usize)
+                            {
+                                small.push((record.key, *position, value));
+                            }
+                        }
+                        Ok((read, small))
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(io::Error::other("warming thread panicked")))
+                })
+                .collect()
+        });
+        let mut read = 0;
+        for result in warmed {
+            let (records, small) = result?;
+            read += records;
+            for (key, position, value) in small {
+                self.inline_put(&key, position, &value);
+            }
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fault::{Fault, FaultInjector};
+    use crate::testing::TestCtx;
+    use crate::{IoOp, Options, StoreFile};
+    use rstest::*;
+    use serial_test::serial;
+    use std::io;
+
+    #[rstest]
+    #[serial]
+    fn test_warm() {
+        let faults = FaultInjector::new();
+        let mut ctx = TestCtx::setup_with_options(
+            "test_warm",
+            Options {
+                inline_values: Some(16),
+                faults: Some(faults.clone()),
+                ..Options::default()
+            },
+        );
+        let store = &mut ctx.test_file;
+        let pairs = (0..100)
+            .flat_map(|i| {
+                [
+                    (format!("config:{}", i).into_bytes(), b"on".to_vec()),
+                    (format!("flag:{}", i).into_bytes(), b"off".to_vec()),
+                    (format!("blob:{}", i).into_bytes(), vec![7; 100]),
+                ]
+            })
+            .collect::<Vec<_>>();
+        store.bulk_load(pairs).unwrap();
+        assert_eq!(store.warm(&[b"config:", b"flag:", b"conf"]).unwrap(), 200);
+        assert_eq!(store.warm(&[b""]).unwrap(), 300);
+
+        faults.arm(Fault {
+            file: Some(StoreFile::Data),
+            times: None,
+            ..Fault::new(IoOp::Read, io::ErrorKind::Other)
+        });
+        assert_eq!(store.get(b"config:42").unwrap(), Some(b"on".to_vec()));
+        assert_eq!(store.get(b"flag:7").unwrap(), Some(b"off".to_vec()));
+        assert!(store.get(b"blob:1").is_err());
+    }
+}
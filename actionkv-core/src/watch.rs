@@ -0,0 +1,95 @@
+use crate::{is_internal_key, ActionKV, ByteStr, ByteString, Glob};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// A write to a key a subscription's pattern matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyEvent {
+    Put(ByteString),
+    Delete(ByteString),
+}
+
+impl KeyEvent {
+    pub fn key(&self) -> &ByteStr {
+        match self {
+            KeyEvent::Put(key) | KeyEvent::Delete(key) => key,
+        }
+    }
+}
+
+/// Where the events of one `subscribe` call go.
+#[derive(Debug)]
+pub(crate) struct Subscriber {
+    glob: Glob,
+    events: Sender<KeyEvent>,
+}
+
+impl ActionKV {
+    /// Keyspace notifications: the receiver gets a `KeyEvent` for every put
+    /// and delete of a key matching the glob `pattern` done through this
+    /// handle from now on, once it is written. Events queue up until they
+    /// are read, dropping the receiver ends the subscription.
+    pub fn subscribe(&mut self, pattern: &ByteStr) -> Receiver<KeyEvent> {
+        let (events, receiver) = mpsc::channel();
+        self.subscribers.push(Subscriber {
+            glob: Glob::new(pattern),
+            events,
+        });
+        receiver
+    }
+    /// Hands `event` to every subscriber whose pattern matches its key,
+    /// forgetting the ones that went away.
+    pub(crate) fn notify(&mut self, event: KeyEvent) {
+        if is_internal_key(event.key()) {
+            return;
+        }
+        self.subscribers.retain(|subscriber| {
+            !subscriber.glob.matches(event.key()) || subscriber.events.send(event.clone()).is_ok()
+        });
+    }
+    pub(crate) fn has_subscribers(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_watch")
+    }
+    #[rstest]
+    #[serial]
+    fn test_subscribe(mut ctx: TestCtx) {
+        let store = &mut ctx.test_file;
+        let users = store.subscribe(b"user:*");
+        let everything = store.subscribe(b"*");
+        store.insert(b"user:1", b"a").unwrap();
+        store.insert(b"order:1", b"b").unwrap();
+        store.delete(b"user:1").unwrap();
+        store
+            .bulk_load(vec![(b"user:2".to_vec(), b"c".to_vec())])
+            .unwrap();
+        assert_eq!(
+            users.try_iter().collect::<Vec<_>>(),
+            vec![
+                KeyEvent::Put(b"user:1".to_vec()),
+                KeyEvent::Delete(b"user:1".to_vec()),
+                KeyEvent::Put(b"user:2".to_vec()),
+            ]
+        );
+        assert_eq!(everything.try_iter().count(), 4);
+
+        drop(users);
+        store.insert(b"user:3", b"d").unwrap();
+        assert_eq!(store.subscribers.len(), 1);
+        assert_eq!(
+            everything.try_recv().unwrap(),
+            KeyEvent::Put(b"user:3".to_vec())
+        );
+    }
+}
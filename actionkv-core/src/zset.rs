@@ -0,0 +1,162 @@
+use crate::{namespaced_key, ActionKV, ByteStr, ByteString};
+use byteorder::{BigEndian, ByteOrder};
+use std::io;
+
+/*
+    SORTED SET LAYOUT
+    score entry:  +zs: | set_len | set | score | member  ->  score
+    member entry: +zm: | set_len | set | member          ->  score
+    set_len is a big endian u32 and score is the order preserving encoding below,
+    so sorting the score entries of one set by key sorts them by (score, member).
+*/
+const SCORE_PREFIX: &ByteStr = b"+zs:";
+const MEMBER_PREFIX: &ByteStr = b"+zm:";
+
+fn member_key(set: &ByteStr, member: &ByteStr) -> ByteString {
+    let mut key = namespaced_key(MEMBER_PREFIX, set);
+    key.extend(member);
+    key
+}
+
+fn score_key(set: &ByteStr, score: f64, member: &ByteStr) -> ByteString {
+    let mut key = namespaced_key(SCORE_PREFIX, set);
+    key.extend(encode_score(score));
+    key.extend(member);
+    key
+}
+
+/// Maps an `f64` onto 8 bytes whose lexicographic order matches the numeric order.
+pub fn encode_score(score: f64) -> [u8; 8] {
+    let bits = score.to_bits();
+    let ordered = if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits ^ (1 << 63)
+    };
+    ordered.to_be_bytes()
+}
+
+pub fn decode_score(bytes: &ByteStr) -> f64 {
+    let ordered = BigEndian::read_u64(bytes);
+    let bits = if ordered >> 63 == 1 {
+        ordered ^ (1 << 63)
+    } else {
+        !ordered
+    };
+    f64::from_bits(bits)
+}
+
+/// `decode_score` for bytes read back from the store.
+fn read_score(bytes: &ByteStr) -> io::Result<f64> {
+    if bytes.len() < 8 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("sorted set score is {} bytes instead of 8", bytes.len()),
+        ));
+    }
+    Ok(decode_score(bytes))
+}
+
+impl ActionKV {
+    /// Adds `member` to the sorted set `set` with `score`, moving it if it is already there.
+    pub fn zadd(&mut self, set: &ByteStr, member: &ByteStr, score: f64) -> io::Result<()> {
+        if score.is_nan() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sorted set score can't be NaN",
+            ));
+        }
+        if let Some(old_score) = self.zscore(set, member)? {
+            self.delete(&score_key(set, old_score, member))?;
+        }
+        let encoded = encode_score(score);
+        self.insert(&score_key(set, score, member), &encoded)?;
+        self.insert(&member_key(set, member), &encoded)?;
+        Ok(())
+    }
+    pub fn zscore(&mut self, set: &ByteStr, member: &ByteStr) -> io::Result<Option<f64>> {
+        self.get(&member_key(set, member))?
+            .map(|encoded| read_score(&encoded))
+            .transpose()
+    }
+    /// Members of `set` with `min <= score <= max`, lowest score first.
+    pub fn zrange_by_score(
+        &mut self,
+        set: &ByteStr,
+        min: f64,
+        max: f64,
+    ) -> io::Result<Vec<(ByteString, f64)>> {
+        let prefix = namespaced_key(SCORE_PREFIX, set);
+        let mut members = Vec::new();
+        for key in self.keys_with_prefix(&prefix)? {
+            let entry = &key[prefix.len()..];
+            let score = read_score(entry)?;
+            if score >= min && score <= max {
+                members.push((entry[8..].to_vec(), score));
+            }
+        }
+        Ok(members)
+    }
+    /// Zero based position of `member` in `set` ordered by ascending score.
+    pub fn zrank(&mut self, set: &ByteStr, member: &ByteStr) -> io::Result<Option<u64>> {
+        let score = match self.zscore(set, member)? {
+            Some(score) => score,
+            None => return Ok(None),
+        };
+        let wanted = score_key(set, score, member);
+        let keys = self.keys_with_prefix(&namespaced_key(SCORE_PREFIX, set))?;
+        Ok(keys
+            .iter()
+            .position(|key| *key == wanted)
+            .map(|rank| rank as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCtx;
+    use rstest::*;
+    use serial_test::serial;
+
+    #[fixture]
+    fn ctx() -> TestCtx {
+        TestCtx::setup("test_zset")
+    }
+    #[rstest]
+    fn test_score_encoding_keeps_order() {
+        let scores = [f64::NEG_INFINITY, -10.5, -0.0, 0.0, 1.0, 2.5, 1e300];
+        for pair in scores.windows(2) {
+            assert!(encode_score(pair[0]) <= encode_score(pair[1]));
+            assert_eq!(decode_score(&encode_score(pair[0])), pair[0]);
+        }
+    }
+    #[rstest]
+    #[serial]
+    fn test_zadd_range_and_rank(mut ctx: TestCtx) {
+        let board = b"board";
+        ctx.test_file.zadd(board, b"alice", 30.0).unwrap();
+        ctx.test_file.zadd(board, b"bob", -5.0).unwrap();
+        ctx.test_file.zadd(board, b"carol", 12.5).unwrap();
+        ctx.test_file.zadd(b"other", b"dave", 1.0).unwrap();
+
+        let range = ctx.test_file.zrange_by_score(board, 0.0, 100.0).unwrap();
+        assert_eq!(
+            range,
+            vec![(b"carol".to_vec(), 12.5), (b"alice".to_vec(), 30.0)]
+        );
+        assert_eq!(ctx.test_file.zrank(board, b"bob").unwrap(), Some(0));
+        assert_eq!(ctx.test_file.zrank(board, b"alice").unwrap(), Some(2));
+
+        ctx.test_file.zadd(board, b"alice", -10.0).unwrap();
+        assert_eq!(ctx.test_file.zrank(board, b"alice").unwrap(), Some(0));
+        assert_eq!(
+            ctx.test_file
+                .zrange_by_score(board, f64::MIN, f64::MAX)
+                .unwrap()
+                .len(),
+            3
+        );
+        assert_eq!(ctx.test_file.zrank(board, b"dave").unwrap(), None);
+    }
+}